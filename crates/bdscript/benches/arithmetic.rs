@@ -0,0 +1,55 @@
+//! 整数运算快速路径的性能
+//!
+//! `cargo bench --bench arithmetic`，同一个运算的`fast`和`decimal`对比快速路径的提升
+#![feature(test, decl_macro)]
+
+extern crate test;
+
+use bdscript::{
+    eval::{int::fast_binary, interpreter::Interpreter},
+    parser::{parse, scanner::BinaryOp},
+};
+use rust_decimal::Decimal;
+use test::{Bencher, black_box};
+
+/// 整数运算密集的循环
+const INT_LOOP: &str = "sum = 0\nfor i in 0..1000:\n\tsum = (sum + i * 3 - 1) % 9973\n";
+
+#[bench]
+fn int_loop(b: &mut Bencher) {
+    let ast = parse(INT_LOOP).unwrap();
+    b.iter(|| {
+        let mut interpreter = Interpreter::new();
+        interpreter.run(black_box(&ast)).unwrap();
+    });
+}
+
+/// 对1000对整数做同一个运算
+fn bench_op(b: &mut Bencher, f: impl Fn(Decimal, Decimal) -> Option<Decimal>) {
+    let pairs: Vec<_> = (1..1000)
+        .map(|i| (Decimal::from(i * 37), Decimal::from(i % 13 + 1)))
+        .collect();
+    b.iter(|| {
+        for &(a, b) in &pairs {
+            black_box(f(black_box(a), black_box(b)));
+        }
+    });
+}
+
+macro compare($fast:ident, $decimal:ident, $op:expr, $method:ident) {
+    #[bench]
+    fn $fast(b: &mut Bencher) {
+        bench_op(b, |a, b| fast_binary(&$op, a, b));
+    }
+
+    #[bench]
+    fn $decimal(b: &mut Bencher) {
+        bench_op(b, |a, b| a.$method(b));
+    }
+}
+
+compare!(add_fast, add_decimal, BinaryOp::Add, checked_add);
+compare!(sub_fast, sub_decimal, BinaryOp::Sub, checked_sub);
+compare!(mul_fast, mul_decimal, BinaryOp::Mul, checked_mul);
+compare!(div_fast, div_decimal, BinaryOp::Div, checked_div);
+compare!(mod_fast, mod_decimal, BinaryOp::Mod, checked_rem);
@@ -0,0 +1,2143 @@
+//! 解释执行语法树
+pub mod builtin;
+pub mod env;
+pub mod error;
+pub mod generator;
+pub mod int;
+pub mod interpreter;
+#[cfg(feature = "serde_json")]
+pub mod json;
+pub mod operator;
+pub mod profile;
+#[cfg(feature = "serde")]
+pub mod save;
+pub mod state;
+pub mod testing;
+pub mod trace;
+pub mod value;
+mod vector;
+pub mod world;
+
+use std::{borrow::Cow, cmp::Ordering, collections::BTreeSet, vec};
+
+use rust_decimal::{Decimal, prelude::ToPrimitive};
+
+use crate::parser::scanner::{BinaryOp, Branch, Command, Expression, Object, UnaryOp};
+
+use self::{
+    builtin::{arity, call_builtin, is_builtin},
+    env::{Env, Function},
+    error::{Frame, RuntimeError},
+    generator::Resume,
+    int::fast_binary,
+    trace::{locate_command, locate_expression},
+    value::Value,
+    world::Entity,
+};
+
+/// 求值配置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvalConfig {
+    /// 运算结果超出数字能表示的范围时报[`RuntimeError::Overflow`]，
+    /// 默认尽量计算，溢出时取能表示的最大(最小)值
+    pub checked_arithmetic: bool,
+    /// 脚本函数最多的嵌套调用层数，超过时报[`RuntimeError::StackOverflow`]，
+    /// 避免脚本的无限递归耗尽宿主的栈
+    ///
+    /// 默认128层，在较小的线程栈上执行脚本时应当调小
+    pub max_call_depth: usize,
+    /// 统计每个脚本函数和状态块的调用次数和耗时，见[`profile`]
+    pub profile: bool,
+}
+
+impl Default for EvalConfig {
+    fn default() -> Self {
+        Self {
+            checked_arithmetic: false,
+            max_call_depth: 128,
+            profile: false,
+        }
+    }
+}
+
+/// 运算符的写法，用于报错
+const fn op_symbol(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Pow => "^",
+        BinaryOp::Mod => "%",
+        BinaryOp::Transform => "@",
+        BinaryOp::Equal => "==",
+        BinaryOp::NotEqual => "!=",
+        BinaryOp::Greater => ">",
+        BinaryOp::Less => "<",
+        BinaryOp::GreaterEqual => ">=",
+        BinaryOp::LessEqual => "<=",
+        BinaryOp::And => "&&",
+        BinaryOp::Or => "||",
+        BinaryOp::Merge => "|",
+        BinaryOp::Not => "!",
+        BinaryOp::Assign => "=",
+        BinaryOp::AddAssign => "+=",
+        BinaryOp::SubAssign => "-=",
+        BinaryOp::MulAssign => "*=",
+        BinaryOp::DivAssign => "/=",
+        BinaryOp::ModAssign => "%=",
+        BinaryOp::PowAssign => "^=",
+        BinaryOp::OrAssign => "|=",
+        BinaryOp::Index => "[]",
+        BinaryOp::Key => "{}",
+        BinaryOp::Dot => ".",
+        BinaryOp::Call => "()",
+        BinaryOp::Custom(_) => "自定义运算符",
+    }
+}
+
+/// 运算不支持这些类型
+fn mismatch(op: impl ToString, values: &[&Value]) -> RuntimeError {
+    RuntimeError::TypeMismatch {
+        op: op.to_string(),
+        types: values.iter().map(|value| value.type_name()).collect(),
+    }
+}
+
+/// 非负整数次幂，超出能表示的范围时返回None
+fn powu(base: Decimal, mut n: u64) -> Option<Decimal> {
+    let mut square = base;
+    let mut result = Decimal::ONE;
+    while n > 0 {
+        if n & 1 == 1 {
+            result = result.checked_mul(square)?;
+        }
+        n >>= 1;
+        if n > 0 {
+            square = square.checked_mul(square)?;
+        }
+    }
+    Some(result)
+}
+
+/// 整数次幂，结果的绝对值小于能表示的精度时是0，只有真正超出能表示的范围时才报
+/// [`RuntimeError::Overflow`]
+fn pow(base: Decimal, exponent: Decimal) -> Result<Decimal, RuntimeError> {
+    if !exponent.fract().is_zero() {
+        return Err(RuntimeError::Unsupported("非整数次幂".to_string()));
+    }
+    let negative = exponent.is_sign_negative();
+    // 0、1和-1的任意次幂都不用计算，指数再大也不会溢出
+    if base.is_zero() {
+        return match exponent.cmp(&Decimal::ZERO) {
+            Ordering::Less => Err(RuntimeError::DivByZero),
+            Ordering::Equal => Ok(Decimal::ONE),
+            Ordering::Greater => Ok(Decimal::ZERO),
+        };
+    }
+    if base.abs() == Decimal::ONE {
+        let odd = !(exponent % Decimal::TWO).is_zero();
+        return Ok(if base.is_sign_negative() && odd {
+            Decimal::NEGATIVE_ONE
+        } else {
+            Decimal::ONE
+        });
+    }
+    // 结果的绝对值是否随着指数的绝对值增大
+    let grows = (base.abs() > Decimal::ONE) != negative;
+    match exponent.abs().to_u64().and_then(|n| powu(base, n)) {
+        None if grows => Err(RuntimeError::Overflow),
+        None => Ok(Decimal::ZERO),
+        // 正数次幂太小舍入成了0，倒数超出范围
+        Some(result) if negative && result.is_zero() => Err(RuntimeError::Overflow),
+        Some(result) if negative => Decimal::ONE
+            .checked_div(result)
+            .ok_or(RuntimeError::Overflow),
+        Some(result) => Ok(result),
+    }
+}
+
+/// 计算两个值的二元运算(不包括短路运算和赋值)
+fn binary(
+    op: &BinaryOp,
+    lhs: Value,
+    rhs: Value,
+    config: EvalConfig,
+) -> Result<Value, RuntimeError> {
+    if let (Value::Number(a), Value::Number(b)) = (&lhs, &rhs)
+        && let Some(n) = fast_binary(op, *a, *b)
+    {
+        return Ok(Value::Number(n));
+    }
+    // 溢出时按配置报错，或者取结果符号方向上能表示的最大值
+    let saturate = |negative: bool| {
+        if config.checked_arithmetic {
+            Err(RuntimeError::Overflow)
+        } else if negative {
+            Ok(Value::Number(Decimal::MIN))
+        } else {
+            Ok(Value::Number(Decimal::MAX))
+        }
+    };
+    let number = |n: Option<Decimal>, negative: bool| {
+        n.map_or_else(|| saturate(negative), |n| Ok(Value::Number(n)))
+    };
+    match (op, lhs, rhs) {
+        (BinaryOp::Equal, lhs, rhs) => Ok(Value::Bool(lhs == rhs)),
+        (BinaryOp::NotEqual, lhs, rhs) => Ok(Value::Bool(lhs != rhs)),
+        (BinaryOp::Add, Value::Number(a), Value::Number(b)) => {
+            number(a.checked_add(b), a.is_sign_negative())
+        }
+        (BinaryOp::Sub, Value::Number(a), Value::Number(b)) => {
+            number(a.checked_sub(b), a.is_sign_negative())
+        }
+        (BinaryOp::Mul, Value::Number(a), Value::Number(b)) => number(
+            a.checked_mul(b),
+            a.is_sign_negative() != b.is_sign_negative(),
+        ),
+        (BinaryOp::Div | BinaryOp::Mod, Value::Number(_), Value::Number(b)) if b.is_zero() => {
+            Err(RuntimeError::DivByZero)
+        }
+        (BinaryOp::Div, Value::Number(a), Value::Number(b)) => number(
+            a.checked_div(b),
+            a.is_sign_negative() != b.is_sign_negative(),
+        ),
+        (BinaryOp::Mod, Value::Number(a), Value::Number(b)) => {
+            number(a.checked_rem(b), a.is_sign_negative())
+        }
+        (BinaryOp::Pow, Value::Number(a), Value::Number(b)) => match pow(a, b) {
+            Err(RuntimeError::Overflow) => {
+                let odd = (b % Decimal::TWO).abs() == Decimal::ONE;
+                saturate(a.is_sign_negative() && odd)
+            }
+            result => result.map(Value::Number),
+        },
+        // 带单位的数字，同一单位之间可以加减、相除(结果是数字)，和数字之间可以乘除
+        (
+            op @ (BinaryOp::Add | BinaryOp::Sub),
+            Value::Quantity(a, unit),
+            Value::Quantity(b, other),
+        ) if unit == other => {
+            with_unit(binary(op, Value::Number(a), Value::Number(b), config), unit)
+        }
+        (BinaryOp::Div, Value::Quantity(a, unit), Value::Quantity(b, other)) if unit == other => {
+            binary(&BinaryOp::Div, Value::Number(a), Value::Number(b), config)
+        }
+        (op @ (BinaryOp::Mul | BinaryOp::Div), Value::Quantity(a, unit), Value::Number(b)) => {
+            with_unit(binary(op, Value::Number(a), Value::Number(b), config), unit)
+        }
+        (BinaryOp::Mul, Value::Number(a), Value::Quantity(b, unit)) => with_unit(
+            binary(&BinaryOp::Mul, Value::Number(a), Value::Number(b), config),
+            unit,
+        ),
+        // 字符串加上任何值都会把这个值转换成字符串
+        (BinaryOp::Add, Value::Str(a), rhs) => Ok(Value::Str(format!("{a}{rhs}"))),
+        (BinaryOp::Add, Value::Array(mut a), Value::Array(b)) => {
+            a.extend(b);
+            Ok(Value::Array(a))
+        }
+        (
+            op
+            @ (BinaryOp::Greater | BinaryOp::Less | BinaryOp::GreaterEqual | BinaryOp::LessEqual),
+            lhs,
+            rhs,
+        ) => {
+            let ordering = compare(&lhs, &rhs, op_symbol(op))?;
+            Ok(Value::Bool(match op {
+                BinaryOp::Greater => ordering.is_gt(),
+                BinaryOp::Less => ordering.is_lt(),
+                BinaryOp::GreaterEqual => ordering.is_ge(),
+                _ => ordering.is_le(),
+            }))
+        }
+        (op, lhs @ Value::Duration(_), rhs) | (op, lhs, rhs @ Value::Duration(_)) => {
+            duration_binary(op, &lhs, &rhs, config)
+        }
+        (op, lhs @ (Value::Vec2(_) | Value::Vec3(_)), rhs)
+        | (op, lhs, rhs @ (Value::Vec2(_) | Value::Vec3(_))) => vector::binary(op, &lhs, &rhs),
+        (op, lhs, rhs) => Err(mismatch(op_symbol(op), &[&lhs, &rhs])),
+    }
+}
+
+/// 时长的运算：时长之间可以加减、取模，相除得到数字，和数字之间可以乘除；
+/// 时间单位的带单位的数字(例如`2s`)按时长计算，和没有单位的数字相加减时需要先用`seconds`转换
+fn duration_binary(
+    op: &BinaryOp,
+    lhs: &Value,
+    rhs: &Value,
+    config: EvalConfig,
+) -> Result<Value, RuntimeError> {
+    let number = |a: Decimal, b: Decimal| binary(op, Value::Number(a), Value::Number(b), config);
+    let duration = |result: Result<Value, RuntimeError>| {
+        result.map(|value| match value {
+            Value::Number(n) => Value::Duration(n),
+            value => value,
+        })
+    };
+    match (op, lhs, rhs, lhs.as_duration(), rhs.as_duration()) {
+        (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mod, _, _, Some(a), Some(b)) => {
+            duration(number(a, b))
+        }
+        (BinaryOp::Div, _, _, Some(a), Some(b)) => number(a, b),
+        (BinaryOp::Mul | BinaryOp::Div, _, Value::Number(b), Some(a), _)
+        | (BinaryOp::Mul, Value::Number(b), _, _, Some(a)) => duration(number(a, *b)),
+        _ => Err(mismatch(op_symbol(op), &[lhs, rhs])),
+    }
+}
+
+/// 给数字运算的结果加上单位
+fn with_unit(result: Result<Value, RuntimeError>, unit: String) -> Result<Value, RuntimeError> {
+    result.map(|value| match value {
+        Value::Number(n) => Value::Quantity(n, unit),
+        value => value,
+    })
+}
+
+/// 字典合并`a | b`，右边的同名键覆盖左边的
+fn merge(lhs: Value, rhs: Value) -> Result<Value, RuntimeError> {
+    match (lhs, rhs) {
+        (Value::Dict(mut a), Value::Dict(b)) => {
+            for (key, value) in b {
+                match a.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, v)) => *v = value,
+                    None => a.push((key, value)),
+                }
+            }
+            Ok(Value::Dict(a))
+        }
+        (lhs, rhs) => Err(mismatch("|", &[&lhs, &rhs])),
+    }
+}
+
+/// 比较两个值的大小，只有数字、单位相同的带单位的数字和字符串之间可以比较；
+/// 时长可以和时长、时间单位的带单位的数字比较，不能直接和数字比较
+fn compare(lhs: &Value, rhs: &Value, op: &str) -> Result<Ordering, RuntimeError> {
+    match (lhs, rhs) {
+        (Value::Number(a), Value::Number(b)) => Ok(a.cmp(b)),
+        (Value::Quantity(a, unit), Value::Quantity(b, other)) if unit == other => Ok(a.cmp(b)),
+        (Value::Str(a), Value::Str(b)) => Ok(a.cmp(b)),
+        (Value::Duration(_), _) | (_, Value::Duration(_)) => {
+            match (lhs.as_duration(), rhs.as_duration()) {
+                (Some(a), Some(b)) => Ok(a.cmp(&b)),
+                _ => Err(mismatch(op, &[lhs, rhs])),
+            }
+        }
+        _ => Err(mismatch(op, &[lhs, rhs])),
+    }
+}
+
+/// 复合赋值对应的运算
+const fn compound_op(op: &BinaryOp) -> Option<BinaryOp> {
+    match op {
+        BinaryOp::AddAssign => Some(BinaryOp::Add),
+        BinaryOp::SubAssign => Some(BinaryOp::Sub),
+        BinaryOp::MulAssign => Some(BinaryOp::Mul),
+        BinaryOp::DivAssign => Some(BinaryOp::Div),
+        BinaryOp::ModAssign => Some(BinaryOp::Mod),
+        BinaryOp::PowAssign => Some(BinaryOp::Pow),
+        _ => None,
+    }
+}
+
+/// 赋值的目标，目前只能是变量
+fn assign_target<'e>(target: &'e Expression<'_>) -> Result<&'e str, RuntimeError> {
+    match target {
+        Expression::Object(Object::Variable(name)) => Ok(name),
+        _ => Err(RuntimeError::Unsupported(
+            "给变量以外的表达式赋值".to_string(),
+        )),
+    }
+}
+
+/// 读取变量，不存在时报错
+fn read(env: &Env<'_>, name: &str) -> Result<Value, RuntimeError> {
+    env.get(name)
+        .cloned()
+        .ok_or_else(|| RuntimeError::Undefined(name.to_string()))
+}
+
+/// 计算变量，没有这个变量但是有同名的函数时得到函数值
+fn variable(env: &Env<'_>, name: &str) -> Result<Value, RuntimeError> {
+    match env.get(name) {
+        Some(value) => Ok(value.clone()),
+        None if env.has_fn(name) || is_builtin(name) => Ok(Value::Function(name.to_string())),
+        None => Err(RuntimeError::Undefined(name.to_string())),
+    }
+}
+
+/// 给变量赋值，变量不存在时在当前作用域定义
+fn assign(env: &mut Env<'_>, name: &str, value: Value) -> Result<(), RuntimeError> {
+    if env.get(name).is_some() {
+        env.set(name, value)
+    } else {
+        env.define(name, value);
+        Ok(())
+    }
+}
+
+/// 把索引转换成下标，负数从末尾开始计算
+fn offset(index: Decimal, len: usize) -> Result<usize, RuntimeError> {
+    let out_of_bounds = || RuntimeError::IndexOutOfBounds { index, len };
+    if !index.fract().is_zero() {
+        return Err(out_of_bounds());
+    }
+    let i = index.to_i64().ok_or_else(out_of_bounds)?;
+    let i = if i < 0 { i + len as i64 } else { i };
+    usize::try_from(i)
+        .ok()
+        .filter(|&i| i < len)
+        .ok_or_else(out_of_bounds)
+}
+
+/// 在字典中查找键，没有这个键时返回null
+fn lookup(dict: Vec<(Value, Value)>, key: &Value) -> Value {
+    dict.into_iter()
+        .find(|(k, _)| k == key)
+        .map_or(Value::Nil, |(_, v)| v)
+}
+
+/// 索引运算`a[i]`
+fn index(value: Value, index: Value) -> Result<Value, RuntimeError> {
+    match (value, index) {
+        (Value::Array(items) | Value::Tuple(items), Value::Number(i)) => {
+            let i = offset(i, items.len())?;
+            Ok(items.into_iter().nth(i).unwrap_or(Value::Nil))
+        }
+        (Value::Str(s), Value::Number(i)) => {
+            let i = offset(i, s.chars().count())?;
+            Ok(Value::Str(s.chars().nth(i).into_iter().collect()))
+        }
+        (Value::Dict(dict), key) => Ok(lookup(dict, &key)),
+        (value, index) => Err(mismatch(op_symbol(&BinaryOp::Index), &[&value, &index])),
+    }
+}
+
+/// 取出`a[i]`、`a{k}`中括号里唯一的表达式
+fn single<'e, 'a>(
+    op: &BinaryOp,
+    items: &'e [Expression<'a>],
+) -> Result<&'e Expression<'a>, RuntimeError> {
+    match items {
+        [item] => Ok(item),
+        _ => Err(RuntimeError::Unsupported(format!(
+            "{}中有多个值",
+            op_symbol(op)
+        ))),
+    }
+}
+
+/// 查询同时拥有with中所有组件、并且没有without中任何组件的实体
+fn query(with: &[&str], without: &[&str], env: &Env<'_>) -> Result<BTreeSet<Entity>, RuntimeError> {
+    let world = env
+        .world()
+        .ok_or_else(|| RuntimeError::Unsupported("没有提供世界的查询".to_string()))?;
+    let resolve = |names: &[&str]| {
+        names
+            .iter()
+            .map(|name| {
+                world
+                    .component_id(name)
+                    .ok_or_else(|| RuntimeError::UnknownComponent(name.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let with = resolve(with)?;
+    let without = resolve(without)?;
+    Ok(world.query(&with, &without).into_iter().collect())
+}
+
+/// 可以遍历的值中的元素，实体集合按实体编号从小到大给出
+fn elements(value: Value, op: &str) -> Result<Vec<Value>, RuntimeError> {
+    match value {
+        Value::Array(items) | Value::Tuple(items) => Ok(items),
+        Value::EntitySet(entities) => Ok(entities
+            .into_iter()
+            .map(|entity| Value::Number(Decimal::from(entity)))
+            .collect()),
+        value => Err(mismatch(op, &[&value])),
+    }
+}
+
+/// 把值转换成实体，实体就是非负整数编号
+fn to_entity(value: &Value, op: &str) -> Result<Entity, RuntimeError> {
+    match value {
+        Value::Number(n) if n.fract().is_zero() => n.to_u64().ok_or_else(|| mismatch(op, &[value])),
+        value => Err(mismatch(op, &[value])),
+    }
+}
+
+/// 查询的of，parent可以是一个实体，也可以是实体的集合或数组
+fn children(parent: Value, env: &Env<'_>) -> Result<BTreeSet<Entity>, RuntimeError> {
+    let world = env
+        .world()
+        .ok_or_else(|| RuntimeError::Unsupported("没有提供世界的查询".to_string()))?;
+    let parents = match parent {
+        Value::Number(_) => vec![parent],
+        parent => elements(parent, "of")?,
+    };
+    let mut children = BTreeSet::new();
+    for parent in &parents {
+        children.extend(world.children(to_entity(parent, "of")?));
+    }
+    Ok(children)
+}
+
+/// 查询的limit，必须是非负整数
+fn query_limit(limit: Value) -> Result<usize, RuntimeError> {
+    match limit {
+        Value::Number(n) if n.fract().is_zero() && !n.is_sign_negative() => {
+            Ok(n.to_usize().unwrap_or(usize::MAX))
+        }
+        Value::Number(n) => Err(RuntimeError::Unsupported(format!(
+            "limit必须是非负整数，实际是{}",
+            n.normalize()
+        ))),
+        limit => Err(mismatch("limit", &[&limit])),
+    }
+}
+
+/// 按order_by对每个实体计算出的值从小到大排序，值相同的实体保持编号顺序
+///
+/// order_by只写一个函数名时用实体调用这个函数，否则在新的作用域中
+/// 把实体绑定到变量`entity`上计算表达式
+fn sort_entities(
+    entities: BTreeSet<Entity>,
+    order_by: &Expression<'_>,
+    env: &mut Env<'_>,
+) -> Result<Vec<Entity>, RuntimeError> {
+    let mut keyed = vec![];
+    for entity in entities {
+        let id = Value::Number(Decimal::from(entity));
+        let key = match order_by {
+            Expression::Object(Object::Variable(f)) if env.get(f).is_none() => {
+                call(f, vec![id], env)?
+            }
+            order_by => {
+                env.push_scope();
+                env.define("entity", id);
+                let key = eval_expression(order_by, env);
+                env.pop_scope();
+                key?
+            }
+        };
+        keyed.push((key, entity));
+    }
+    let mut error = None;
+    keyed.sort_by(|(a, _), (b, _)| {
+        compare(a, b, "order_by").unwrap_or_else(|e| {
+            error.get_or_insert(e);
+            Ordering::Equal
+        })
+    });
+    if let Some(error) = error {
+        return Err(error);
+    }
+    Ok(keyed.into_iter().map(|(_, entity)| entity).collect())
+}
+
+/// 调用函数，依次查找宿主函数、脚本中定义的函数和内置函数，
+/// 脚本函数的参数绑定在新的作用域中
+fn call<'a>(name: &str, args: Vec<Value>, env: &mut Env<'a>) -> Result<Value, RuntimeError> {
+    if let Some(native) = env.get_native(name) {
+        return native.call(&args);
+    }
+    let Some(function) = env.get_fn(name) else {
+        return call_builtin(name, &args, env)
+            .unwrap_or_else(|| Err(RuntimeError::UndefinedFn(name.to_string())));
+    };
+    arity(name, &args, function.args.len())?;
+    let caller = env.enter_call()?;
+    env.push_frame(Frame::Function(name.to_string()));
+    for (arg, value) in function.args.iter().zip(args) {
+        env.define(arg, value);
+    }
+    let value = with_defers(env, |env| exec_body(function.commands, env));
+    if value.is_err() {
+        // 在离开这一层之前记下调用栈
+        env.trace_error(None);
+    }
+    env.pop_frame();
+    env.leave_call(caller);
+    value
+}
+
+/// 执行函数体，没有执行到`return`时，最后一条语句如果是表达式(赋值除外)，它的值就是返回值，
+/// 否则返回[`Value::Unit`]
+fn exec_body<'a>(commands: &'a [Command<'a>], env: &mut Env<'a>) -> Result<Value, RuntimeError> {
+    // 结尾的空行不算语句
+    let end = commands
+        .iter()
+        .rposition(|command| !matches!(command, Command::NewLine))
+        .map_or(0, |i| i + 1);
+    let (body, tail) = match commands[..end].split_last() {
+        Some((Command::Expression(expression), body)) if !is_assignment(expression) => {
+            (body, Some(expression))
+        }
+        _ => (commands, None),
+    };
+    match exec_block(body, env)? {
+        Flow::Return(value) => Ok(value),
+        _ => tail.map_or(Ok(Value::Unit), |expression| {
+            eval_expression(expression, env)
+        }),
+    }
+}
+
+/// 是否是赋值(包括复合赋值)
+const fn is_assignment(expression: &Expression<'_>) -> bool {
+    matches!(
+        expression,
+        Expression::Binary {
+            op: BinaryOp::Assign
+                | BinaryOp::AddAssign
+                | BinaryOp::SubAssign
+                | BinaryOp::MulAssign
+                | BinaryOp::DivAssign
+                | BinaryOp::ModAssign
+                | BinaryOp::PowAssign
+                | BinaryOp::OrAssign,
+            ..
+        }
+    )
+}
+
+/// 计算条件，按[`Value::is_truthy`]判断真假
+fn eval_condition(expression: &Expression<'_>, env: &mut Env<'_>) -> Result<bool, RuntimeError> {
+    eval_expression(expression, env).map(|value| value.is_truthy())
+}
+
+/// 依次计算多个表达式
+fn eval_all(expressions: &[Expression<'_>], env: &mut Env<'_>) -> Result<Vec<Value>, RuntimeError> {
+    let mut values = vec![];
+    for expression in expressions {
+        match expression {
+            Expression::SpreadIf { condition, value } => {
+                if eval_condition(condition, env)? {
+                    values.push(eval_expression(value, env)?);
+                }
+            }
+            expression => values.push(eval_expression(expression, env)?),
+        }
+    }
+    Ok(values)
+}
+
+/// 计算对象的值
+fn eval_object(object: &Object<'_>, env: &mut Env<'_>) -> Result<Value, RuntimeError> {
+    match object {
+        Object::Constant(n) => Ok(Value::Number(*n)),
+        Object::Bool(b) => Ok(Value::Bool(*b)),
+        Object::Str(s) => Ok(Value::Str(s.to_string())),
+        Object::Variable(name) => variable(env, name),
+        Object::FString(parts) => Ok(Value::Str(
+            eval_all(parts, env)?
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        )),
+        Object::Tuple(items) => Ok(Value::Tuple(eval_all(items, env)?)),
+        Object::Array(items) => Ok(Value::Array(eval_all(items, env)?)),
+        Object::Dict(items) => {
+            let mut dict: Vec<(Value, Value)> = vec![];
+            for item in items {
+                let item = match item {
+                    Expression::SpreadIf { condition, value } => {
+                        if !eval_condition(condition, env)? {
+                            continue;
+                        }
+                        value.as_ref()
+                    }
+                    item => item,
+                };
+                let Expression::Object(Object::DictItem(key, value)) = item else {
+                    return Err(RuntimeError::Unsupported(
+                        "字典中不是键值对的项".to_string(),
+                    ));
+                };
+                let key = eval_expression(key, env)?;
+                let value = eval_expression(value, env)?;
+                // 重复的键保留后面的值
+                match dict.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, v)) => *v = value,
+                    None => dict.push((key, value)),
+                }
+            }
+            Ok(Value::Dict(dict))
+        }
+        Object::DictItem(..) => Err(RuntimeError::Unsupported("字典之外的键值对".to_string())),
+        Object::Quantity(n, unit) => Ok(Value::Quantity(*n, unit.to_string())),
+        Object::Symbol(name) => Ok(Value::Symbol(name.to_string())),
+        Object::Color(color) => Ok(Value::Color(*color)),
+    }
+}
+
+/// 计算表达式的值
+///
+/// 脚本函数每递归一层都要经过几层这个函数，各种表达式的计算都放在单独的函数中，
+/// 让这里的栈帧保持很小，未优化的构建中也不会很快耗尽栈
+///
+/// 出错时记录出错的位置，见[`trace`]
+pub fn eval_expression(
+    expression: &Expression<'_>,
+    env: &mut Env<'_>,
+) -> Result<Value, RuntimeError> {
+    let result = eval_node(expression, env);
+    if result.is_err() {
+        locate_expression(expression, env);
+    }
+    result
+}
+
+/// 按表达式的种类分派，见[`eval_expression`]
+fn eval_node(expression: &Expression<'_>, env: &mut Env<'_>) -> Result<Value, RuntimeError> {
+    match expression {
+        Expression::Object(object) => eval_object(object, env),
+        Expression::Priority(expression) => eval_expression(expression, env),
+        Expression::Unary {
+            op: op @ (UnaryOp::Increment | UnaryOp::Decrement),
+            hs,
+        } => eval_step(op, hs, env),
+        Expression::Unary {
+            op: UnaryOp::Spread,
+            ..
+        } => Err(RuntimeError::Unsupported("多重赋值之外的*变量".to_string())),
+        Expression::Unary { op, hs } => eval_unary(op, hs, env),
+        Expression::Binary {
+            op: op @ (BinaryOp::And | BinaryOp::Or),
+            lhs,
+            rhs,
+        } => eval_logic(op, lhs, rhs, env),
+        Expression::Binary {
+            op: op @ (BinaryOp::Index | BinaryOp::Key),
+            lhs,
+            rhs,
+        } => eval_access(op, lhs, rhs, env),
+        Expression::Binary {
+            op: BinaryOp::Call,
+            lhs,
+            rhs,
+        } => eval_call(lhs, rhs, env),
+        Expression::Binary {
+            op: BinaryOp::Dot,
+            lhs,
+            rhs,
+        } => eval_field(lhs, rhs, env),
+        Expression::Binary {
+            op:
+                op @ (BinaryOp::Assign
+                | BinaryOp::AddAssign
+                | BinaryOp::SubAssign
+                | BinaryOp::MulAssign
+                | BinaryOp::DivAssign
+                | BinaryOp::ModAssign
+                | BinaryOp::PowAssign
+                | BinaryOp::OrAssign),
+            lhs,
+            rhs,
+        } => eval_assign(op, lhs, rhs, env),
+        Expression::Binary {
+            op:
+                op @ (BinaryOp::Add
+                | BinaryOp::Sub
+                | BinaryOp::Mul
+                | BinaryOp::Div
+                | BinaryOp::Pow
+                | BinaryOp::Mod
+                | BinaryOp::Transform
+                | BinaryOp::Merge
+                | BinaryOp::Equal
+                | BinaryOp::NotEqual
+                | BinaryOp::Greater
+                | BinaryOp::Less
+                | BinaryOp::GreaterEqual
+                | BinaryOp::LessEqual),
+            lhs,
+            rhs,
+        } => eval_binary(op, lhs, rhs, env),
+        Expression::Binary {
+            op: BinaryOp::Custom(index),
+            lhs,
+            rhs,
+        } => eval_custom(*index, lhs, rhs, env),
+        Expression::Binary { op, .. } => {
+            Err(RuntimeError::Unsupported(format!("{}运算", op_symbol(op))))
+        }
+        Expression::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => {
+            if eval_condition(cond, env)? {
+                eval_expression(then, env)
+            } else {
+                eval_expression(otherwise, env)
+            }
+        }
+        Expression::Format { value, spec } => {
+            eval_expression(value, env)?.format(spec).map(Value::Str)
+        }
+        Expression::SpreadIf { .. } => Err(RuntimeError::Unsupported(
+            "数组和字典之外的条件展开".to_string(),
+        )),
+        Expression::Range { .. } => Err(RuntimeError::Unsupported("范围".to_string())),
+        Expression::Query {
+            with_compoents,
+            without_compoents,
+            hierarchy,
+            order_by,
+            limit,
+        } => eval_query(
+            (with_compoents, without_compoents),
+            hierarchy.as_deref(),
+            order_by.as_deref(),
+            limit.as_deref(),
+            env,
+        ),
+    }
+}
+
+/// 自增/自减，返回修改之前的值
+fn eval_step(op: &UnaryOp, hs: &Expression<'_>, env: &mut Env<'_>) -> Result<Value, RuntimeError> {
+    let name = assign_target(hs)?;
+    let value = read(env, name)?;
+    let step = if matches!(op, UnaryOp::Increment) {
+        BinaryOp::Add
+    } else {
+        BinaryOp::Sub
+    };
+    let next = binary(
+        &step,
+        value.clone(),
+        Value::Number(Decimal::ONE),
+        env.config(),
+    )?;
+    env.set(name, next)?;
+    Ok(value)
+}
+
+/// 前缀运算
+fn eval_unary(op: &UnaryOp, hs: &Expression<'_>, env: &mut Env<'_>) -> Result<Value, RuntimeError> {
+    match (op, eval_expression(hs, env)?) {
+        (UnaryOp::Plus, Value::Number(n)) => Ok(Value::Number(n)),
+        (UnaryOp::Minus, Value::Number(n)) => Ok(Value::Number(-n)),
+        (UnaryOp::Plus, Value::Quantity(n, unit)) => Ok(Value::Quantity(n, unit)),
+        (UnaryOp::Minus, Value::Quantity(n, unit)) => Ok(Value::Quantity(-n, unit)),
+        (UnaryOp::Plus, Value::Duration(n)) => Ok(Value::Duration(n)),
+        (UnaryOp::Minus, Value::Duration(n)) => Ok(Value::Duration(-n)),
+        (UnaryOp::Plus, value @ (Value::Vec2(_) | Value::Vec3(_))) => Ok(value),
+        (UnaryOp::Minus, Value::Vec2(v)) => Ok(Value::Vec2(-v)),
+        (UnaryOp::Minus, Value::Vec3(v)) => Ok(Value::Vec3(-v)),
+        (UnaryOp::Not, value) => Ok(Value::Bool(!value.is_truthy())),
+        (UnaryOp::Plus | UnaryOp::Minus, value) => {
+            let symbol = if matches!(op, UnaryOp::Plus) {
+                "+"
+            } else {
+                "-"
+            };
+            Err(mismatch(symbol, &[&value]))
+        }
+        (op, _) => Err(RuntimeError::Unsupported(format!("{op:?}运算"))),
+    }
+}
+
+/// 短路运算，两边按[`Value::is_truthy`]判断真假
+fn eval_logic(
+    op: &BinaryOp,
+    lhs: &Expression<'_>,
+    rhs: &Expression<'_>,
+    env: &mut Env<'_>,
+) -> Result<Value, RuntimeError> {
+    let lhs = eval_condition(lhs, env)?;
+    if lhs == matches!(op, BinaryOp::Or) {
+        Ok(Value::Bool(lhs))
+    } else {
+        eval_condition(rhs, env).map(Value::Bool)
+    }
+}
+
+/// 索引和取值，`a[i]`的括号被解析成数组，`a{k}`的括号被解析成字典
+fn eval_access(
+    op: &BinaryOp,
+    lhs: &Expression<'_>,
+    rhs: &Expression<'_>,
+    env: &mut Env<'_>,
+) -> Result<Value, RuntimeError> {
+    match (op, rhs) {
+        (BinaryOp::Index, Expression::Object(Object::Array(items))) => {
+            let value = eval_expression(lhs, env)?;
+            let i = eval_expression(single(op, items)?, env)?;
+            index(value, i)
+        }
+        (BinaryOp::Key, Expression::Object(Object::Dict(items))) => {
+            let value = eval_expression(lhs, env)?;
+            let key = eval_expression(single(op, items)?, env)?;
+            match value {
+                Value::Dict(dict) => Ok(lookup(dict, &key)),
+                value => Err(mismatch(op_symbol(op), &[&value, &key])),
+            }
+        }
+        (BinaryOp::Index, _) => Err(RuntimeError::Unsupported("索引".to_string())),
+        _ => Err(RuntimeError::Unsupported("取值".to_string())),
+    }
+}
+
+/// 函数调用
+fn eval_call(
+    lhs: &Expression<'_>,
+    rhs: &Expression<'_>,
+    env: &mut Env<'_>,
+) -> Result<Value, RuntimeError> {
+    // 方法调用`v.length()`等价于`length(v)`
+    if let Expression::Binary {
+        op: BinaryOp::Dot,
+        lhs: receiver,
+        rhs: method,
+    } = lhs
+        && let Expression::Object(Object::Variable(method)) = method.as_ref()
+    {
+        let mut args = vec![eval_expression(receiver, env)?];
+        args.extend(eval_args(rhs, env)?);
+        return call(method, args, env);
+    }
+    let name: Cow<str> = match lhs {
+        // 保存着函数值的变量(例如作为参数传入的函数)调用它引用的函数，其他变量名按函数名调用
+        Expression::Object(Object::Variable(name)) => match env.get(name) {
+            Some(Value::Function(f)) => Cow::Owned(f.clone()),
+            _ => Cow::Borrowed(name),
+        },
+        lhs => match eval_expression(lhs, env)? {
+            Value::Function(f) => Cow::Owned(f),
+            value => return Err(mismatch("()", &[&value])),
+        },
+    };
+    let args = eval_args(rhs, env)?;
+    call(&name, args, env)
+}
+
+/// 计算调用的参数
+fn eval_args(rhs: &Expression<'_>, env: &mut Env<'_>) -> Result<Vec<Value>, RuntimeError> {
+    match rhs {
+        Expression::Object(Object::Tuple(items)) => eval_all(items, env),
+        rhs => Ok(vec![eval_expression(rhs, env)?]),
+    }
+}
+
+/// 取出字段`v.x`，目前只有向量有字段
+fn eval_field(
+    lhs: &Expression<'_>,
+    rhs: &Expression<'_>,
+    env: &mut Env<'_>,
+) -> Result<Value, RuntimeError> {
+    let value = eval_expression(lhs, env)?;
+    match rhs {
+        Expression::Object(Object::Variable(name)) => vector::field(&value, name),
+        _ => Err(mismatch(op_symbol(&BinaryOp::Dot), &[&value])),
+    }
+}
+
+/// 赋值，返回赋给变量的值
+fn eval_assign(
+    op: &BinaryOp,
+    lhs: &Expression<'_>,
+    rhs: &Expression<'_>,
+    env: &mut Env<'_>,
+) -> Result<Value, RuntimeError> {
+    if let (BinaryOp::Assign, Expression::Object(Object::Tuple(targets))) = (op, lhs) {
+        let value = eval_expression(rhs, env)?;
+        destructure(targets, value.clone(), env)?;
+        return Ok(value);
+    }
+    let name = assign_target(lhs)?;
+    let value = eval_expression(rhs, env)?;
+    let value = match op {
+        BinaryOp::Assign => value,
+        op => compound(op, read(env, name)?, value, env.config())?,
+    };
+    assign(env, name, value.clone())?;
+    Ok(value)
+}
+
+/// 多重赋值，两端的变量依次绑定两端的元素，`*变量`绑定中间剩下的元素组成的数组
+fn destructure(
+    targets: &[Expression<'_>],
+    value: Value,
+    env: &mut Env<'_>,
+) -> Result<(), RuntimeError> {
+    let star = |target: &Expression<'_>| {
+        matches!(
+            target,
+            Expression::Unary {
+                op: UnaryOp::Spread,
+                ..
+            }
+        )
+    };
+    let rest = targets.iter().position(star);
+    if targets.iter().filter(|target| star(target)).count() > 1 {
+        return Err(RuntimeError::Unsupported(
+            "多重赋值中的多个*变量".to_string(),
+        ));
+    }
+    let mut items = elements(value, "=")?;
+    let expected = targets.len() - usize::from(rest.is_some());
+    if items.len() < expected || (rest.is_none() && items.len() > expected) {
+        return Err(RuntimeError::Destructure {
+            expected,
+            found: items.len(),
+            rest: rest.is_some(),
+        });
+    }
+    let (head, tail) = targets.split_at(rest.unwrap_or(targets.len()));
+    let tail = tail.get(1..).unwrap_or_default();
+    let tail_items = items.split_off(items.len() - tail.len());
+    let middle = items.split_off(head.len());
+    for (target, item) in head.iter().zip(items).chain(tail.iter().zip(tail_items)) {
+        assign(env, assign_target(target)?, item)?;
+    }
+    if let Some(Expression::Unary { hs, .. }) = rest.map(|i| &targets[i]) {
+        assign(env, assign_target(hs)?, Value::Array(middle))?;
+    }
+    Ok(())
+}
+
+/// 复合赋值的运算
+fn compound(
+    op: &BinaryOp,
+    current: Value,
+    value: Value,
+    config: EvalConfig,
+) -> Result<Value, RuntimeError> {
+    match compound_op(op) {
+        Some(op) => binary(&op, current, value, config),
+        None => merge(current, value),
+    }
+}
+
+/// 先计算两边再运算的二元运算
+fn eval_binary(
+    op: &BinaryOp,
+    lhs: &Expression<'_>,
+    rhs: &Expression<'_>,
+    env: &mut Env<'_>,
+) -> Result<Value, RuntimeError> {
+    let lhs = eval_expression(lhs, env)?;
+    let rhs = eval_expression(rhs, env)?;
+    match op {
+        BinaryOp::Merge => merge(lhs, rhs),
+        op => binary(op, lhs, rhs, env.config()),
+    }
+}
+
+/// 宿主登记的自定义运算符，两边都计算之后交给登记的实现
+fn eval_custom(
+    index: usize,
+    lhs: &Expression<'_>,
+    rhs: &Expression<'_>,
+    env: &mut Env<'_>,
+) -> Result<Value, RuntimeError> {
+    let lhs = eval_expression(lhs, env)?;
+    let rhs = eval_expression(rhs, env)?;
+    env.operators().apply(index, lhs, rhs)
+}
+
+/// 执行查询，依次处理of、order_by和limit
+///
+fn eval_query(
+    (with, without): (&[&str], &[&str]),
+    hierarchy: Option<&Expression<'_>>,
+    order_by: Option<&Expression<'_>>,
+    limit: Option<&Expression<'_>>,
+    env: &mut Env<'_>,
+) -> Result<Value, RuntimeError> {
+    let mut entities = query(with, without, env)?;
+    if let Some(parent) = hierarchy {
+        let children = children(eval_expression(parent, env)?, env)?;
+        entities.retain(|entity| children.contains(entity));
+    }
+    let limit = match limit {
+        Some(limit) => query_limit(eval_expression(limit, env)?)?,
+        None => usize::MAX,
+    };
+    match order_by {
+        // 排序之后的结果是按顺序排列的实体数组
+        Some(order_by) => {
+            let mut entities = sort_entities(entities, order_by, env)?;
+            entities.truncate(limit);
+            Ok(Value::Array(
+                entities
+                    .into_iter()
+                    .map(|entity| Value::Number(Decimal::from(entity)))
+                    .collect(),
+            ))
+        }
+        None => Ok(Value::EntitySet(entities.into_iter().take(limit).collect())),
+    }
+}
+
+/// 执行完一条语句之后如何继续
+#[derive(Debug, Clone, PartialEq)]
+pub enum Flow<'a> {
+    /// 继续执行下一条语句
+    Next,
+    /// 跳出所在的循环，带标签时一直跳出到标签对应的循环
+    Break(Option<&'a str>),
+    /// 跳过本次循环剩下的语句，带标签时继续标签对应的循环
+    Continue(Option<&'a str>),
+    /// 从函数返回
+    Return(Value),
+    /// 生成器执行到`yield`，挂起并交出值，见[`Generator`](generator::Generator)
+    Yield(Value),
+}
+
+/// 依次执行块内的语句，遇到`break`/`continue`/`return`时提前结束
+///
+/// 其中注册的defer语句由外层的[`exec_scope`]执行
+fn exec_block<'a>(
+    commands: &'a [Command<'a>],
+    env: &mut Env<'a>,
+) -> Result<Flow<'a>, RuntimeError> {
+    let start = match env.resume() {
+        Some(Resume::Block(start)) => start,
+        _ => 0,
+    };
+    for (i, command) in commands.iter().enumerate().skip(start) {
+        match exec_command(command, env)? {
+            Flow::Next => {}
+            Flow::Yield(value) => {
+                // yield本身从下一条语句继续，其他语句回到块内挂起的位置
+                let resume = if matches!(command, Command::Yield(_)) {
+                    i + 1
+                } else {
+                    i
+                };
+                env.suspend(Resume::Block(resume));
+                return Ok(Flow::Yield(value));
+            }
+            flow => return Ok(flow),
+        }
+    }
+    Ok(Flow::Next)
+}
+
+/// 执行脚本并返回最后一条语句的值，规则和函数的返回值相同：
+/// 最后一条语句是赋值、`if`、`while`等语句时返回[`Value::Unit`]，顶层的defer语句在最后执行
+pub fn eval_program<'a>(
+    commands: &'a [Command<'a>],
+    env: &mut Env<'a>,
+) -> Result<Value, RuntimeError> {
+    with_defers(env, |env| exec_body(commands, env))
+}
+
+/// 执行函数或状态块的语句，退出时(包括提前return和出错)按注册的逆序执行其中的defer语句
+///
+/// 块内的语句出错时返回这个错误，否则返回第一个出错的defer语句的错误
+pub fn exec_scope<'a>(
+    commands: &'a [Command<'a>],
+    env: &mut Env<'a>,
+) -> Result<Flow<'a>, RuntimeError> {
+    with_defers(env, |env| exec_block(commands, env))
+}
+
+/// 从上次挂起的位置继续执行生成器的脚本，执行到`yield`时返回交出的值；
+/// 执行完时返回None，这时才执行顶层的defer语句
+pub(crate) fn resume_program<'a>(
+    commands: &'a [Command<'a>],
+    env: &mut Env<'a>,
+) -> Result<Option<Value>, RuntimeError> {
+    match exec_block(commands, env) {
+        Ok(Flow::Yield(value)) => Ok(Some(value)),
+        result => run_defers(env, 0, result.map(|_| None)),
+    }
+}
+
+/// 执行`f`，之后按注册的逆序执行其间注册的defer语句，见[`exec_scope`]
+fn with_defers<'a, T>(
+    env: &mut Env<'a>,
+    f: impl FnOnce(&mut Env<'a>) -> Result<T, RuntimeError>,
+) -> Result<T, RuntimeError> {
+    let mark = env.deferred_len();
+    let result = f(env);
+    run_defers(env, mark, result)
+}
+
+/// 按注册的逆序执行从`mark`开始注册的defer语句，`result`出错时返回这个错误，否则返回第一个出错的defer语句的错误
+fn run_defers<'a, T>(
+    env: &mut Env<'a>,
+    mark: usize,
+    mut result: Result<T, RuntimeError>,
+) -> Result<T, RuntimeError> {
+    for command in env.take_deferred(mark).into_iter().rev() {
+        if let Err(error) = exec_command(command, env)
+            && result.is_ok()
+        {
+            result = Err(error);
+        }
+    }
+    result
+}
+
+/// 范围的起点和终点，必须是数字
+fn range_bound(value: Value) -> Result<Decimal, RuntimeError> {
+    match value {
+        Value::Number(n) => Ok(n),
+        value => Err(mismatch("..", &[&value])),
+    }
+}
+
+/// `break`/`continue`是否作用于标签为`label`的循环，不带标签时作用于最内层的循环
+fn targets(target: Option<&str>, label: Option<&str>) -> bool {
+    target.is_none() || target == label
+}
+
+/// for循环还没有遍历的元素，生成器挂起时保存在[`Resume::For`]中
+#[derive(Debug, Clone)]
+pub(crate) enum Items {
+    /// 范围，从`next`开始每次加1
+    Range {
+        next: Option<Decimal>,
+        end: Decimal,
+        inclusive: bool,
+    },
+    /// 其他值按[`elements`]得到的元素
+    Values(vec::IntoIter<Value>),
+}
+
+impl Iterator for Items {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        match self {
+            Self::Range {
+                next,
+                end,
+                inclusive,
+            } => {
+                let i = next.take()?;
+                if if *inclusive { i > *end } else { i >= *end } {
+                    return None;
+                }
+                *next = i.checked_add(Decimal::ONE);
+                Some(Value::Number(i))
+            }
+            Self::Values(values) => values.next(),
+        }
+    }
+}
+
+/// 遍历循环，范围从起点开始每次加1(不会展开成数组，终点小于起点时不执行)，
+/// 其他值按[`elements`]遍历；循环变量和块内的其他变量一样定义在当前作用域中
+fn exec_for<'a>(
+    var: &str,
+    iter: &'a Expression<'a>,
+    commands: &'a [Command<'a>],
+    label: Option<&'a str>,
+    env: &mut Env<'a>,
+) -> Result<Flow<'a>, RuntimeError> {
+    // 生成器从循环体中继续时，循环变量已经绑定好了
+    let (mut items, mut resuming) = match env.resume() {
+        Some(Resume::For(items)) => (items, true),
+        _ => (for_items(iter, env)?, false),
+    };
+    loop {
+        if !resuming {
+            let Some(item) = items.next() else { break };
+            assign(env, var, item)?;
+        }
+        resuming = false;
+        match exec_block(commands, env)? {
+            Flow::Break(target) if targets(target, label) => break,
+            Flow::Continue(target) if targets(target, label) => {}
+            Flow::Next => {}
+            Flow::Yield(value) => {
+                env.suspend(Resume::For(items));
+                return Ok(Flow::Yield(value));
+            }
+            flow => return Ok(flow),
+        }
+    }
+    Ok(Flow::Next)
+}
+
+/// 计算for循环要遍历的元素
+fn for_items(iter: &Expression<'_>, env: &mut Env<'_>) -> Result<Items, RuntimeError> {
+    Ok(match iter {
+        Expression::Range {
+            start,
+            end,
+            inclusive,
+        } => Items::Range {
+            next: Some(range_bound(eval_expression(start, env)?)?),
+            end: range_bound(eval_expression(end, env)?)?,
+            inclusive: *inclusive,
+        },
+        iter => Items::Values(elements(eval_expression(iter, env)?, "for")?.into_iter()),
+    })
+}
+
+/// 依次检查分支的条件，执行第一个条件成立的分支
+fn exec_if<'a>(
+    if_branch: &'a [Branch<'a>],
+    else_branch: Option<&'a [Command<'a>]>,
+    env: &mut Env<'a>,
+) -> Result<Flow<'a>, RuntimeError> {
+    // else分支的下标是条件分支的个数
+    let index = match env.resume() {
+        Some(Resume::Branch(index)) => index,
+        _ => {
+            let mut index = if_branch.len();
+            for (i, branch) in if_branch.iter().enumerate() {
+                if eval_condition(&branch.condition, env)? {
+                    index = i;
+                    break;
+                }
+            }
+            index
+        }
+    };
+    let commands = match if_branch.get(index) {
+        Some(branch) => &branch.commands,
+        None => match else_branch {
+            Some(commands) => commands,
+            None => return Ok(Flow::Next),
+        },
+    };
+    let flow = exec_block(commands, env)?;
+    if matches!(flow, Flow::Yield(_)) {
+        env.suspend(Resume::Branch(index));
+    }
+    Ok(flow)
+}
+
+/// 条件成立时反复执行块内的语句
+fn exec_while<'a>(
+    condition: &'a Expression<'a>,
+    commands: &'a [Command<'a>],
+    label: Option<&'a str>,
+    env: &mut Env<'a>,
+) -> Result<Flow<'a>, RuntimeError> {
+    // 生成器从循环体中继续时不再检查这一轮的条件
+    let mut resuming = env.resuming();
+    while resuming || eval_condition(condition, env)? {
+        resuming = false;
+        match exec_block(commands, env)? {
+            Flow::Break(target) if targets(target, label) => break,
+            Flow::Continue(target) if targets(target, label) => {}
+            Flow::Next => {}
+            flow => return Ok(flow),
+        }
+    }
+    Ok(Flow::Next)
+}
+
+/// 把表达式的值绑定到名字上执行块内的语句，绑定的名字只在块内可见
+fn exec_with<'a>(
+    expr: &'a Expression<'a>,
+    binding: &str,
+    body: &'a [Command<'a>],
+    env: &mut Env<'a>,
+) -> Result<Flow<'a>, RuntimeError> {
+    // 生成器从块内继续时，绑定所在的作用域还在
+    if !env.resuming() {
+        let value = eval_expression(expr, env)?;
+        env.push_scope();
+        env.define(binding, value);
+    }
+    let flow = exec_block(body, env);
+    // 出错时也要离开这层作用域，挂起时留到继续执行
+    if !matches!(flow, Ok(Flow::Yield(_))) {
+        env.pop_scope();
+    }
+    flow
+}
+
+/// 解释器不能直接执行的语句
+fn unsupported(command: &Command<'_>) -> RuntimeError {
+    RuntimeError::Unsupported(match command {
+        // 函数调用不能挂起，yield只能出现在生成器脚本的顶层和其中的块中
+        Command::Yield(_) => "生成器之外或者函数中的yield".to_string(),
+        Command::Import(path) => {
+            format!("未处理的导入{path}(需要先用ParseConfig::parse_with_imports解析)")
+        }
+        Command::StateBlock { name, .. } => {
+            format!("状态块@{name}(状态块由宿主按当前状态执行)")
+        }
+        // 模板在解析时就已经展开，只有手工构造的语法树里才会出现
+        Command::Template { name, .. } => format!("未展开的模板定义{name}"),
+        Command::Invoke { name, .. } => format!("未展开的模板调用{name}!"),
+        _ => unreachable!("其他语句都可以执行"),
+    })
+}
+
+/// 执行一条语句，出错时记录出错的位置
+fn exec_command<'a>(command: &'a Command<'a>, env: &mut Env<'a>) -> Result<Flow<'a>, RuntimeError> {
+    let result = exec_statement(command, env);
+    if result.is_err() {
+        locate_command(command, env);
+    }
+    result
+}
+
+/// 按语句的种类分派，和[`eval_expression`]一样保持栈帧很小
+fn exec_statement<'a>(
+    command: &'a Command<'a>,
+    env: &mut Env<'a>,
+) -> Result<Flow<'a>, RuntimeError> {
+    match command {
+        Command::Expression(expression) => {
+            eval_expression(expression, env)?;
+        }
+        Command::If {
+            if_branch,
+            else_branch,
+        } => return exec_if(if_branch, else_branch.as_deref(), env),
+        Command::While {
+            condition,
+            command,
+            label,
+        } => return exec_while(condition, command, *label, env),
+        Command::For {
+            var,
+            iter,
+            commands,
+            label,
+        } => return exec_for(var, iter, commands, *label, env),
+        Command::Function {
+            name,
+            args,
+            commands,
+            ..
+        } => env.define_fn(name, Function { args, commands }),
+        Command::Return(value) => {
+            let value = match value {
+                Some(value) => eval_expression(value, env)?,
+                None => Value::Nil,
+            };
+            return Ok(Flow::Return(value));
+        }
+        Command::Defer(command) => env.defer(command),
+        // 测试由[`testing::run_tests`]单独运行
+        Command::Test { .. } => {}
+        Command::With {
+            expr,
+            binding,
+            body,
+        } => return exec_with(expr, binding, body, env),
+        // 切换在这一轮执行结束之后由宿主完成，后面的语句照常执行
+        Command::SetState(name) => env.set_state(name),
+        Command::Yield(expression) if env.generating() && !env.in_call() => {
+            return Ok(Flow::Yield(eval_expression(expression, env)?));
+        }
+        Command::Yield(_)
+        | Command::Import(_)
+        | Command::StateBlock { .. }
+        | Command::Template { .. }
+        | Command::Invoke { .. } => return Err(unsupported(command)),
+        Command::Break(label) => return Ok(Flow::Break(*label)),
+        Command::Continue(label) => return Ok(Flow::Continue(*label)),
+        Command::NewLine => {}
+    }
+    Ok(Flow::Next)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::parser::{parse, scanner::Command};
+
+    use super::{
+        EvalConfig, env::Env, error::RuntimeError, eval_expression, eval_program, exec_block,
+        exec_scope, value::Value,
+    };
+
+    /// 计算只有一个表达式的脚本
+    fn eval(src: &str) -> Result<Value, RuntimeError> {
+        let ast = parse(src).unwrap();
+        let Command::Expression(expression) = &ast[0] else {
+            panic!("expected expression, got {:?}", ast[0]);
+        };
+        eval_expression(expression, &mut Env::new())
+    }
+
+    #[test]
+    fn eval_arithmetic() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(Value::Number(Decimal::from(7))));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(Value::Number(Decimal::from(9))));
+        assert_eq!(eval("2 ^ 10 % 1000"), Ok(Value::Number(Decimal::from(24))));
+        assert_eq!(eval("2 ^ -1"), Ok(Value::Number(Decimal::new(5, 1))));
+        assert_eq!(eval("1 / 0"), Err(RuntimeError::DivByZero));
+        assert_eq!(eval("1 % 0"), Err(RuntimeError::DivByZero));
+    }
+
+    #[test]
+    fn eval_overflow() {
+        let src = "100000000000000000000 * -100000000000000000000";
+        // 默认溢出时取能表示的最小值
+        assert_eq!(eval(src), Ok(Value::Number(Decimal::MIN)));
+        assert_eq!(eval("2 ^ 200"), Ok(Value::Number(Decimal::MAX)));
+
+        let ast = parse(src).unwrap();
+        let Command::Expression(expression) = &ast[0] else {
+            panic!("expected expression, got {:?}", ast[0]);
+        };
+        let mut env = Env::new();
+        env.set_config(EvalConfig {
+            checked_arithmetic: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            eval_expression(expression, &mut env),
+            Err(RuntimeError::Overflow)
+        );
+    }
+
+    #[test]
+    fn eval_pow() {
+        let number = |n: &str| Ok(Value::Number(n.parse::<Decimal>().unwrap()));
+        let checked = |src: &str| {
+            let ast = parse(src).unwrap();
+            let Command::Expression(expression) = &ast[0] else {
+                panic!("expected expression, got {:?}", ast[0]);
+            };
+            let mut env = Env::new();
+            env.set_config(EvalConfig {
+                checked_arithmetic: true,
+                ..Default::default()
+            });
+            eval_expression(expression, &mut env)
+        };
+        // 0、1和-1的次幂在指数很大时也不会溢出，两种模式的结果相同
+        for (src, expected) in [
+            ("1 ^ 100000000000000000000000", "1"),
+            ("0 ^ 100000000000000000000000", "0"),
+            ("(0-1) ^ 1000000000000000000001", "-1"),
+            ("(0-1) ^ 1000000000000000000000", "1"),
+            ("(0-1) ^ -3", "-1"),
+            ("0 ^ 0", "1"),
+            // 结果太小时舍入成0
+            ("2 ^ -200", "0"),
+            ("0.5 ^ 100000000000000000000000", "0"),
+        ] {
+            assert_eq!(eval(src), number(expected), "{src}");
+            assert_eq!(checked(src), number(expected), "{src}");
+        }
+        assert_eq!(eval("0 ^ -1"), Err(RuntimeError::DivByZero));
+        // 真正溢出时默认按符号取最大(最小)值，检查模式报错
+        assert_eq!(eval("(0-2) ^ 201"), Ok(Value::Number(Decimal::MIN)));
+        assert_eq!(eval("0.5 ^ -200"), Ok(Value::Number(Decimal::MAX)));
+        assert_eq!(
+            eval("2 ^ 100000000000000000000000"),
+            Ok(Value::Number(Decimal::MAX))
+        );
+        assert_eq!(checked("2 ^ 200"), Err(RuntimeError::Overflow));
+        assert_eq!(checked("0.5 ^ -200"), Err(RuntimeError::Overflow));
+    }
+
+    #[test]
+    fn eval_strings() {
+        assert_eq!(
+            eval("'hp: ' + 10 + \"!\""),
+            Ok(Value::Str("hp: 10!".to_string()))
+        );
+        assert_eq!(
+            eval("f'{1 + 1} {[1, 'a']}'"),
+            Ok(Value::Str("2 [1, \"a\"]".to_string()))
+        );
+        assert_eq!(
+            eval("1 + 'a'"),
+            Err(RuntimeError::TypeMismatch {
+                op: "+".to_string(),
+                types: vec!["数字", "字符串"]
+            })
+        );
+    }
+
+    #[test]
+    fn eval_comparison() {
+        assert_eq!(eval("5 > 3"), Ok(Value::Bool(true)));
+        assert_eq!(eval("'a' >= 'b'"), Ok(Value::Bool(false)));
+        assert_eq!(eval("[1, 2] == [1, 2]"), Ok(Value::Bool(true)));
+        assert_eq!(eval("1 > 2 || 2 > 1 && !false"), Ok(Value::Bool(true)));
+        // 短路运算不会计算右边
+        assert_eq!(eval("false && 1 / 0"), Ok(Value::Bool(false)));
+        // 不是布尔值的操作数按真假判断
+        assert_eq!(eval("1 && true"), Ok(Value::Bool(true)));
+        assert_eq!(eval("'' || 0"), Ok(Value::Bool(false)));
+        assert_eq!(eval("1 || 2"), Ok(Value::Bool(true)));
+        assert_eq!(eval("!0"), Ok(Value::Bool(true)));
+        assert_eq!(eval("![]"), Ok(Value::Bool(false)));
+        assert_eq!(
+            eval("{'a': 1} | {'b': 2}").map(|d| d.to_string()),
+            Ok("{\"a\": 1, \"b\": 2}".to_string())
+        );
+        assert_eq!(
+            eval("{'a': 1} | 2"),
+            Err(RuntimeError::TypeMismatch {
+                op: "|".to_string(),
+                types: vec!["字典", "数字"]
+            })
+        );
+        // |只用于字典合并，不是||的另一种写法
+        assert_eq!(
+            eval("1 | 2"),
+            Err(RuntimeError::TypeMismatch {
+                op: "|".to_string(),
+                types: vec!["数字", "数字"]
+            })
+        );
+        assert_eq!(
+            eval("[1] | [2]"),
+            Err(RuntimeError::TypeMismatch {
+                op: "|".to_string(),
+                types: vec!["数组", "数组"]
+            })
+        );
+        assert_eq!(eval("1 < 2 ? 'a' : 'b'"), Ok(Value::Str("a".to_string())));
+    }
+
+    #[test]
+    fn eval_assignment() {
+        let number = |n: i64| Value::Number(Decimal::from(n));
+        let mut env = Env::new();
+        let mut run = |src: &str| {
+            let ast = parse(src).unwrap();
+            let mut result = Ok(Value::Nil);
+            for command in &ast {
+                if let Command::Expression(expression) = command {
+                    result = eval_expression(expression, &mut env);
+                }
+            }
+            result
+        };
+        assert_eq!(run("x = y = 1\nx += 1\nx *= 5\n"), Ok(number(10)));
+        assert_eq!(run("y++\ny\n"), Ok(number(2)));
+        assert_eq!(
+            run("d = {'a': 1}\nd |= {'b': 2}\nd\n").map(|d| d.to_string()),
+            Ok("{\"a\": 1, \"b\": 2}".to_string())
+        );
+        assert_eq!(run("z += 1"), Err(RuntimeError::Undefined("z".to_string())));
+    }
+
+    #[test]
+    fn exec_destructure() {
+        let number = |n: i64| Value::Number(Decimal::from(n));
+        let numbers = |ns: &[i64]| Value::Array(ns.iter().map(|&n| number(n)).collect());
+        let ast = parse(
+            "a, *rest = [1, 2, 3]\nfirst, *middle, last = (1, 2)\nx, y = 1, 2\nx, y = y, x\n",
+        )
+        .unwrap();
+        let mut env = Env::new();
+        exec_scope(&ast, &mut env).unwrap();
+        assert_eq!(env.get("a"), Some(&number(1)));
+        assert_eq!(env.get("rest"), Some(&numbers(&[2, 3])));
+        // *变量可以绑定空数组
+        assert_eq!(env.get("first"), Some(&number(1)));
+        assert_eq!(env.get("middle"), Some(&numbers(&[])));
+        assert_eq!(env.get("last"), Some(&number(2)));
+        assert_eq!(
+            (env.get("x"), env.get("y")),
+            (Some(&number(2)), Some(&number(1)))
+        );
+
+        let run = |src: &str| exec_scope(&parse(src).unwrap(), &mut Env::new()).map(|_| ());
+        assert_eq!(
+            run("a, b = [1, 2, 3]\n"),
+            Err(RuntimeError::Destructure {
+                expected: 2,
+                found: 3,
+                rest: false
+            })
+        );
+        assert_eq!(
+            run("a, *b, c = [1]\n"),
+            Err(RuntimeError::Destructure {
+                expected: 2,
+                found: 1,
+                rest: true
+            })
+        );
+    }
+
+    /// 执行脚本，返回执行之后变量name的值
+    fn exec(src: &str, name: &str) -> Option<Value> {
+        let ast = parse(src).unwrap();
+        let mut env = Env::new();
+        exec_block(&ast, &mut env).unwrap();
+        env.get(name).cloned()
+    }
+
+    #[test]
+    // 测试的是f-string的格式，不是Rust的格式字符串
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn eval_format_spec() {
+        let s = |s: &str| Ok(Value::Str(s.to_string()));
+        assert_eq!(eval("f\"{3.14159:.2}\""), s("3.14"));
+        assert_eq!(eval("f\"{5:03}\""), s("005"));
+        // 四舍五入，补齐小数位数，负号在补的0前面
+        assert_eq!(eval("f\"{2.675:.2} {1:.3} {-5:04}\""), s("2.68 1.000 -005"));
+        // 宽度不足时补空格，带单位的数字单位也算在宽度里
+        assert_eq!(
+            eval("f\"[{5:3}] [{1.5m:06.2}] [{'ab':4}]\""),
+            s("[  5] [01.50m] [  ab]")
+        );
+        // 条件表达式的冒号不是格式
+        assert_eq!(eval("f\"{1 ? 2 :3}\""), s("2"));
+        assert_eq!(
+            eval("f\"{'ab':.1}\""),
+            Err(RuntimeError::TypeMismatch {
+                op: ":.1".to_string(),
+                types: vec!["字符串"]
+            })
+        );
+    }
+
+    #[test]
+    fn exec_if_chain() {
+        let grade = |score: i64| {
+            exec(
+                &format!(
+                    "score = {score}\nif score >= 90:\n\tgrade = 'A'\nelif score >= 60:\n\tgrade = 'B'\nelse:\n\tgrade = 'C'\n"
+                ),
+                "grade",
+            )
+        };
+        assert_eq!(grade(95), Some(Value::Str("A".to_string())));
+        assert_eq!(grade(60), Some(Value::Str("B".to_string())));
+        assert_eq!(grade(10), Some(Value::Str("C".to_string())));
+        // 0和空字符串为假
+        let a = exec(
+            "a = 1\nif 0:\n\ta = 2\nelif '':\n\ta = 3\nelif 'x':\n\ta = 4\n",
+            "a",
+        );
+        assert_eq!(a, Some(Value::Number(Decimal::from(4))));
+    }
+
+    #[test]
+    fn exec_while() {
+        let src = "i = 0\nsum = 0\nwhile i < 10:\n\ti += 1\n\tif i % 2:\n\t\tcontinue\n\tif i > 8:\n\t\tbreak\n\tsum += i\n";
+        assert_eq!(exec(src, "i"), Some(Value::Number(Decimal::from(10))));
+        assert_eq!(exec(src, "sum"), Some(Value::Number(Decimal::from(20))));
+    }
+
+    #[test]
+    fn exec_for() {
+        let number = |n: i64| Some(Value::Number(Decimal::from(n)));
+        assert_eq!(
+            exec("sum = 0\nfor i in 0..5:\n\tsum += i\n", "sum"),
+            number(10)
+        );
+        assert_eq!(
+            exec("sum = 0\nfor i in 1..=3:\n\tsum += i\n", "sum"),
+            number(6)
+        );
+        // 终点小于起点时不执行
+        assert_eq!(
+            exec("sum = 0\nfor i in 3..0:\n\tsum += i\n", "sum"),
+            number(0)
+        );
+        let src = "sum = 0\nfor x in [1, 2, 3, 4, 5]:\n\tif x == 2:\n\t\tcontinue\n\tif x == 4:\n\t\tbreak\n\tsum += x\n";
+        assert_eq!(exec(src, "sum"), number(4));
+        // 循环变量在循环结束之后保留最后一个值
+        assert_eq!(exec(src, "x"), number(4));
+        let src = "fn find(items, target):\n\tfor i in items:\n\t\tif i == target:\n\t\t\treturn true\n\treturn false\nfound = find((1, 2), 2)\n";
+        assert_eq!(exec(src, "found"), Some(Value::Bool(true)));
+
+        let ast = parse("for x in 1:\n\tx\n").unwrap();
+        assert_eq!(
+            exec_block(&ast, &mut Env::new()),
+            Err(RuntimeError::TypeMismatch {
+                op: "for".to_string(),
+                types: vec!["数字"]
+            })
+        );
+    }
+
+    #[test]
+    fn exec_nested_flow() {
+        let number = |n: i64| Some(Value::Number(Decimal::from(n)));
+        // if里的return直接从函数返回，不会继续执行循环和函数剩下的语句
+        let src = "fn first_over(limit):\n\ti = 0\n\twhile true:\n\t\ti += 1\n\t\tif i * i > limit:\n\t\t\treturn i\n\treturn -1\nx = first_over(10)\n";
+        assert_eq!(exec(src, "x"), number(4));
+        // if里的break只跳出while，循环后面的语句照常执行
+        let src = "i = 0\nwhile true:\n\ti += 1\n\tif i == 3:\n\t\tbreak\nafter = i * 10\n";
+        assert_eq!(exec(src, "after"), number(30));
+        // 函数内的循环里的break不会影响调用者所在的循环
+        let src = "fn stop():\n\twhile true:\n\t\tbreak\n\treturn 1\nn = 0\nfor i in 0..3:\n\tn += stop()\n";
+        assert_eq!(exec(src, "n"), number(3));
+    }
+
+    #[test]
+    fn exec_loop_label() {
+        let number = |n: i64| Some(Value::Number(Decimal::from(n)));
+        // break outer同时跳出两层循环
+        let src = "pairs = 0\nouter: for i in 0..3:\n\tfor j in 0..3:\n\t\tif i * j == 2:\n\t\t\tbreak outer\n\t\tpairs += 1\n";
+        assert_eq!(exec(src, "pairs"), number(5));
+        assert_eq!(exec(src, "i"), number(1));
+        // continue outer跳过内层循环剩下的部分，继续外层循环
+        let src = "pairs = 0\nouter: for i in 0..3:\n\tfor j in 0..3:\n\t\tpairs += 1\n\t\tif j == 1:\n\t\t\tcontinue outer\n\t\tpairs += 10\n";
+        assert_eq!(exec(src, "pairs"), number(36));
+        // 不带标签的break只跳出最内层的循环
+        let src = "n = 0\nouter: for i in 0..3:\n\tfor j in 0..3:\n\t\tbreak\n\tn += 1\n";
+        assert_eq!(exec(src, "n"), number(3));
+    }
+
+    #[test]
+    fn exec_function() {
+        let number = |n: i64| Some(Value::Number(Decimal::from(n)));
+        let src = "fn add(a, b):\n\treturn a + b\nx = add(2, 3)\n";
+        assert_eq!(exec(src, "x"), number(5));
+        // 递归，函数内部的变量不会泄漏到调用者
+        let src = "fn fib(n):\n\tif n < 2:\n\t\treturn n\n\tm = fib(n - 1)\n\treturn m + fib(n - 2)\nx = fib(10)\n";
+        assert_eq!(exec(src, "x"), number(55));
+        assert_eq!(exec(src, "m"), None);
+
+        let ast = parse("fn add(a, b):\n\treturn a + b\nadd(1)\n").unwrap();
+        assert_eq!(
+            exec_block(&ast, &mut Env::new()),
+            Err(RuntimeError::Arity {
+                name: "add".to_string(),
+                expected: 2,
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn exec_function_value() {
+        let number = |n: i64| Value::Number(Decimal::from(n));
+        let src = "fn double(x):\n\treturn x * 2\nfn odd(x):\n\treturn x % 2\nfn apply(f, x):\n\treturn f(x)\nys = map([1, 2, 3], double)\nodds = filter((1, 2, 3), odd)\nz = apply(double, 5)\nf = double\nw = f(4)\n";
+        assert_eq!(
+            exec(src, "ys"),
+            Some(Value::Array(vec![number(2), number(4), number(6)]))
+        );
+        assert_eq!(
+            exec(src, "odds"),
+            Some(Value::Array(vec![number(1), number(3)]))
+        );
+        assert_eq!(exec(src, "z"), Some(number(10)));
+        assert_eq!(exec(src, "f"), Some(Value::Function("double".to_string())));
+        assert_eq!(exec(src, "w"), Some(number(8)));
+        // 同名的变量优先于函数
+        let src = "fn hp():\n\treturn 1\nhp = 3\nx = hp\ny = hp()\n";
+        assert_eq!(exec(src, "x"), Some(number(3)));
+        assert_eq!(exec(src, "y"), Some(number(1)));
+
+        let ast = parse("x = [1][0](2)\n").unwrap();
+        assert_eq!(
+            exec_block(&ast, &mut Env::new()),
+            Err(RuntimeError::TypeMismatch {
+                op: "()".to_string(),
+                types: vec!["数字"]
+            })
+        );
+        let ast = parse("map([1], 2)\n").unwrap();
+        assert_eq!(
+            exec_block(&ast, &mut Env::new()),
+            Err(RuntimeError::ArgType {
+                arg: 1,
+                expected: "函数",
+                found: "数字"
+            })
+        );
+    }
+
+    #[test]
+    fn exec_min_max_by() {
+        let src = "fn neg(x):\n\treturn -x\nfn odd(x):\n\treturn x % 2\nitems = [3, 1, 4, 5]\nbig = max_by(items, neg)\nsmall = min_by(items, neg)\nnear = closest((2, 7), neg)\nfirst = max_by([2, 3, 5], odd)\nnone = min_by([], neg)\n";
+        let number = |n: i64| Some(Value::Number(Decimal::from(n)));
+        assert_eq!(exec(src, "big"), number(1));
+        assert_eq!(exec(src, "small"), number(5));
+        assert_eq!(exec(src, "near"), number(7));
+        // 键相同时取先出现的元素
+        assert_eq!(exec(src, "first"), number(3));
+        // 空集合返回null
+        assert_eq!(exec(src, "none"), Some(Value::Nil));
+
+        let ast = parse("fn f(x):\n\treturn x\nmax_by([1, 'a'], f)\n").unwrap();
+        assert_eq!(
+            exec_block(&ast, &mut Env::new()).map(|_| ()),
+            Err(RuntimeError::TypeMismatch {
+                op: "max_by".to_string(),
+                types: vec!["字符串", "数字"]
+            })
+        );
+    }
+
+    #[test]
+    fn exec_implicit_return() {
+        let src = "fn add(a, b):\n\ta + b\nfn set(a):\n\tb = a\nfn early(a):\n\tif a:\n\t\treturn 'early'\n\t'late'\nx = add(2, 3)\ny = set(1)\nz = early(true)\nw = early(false)\n";
+        assert_eq!(exec(src, "x"), Some(Value::Number(Decimal::from(5))));
+        // 最后一条语句是赋值时没有返回值
+        assert_eq!(exec(src, "y"), Some(Value::Unit));
+        assert_eq!(exec(src, "z"), Some(Value::Str("early".to_string())));
+        assert_eq!(exec(src, "w"), Some(Value::Str("late".to_string())));
+    }
+
+    #[test]
+    fn eval_program_unit() {
+        let run = |src: &str| eval_program(&parse(src).unwrap(), &mut Env::new());
+        assert_eq!(run("x = 1\nx + 1\n"), Ok(Value::Number(Decimal::TWO)));
+        // 赋值、if、while等语句的结果是无值
+        assert_eq!(run("x = 1\n"), Ok(Value::Unit));
+        assert_eq!(run("if true:\n\t1\n"), Ok(Value::Unit));
+        assert_eq!(run("while false:\n\t1\n"), Ok(Value::Unit));
+        // 无值不能参与运算
+        assert_eq!(
+            run("fn set():\n\tx = 1\nset() + 1\n"),
+            Err(RuntimeError::TypeMismatch {
+                op: "+".to_string(),
+                types: vec!["无值", "数字"]
+            })
+        );
+        assert_eq!(
+            run("fn set():\n\tx = 1\n-set()\n"),
+            Err(RuntimeError::TypeMismatch {
+                op: "-".to_string(),
+                types: vec!["无值"]
+            })
+        );
+    }
+
+    #[test]
+    fn exec_reduce() {
+        let src = "fn add(a, b):\n\ta + b\nfn fold(f):\n\treturn reduce((1, 2, 3), f, 0)\nx = reduce([1, 2, 3], add, 0)\ny = reduce([], add, 10)\nz = fold(add)\n";
+        assert_eq!(exec(src, "x"), Some(Value::Number(Decimal::from(6))));
+        // 空集合直接返回初始值
+        assert_eq!(exec(src, "y"), Some(Value::Number(Decimal::from(10))));
+        // 函数值可以通过变量传给reduce
+        assert_eq!(exec(src, "z"), Some(Value::Number(Decimal::from(6))));
+        let run = |src: &str| exec_block(&parse(src).unwrap(), &mut Env::new()).map(|_| ());
+        assert_eq!(
+            run("fn add(a, b):\n\ta + b\nreduce(1, add, 0)\n"),
+            Err(RuntimeError::TypeMismatch {
+                op: "reduce".to_string(),
+                types: vec!["数字"]
+            })
+        );
+        assert!(matches!(
+            run("fn add(a, b):\n\ta + b\nreduce([1], add)\n"),
+            Err(RuntimeError::Arity { expected: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn exec_defer() {
+        let src = "log = []\nfn f(x):\n\tdefer log += ['first']\n\tdefer log += ['second']\n\tif x:\n\t\treturn 1\n\tlog += ['body']\nr = f(1)\nf(0)\n";
+        let log = |items: &[&str]| {
+            Some(Value::Array(
+                items
+                    .iter()
+                    .map(|item| Value::Str(item.to_string()))
+                    .collect(),
+            ))
+        };
+        // 提前return时也会按逆序执行
+        assert_eq!(
+            exec(src, "log"),
+            log(&["second", "first", "body", "second", "first"])
+        );
+        assert_eq!(exec(src, "r"), Some(Value::Number(Decimal::ONE)));
+
+        // 顶层的defer在脚本执行完之后才执行
+        let ast = parse("log = []\ndefer log += ['end']\nlog += ['start']\n").unwrap();
+        let mut env = Env::new();
+        exec_scope(&ast, &mut env).unwrap();
+        assert_eq!(env.get("log").cloned(), log(&["start", "end"]));
+    }
+
+    #[test]
+    fn exec_set_state() {
+        // defer中的状态切换在函数退出时执行，覆盖函数体中的切换
+        let ast = parse("fn flee():\n\tdefer @idle\n\t@flee\n\tran = true\nflee()\n").unwrap();
+        let mut env = Env::new();
+        exec_scope(&ast, &mut env).unwrap();
+        assert_eq!(env.take_state(), Some("idle"));
+        assert_eq!(env.take_state(), None);
+
+        let ast = parse("@patrol:\n\tx = 1\n").unwrap();
+        assert_eq!(
+            exec_scope(&ast, &mut Env::new()),
+            Err(RuntimeError::Unsupported(
+                "状态块@patrol(状态块由宿主按当前状态执行)".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn exec_with() {
+        let src = "hp = 10\nwith hp * 2 as doubled:\n\ttotal = doubled + 1\n\thp = doubled\n";
+        assert_eq!(exec(src, "hp"), Some(Value::Number(Decimal::from(20))));
+        // 绑定的名字和块内新定义的变量离开块之后都不可见
+        assert_eq!(exec(src, "doubled"), None);
+        assert_eq!(exec(src, "total"), None);
+        // 块内的return会传到外层
+        let src = "fn f():\n\twith 3 as n:\n\t\treturn n\n\treturn 0\nr = f()\n";
+        assert_eq!(exec(src, "r"), Some(Value::Number(Decimal::from(3))));
+    }
+
+    #[test]
+    fn eval_symbol() {
+        assert_eq!(eval(":attack"), Ok(Value::Symbol("attack".to_string())));
+        assert_eq!(eval(":attack == :attack"), Ok(Value::Bool(true)));
+        assert_eq!(eval(":attack != :flee"), Ok(Value::Bool(true)));
+        // 符号和同名的字符串不相等
+        assert_eq!(eval(":attack == 'attack'"), Ok(Value::Bool(false)));
+        assert_eq!(
+            eval("{:attack: 1}{:attack}"),
+            Ok(Value::Number(Decimal::ONE))
+        );
+        assert_eq!(
+            eval("'state: ' + :flee"),
+            Ok(Value::Str("state: :flee".to_string()))
+        );
+    }
+
+    #[test]
+    fn eval_quantity() {
+        let quantity = |n: i64, unit: &str| Ok(Value::Quantity(Decimal::from(n), unit.to_string()));
+        assert_eq!(eval("10s"), quantity(10, "s"));
+        assert_eq!(eval("10s + 5s"), quantity(15, "s"));
+        assert_eq!(eval("2 * 3m - 1m"), quantity(5, "m"));
+        assert_eq!(eval("-(10s / 2)"), quantity(-5, "s"));
+        assert_eq!(eval("10s / 5s"), Ok(Value::Number(Decimal::TWO)));
+        assert_eq!(eval("10s > 5s"), Ok(Value::Bool(true)));
+        assert_eq!(
+            eval("'wait ' + 1.50s"),
+            Ok(Value::Str("wait 1.5s".to_string()))
+        );
+        // 单位不同或者和数字相加都会报错
+        assert_eq!(
+            eval("10s + 5m"),
+            Err(RuntimeError::TypeMismatch {
+                op: "+".to_string(),
+                types: vec!["带单位的数字", "带单位的数字"]
+            })
+        );
+        assert!(matches!(
+            eval("10s + 5"),
+            Err(RuntimeError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn eval_duration() {
+        let seconds = |n: &str| Ok(Value::Duration(n.parse().unwrap()));
+        assert_eq!(eval("seconds(2) - seconds(0.25)"), seconds("1.75"));
+        // 时间单位的带单位的数字按时长计算
+        assert_eq!(eval("2s - seconds(0.5)"), seconds("1.5"));
+        assert_eq!(eval("seconds(1) + 250ms"), seconds("1.25"));
+        assert_eq!(eval("seconds(1.5) * 2"), seconds("3.0"));
+        assert_eq!(eval("2 * seconds(1.5)"), seconds("3.0"));
+        assert_eq!(eval("-seconds(3) / 2"), seconds("-1.5"));
+        assert_eq!(eval("seconds(5) % 2s"), seconds("1"));
+        assert_eq!(
+            eval("1h / seconds(900)"),
+            Ok(Value::Number(Decimal::from(4)))
+        );
+        assert_eq!(eval("seconds(0.5) < 1s"), Ok(Value::Bool(true)));
+        assert_eq!(eval("seconds(3600) >= 1h"), Ok(Value::Bool(true)));
+        assert_eq!(
+            eval("as_seconds(seconds(2) * 3)"),
+            Ok(Value::Number(Decimal::from(6)))
+        );
+        assert_eq!(
+            eval("'cd ' + seconds(1.50)"),
+            Ok(Value::Str("cd 1.5s".to_string()))
+        );
+        // 和没有单位的数字比较、加减需要显式转换
+        assert_eq!(
+            eval("seconds(1) > 0"),
+            Err(RuntimeError::TypeMismatch {
+                op: ">".to_string(),
+                types: vec!["时长", "数字"]
+            })
+        );
+        assert!(matches!(
+            eval("seconds(1) + 1"),
+            Err(RuntimeError::TypeMismatch { .. })
+        ));
+        assert!(matches!(
+            eval("seconds(1) + 1m"),
+            Err(RuntimeError::TypeMismatch { .. })
+        ));
+        assert_eq!(
+            Value::from(std::time::Duration::from_millis(16)),
+            Value::Duration(Decimal::new(16, 3))
+        );
+    }
+
+    #[test]
+    fn eval_color() {
+        assert_eq!(eval("(#ff8000)"), Ok(Value::Color([255, 128, 0, 255])));
+        assert_eq!(eval("rgb(255, 128, 0) == #ff8000"), Ok(Value::Bool(true)));
+        assert_eq!(
+            eval("'' + hsv(120, 1, 1) + ' ' + #00000080"),
+            Ok(Value::Str("#00ff00 #00000080".to_string()))
+        );
+        // 脚本中的同名函数覆盖内置函数
+        assert_eq!(
+            exec("fn rgb(r, g, b):\n\tr + g + b\nc = rgb(1, 2, 3)\n", "c"),
+            Some(Value::Number(Decimal::from(6)))
+        );
+    }
+
+    #[test]
+    fn eval_index() {
+        let number = |n: i64| Ok(Value::Number(Decimal::from(n)));
+        assert_eq!(eval("[1, 2, 3][0]"), number(1));
+        assert_eq!(eval("[1, 2, 3][-1]"), number(3));
+        assert_eq!(eval("(1, 2)[1]"), number(2));
+        assert_eq!(eval("'abc'[-2]"), Ok(Value::Str("b".to_string())));
+        assert_eq!(
+            eval("[1, 2, 3][3]"),
+            Err(RuntimeError::IndexOutOfBounds {
+                index: Decimal::from(3),
+                len: 3
+            })
+        );
+        assert_eq!(
+            eval("[1, 2, 3][-4]"),
+            Err(RuntimeError::IndexOutOfBounds {
+                index: Decimal::from(-4),
+                len: 3
+            })
+        );
+        assert!(matches!(
+            eval("1[0]"),
+            Err(RuntimeError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn eval_key() {
+        assert_eq!(
+            eval("{'a': 1, 'b': 2}{'b'}"),
+            Ok(Value::Number(Decimal::from(2)))
+        );
+        assert_eq!(eval("{'a': 1}['a']"), Ok(Value::Number(Decimal::from(1))));
+        // 没有这个键时返回null
+        assert_eq!(eval("{'a': 1}{'c'}"), Ok(Value::Nil));
+        assert!(matches!(
+            eval("[1]{0}"),
+            Err(RuntimeError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn eval_collections() {
+        assert_eq!(
+            eval("[1, *if false then 2, *if true then 3]"),
+            Ok(Value::Array(vec![
+                Value::Number(Decimal::from(1)),
+                Value::Number(Decimal::from(3))
+            ]))
+        );
+        assert_eq!(
+            eval("{'a': 1, 'a': 2}"),
+            Ok(Value::Dict(vec![(
+                Value::Str("a".to_string()),
+                Value::Number(Decimal::from(2))
+            )]))
+        );
+    }
+}
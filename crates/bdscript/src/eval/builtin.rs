@@ -0,0 +1,464 @@
+//! 内置函数，宿主函数和脚本中的同名函数会覆盖它们
+
+use std::cmp::Ordering;
+
+use rust_decimal::{Decimal, prelude::ToPrimitive};
+
+use crate::parser::tokenizer::FormatSpec;
+
+use super::{call, compare, elements, env::Env, error::RuntimeError, value::Value, vector};
+
+/// 所有内置函数的名字
+pub const BUILTINS: [&str; 18] = [
+    "rgb",
+    "hsv",
+    "assert",
+    "format",
+    "map",
+    "filter",
+    "reduce",
+    "min_by",
+    "max_by",
+    "closest",
+    "farthest",
+    "seconds",
+    "as_seconds",
+    "vec2",
+    "vec3",
+    "length",
+    "normalize",
+    "dot",
+];
+
+/// 开启`serde_json`特性时才有的内置函数
+const JSON_BUILTINS: [&str; 2] = ["to_json", "from_json"];
+
+/// 是否有名为`name`的内置函数
+pub fn is_builtin(name: &str) -> bool {
+    BUILTINS.contains(&name) || (cfg!(feature = "serde_json") && JSON_BUILTINS.contains(&name))
+}
+
+/// 调用内置函数，没有这个内置函数时返回None
+///
+/// map/filter/reduce/min_by等会回调作为参数传入的函数，所以需要变量环境
+pub fn call_builtin(
+    name: &str,
+    args: &[Value],
+    env: &mut Env<'_>,
+) -> Option<Result<Value, RuntimeError>> {
+    match name {
+        "rgb" => Some(rgb(args)),
+        "hsv" => Some(hsv(args)),
+        "assert" => Some(assert(args)),
+        "format" => Some(format(args)),
+        "map" => Some(map(args, env)),
+        "filter" => Some(filter(args, env)),
+        "reduce" => Some(reduce(args, env)),
+        "min_by" | "closest" => Some(extremum(name, args, Ordering::Less, env)),
+        "max_by" | "farthest" => Some(extremum(name, args, Ordering::Greater, env)),
+        "seconds" => Some(seconds(args)),
+        "as_seconds" => Some(as_seconds(args)),
+        "vec2" => Some(vector::vec2(args)),
+        "vec3" => Some(vector::vec3(args)),
+        "length" => Some(vector::length(args)),
+        "normalize" => Some(vector::normalize(args)),
+        "dot" => Some(vector::dot(args)),
+        #[cfg(feature = "serde_json")]
+        "to_json" => Some(super::json::to_json_builtin(args)),
+        #[cfg(feature = "serde_json")]
+        "from_json" => Some(super::json::from_json_builtin(args)),
+        _ => None,
+    }
+}
+
+/// 检查函数`name`的参数个数，不是`expected`个时报[`RuntimeError::Arity`]
+///
+/// 和[`Value::expect_number`]一起用于检查宿主函数的参数，报错和内置函数保持一致
+pub fn arity(name: &str, args: &[Value], expected: usize) -> Result<(), RuntimeError> {
+    if args.len() == expected {
+        Ok(())
+    } else {
+        Err(RuntimeError::Arity {
+            name: name.to_string(),
+            expected,
+            found: args.len(),
+        })
+    }
+}
+
+/// `seconds(n)`，把秒数转换成时长
+fn seconds(args: &[Value]) -> Result<Value, RuntimeError> {
+    arity("seconds", args, 1)?;
+    Ok(Value::Duration(args[0].expect_number(0)?))
+}
+
+/// `as_seconds(时长)`，把时长转换成秒数
+fn as_seconds(args: &[Value]) -> Result<Value, RuntimeError> {
+    arity("as_seconds", args, 1)?;
+    Ok(Value::Number(args[0].expect_duration(0)?))
+}
+
+/// 取出三个数字参数
+fn three_numbers(name: &str, args: &[Value]) -> Result<[Decimal; 3], RuntimeError> {
+    arity(name, args, 3)?;
+    Ok([
+        args[0].expect_number(0)?,
+        args[1].expect_number(1)?,
+        args[2].expect_number(2)?,
+    ])
+}
+
+/// 参数超出范围
+fn out_of_range(name: &str) -> RuntimeError {
+    RuntimeError::Unsupported(format!("{name}的参数超出范围"))
+}
+
+/// `rgb(r, g, b)`，每个分量是0到255之间的数字，小数会四舍五入
+fn rgb(args: &[Value]) -> Result<Value, RuntimeError> {
+    let channels = three_numbers("rgb", args)?;
+    let mut color = [u8::MAX; 4];
+    for (slot, channel) in color.iter_mut().zip(channels) {
+        *slot = channel.round().to_u8().ok_or_else(|| out_of_range("rgb"))?;
+    }
+    Ok(Value::Color(color))
+}
+
+/// `hsv(h, s, v)`，色相h以度为单位(会取模360)，饱和度s和明度v是0到1之间的数字
+fn hsv(args: &[Value]) -> Result<Value, RuntimeError> {
+    let [h, s, v] = three_numbers("hsv", args)?;
+    let unit = Decimal::ZERO..=Decimal::ONE;
+    if !unit.contains(&s) || !unit.contains(&v) {
+        return Err(out_of_range("hsv"));
+    }
+    let [h, s, v] = [h, s, v].map(|n| n.to_f64().unwrap_or_default());
+    let sector = h.rem_euclid(360.0) / 60.0;
+    let chroma = v * s;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u8 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = v - chroma;
+    let channel = |c: f64| ((c + m) * 255.0).round() as u8;
+    Ok(Value::Color([channel(r), channel(g), channel(b), u8::MAX]))
+}
+
+/// `assert(条件)`或`assert(条件, 说明)`，条件为假时报错，没有说明时用条件的值作为说明
+fn assert(args: &[Value]) -> Result<Value, RuntimeError> {
+    let (condition, message) = match args {
+        [condition] => (condition, condition.to_string()),
+        [condition, message] => (condition, message.to_string()),
+        _ => {
+            return Err(RuntimeError::Arity {
+                name: "assert".to_string(),
+                expected: if args.is_empty() { 1 } else { 2 },
+                found: args.len(),
+            });
+        }
+    };
+    if condition.is_truthy() {
+        Ok(Value::Nil)
+    } else {
+        Err(RuntimeError::AssertionFailed(message))
+    }
+}
+
+/// `format(模板, 参数...)`，把模板中的`{0}`、`{1}`换成对应位置的参数，
+/// `{}`依次取下一个参数，`{{`和`}}`是花括号本身，冒号后面可以和f-string一样写格式，例如`{0:.2}`
+fn format(args: &[Value]) -> Result<Value, RuntimeError> {
+    let Some((template, values)) = args.split_first() else {
+        return Err(RuntimeError::Arity {
+            name: "format".to_string(),
+            expected: 1,
+            found: 0,
+        });
+    };
+    let template = template.expect_str(0)?;
+    let malformed = || RuntimeError::Unsupported(format!("format模板{template:?}中的占位符"));
+    let mut output = String::new();
+    let mut next = 0;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                output.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                output.push('}');
+            }
+            '{' => {
+                let (placeholder, rest) = chars.as_str().split_once('}').ok_or_else(malformed)?;
+                chars = rest.chars();
+                let (placeholder, spec) = match placeholder.split_once(':') {
+                    Some((placeholder, spec)) => (
+                        placeholder,
+                        Some(FormatSpec::parse(spec).ok_or_else(malformed)?),
+                    ),
+                    None => (placeholder, None),
+                };
+                let index = if placeholder.is_empty() {
+                    next += 1;
+                    next - 1
+                } else {
+                    placeholder.parse::<usize>().map_err(|_| malformed())?
+                };
+                let value = values
+                    .get(index)
+                    .ok_or_else(|| RuntimeError::IndexOutOfBounds {
+                        index: Decimal::from(index),
+                        len: values.len(),
+                    })?;
+                match spec {
+                    Some(spec) => output.push_str(&value.format(&spec)?),
+                    None => output.push_str(&value.to_string()),
+                }
+            }
+            '}' => return Err(malformed()),
+            c => output.push(c),
+        }
+    }
+    Ok(Value::Str(output))
+}
+
+/// `map(集合, 函数)`，用函数依次处理集合中的每个元素，结果是数组
+fn map(args: &[Value], env: &mut Env<'_>) -> Result<Value, RuntimeError> {
+    arity("map", args, 2)?;
+    let f = args[1].expect_function(1)?;
+    let items = elements(args[0].clone(), "map")?;
+    let mut mapped = Vec::with_capacity(items.len());
+    for item in items {
+        mapped.push(call(f, vec![item], env)?);
+    }
+    Ok(Value::Array(mapped))
+}
+
+/// `filter(集合, 函数)`，只保留函数返回值为真的元素，结果是数组
+fn filter(args: &[Value], env: &mut Env<'_>) -> Result<Value, RuntimeError> {
+    arity("filter", args, 2)?;
+    let f = args[1].expect_function(1)?;
+    let mut kept = vec![];
+    for item in elements(args[0].clone(), "filter")? {
+        if call(f, vec![item.clone()], env)?.is_truthy() {
+            kept.push(item);
+        }
+    }
+    Ok(Value::Array(kept))
+}
+
+/// `reduce(集合, 函数, 初始值)`，从初始值开始依次用函数合并每个元素，集合为空时返回初始值
+fn reduce(args: &[Value], env: &mut Env<'_>) -> Result<Value, RuntimeError> {
+    arity("reduce", args, 3)?;
+    let items = elements(args[0].clone(), "reduce")?;
+    let f = args[1].expect_function(1)?;
+    let mut acc = args[2].clone();
+    for item in items {
+        acc = call(f, vec![acc, item], env)?;
+    }
+    Ok(acc)
+}
+
+/// `min_by(集合, 函数)`和`max_by(集合, 函数)`，用函数计算每个元素的键，返回键最小(最大)的元素，
+/// 键相同时取先出现的元素，集合为空时返回null
+///
+/// `closest`/`farthest`是它们的别名，用于按距离选择目标，例如`closest(Query<Enemy>, distance)`
+fn extremum(
+    name: &str,
+    args: &[Value],
+    wanted: Ordering,
+    env: &mut Env<'_>,
+) -> Result<Value, RuntimeError> {
+    arity(name, args, 2)?;
+    let f = args[1].expect_function(1)?;
+    let mut best: Option<(Value, Value)> = None;
+    for item in elements(args[0].clone(), name)? {
+        let key = call(f, vec![item.clone()], env)?;
+        let better = match &best {
+            Some((best_key, _)) => compare(&key, best_key, name)? == wanted,
+            None => true,
+        };
+        if better {
+            best = Some((key, item));
+        }
+    }
+    Ok(best.map_or(Value::Nil, |(_, item)| item))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::eval::env::Env;
+
+    use super::{RuntimeError, Value, arity, call_builtin};
+
+    fn builtin(name: &str, args: &[Value]) -> Option<Result<Value, RuntimeError>> {
+        call_builtin(name, args, &mut Env::new())
+    }
+
+    fn numbers(ns: &[&str]) -> Vec<Value> {
+        ns.iter()
+            .map(|n| Value::Number(n.parse::<Decimal>().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn color_builtins() {
+        let color = |c: [u8; 4]| Some(Ok(Value::Color(c)));
+        assert_eq!(
+            builtin("rgb", &numbers(&["255", "128", "0"])),
+            color([255, 128, 0, 255])
+        );
+        assert_eq!(
+            builtin("hsv", &numbers(&["0", "1", "1"])),
+            color([255, 0, 0, 255])
+        );
+        assert_eq!(
+            builtin("hsv", &numbers(&["-240", "1", "0.5"])),
+            color([0, 128, 0, 255])
+        );
+        assert_eq!(
+            builtin("rgb", &numbers(&["256", "0", "0"])),
+            Some(Err(RuntimeError::Unsupported(
+                "rgb的参数超出范围".to_string()
+            )))
+        );
+        assert!(matches!(
+            builtin("rgb", &numbers(&["1", "2"])),
+            Some(Err(RuntimeError::Arity { expected: 3, .. }))
+        ));
+        assert_eq!(builtin("hsl", &[]), None);
+    }
+
+    #[test]
+    fn argument_guards() {
+        assert_eq!(
+            Value::Str("hp".to_string()).expect_number(0),
+            Err(RuntimeError::ArgType {
+                arg: 0,
+                expected: "数字",
+                found: "字符串"
+            })
+        );
+        assert_eq!(
+            Value::Number(Decimal::TEN).expect_number(1),
+            Ok(Decimal::TEN)
+        );
+        assert_eq!(arity("f", &numbers(&["1"]), 1), Ok(()));
+        assert_eq!(
+            arity("f", &[], 2),
+            Err(RuntimeError::Arity {
+                name: "f".to_string(),
+                expected: 2,
+                found: 0
+            })
+        );
+        // 内置函数用同样的方式报错
+        assert_eq!(
+            builtin(
+                "rgb",
+                &[Value::Number(Decimal::ONE), Value::Nil, Value::Nil]
+            ),
+            Some(Err(RuntimeError::ArgType {
+                arg: 1,
+                expected: "数字",
+                found: "null"
+            }))
+        );
+        assert_eq!(
+            RuntimeError::ArgType {
+                arg: 0,
+                expected: "数字",
+                found: "字符串"
+            }
+            .to_string(),
+            "第1个参数应该是数字，但是传入了字符串"
+        );
+    }
+
+    #[test]
+    // 测试的是format的模板，不是Rust的格式字符串
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn format_builtin() {
+        let format = |args: &[Value]| builtin("format", args);
+        let s = |s: &str| Value::Str(s.to_string());
+        let n = |n: i64| Value::Number(Decimal::from(n));
+        assert_eq!(
+            format(&[s("{0} hit {1} for {2}"), s("orc"), s("elf"), n(7)]),
+            Some(Ok(s("orc hit elf for 7")))
+        );
+        // {}依次取下一个参数，和位置占位符可以重复使用同一个参数
+        assert_eq!(
+            format(&[s("{} and {}, {0}{{}}"), n(1), n(2)]),
+            Some(Ok(s("1 and 2, 1{}")))
+        );
+        assert_eq!(
+            format(&[s("{0:.1}|{1:04}"), n(1), n(-7)]),
+            Some(Ok(s("1.0|-007")))
+        );
+        assert_eq!(
+            format(&[s("{2}"), n(1)]),
+            Some(Err(RuntimeError::IndexOutOfBounds {
+                index: Decimal::TWO,
+                len: 1
+            }))
+        );
+        assert_eq!(
+            format(&[s("{}{}"), n(1)]),
+            Some(Err(RuntimeError::IndexOutOfBounds {
+                index: Decimal::ONE,
+                len: 1
+            }))
+        );
+        assert!(matches!(
+            format(&[s("{x}")]),
+            Some(Err(RuntimeError::Unsupported(_)))
+        ));
+        assert!(matches!(
+            format(&[s("{0:x}"), n(1)]),
+            Some(Err(RuntimeError::Unsupported(_)))
+        ));
+        assert!(matches!(
+            format(&[s("{0")]),
+            Some(Err(RuntimeError::Unsupported(_)))
+        ));
+        assert_eq!(
+            format(&[n(1)]),
+            Some(Err(RuntimeError::ArgType {
+                arg: 0,
+                expected: "字符串",
+                found: "数字"
+            }))
+        );
+    }
+
+    #[test]
+    fn assert_builtin() {
+        assert_eq!(
+            builtin("assert", &[Value::Bool(true)]),
+            Some(Ok(Value::Nil))
+        );
+        assert_eq!(
+            builtin("assert", &[Value::Nil]),
+            Some(Err(RuntimeError::AssertionFailed("null".to_string())))
+        );
+        assert_eq!(
+            builtin(
+                "assert",
+                &[Value::Bool(false), Value::Str("hp应该大于0".to_string())]
+            ),
+            Some(Err(RuntimeError::AssertionFailed(
+                "hp应该大于0".to_string()
+            )))
+        );
+        assert!(matches!(
+            builtin("assert", &[]),
+            Some(Err(RuntimeError::Arity { expected: 1, .. }))
+        ));
+    }
+}
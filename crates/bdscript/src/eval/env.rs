@@ -0,0 +1,475 @@
+//! 变量环境
+
+use std::{collections::HashMap, fmt, mem, ops::Range, rc::Rc, time::Instant};
+
+use crate::parser::scanner::Command;
+
+use super::{
+    EvalConfig,
+    error::{Frame, RuntimeError},
+    generator::Resume,
+    operator::Operators,
+    profile::ProfileReport,
+    trace::Trace,
+    value::Value,
+    world::World,
+};
+
+/// 脚本中定义的函数，借用语法树中的参数和函数体
+#[derive(Debug, Clone, Copy)]
+pub struct Function<'a> {
+    pub args: &'a [&'a str],
+    pub commands: &'a [Command<'a>],
+}
+
+/// 宿主函数的签名
+type Native<'a> = dyn Fn(&[Value]) -> Result<Value, RuntimeError> + 'a;
+
+/// 宿主注册的函数，可以捕获宿主的状态
+#[derive(Clone)]
+pub struct NativeFn<'a>(Rc<Native<'a>>);
+
+impl<'a> NativeFn<'a> {
+    pub fn new(f: impl Fn(&[Value]) -> Result<Value, RuntimeError> + 'a) -> Self {
+        Self(Rc::new(f))
+    }
+
+    /// 调用函数
+    pub fn call(&self, args: &[Value]) -> Result<Value, RuntimeError> {
+        (self.0)(args)
+    }
+}
+
+impl fmt::Debug for NativeFn<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeFn")
+    }
+}
+
+/// 变量环境，由多层作用域组成，内层的变量会遮住外层的同名变量
+#[derive(Debug, Clone)]
+pub struct Env<'a> {
+    /// 作用域栈，最后一个是最内层，至少有一层全局作用域
+    frames: Vec<HashMap<String, Value>>,
+    /// 函数表，函数都是全局的
+    functions: HashMap<&'a str, Function<'a>>,
+    /// 宿主注册的函数
+    natives: HashMap<String, NativeFn<'a>>,
+    /// 宿主登记的自定义运算符
+    operators: Operators<'a>,
+    /// 宿主提供的世界，查询需要用到
+    world: Option<&'a dyn World>,
+    /// 当前的函数调用深度
+    depth: usize,
+    /// 求值配置
+    config: EvalConfig,
+    /// 已经注册、等待所在的函数/状态块退出时执行的defer语句
+    deferred: Vec<&'a Command<'a>>,
+    /// 脚本请求切换到的状态，由宿主在这一轮执行结束后取走
+    next_state: Option<&'a str>,
+    /// 语法树借用的源码，用于计算出错位置
+    source: Option<&'a str>,
+    /// 正在执行的脚本函数和状态块，最外层在前，打开性能分析时带有进入的时间
+    calls: Vec<(Frame, Option<Instant>)>,
+    /// 性能分析的结果
+    profile: ProfileReport,
+    /// 正在向外传递的错误的位置和调用栈
+    trace: Option<Trace>,
+    /// 是否作为生成器执行，只有生成器可以执行`yield`
+    generating: bool,
+    /// 生成器挂起时各层语句块执行到的位置，最内层在前
+    resume: Vec<Resume>,
+}
+
+/// 变量环境在某一时刻的状态，见[`Env::snapshot`]
+#[derive(Debug, Clone)]
+pub struct EnvSnapshot<'a> {
+    frames: Vec<HashMap<String, Value>>,
+    functions: HashMap<&'a str, Function<'a>>,
+    next_state: Option<&'a str>,
+}
+
+impl Default for Env<'_> {
+    fn default() -> Self {
+        Self {
+            frames: vec![HashMap::new()],
+            functions: HashMap::new(),
+            natives: HashMap::new(),
+            operators: Operators::new(),
+            world: None,
+            depth: 0,
+            config: EvalConfig::default(),
+            deferred: vec![],
+            next_state: None,
+            source: None,
+            calls: vec![],
+            profile: ProfileReport::default(),
+            trace: None,
+            generating: false,
+            resume: vec![],
+        }
+    }
+}
+
+impl<'a> Env<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 从内到外查找变量
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.frames.iter().rev().find_map(|frame| frame.get(name))
+    }
+
+    /// 修改已经存在的变量(最内层的同名变量)
+    pub fn set(&mut self, name: &str, value: Value) -> Result<(), RuntimeError> {
+        let slot = self
+            .frames
+            .iter_mut()
+            .rev()
+            .find_map(|frame| frame.get_mut(name))
+            .ok_or_else(|| RuntimeError::Undefined(name.to_string()))?;
+        *slot = value;
+        Ok(())
+    }
+
+    /// 在最内层作用域定义变量，已经存在时覆盖
+    pub fn define(&mut self, name: impl ToString, value: Value) {
+        self.frames
+            .last_mut()
+            .expect("至少有一层作用域")
+            .insert(name.to_string(), value);
+    }
+
+    /// 进入新的作用域
+    pub fn push_scope(&mut self) {
+        self.frames.push(HashMap::new());
+    }
+
+    /// 离开最内层作用域，全局作用域不会被移除
+    pub fn pop_scope(&mut self) {
+        if self.frames.len() > 1 {
+            self.frames.pop();
+        }
+    }
+
+    /// 定义函数，同名函数会被覆盖
+    pub fn define_fn(&mut self, name: &'a str, function: Function<'a>) {
+        self.functions.insert(name, function);
+    }
+
+    /// 查找函数
+    pub fn get_fn(&self, name: &str) -> Option<Function<'a>> {
+        self.functions.get(name).copied()
+    }
+
+    /// 注册宿主函数，同名的宿主函数会被覆盖
+    pub fn define_native(&mut self, name: impl ToString, function: NativeFn<'a>) {
+        self.natives.insert(name.to_string(), function);
+    }
+
+    /// 查找宿主函数
+    pub fn get_native(&self, name: &str) -> Option<NativeFn<'a>> {
+        self.natives.get(name).cloned()
+    }
+
+    /// 是否有这个名字的宿主函数或者脚本函数
+    pub fn has_fn(&self, name: &str) -> bool {
+        self.natives.contains_key(name) || self.functions.contains_key(name)
+    }
+
+    /// 设置自定义运算符，需要和解析时使用的[`Operators::syntax`]来自同一份登记
+    pub fn set_operators(&mut self, operators: Operators<'a>) {
+        self.operators = operators;
+    }
+
+    /// 宿主登记的自定义运算符
+    pub const fn operators(&self) -> &Operators<'a> {
+        &self.operators
+    }
+
+    /// 设置求值配置
+    pub const fn set_config(&mut self, config: EvalConfig) {
+        self.config = config;
+    }
+
+    /// 求值配置
+    pub const fn config(&self) -> EvalConfig {
+        self.config
+    }
+
+    /// 设置宿主提供的世界
+    pub fn set_world(&mut self, world: &'a dyn World) {
+        self.world = Some(world);
+    }
+
+    /// 宿主提供的世界
+    pub fn world(&self) -> Option<&'a dyn World> {
+        self.world
+    }
+
+    /// 注册一条defer语句
+    pub fn defer(&mut self, command: &'a Command<'a>) {
+        self.deferred.push(command);
+    }
+
+    /// 已经注册的defer语句数量，进入函数/状态块时记录下来交给[`Env::take_deferred`]
+    pub const fn deferred_len(&self) -> usize {
+        self.deferred.len()
+    }
+
+    /// 取出从`from`开始注册的defer语句，按注册的顺序返回
+    pub fn take_deferred(&mut self, from: usize) -> Vec<&'a Command<'a>> {
+        self.deferred.split_off(from.min(self.deferred.len()))
+    }
+
+    /// 请求切换到另一个状态，同一轮中后面的请求覆盖前面的
+    pub const fn set_state(&mut self, name: &'a str) {
+        self.next_state = Some(name);
+    }
+
+    /// 取走请求切换到的状态
+    pub const fn take_state(&mut self) -> Option<&'a str> {
+        self.next_state.take()
+    }
+
+    /// 保存当前的变量、脚本函数和请求切换到的状态，之后可以用[`Env::restore`]回到这个状态
+    ///
+    /// 用于规划时假设执行某个行动：执行之后检查结果，再回滚到执行之前。
+    /// 宿主函数、世界和求值配置由宿主管理，不在快照中
+    pub fn snapshot(&self) -> EnvSnapshot<'a> {
+        EnvSnapshot {
+            frames: self.frames.clone(),
+            functions: self.functions.clone(),
+            next_state: self.next_state,
+        }
+    }
+
+    /// 回到快照时的状态，快照之后定义和修改的变量、函数都会被丢弃
+    pub fn restore(&mut self, snapshot: EnvSnapshot<'a>) {
+        self.frames = snapshot.frames;
+        self.functions = snapshot.functions;
+        self.next_state = snapshot.next_state;
+    }
+
+    /// 设置语法树借用的源码，之后出错时可以给出位置
+    pub const fn set_source(&mut self, source: &'a str) {
+        self.source = Some(source);
+    }
+
+    /// 语法树借用的源码
+    pub const fn source(&self) -> Option<&'a str> {
+        self.source
+    }
+
+    /// 进入函数或状态块，出错时调用栈中会有这一层；打开性能分析时从这里开始计时
+    pub fn push_frame(&mut self, frame: Frame) {
+        let start = self.config.profile.then(Instant::now);
+        self.calls.push((frame, start));
+    }
+
+    /// 离开最内层的函数或状态块，打开性能分析时记下这一层的耗时
+    pub fn pop_frame(&mut self) {
+        if let Some((frame, Some(start))) = self.calls.pop() {
+            self.profile.record(frame, start.elapsed());
+        }
+    }
+
+    /// 性能分析的结果
+    pub const fn profile(&self) -> &ProfileReport {
+        &self.profile
+    }
+
+    /// 取走性能分析的结果，之后重新开始统计
+    pub fn take_profile(&mut self) -> ProfileReport {
+        mem::take(&mut self.profile)
+    }
+
+    /// 当前的调用栈
+    fn frames(&self) -> Vec<Frame> {
+        self.calls.iter().map(|(frame, _)| frame.clone()).collect()
+    }
+
+    /// 记录出错的位置，第一次记录时同时保存调用栈；
+    /// 错误向外传递时再次记录只会补上还没有的位置，不会覆盖内层给出的位置
+    pub fn trace_error(&mut self, span: Option<Range<usize>>) {
+        if self.trace.is_none() {
+            self.trace = Some(Trace {
+                span: None,
+                frames: self.frames(),
+            });
+        }
+        if let Some(trace) = &mut self.trace
+            && trace.span.is_none()
+        {
+            trace.span = span;
+        }
+    }
+
+    /// 取走记录的出错位置和调用栈，没有记录时调用栈为当前的调用栈
+    pub fn take_trace(&mut self) -> Trace {
+        self.trace.take().unwrap_or_else(|| Trace {
+            span: None,
+            frames: self.frames(),
+        })
+    }
+
+    /// 作为生成器执行，之后可以执行`yield`
+    pub const fn set_generating(&mut self, generating: bool) {
+        self.generating = generating;
+    }
+
+    /// 是否作为生成器执行
+    pub const fn generating(&self) -> bool {
+        self.generating
+    }
+
+    /// 生成器挂起时记下一层语句块执行到的位置，从内到外依次记录
+    pub(super) fn suspend(&mut self, resume: Resume) {
+        self.resume.push(resume);
+    }
+
+    /// 生成器继续执行时取出最外层还没有回到的位置，不在继续执行的过程中时为None
+    pub(super) fn resume(&mut self) -> Option<Resume> {
+        self.resume.pop()
+    }
+
+    /// 生成器是否正在回到挂起的位置
+    pub(super) const fn resuming(&self) -> bool {
+        !self.resume.is_empty()
+    }
+
+    /// 是否在函数调用中
+    pub const fn in_call(&self) -> bool {
+        self.depth > 0
+    }
+
+    /// 进入函数调用，函数体只能看到全局作用域和自己的作用域，
+    /// 返回调用者的局部作用域，调用结束后交给[`Env::leave_call`]恢复
+    ///
+    /// 调用深度超过[`EvalConfig::max_call_depth`]时报错
+    pub fn enter_call(&mut self) -> Result<Vec<HashMap<String, Value>>, RuntimeError> {
+        if self.depth >= self.config.max_call_depth {
+            return Err(RuntimeError::StackOverflow { depth: self.depth });
+        }
+        self.depth += 1;
+        let caller = self.frames.split_off(1);
+        self.frames.push(HashMap::new());
+        Ok(caller)
+    }
+
+    /// 结束函数调用，恢复调用者的局部作用域
+    pub fn leave_call(&mut self, caller: Vec<HashMap<String, Value>>) {
+        self.depth -= 1;
+        self.frames.truncate(1);
+        self.frames.extend(caller);
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'a> Env<'a> {
+    /// 保存变量、脚本函数、等待执行的defer语句、请求切换到的状态和生成器挂起的位置，
+    /// `commands`是正在执行的脚本，见[`SavedState`](super::save::SavedState)
+    ///
+    /// 函数调用中不能保存
+    pub fn save_state(
+        &self,
+        commands: &'a [Command<'a>],
+    ) -> Result<super::save::SavedState, RuntimeError> {
+        use super::save::{SavedState, save_deferred, save_functions};
+
+        if self.in_call() {
+            return Err(RuntimeError::SavedState("函数调用中不能保存".to_string()));
+        }
+        Ok(SavedState {
+            scopes: self
+                .frames
+                .iter()
+                .map(|frame| frame.clone().into_iter().collect())
+                .collect(),
+            functions: save_functions(commands, &self.functions)?,
+            deferred: save_deferred(commands, &self.deferred)?,
+            next_state: self.next_state.map(str::to_string),
+            generating: self.generating,
+            resume: self.resume.iter().map(Into::into).collect(),
+        })
+    }
+
+    /// 读取[`Env::save_state`]保存的状态，`commands`需要是保存时执行的同一份脚本，
+    /// 宿主函数、世界和求值配置保持不变
+    pub fn load_state(
+        &mut self,
+        commands: &'a [Command<'a>],
+        state: super::save::SavedState,
+    ) -> Result<(), RuntimeError> {
+        use super::save::{load_deferred, load_functions, load_state_name};
+
+        let functions = load_functions(commands, &state.functions)?;
+        let deferred = load_deferred(commands, &state.deferred)?;
+        let next_state = state
+            .next_state
+            .map(|name| load_state_name(commands, &name))
+            .transpose()?;
+        let mut frames: Vec<_> = state
+            .scopes
+            .into_iter()
+            .map(|frame| frame.into_iter().collect())
+            .collect();
+        if frames.is_empty() {
+            frames.push(HashMap::new());
+        }
+        self.frames = frames;
+        self.functions = functions;
+        self.deferred = deferred;
+        self.next_state = next_state;
+        self.generating = state.generating;
+        self.resume = state.resume.into_iter().map(Into::into).collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::{Env, RuntimeError, Value};
+
+    #[test]
+    fn env_shadowing() {
+        let number = |n: i64| Value::Number(Decimal::from(n));
+        let mut env = Env::new();
+        env.define("x", number(1));
+        env.define("y", number(1));
+        env.push_scope();
+        env.define("x", number(2));
+        env.set("y", number(3)).unwrap();
+        assert_eq!(env.get("x"), Some(&number(2)));
+        env.pop_scope();
+        // 内层定义的变量离开作用域后消失，修改的外层变量保留
+        assert_eq!(env.get("x"), Some(&number(1)));
+        assert_eq!(env.get("y"), Some(&number(3)));
+        assert_eq!(
+            env.set("z", number(0)),
+            Err(RuntimeError::Undefined("z".to_string()))
+        );
+    }
+
+    #[test]
+    fn env_snapshot() {
+        let number = |n: i64| Value::Number(Decimal::from(n));
+        let mut env = Env::new();
+        env.define("hp", number(10));
+        let snapshot = env.snapshot();
+        env.set("hp", number(3)).unwrap();
+        env.define("target", number(7));
+        env.set_state("flee");
+        env.restore(snapshot.clone());
+        assert_eq!(env.get("hp"), Some(&number(10)));
+        assert_eq!(env.get("target"), None);
+        assert_eq!(env.take_state(), None);
+        // 同一个快照可以反复回滚
+        env.set("hp", number(0)).unwrap();
+        env.restore(snapshot);
+        assert_eq!(env.get("hp"), Some(&number(10)));
+    }
+}
@@ -0,0 +1,177 @@
+//! 运行时错误
+
+use std::{fmt, ops::Range};
+
+use rust_decimal::Decimal;
+
+/// 执行脚本时产生的错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// 除以0(包括取模)
+    DivByZero,
+    /// 数字运算溢出
+    Overflow,
+    /// 未定义的变量
+    Undefined(String),
+    /// 未定义的函数
+    UndefinedFn(String),
+    /// 切换到脚本中没有定义的状态
+    UndefinedState(String),
+    /// 状态的继承形成了循环，依次记录循环中的状态，首尾相同
+    InheritanceCycle(Vec<String>),
+    /// 函数调用嵌套太深(通常是无限递归)
+    StackOverflow { depth: usize },
+    /// 调用函数时参数个数不对
+    Arity {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// 索引越界或者不是整数
+    IndexOutOfBounds { index: Decimal, len: usize },
+    /// 多重赋值左边的变量和右边的元素个数对不上
+    Destructure {
+        /// 左边固定的变量个数(不包括*变量)
+        expected: usize,
+        found: usize,
+        /// 左边有*变量，右边的元素可以更多
+        rest: bool,
+    },
+    /// 查询中用到了世界中不存在的组件
+    UnknownComponent(String),
+    /// 函数的参数类型不对
+    ArgType {
+        /// 参数的位置，从0开始
+        arg: usize,
+        /// 需要的类型
+        expected: &'static str,
+        /// 实际传入的值的类型
+        found: &'static str,
+    },
+    /// 运算不支持这些类型
+    TypeMismatch {
+        /// 运算符
+        op: String,
+        /// 参与运算的值的类型
+        types: Vec<&'static str>,
+    },
+    /// 还不支持执行的语法
+    Unsupported(String),
+    /// `assert`的条件为假，记录断言的说明
+    AssertionFailed(String),
+    /// 值和JSON之间转换失败
+    Json(String),
+    /// 读取的执行状态和脚本对不上，或者当前不能保存执行状态
+    SavedState(String),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DivByZero => write!(f, "除以0"),
+            Self::Overflow => write!(f, "数字溢出"),
+            Self::Undefined(name) => write!(f, "未定义的变量{name}"),
+            Self::UndefinedFn(name) => write!(f, "未定义的函数{name}"),
+            Self::UndefinedState(name) => write!(f, "未定义的状态@{name}"),
+            Self::InheritanceCycle(states) => {
+                write!(f, "状态的继承形成了循环：@{}", states.join(" -> @"))
+            }
+            Self::StackOverflow { depth } => write!(f, "函数调用嵌套超过{depth}层"),
+            Self::Arity {
+                name,
+                expected,
+                found,
+            } => write!(f, "函数{name}需要{expected}个参数，但是传入了{found}个"),
+            Self::IndexOutOfBounds { index, len } => {
+                write!(f, "索引{index}越界，长度为{len}")
+            }
+            Self::Destructure {
+                expected,
+                found,
+                rest,
+            } => {
+                let at_least = if *rest { "至少" } else { "" };
+                write!(
+                    f,
+                    "多重赋值需要{at_least}{expected}个值，但是右边有{found}个"
+                )
+            }
+            Self::UnknownComponent(name) => write!(f, "未知的组件{name}"),
+            Self::ArgType {
+                arg,
+                expected,
+                found,
+            } => write!(f, "第{}个参数应该是{expected}，但是传入了{found}", arg + 1),
+            Self::TypeMismatch { op, types } => {
+                write!(f, "{}不支持{op}运算", types.join("和"))
+            }
+            Self::Unsupported(what) => write!(f, "还不支持执行{what}"),
+            Self::AssertionFailed(message) => write!(f, "断言失败：{message}"),
+            Self::Json(message) => write!(f, "JSON转换失败：{message}"),
+            Self::SavedState(message) => write!(f, "保存或读取执行状态失败：{message}"),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// 调用栈中的一层：正在执行的脚本函数或状态块
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Frame {
+    /// 脚本函数
+    Function(String),
+    /// 状态块，由执行状态块的宿主压入
+    State(String),
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Function(name) => write!(f, "函数{name}"),
+            Self::State(name) => write!(f, "状态@{name}"),
+        }
+    }
+}
+
+/// 带出错位置和调用栈的运行时错误，由[`Interpreter`](super::interpreter::Interpreter)返回
+///
+/// 位置需要宿主先用[`Interpreter::set_source`](super::interpreter::Interpreter::set_source)提供源码，
+/// 可以和[`ParseError`](crate::parser::error::ParseError)一样交给ariadne/miette之类的库渲染
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalError {
+    pub error: RuntimeError,
+    /// 出错的表达式在源码中的位置，见[`trace`](super::trace)
+    pub span: Option<Range<usize>>,
+    /// 出错时的调用栈，最外层在前
+    pub frames: Vec<Frame>,
+}
+
+impl From<RuntimeError> for EvalError {
+    fn from(error: RuntimeError) -> Self {
+        Self {
+            error,
+            span: None,
+            frames: vec![],
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(span) = &self.span {
+            write!(f, "{}..{}: ", span.start, span.end)?;
+        }
+        write!(f, "{}", self.error)?;
+        // 和常见的回溯一样，最内层在前
+        for frame in self.frames.iter().rev() {
+            write!(f, "\n  在{frame}中")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for EvalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
@@ -0,0 +1,174 @@
+//! 生成器：逐步执行含有`yield`的脚本
+//!
+//! 执行到`yield`时，各层语句块从内到外记下执行到的位置([`Resume`])后依次返回，变量留在变量环境中；
+//! 下一次[`Generator::next`]从最外层的块开始按记下的位置回到`yield`之后继续执行。
+//! 函数调用不能挂起，所以`yield`只能出现在脚本的顶层以及其中的if、while、for、with块中
+
+use crate::parser::scanner::Command;
+
+use super::{Items, error::EvalError, interpreter::Interpreter, resume_program, value::Value};
+
+/// 挂起时一层语句块执行到的位置
+#[derive(Debug, Clone)]
+pub(crate) enum Resume {
+    /// 语句块从第几条语句继续
+    Block(usize),
+    /// if语句正在执行的分支，else分支的下标是条件分支的个数
+    Branch(usize),
+    /// for循环还没有遍历的元素
+    For(Items),
+}
+
+/// 作为生成器执行的脚本，见[`Interpreter::generator`]
+///
+/// 每次[`Generator::next`]执行到下一个`yield`并得到交出的值，脚本执行完之后返回None，
+/// 这时才执行顶层的defer语句；出错之后也不再继续执行
+#[derive(Debug)]
+pub struct Generator<'a> {
+    interpreter: Interpreter<'a>,
+    commands: &'a [Command<'a>],
+    finished: bool,
+}
+
+impl<'a> Generator<'a> {
+    pub(super) const fn new(interpreter: Interpreter<'a>, commands: &'a [Command<'a>]) -> Self {
+        Self {
+            interpreter,
+            commands,
+            finished: false,
+        }
+    }
+
+    /// 执行脚本的解释器，可以查看挂起时的变量
+    pub const fn interpreter(&self) -> &Interpreter<'a> {
+        &self.interpreter
+    }
+
+    /// 取回执行脚本的解释器
+    pub fn into_interpreter(self) -> Interpreter<'a> {
+        self.interpreter
+    }
+
+    /// 脚本是否已经执行完(或者出错)
+    pub const fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// 保存挂起时的执行状态，见[`Interpreter::save_state`]
+    #[cfg(feature = "serde")]
+    pub fn save_state(&self) -> Result<super::save::SavedState, super::error::RuntimeError> {
+        self.interpreter.save_state(self.commands)
+    }
+
+    /// 读取保存的执行状态，之后从保存时挂起的位置继续执行
+    #[cfg(feature = "serde")]
+    pub fn load_state(
+        &mut self,
+        state: super::save::SavedState,
+    ) -> Result<(), super::error::RuntimeError> {
+        self.interpreter.load_state(self.commands, state)?;
+        self.finished = false;
+        Ok(())
+    }
+}
+
+impl Iterator for Generator<'_> {
+    type Item = Result<Value, EvalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let commands = self.commands;
+        let result = self
+            .interpreter
+            .traced(|env| resume_program(commands, env))
+            .transpose();
+        self.finished = !matches!(result, Some(Ok(_)));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::{
+        eval::{error::RuntimeError, interpreter::Interpreter, value::Value},
+        parser::parse,
+    };
+
+    fn number(n: i64) -> Value {
+        Value::Number(Decimal::from(n))
+    }
+
+    #[test]
+    fn yield_in_loop() {
+        let ast = parse("i = 0\nwhile i < 3:\n\tyield i * 10\n\ti += 1\n").unwrap();
+        let mut generator = Interpreter::new().generator(&ast);
+        assert_eq!(generator.next().unwrap().unwrap(), number(0));
+        // 挂起时变量保留在环境中
+        assert_eq!(generator.interpreter().env().get("i"), Some(&number(0)));
+        assert_eq!(generator.next().unwrap().unwrap(), number(10));
+        assert_eq!(generator.next().unwrap().unwrap(), number(20));
+        assert!(generator.next().is_none());
+        assert!(generator.is_finished());
+        assert_eq!(generator.interpreter().env().get("i"), Some(&number(3)));
+        assert!(generator.next().is_none());
+    }
+
+    #[test]
+    fn yield_in_blocks() {
+        let src = "\
+log = []
+defer log = log + ['done']
+for target in ['a', 'b']:
+\twith target + '!' as shout:
+\t\tif target == 'a':
+\t\t\tyield target
+\t\tyield shout
+\t\tlog = log + [shout]
+yield log
+";
+        let ast = parse(src).unwrap();
+        let mut generator = Interpreter::new().generator(&ast);
+        let values: Vec<_> = generator.by_ref().map(Result::unwrap).collect();
+        let str = |s: &str| Value::Str(s.to_string());
+        assert_eq!(
+            values,
+            [
+                str("a"),
+                str("a!"),
+                str("b!"),
+                Value::Array(vec![str("a!"), str("b!")])
+            ]
+        );
+        // 顶层的defer在脚本执行完之后执行，with的作用域已经离开
+        let env = generator.interpreter().env();
+        assert_eq!(
+            env.get("log"),
+            Some(&Value::Array(vec![str("a!"), str("b!"), str("done")]))
+        );
+        assert_eq!(env.get("shout"), None);
+    }
+
+    #[test]
+    fn yield_errors() {
+        let ast = parse("fn f():\n\tyield 1\nyield 0\nf()\n").unwrap();
+        let mut generator = Interpreter::new().generator(&ast);
+        assert_eq!(generator.next().unwrap().unwrap(), number(0));
+        let unsupported = RuntimeError::Unsupported("生成器之外或者函数中的yield".to_string());
+        assert_eq!(
+            generator.next().unwrap().map_err(|error| error.error),
+            Err(unsupported.clone())
+        );
+        // 出错之后不再继续
+        assert!(generator.next().is_none());
+        // 不作为生成器执行时不能yield
+        let ast = parse("yield 1\n").unwrap();
+        assert_eq!(
+            Interpreter::new().run(&ast).map_err(|error| error.error),
+            Err(unsupported)
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! 整数运算的快速路径
+//!
+//! 脚本中的数字都是[`Decimal`]，但AI脚本里大多是计数、下标之类的整数。两边都是没有小数位、
+//! 能放进`i64`的整数时直接用`i64`计算，结果有小数(除不尽)或者溢出时交回[`Decimal`]计算，
+//! 所以结果和只用[`Decimal`]计算时完全相同(包括小数位数)
+
+use rust_decimal::Decimal;
+
+use crate::parser::scanner::BinaryOp;
+
+/// 没有小数位、能放进`i64`的整数
+#[inline]
+fn small_int(n: Decimal) -> Option<i64> {
+    let parts = n.unpack();
+    // -0在Decimal中带符号，交给Decimal处理
+    if parts.scale != 0 || parts.hi != 0 || parts.mid >= 1 << 31 || (parts.negative && n.is_zero())
+    {
+        return None;
+    }
+    let magnitude = (i64::from(parts.mid) << 32) | i64::from(parts.lo);
+    Some(if parts.negative {
+        -magnitude
+    } else {
+        magnitude
+    })
+}
+
+/// 用`i64`计算两个整数的加减乘除和取模，不能用快速路径时返回None，
+/// 由调用方按[`Decimal`]计算(包括除以0的报错)
+#[inline]
+pub fn fast_binary(op: &BinaryOp, a: Decimal, b: Decimal) -> Option<Decimal> {
+    let (a, b) = (small_int(a)?, small_int(b)?);
+    let n = match op {
+        BinaryOp::Add => a.checked_add(b)?,
+        BinaryOp::Sub => a.checked_sub(b)?,
+        BinaryOp::Mul => a.checked_mul(b)?,
+        // 除不尽时结果有小数
+        BinaryOp::Div if a.checked_rem(b)? == 0 => a.checked_div(b)?,
+        BinaryOp::Mod => a.checked_rem(b)?,
+        _ => return None,
+    };
+    Some(Decimal::from(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::{
+        eval::{EvalConfig, binary, value::Value},
+        parser::scanner::BinaryOp,
+    };
+
+    use super::fast_binary;
+
+    /// 只用Decimal计算
+    fn slow(op: &BinaryOp, a: Decimal, b: Decimal) -> Option<Decimal> {
+        match op {
+            BinaryOp::Add => a.checked_add(b),
+            BinaryOp::Sub => a.checked_sub(b),
+            BinaryOp::Mul => a.checked_mul(b),
+            BinaryOp::Div => a.checked_div(b),
+            BinaryOp::Mod => a.checked_rem(b),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn same_as_decimal() {
+        let numbers = [
+            0,
+            1,
+            -1,
+            2,
+            3,
+            -7,
+            10,
+            100,
+            i64::from(i32::MAX),
+            -(1 << 40) - 3,
+            i64::MAX,
+            i64::MIN,
+        ]
+        .map(Decimal::from);
+        let ops = [
+            BinaryOp::Add,
+            BinaryOp::Sub,
+            BinaryOp::Mul,
+            BinaryOp::Div,
+            BinaryOp::Mod,
+        ];
+        for op in &ops {
+            for a in numbers {
+                for b in numbers {
+                    if let Some(fast) = fast_binary(op, a, b) {
+                        let slow = slow(op, a, b).unwrap();
+                        // 值和小数位数都相同
+                        assert_eq!(fast.serialize(), slow.serialize(), "{a} {op:?} {b}");
+                    }
+                }
+            }
+        }
+        // 除不尽、有小数、溢出和除以0时交给Decimal
+        let n = |n: i64| Decimal::from(n);
+        assert_eq!(fast_binary(&BinaryOp::Div, n(7), n(2)), None);
+        assert_eq!(fast_binary(&BinaryOp::Add, Decimal::new(15, 1), n(1)), None);
+        assert_eq!(fast_binary(&BinaryOp::Add, n(i64::MAX), n(1)), None);
+        assert_eq!(fast_binary(&BinaryOp::Div, n(1), n(0)), None);
+        assert_eq!(fast_binary(&BinaryOp::Mod, n(i64::MIN), n(-1)), None);
+    }
+
+    #[test]
+    fn binary_falls_back() {
+        let config = EvalConfig::default();
+        let number = |n: Decimal| Value::Number(n);
+        // 溢出i64之后由Decimal继续计算
+        assert_eq!(
+            binary(
+                &BinaryOp::Add,
+                number(Decimal::from(i64::MAX)),
+                number(Decimal::ONE),
+                config
+            ),
+            Ok(number(Decimal::from(i128::from(i64::MAX) + 1)))
+        );
+        assert_eq!(
+            binary(
+                &BinaryOp::Div,
+                number(Decimal::from(7)),
+                number(Decimal::from(2)),
+                config
+            ),
+            Ok(number(Decimal::new(35, 1)))
+        );
+        assert_eq!(
+            binary(
+                &BinaryOp::Mod,
+                number(Decimal::from(-7)),
+                number(Decimal::from(3)),
+                config
+            ),
+            Ok(number(Decimal::from(-1)))
+        );
+    }
+}
@@ -0,0 +1,371 @@
+//! 解释器
+
+use crate::parser::scanner::Command;
+
+use super::{
+    EvalConfig, Flow, call,
+    env::{Env, EnvSnapshot, NativeFn},
+    error::{EvalError, RuntimeError},
+    eval_program, exec_scope,
+    generator::Generator,
+    operator::Operators,
+    profile::ProfileReport,
+    state::StateMachine,
+    testing::{TestReport, run_tests},
+    value::Value,
+    world::World,
+};
+
+/// 解释器，持有变量环境以及宿主注册的函数
+#[derive(Debug, Default)]
+pub struct Interpreter<'a> {
+    env: Env<'a>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 使用指定的求值配置创建解释器
+    pub fn with_config(config: EvalConfig) -> Self {
+        let mut interpreter = Self::default();
+        interpreter.env.set_config(config);
+        interpreter
+    }
+
+    /// 注册宿主函数，调用时优先于脚本中定义的同名函数
+    pub fn register_fn(
+        &mut self,
+        name: &str,
+        f: impl Fn(&[Value]) -> Result<Value, RuntimeError> + 'a,
+    ) {
+        self.env.define_native(name, NativeFn::new(f));
+    }
+
+    /// 设置自定义运算符，见[`Operators`]
+    pub fn set_operators(&mut self, operators: Operators<'a>) {
+        self.env.set_operators(operators);
+    }
+
+    /// 设置查询使用的世界
+    pub fn set_world(&mut self, world: &'a dyn World) {
+        self.env.set_world(world);
+    }
+
+    /// 设置语法树借用的源码，之后返回的[`EvalError`]带有出错的位置
+    pub const fn set_source(&mut self, source: &'a str) {
+        self.env.set_source(source);
+    }
+
+    /// 执行`f`，出错时带上出错的位置和调用栈
+    pub(super) fn traced<T>(
+        &mut self,
+        f: impl FnOnce(&mut Env<'a>) -> Result<T, RuntimeError>,
+    ) -> Result<T, EvalError> {
+        // 丢掉之前没有取走的记录
+        self.env.take_trace();
+        f(&mut self.env).map_err(|error| self.env.take_trace().into_error(error))
+    }
+
+    /// 执行脚本，顶层的defer语句在脚本执行完之后执行
+    pub fn run(&mut self, commands: &'a [Command<'a>]) -> Result<Flow<'a>, EvalError> {
+        self.traced(|env| exec_scope(commands, env))
+    }
+
+    /// 执行脚本并返回最后一条语句的值，最后一条语句不是表达式时为[`Value::Unit`]，见[`eval_program`]
+    pub fn eval_program(&mut self, commands: &'a [Command<'a>]) -> Result<Value, EvalError> {
+        self.traced(|env| eval_program(commands, env))
+    }
+
+    /// 把脚本作为生成器执行，每次调用[`Generator::next`]执行到下一个`yield`
+    pub const fn generator(mut self, commands: &'a [Command<'a>]) -> Generator<'a> {
+        self.env.set_generating(true);
+        Generator::new(self, commands)
+    }
+
+    /// 把脚本交给状态机执行，先执行状态块之外的顶层语句，之后每次调用[`StateMachine::tick`]执行当前状态的状态块
+    ///
+    /// 从`initial`状态开始，顶层语句中请求的切换立即生效，没有这个状态时报错
+    pub fn state_machine(
+        self,
+        commands: &'a [Command<'a>],
+        initial: &str,
+    ) -> Result<StateMachine<'a>, EvalError> {
+        StateMachine::new(self, commands, initial)
+    }
+
+    /// 取走脚本在执行中请求切换到的状态(`@名字`或者`defer @名字`)，没有请求时为None
+    pub const fn take_state(&mut self) -> Option<&'a str> {
+        self.env.take_state()
+    }
+
+    /// 保存变量环境，见[`Env::snapshot`]
+    pub fn snapshot(&self) -> EnvSnapshot<'a> {
+        self.env.snapshot()
+    }
+
+    /// 回到快照时的变量环境，见[`Env::restore`]
+    pub fn restore(&mut self, snapshot: EnvSnapshot<'a>) {
+        self.env.restore(snapshot);
+    }
+
+    /// 保存执行状态，见[`Env::save_state`]
+    #[cfg(feature = "serde")]
+    pub fn save_state(
+        &self,
+        commands: &'a [Command<'a>],
+    ) -> Result<super::save::SavedState, RuntimeError> {
+        self.env.save_state(commands)
+    }
+
+    /// 读取执行状态，见[`Env::load_state`]
+    #[cfg(feature = "serde")]
+    pub fn load_state(
+        &mut self,
+        commands: &'a [Command<'a>],
+        state: super::save::SavedState,
+    ) -> Result<(), RuntimeError> {
+        self.env.load_state(commands, state)
+    }
+
+    /// 运行脚本中的测试块，见[`run_tests`]
+    pub fn run_tests(&self, commands: &'a [Command<'a>]) -> TestReport {
+        run_tests(commands, &self.env)
+    }
+
+    /// 从宿主调用函数
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, EvalError> {
+        self.traced(|env| call(name, args, env))
+    }
+
+    /// 取走性能分析的结果，需要在求值配置中打开[`EvalConfig::profile`]
+    pub fn take_profile(&mut self) -> ProfileReport {
+        self.env.take_profile()
+    }
+
+    /// 变量环境
+    pub const fn env(&self) -> &Env<'a> {
+        &self.env
+    }
+
+    /// 可修改的变量环境，用于宿主设置全局变量
+    pub const fn env_mut(&mut self) -> &mut Env<'a> {
+        &mut self.env
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use rust_decimal::Decimal;
+
+    use crate::{
+        eval::{
+            EvalConfig,
+            builtin::arity,
+            error::{EvalError, Frame, RuntimeError},
+            operator::Operators,
+            value::Value,
+        },
+        parser::parse,
+        parser::{
+            ParseConfig,
+            operator::{Associativity, Precedence},
+        },
+    };
+
+    use super::Interpreter;
+
+    #[test]
+    fn native_functions() {
+        let logs = RefCell::new(vec![]);
+        let ast =
+            parse("fn double(x):\n\treturn 0\nlog('start')\ny = double(21)\nlog(y)\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.register_fn("double", |args| {
+            arity("double", args, 1)?;
+            Ok(Value::Number(args[0].expect_number(0)? * Decimal::TWO))
+        });
+        interpreter.register_fn("log", |args| {
+            logs.borrow_mut()
+                .extend(args.iter().map(ToString::to_string));
+            Ok(Value::Nil)
+        });
+        interpreter.run(&ast).unwrap();
+        // 宿主函数优先于脚本中的同名函数
+        assert_eq!(
+            interpreter.env().get("y"),
+            Some(&Value::Number(Decimal::from(42)))
+        );
+        drop(interpreter);
+        assert_eq!(logs.into_inner(), ["start", "42"]);
+    }
+
+    #[test]
+    fn unbounded_recursion() {
+        let ast = parse("fn f(n):\n\treturn f(n + 1)\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&ast).unwrap();
+        assert_eq!(
+            interpreter
+                .call("f", vec![Value::Number(Decimal::ZERO)])
+                .map_err(|error| error.error),
+            Err(RuntimeError::StackOverflow { depth: 128 })
+        );
+        // 出错之后调用深度恢复，仍然可以正常调用
+        assert_eq!(interpreter.env().get("n"), None);
+        assert_eq!(
+            interpreter
+                .call("f", vec![Value::Number(Decimal::ONE)])
+                .map_err(|error| error.error),
+            Err(RuntimeError::StackOverflow { depth: 128 })
+        );
+
+        // 宿主可以调整最多的嵌套层数
+        let ast = parse("fn f(n):\n\tif n > 0:\n\t\treturn f(n - 1)\n\treturn 'done'\n").unwrap();
+        let mut interpreter = Interpreter::with_config(EvalConfig {
+            max_call_depth: 5,
+            ..Default::default()
+        });
+        interpreter.run(&ast).unwrap();
+        let number = |n: i64| vec![Value::Number(Decimal::from(n))];
+        assert_eq!(
+            interpreter.call("f", number(4)),
+            Ok(Value::Str("done".to_string()))
+        );
+        assert_eq!(
+            interpreter
+                .call("f", number(5))
+                .map_err(|error| error.error),
+            Err(RuntimeError::StackOverflow { depth: 5 })
+        );
+    }
+
+    #[test]
+    fn what_if_rollback() {
+        let ast = parse("hp = 10\nfn attack():\n\thp -= 4\n\t@chase\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&ast).unwrap();
+        // 假设执行攻击，检查结果之后回滚
+        let snapshot = interpreter.snapshot();
+        interpreter.call("attack", vec![]).unwrap();
+        assert_eq!(
+            interpreter.env().get("hp"),
+            Some(&Value::Number(Decimal::from(6)))
+        );
+        interpreter.restore(snapshot);
+        assert_eq!(
+            interpreter.env().get("hp"),
+            Some(&Value::Number(Decimal::TEN))
+        );
+        assert_eq!(interpreter.take_state(), None);
+    }
+
+    #[test]
+    fn error_span_and_frames() {
+        let src =
+            "fn inner(x):\n\treturn x / 0\nfn outer():\n\treturn inner(hp)\nhp = 3\nouter()\n";
+        let ast = parse(src).unwrap();
+        let other = parse("y = missing + 1\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_source(src);
+        let error = interpreter.run(&ast).unwrap_err();
+        let start = src.find("x / 0").unwrap();
+        assert_eq!(
+            error,
+            EvalError {
+                error: RuntimeError::DivByZero,
+                // 指向被调用的函数中出错的表达式
+                span: Some(start..start + 1),
+                frames: vec![
+                    Frame::Function("outer".to_string()),
+                    Frame::Function("inner".to_string())
+                ],
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{}..{}: 除以0\n  在函数inner中\n  在函数outer中",
+                start,
+                start + 1
+            )
+        );
+
+        // 出错之后调用栈恢复，顶层的错误没有调用栈
+        let error = interpreter.run(&other).unwrap_err();
+        assert_eq!(error.error, RuntimeError::Undefined("missing".to_string()));
+        assert!(error.frames.is_empty());
+        // 没有从提供的源码解析出来的语法树没有位置
+        assert_eq!(error.span, None);
+    }
+
+    #[test]
+    fn profile_functions() {
+        let ast = parse(
+            "fn leaf(x):\n\treturn x + 1\nfn branch():\n\treturn leaf(1) + leaf(2)\n\
+             for i in 0..3:\n\tbranch()\n",
+        )
+        .unwrap();
+        let mut interpreter = Interpreter::with_config(EvalConfig {
+            profile: true,
+            ..Default::default()
+        });
+        interpreter.run(&ast).unwrap();
+        let report = interpreter.take_profile();
+        let branch = report.get(&Frame::Function("branch".to_string())).unwrap();
+        let leaf = report.get(&Frame::Function("leaf".to_string())).unwrap();
+        assert_eq!((branch.calls, leaf.calls), (3, 6));
+        // 外层函数的耗时包括其中调用的函数
+        assert!(branch.total >= leaf.total);
+        assert_eq!(report.entries().len(), 2);
+        assert!(interpreter.take_profile().is_empty());
+
+        // 默认不统计
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&ast).unwrap();
+        assert!(interpreter.take_profile().is_empty());
+    }
+
+    #[test]
+    fn custom_operator() {
+        // 三路比较，和比较运算同级
+        let operators = Operators::new().operator(
+            "<=>",
+            Precedence::Comparison,
+            Associativity::Left,
+            |a, b| {
+                let ordering = a.expect_number(0)?.cmp(&b.expect_number(1)?);
+                Ok(Value::Number(Decimal::from(ordering as i8)))
+            },
+        );
+        let config = ParseConfig {
+            operators: operators.syntax(),
+            ..Default::default()
+        };
+        let ast = config.parse("a = 1 + 2 <=> 2 * 2\nb = 5 <=> 5\n").unwrap();
+        let invalid = config.parse("'a' <=> 1\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_operators(operators);
+        interpreter.run(&ast).unwrap();
+        assert_eq!(
+            interpreter.env().get("a"),
+            Some(&Value::Number(Decimal::NEGATIVE_ONE))
+        );
+        assert_eq!(
+            interpreter.env().get("b"),
+            Some(&Value::Number(Decimal::ZERO))
+        );
+        // 实现中的错误照常返回
+        assert_eq!(
+            interpreter.run(&invalid).map_err(|error| error.error),
+            Err(RuntimeError::ArgType {
+                arg: 0,
+                expected: "数字",
+                found: "字符串"
+            })
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! 值和JSON之间的转换，需要开启`serde_json`特性
+//!
+//! 数字、字符串、布尔值、`null`、数组和字典可以转换，元组转换成JSON数组，
+//! 字典的键必须是字符串。数字按十进制原样读写，不经过浮点数
+
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use serde_json::{Map, Number};
+
+use super::{builtin::arity, error::RuntimeError, value::Value};
+
+/// 把值转换成JSON
+pub fn to_json(value: &Value) -> Result<serde_json::Value, RuntimeError> {
+    Ok(match value {
+        Value::Number(n) => {
+            let number = Number::from_str(&n.normalize().to_string())
+                .map_err(|error| RuntimeError::Json(error.to_string()))?;
+            serde_json::Value::Number(number)
+        }
+        Value::Str(s) => serde_json::Value::String(s.clone()),
+        Value::Bool(b) => serde_json::Value::Bool(*b),
+        Value::Nil => serde_json::Value::Null,
+        Value::Array(items) | Value::Tuple(items) => {
+            serde_json::Value::Array(items.iter().map(to_json).try_collect()?)
+        }
+        Value::Dict(items) => {
+            let mut object = Map::with_capacity(items.len());
+            for (key, value) in items {
+                let Value::Str(key) = key else {
+                    return Err(RuntimeError::Json(format!(
+                        "字典的键只能是字符串，不能是{}",
+                        key.type_name()
+                    )));
+                };
+                object.insert(key.clone(), to_json(value)?);
+            }
+            serde_json::Value::Object(object)
+        }
+        value => {
+            return Err(RuntimeError::Json(format!(
+                "{}不能转换成JSON",
+                value.type_name()
+            )));
+        }
+    })
+}
+
+/// 把JSON转换成值，对象转换成字典
+pub fn from_json(json: &serde_json::Value) -> Result<Value, RuntimeError> {
+    Ok(match json {
+        serde_json::Value::Number(n) => {
+            let digits = n.to_string();
+            // 带指数的写法(例如`1e3`)只有from_scientific能读
+            let number = Decimal::from_str(&digits)
+                .or_else(|_| Decimal::from_scientific(&digits))
+                .map_err(|_| RuntimeError::Json(format!("数字{digits}超出范围")))?;
+            Value::Number(number)
+        }
+        serde_json::Value::String(s) => Value::Str(s.clone()),
+        serde_json::Value::Bool(b) => Value::Bool(*b),
+        serde_json::Value::Null => Value::Nil,
+        serde_json::Value::Array(items) => Value::Array(items.iter().map(from_json).try_collect()?),
+        serde_json::Value::Object(object) => Value::Dict(
+            object
+                .iter()
+                .map(|(key, value)| Ok((Value::Str(key.clone()), from_json(value)?)))
+                .try_collect()?,
+        ),
+    })
+}
+
+/// 内置函数`to_json(value)`，得到JSON字符串
+pub(super) fn to_json_builtin(args: &[Value]) -> Result<Value, RuntimeError> {
+    arity("to_json", args, 1)?;
+    Ok(Value::Str(to_json(&args[0])?.to_string()))
+}
+
+/// 内置函数`from_json(string)`，解析JSON字符串
+pub(super) fn from_json_builtin(args: &[Value]) -> Result<Value, RuntimeError> {
+    arity("from_json", args, 1)?;
+    let json = serde_json::from_str(args[0].expect_str(0)?)
+        .map_err(|error| RuntimeError::Json(error.to_string()))?;
+    from_json(&json)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use rust_decimal::Decimal;
+
+    use crate::{
+        eval::{error::RuntimeError, interpreter::Interpreter, value::Value},
+        parser::parse,
+    };
+
+    use super::{from_json_builtin, to_json_builtin};
+
+    fn str(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let value = Value::Dict(vec![
+            (str("name"), str("goblin")),
+            (str("hp"), Value::Number(Decimal::new(125, 1))),
+            (str("alive"), Value::Bool(true)),
+            (str("target"), Value::Nil),
+            (
+                str("path"),
+                Value::Array(vec![
+                    Value::Dict(vec![
+                        (str("x"), Value::Number(Decimal::from(1))),
+                        (str("y"), Value::Number(Decimal::new(-25, 1))),
+                    ]),
+                    Value::Array(vec![]),
+                ]),
+            ),
+        ]);
+        let json = to_json_builtin(slice::from_ref(&value)).unwrap();
+        assert_eq!(
+            json,
+            str(
+                r#"{"name":"goblin","hp":12.5,"alive":true,"target":null,"path":[{"x":1,"y":-2.5},[]]}"#
+            )
+        );
+        assert_eq!(from_json_builtin(&[json]), Ok(value));
+        // 超过f64精度的数字也能原样读写
+        let precise = str("[0.1000000000000000000000000001,1e3]");
+        assert_eq!(
+            from_json_builtin(&[precise]),
+            Ok(Value::Array(vec![
+                Value::Number("0.1000000000000000000000000001".parse().unwrap()),
+                Value::Number(Decimal::from(1000)),
+            ]))
+        );
+    }
+
+    #[test]
+    fn json_errors() {
+        let dict = Value::Dict(vec![(Value::Number(Decimal::ONE), str("a"))]);
+        assert_eq!(
+            to_json_builtin(&[dict]),
+            Err(RuntimeError::Json(
+                "字典的键只能是字符串，不能是数字".to_string()
+            ))
+        );
+        assert_eq!(
+            to_json_builtin(&[Value::Symbol("attack".to_string())]),
+            Err(RuntimeError::Json("符号不能转换成JSON".to_string()))
+        );
+        assert!(matches!(
+            from_json_builtin(&[str("{")]),
+            Err(RuntimeError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn json_in_script() {
+        let ast = parse(
+            "save = to_json({\"hp\": 10, \"items\": [\"sword\"]})\nassert(save == '{\"hp\":10,\"items\":[\"sword\"]}')\nfrom_json(save)\n",
+        )
+        .unwrap();
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.eval_program(&ast).map_err(|error| error.error),
+            Ok(Value::Dict(vec![
+                (str("hp"), Value::Number(Decimal::from(10))),
+                (str("items"), Value::Array(vec![str("sword")])),
+            ]))
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! 自定义二元运算符的实现
+//!
+//! 同一份[`Operators`]既提供解析需要的写法([`Operators::syntax`])，也提供求值需要的实现，
+//! 保证[`BinaryOp::Custom`](crate::parser::scanner::BinaryOp::Custom)中的下标在两边一致
+
+use std::{fmt, rc::Rc};
+
+use crate::parser::operator::{Associativity, OperatorSyntax, Precedence};
+
+use super::{error::RuntimeError, value::Value};
+
+/// 自定义运算符的实现
+type Apply<'a> = dyn Fn(Value, Value) -> Result<Value, RuntimeError> + 'a;
+
+/// 登记的自定义运算符，按登记的顺序编号
+#[derive(Clone, Default)]
+pub struct Operators<'a> {
+    syntax: Vec<OperatorSyntax>,
+    apply: Vec<Rc<Apply<'a>>>,
+}
+
+impl<'a> Operators<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 登记一个运算符，写法相同时后登记的覆盖先登记的
+    #[must_use]
+    pub fn operator(
+        mut self,
+        symbol: &str,
+        precedence: Precedence,
+        associativity: Associativity,
+        apply: impl Fn(Value, Value) -> Result<Value, RuntimeError> + 'a,
+    ) -> Self {
+        let syntax = OperatorSyntax {
+            symbol: symbol.to_string(),
+            precedence,
+            associativity,
+        };
+        match self.syntax.iter().position(|s| s.symbol == symbol) {
+            Some(index) => {
+                self.syntax[index] = syntax;
+                self.apply[index] = Rc::new(apply);
+            }
+            None => {
+                self.syntax.push(syntax);
+                self.apply.push(Rc::new(apply));
+            }
+        }
+        self
+    }
+
+    /// 解析需要的写法，放进[`ParseConfig::operators`](crate::parser::ParseConfig::operators)
+    pub fn syntax(&self) -> Vec<OperatorSyntax> {
+        self.syntax.clone()
+    }
+
+    /// 计算下标为`index`的运算符
+    pub fn apply(&self, index: usize, lhs: Value, rhs: Value) -> Result<Value, RuntimeError> {
+        let apply = self.apply.get(index).ok_or_else(|| {
+            RuntimeError::Unsupported(format!("没有登记的第{index}个自定义运算符"))
+        })?;
+        apply(lhs, rhs)
+    }
+}
+
+impl fmt::Debug for Operators<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(&self.syntax).finish()
+    }
+}
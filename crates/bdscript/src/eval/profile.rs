@@ -0,0 +1,97 @@
+//! 性能分析
+//!
+//! 打开[`EvalConfig::profile`](super::EvalConfig::profile)之后，每次进入和离开脚本函数、状态块时计时，
+//! 按函数/状态块累计调用次数和耗时。耗时包括其中调用的其他函数
+
+use std::{cmp::Reverse, collections::HashMap, fmt, time::Duration};
+
+use super::error::Frame;
+
+/// 一个函数或状态块的统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProfileEntry {
+    /// 调用次数(状态块为执行的轮数)
+    pub calls: u32,
+    /// 累计耗时
+    pub total: Duration,
+}
+
+impl ProfileEntry {
+    /// 平均每次调用的耗时
+    pub fn average(&self) -> Duration {
+        self.total.checked_div(self.calls).unwrap_or_default()
+    }
+}
+
+/// 性能分析的结果，按函数/状态块分别统计
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileReport {
+    entries: HashMap<Frame, ProfileEntry>,
+}
+
+impl ProfileReport {
+    /// 记录一次调用
+    pub fn record(&mut self, frame: Frame, elapsed: Duration) {
+        let entry = self.entries.entry(frame).or_default();
+        entry.calls += 1;
+        entry.total += elapsed;
+    }
+
+    /// 一个函数或状态块的统计，没有调用过时为None
+    pub fn get(&self, frame: &Frame) -> Option<&ProfileEntry> {
+        self.entries.get(frame)
+    }
+
+    /// 所有的统计，按累计耗时从多到少排列
+    pub fn entries(&self) -> Vec<(&Frame, &ProfileEntry)> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(_, entry)| Reverse(entry.total));
+        entries
+    }
+
+    /// 是否没有任何统计
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (frame, entry) in self.entries() {
+            writeln!(
+                f,
+                "{frame}: {}次，共{:?}，平均{:?}",
+                entry.calls,
+                entry.total,
+                entry.average()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::eval::error::Frame;
+
+    use super::ProfileReport;
+
+    #[test]
+    fn profile_entries() {
+        let mut report = ProfileReport::default();
+        let tick = Frame::State("patrol".to_string());
+        report.record(tick.clone(), Duration::from_millis(3));
+        report.record(tick.clone(), Duration::from_millis(1));
+        report.record(Frame::Function("f".to_string()), Duration::from_millis(5));
+        let entry = report.get(&tick).unwrap();
+        assert_eq!(entry.calls, 2);
+        assert_eq!(entry.average(), Duration::from_millis(2));
+        // 按累计耗时排列
+        assert_eq!(
+            report.to_string(),
+            "函数f: 1次，共5ms，平均5ms\n状态@patrol: 2次，共4ms，平均2ms\n"
+        );
+    }
+}
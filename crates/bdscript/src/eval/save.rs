@@ -0,0 +1,311 @@
+//! 保存和读取执行状态，需要开启`serde`特性
+//!
+//! 变量按值保存；脚本函数、等待执行的defer语句和状态名借用语法树，保存的是它们在语法树中的位置，
+//! 读取时在同一份脚本的语法树中重新找到。宿主函数、世界和求值配置由宿主在读取前重新设置，
+//! 保存着函数值的变量按名字引用函数，读取之后引用宿主重新注册的同名函数
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    iter, ptr, slice,
+};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::parser::scanner::Command;
+
+use super::{Items, env::Function, error::RuntimeError, generator::Resume, value::Value};
+
+/// 语句在语法树中的位置：依次是语句在块中的下标、进入的是语句中的第几个块
+type Path = Vec<usize>;
+
+/// 可以保存的执行状态，见[`Interpreter::save_state`](super::interpreter::Interpreter::save_state)
+///
+/// 变量和函数按名字排序，同样的状态总是保存出同样的结果
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    /// 作用域栈中的变量，第一个是全局作用域
+    pub(super) scopes: Vec<BTreeMap<String, Value>>,
+    /// 已经定义的脚本函数
+    pub(super) functions: BTreeMap<String, Path>,
+    /// 等待执行的defer语句，按注册的顺序
+    pub(super) deferred: Vec<Path>,
+    /// 脚本请求切换到的状态
+    pub(super) next_state: Option<String>,
+    /// 是否作为生成器执行
+    pub(super) generating: bool,
+    /// 生成器挂起的位置，最内层在前
+    pub(super) resume: Vec<SavedResume>,
+}
+
+/// 保存的[`Resume`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) enum SavedResume {
+    Block(usize),
+    Branch(usize),
+    Range {
+        next: Option<Decimal>,
+        end: Decimal,
+        inclusive: bool,
+    },
+    Values(Vec<Value>),
+}
+
+impl From<&Resume> for SavedResume {
+    fn from(resume: &Resume) -> Self {
+        match resume {
+            Resume::Block(index) => Self::Block(*index),
+            Resume::Branch(index) => Self::Branch(*index),
+            Resume::For(Items::Range {
+                next,
+                end,
+                inclusive,
+            }) => Self::Range {
+                next: *next,
+                end: *end,
+                inclusive: *inclusive,
+            },
+            Resume::For(Items::Values(values)) => Self::Values(values.as_slice().to_vec()),
+        }
+    }
+}
+
+impl From<SavedResume> for Resume {
+    fn from(resume: SavedResume) -> Self {
+        match resume {
+            SavedResume::Block(index) => Self::Block(index),
+            SavedResume::Branch(index) => Self::Branch(index),
+            SavedResume::Range {
+                next,
+                end,
+                inclusive,
+            } => Self::For(Items::Range {
+                next,
+                end,
+                inclusive,
+            }),
+            SavedResume::Values(values) => Self::For(Items::Values(values.into_iter())),
+        }
+    }
+}
+
+/// 读取的状态和语法树对不上
+fn mismatch(what: impl std::fmt::Display) -> RuntimeError {
+    RuntimeError::SavedState(format!("脚本中找不到保存时的{what}"))
+}
+
+/// 语句中包含的块
+fn blocks<'a>(command: &'a Command<'a>) -> Vec<&'a [Command<'a>]> {
+    match command {
+        Command::If {
+            if_branch,
+            else_branch,
+        } => if_branch
+            .iter()
+            .map(|branch| branch.commands.as_slice())
+            .chain(else_branch.as_deref())
+            .collect(),
+        Command::While { command, .. } => vec![command],
+        Command::For { commands, .. } | Command::Function { commands, .. } => vec![commands],
+        Command::StateBlock {
+            handlers, commands, ..
+        } => iter::once(commands.as_slice())
+            .chain(handlers.iter().map(|handler| handler.commands.as_slice()))
+            .collect(),
+        Command::With { body, .. }
+        | Command::Template { body, .. }
+        | Command::Test { body, .. } => vec![body],
+        Command::Defer(command) => vec![slice::from_ref(command.as_ref())],
+        _ => vec![],
+    }
+}
+
+/// 找到第一条满足条件的语句的位置
+fn find_path<'a>(
+    commands: &'a [Command<'a>],
+    found: &impl Fn(&Command<'a>) -> bool,
+) -> Option<Path> {
+    commands.iter().enumerate().find_map(|(i, command)| {
+        if found(command) {
+            return Some(vec![i]);
+        }
+        blocks(command)
+            .into_iter()
+            .enumerate()
+            .find_map(|(block, commands)| {
+                let mut path = find_path(commands, found)?;
+                path.splice(0..0, [i, block]);
+                Some(path)
+            })
+    })
+}
+
+/// 按位置找到语句
+fn resolve<'a>(commands: &'a [Command<'a>], path: &[usize]) -> Option<&'a Command<'a>> {
+    let (&i, rest) = path.split_first()?;
+    let command = commands.get(i)?;
+    match rest.split_first() {
+        None => Some(command),
+        Some((&block, rest)) => resolve(blocks(command).get(block)?, rest),
+    }
+}
+
+/// 保存脚本函数的位置
+pub(super) fn save_functions<'a>(
+    commands: &'a [Command<'a>],
+    functions: &HashMap<&'a str, Function<'a>>,
+) -> Result<BTreeMap<String, Path>, RuntimeError> {
+    functions
+        .iter()
+        .map(|(name, function)| {
+            let path = find_path(commands, &|command| {
+                matches!(command, Command::Function { commands, .. } if ptr::eq(commands.as_slice(), function.commands))
+            })
+            .ok_or_else(|| mismatch(format_args!("函数{name}")))?;
+            Ok((name.to_string(), path))
+        })
+        .collect()
+}
+
+/// 按位置重新找到脚本函数
+pub(super) fn load_functions<'a>(
+    commands: &'a [Command<'a>],
+    functions: &BTreeMap<String, Path>,
+) -> Result<HashMap<&'a str, Function<'a>>, RuntimeError> {
+    functions
+        .iter()
+        .map(|(saved, path)| match resolve(commands, path) {
+            Some(Command::Function {
+                name,
+                args,
+                commands,
+                ..
+            }) if name == saved => Ok((*name, Function { args, commands })),
+            _ => Err(mismatch(format_args!("函数{saved}"))),
+        })
+        .collect()
+}
+
+/// 保存defer语句的位置
+pub(super) fn save_deferred<'a>(
+    commands: &'a [Command<'a>],
+    deferred: &[&'a Command<'a>],
+) -> Result<Vec<Path>, RuntimeError> {
+    deferred
+        .iter()
+        .map(|&target| {
+            find_path(commands, &|command| ptr::eq(command, target))
+                .ok_or_else(|| mismatch("defer语句"))
+        })
+        .collect()
+}
+
+/// 按位置重新找到defer语句
+pub(super) fn load_deferred<'a>(
+    commands: &'a [Command<'a>],
+    deferred: &[Path],
+) -> Result<Vec<&'a Command<'a>>, RuntimeError> {
+    deferred
+        .iter()
+        .map(|path| resolve(commands, path).ok_or_else(|| mismatch("defer语句")))
+        .collect()
+}
+
+/// 在语法树中找到同名的状态(`@name`或者`@name:`)，借用其中的名字
+pub(super) fn load_state_name<'a>(
+    commands: &'a [Command<'a>],
+    saved: &str,
+) -> Result<&'a str, RuntimeError> {
+    let is_state = |command: &Command<'_>| match command {
+        Command::SetState(name) | Command::StateBlock { name, .. } => *name == saved,
+        _ => false,
+    };
+    find_path(commands, &is_state)
+        .and_then(|path| resolve(commands, &path))
+        .and_then(|command| match command {
+            Command::SetState(name) | Command::StateBlock { name, .. } => Some(*name),
+            _ => None,
+        })
+        .ok_or_else(|| mismatch(format_args!("状态@{saved}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::{
+        eval::{error::RuntimeError, interpreter::Interpreter, value::Value},
+        parser::parse,
+    };
+
+    use super::SavedState;
+
+    const SCRIPT: &str = "\
+log = []
+fn record(x):
+\tlog = log + [x]
+\treturn x
+defer record('done')
+for i in 1..=3:
+\twith i * 10 as n:
+\t\tyield record(n + bonus())
+@rest
+";
+
+    #[test]
+    fn save_and_load_generator() {
+        let ast = parse(SCRIPT).unwrap();
+        let number = |n: i64| Value::Number(Decimal::from(n));
+        let mut interpreter = Interpreter::new();
+        interpreter.register_fn("bonus", |_| Ok(Value::Number(Decimal::ONE)));
+        let mut generator = interpreter.generator(&ast);
+        assert_eq!(generator.next().unwrap().unwrap(), number(11));
+        let saved = serde_json::to_string(&generator.save_state().unwrap()).unwrap();
+        // 同样的状态保存出同样的结果
+        assert_eq!(
+            serde_json::to_string(&generator.save_state().unwrap()).unwrap(),
+            saved
+        );
+        // 原来的生成器继续执行
+        let rest: Vec<_> = generator.map(Result::unwrap).collect();
+        assert_eq!(rest, [number(21), number(31)]);
+
+        // 在新的解释器中读取，宿主函数需要重新注册
+        let mut interpreter = Interpreter::new();
+        interpreter.register_fn("bonus", |_| Ok(Value::Number(Decimal::TWO)));
+        let mut generator = interpreter.generator(&ast);
+        let state: SavedState = serde_json::from_str(&saved).unwrap();
+        generator.load_state(state).unwrap();
+        let rest: Vec<_> = generator.by_ref().map(Result::unwrap).collect();
+        assert_eq!(rest, [number(22), number(32)]);
+        let str = |s: &str| Value::Str(s.to_string());
+        let mut interpreter = generator.into_interpreter();
+        assert_eq!(
+            interpreter.env().get("log"),
+            Some(&Value::Array(vec![
+                number(11),
+                number(22),
+                number(32),
+                str("done")
+            ]))
+        );
+        assert_eq!(interpreter.take_state(), Some("rest"));
+    }
+
+    #[test]
+    fn load_mismatched_script() {
+        let ast = parse(SCRIPT).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.register_fn("bonus", |_| Ok(Value::Nil));
+        interpreter.run(&ast[..2]).unwrap();
+        let state = interpreter.save_state(&ast).unwrap();
+        // 其他脚本中没有保存时定义的函数
+        let other = parse("x = 1\n").unwrap();
+        assert_eq!(
+            Interpreter::new().load_state(&other, state),
+            Err(RuntimeError::SavedState(
+                "脚本中找不到保存时的函数record".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,555 @@
+//! 状态机：按当前状态执行脚本顶层的状态块
+//!
+//! 每一轮([`StateMachine::tick`])先把宿主给出的时间计入当前状态持续的时间，达到状态的`timeout`时长时切换到目标状态，
+//! 然后执行当前状态的状态块，最后完成块中请求的切换(`@名字`)。每次切换之后重新计时，切换到当前状态也会重新计时
+//!
+//! 宿主收到的事件(例如Bevy的事件)通过[`StateMachine::dispatch`]交给当前状态中同名的`on "事件":`处理，
+//! 没有处理的事件被忽略。切换状态时依次分派原来状态的`exit`事件和新状态的`enter`事件
+//!
+//! `@attack extends @combat:`继承另一个状态的超时、事件处理和状态块语句，自己定义的覆盖继承的
+
+use std::{collections::HashMap, time::Duration};
+
+use rust_decimal::Decimal;
+
+use crate::parser::scanner::{Command, Handler, Timeout};
+
+use super::{
+    Flow,
+    env::Env,
+    error::{EvalError, Frame, RuntimeError},
+    eval_expression, exec_command, exec_scope,
+    interpreter::Interpreter,
+    mismatch,
+    value::{Value, seconds},
+    with_defers,
+};
+
+/// 进入状态时分派的事件，事件的值是原来的状态名，状态机开始时为null
+pub const ENTER: &str = "enter";
+
+/// 离开状态时分派的事件，事件的值是切换到的状态名
+pub const EXIT: &str = "exit";
+
+/// 语法树中的状态块
+#[derive(Debug, Clone, Copy)]
+struct Block<'a> {
+    parent: Option<&'a str>,
+    timeout: Option<&'a Timeout<'a>>,
+    handlers: &'a [Handler<'a>],
+    commands: &'a [Command<'a>],
+}
+
+/// 合并了继承的内容之后的状态
+#[derive(Debug, Clone)]
+struct State<'a> {
+    timeout: Option<&'a Timeout<'a>>,
+    handlers: Vec<&'a Handler<'a>>,
+    commands: &'a [Command<'a>],
+}
+
+/// 按继承关系合并状态：子状态的超时和同名事件的处理覆盖父状态的，
+/// 子状态块中有语句时每一轮只执行子状态的语句，没有语句时执行父状态的；`chain`是正在合并的子状态
+fn inherit<'a>(
+    blocks: &HashMap<&'a str, Block<'a>>,
+    name: &'a str,
+    chain: &mut Vec<&'a str>,
+) -> Result<State<'a>, RuntimeError> {
+    if chain.contains(&name) {
+        chain.push(name);
+        let start = chain.iter().position(|&state| state == name).unwrap_or(0);
+        return Err(RuntimeError::InheritanceCycle(
+            chain[start..].iter().map(ToString::to_string).collect(),
+        ));
+    }
+    let block = blocks
+        .get(name)
+        .ok_or_else(|| RuntimeError::UndefinedState(name.to_string()))?;
+    let mut state = State {
+        timeout: block.timeout,
+        handlers: block.handlers.iter().collect(),
+        commands: block.commands,
+    };
+    if let Some(parent) = block.parent {
+        chain.push(name);
+        let parent = inherit(blocks, parent, chain)?;
+        chain.pop();
+        state.timeout = state.timeout.or(parent.timeout);
+        state
+            .handlers
+            .extend(parent.handlers.into_iter().filter(|inherited| {
+                !block
+                    .handlers
+                    .iter()
+                    .any(|handler| handler.event == inherited.event)
+            }));
+        if block
+            .commands
+            .iter()
+            .all(|command| matches!(command, Command::NewLine))
+        {
+            state.commands = parent.commands;
+        }
+    }
+    Ok(state)
+}
+
+/// 所有的状态以及当前所处的状态
+#[derive(Debug)]
+struct States<'a> {
+    states: HashMap<&'a str, State<'a>>,
+    current: &'a str,
+    /// 当前状态已经持续的秒数
+    elapsed: Decimal,
+}
+
+impl<'a> States<'a> {
+    /// 找到状态，返回语法树中的状态名
+    fn resolve(&self, name: &str) -> Result<&'a str, RuntimeError> {
+        self.states
+            .get_key_value(name)
+            .map(|(&name, _)| name)
+            .ok_or_else(|| RuntimeError::UndefinedState(name.to_string()))
+    }
+
+    /// 由`state`处理事件，处理的语句在新的作用域中执行，其中的`event`变量是事件的值；
+    /// 返回这个状态有没有处理这个事件
+    fn handle(
+        &self,
+        state: &'a str,
+        event: &str,
+        payload: Value,
+        env: &mut Env<'a>,
+    ) -> Result<bool, RuntimeError> {
+        let Some(handler) = self.states[state]
+            .handlers
+            .iter()
+            .copied()
+            .find(|handler| handler.event == event)
+        else {
+            return Ok(false);
+        };
+        env.push_scope();
+        env.define("event", payload);
+        let result = run_state(state, &handler.commands, env);
+        env.pop_scope();
+        result.map(|()| true)
+    }
+
+    /// 进入状态并重新计时
+    fn enter(
+        &mut self,
+        state: &'a str,
+        from: Value,
+        env: &mut Env<'a>,
+    ) -> Result<(), RuntimeError> {
+        self.current = state;
+        self.elapsed = Decimal::ZERO;
+        self.handle(state, ENTER, from, env).map(drop)
+    }
+
+    /// 切换到另一个状态；exit和enter事件中请求的切换和其他请求一样，留到下一次完成请求时生效
+    fn switch(&mut self, name: &str, env: &mut Env<'a>) -> Result<(), RuntimeError> {
+        let next = self.resolve(name)?;
+        let previous = self.current;
+        self.handle(previous, EXIT, Value::Str(next.to_string()), env)?;
+        self.enter(next, Value::Str(previous.to_string()), env)
+    }
+
+    /// 完成脚本请求的切换
+    fn settle(&mut self, env: &mut Env<'a>) -> Result<(), RuntimeError> {
+        env.take_state()
+            .map_or(Ok(()), |next| self.switch(next, env))
+    }
+
+    /// 执行一轮，见[`StateMachine::tick`]
+    fn tick(&mut self, delta: Decimal, env: &mut Env<'a>) -> Result<(), RuntimeError> {
+        self.elapsed = self
+            .elapsed
+            .checked_add(delta)
+            .ok_or(RuntimeError::Overflow)?;
+        if let Some(timeout) = self.states[self.current].timeout
+            && self.elapsed >= timeout_seconds(timeout, env)?
+        {
+            self.switch(timeout.state, env)?;
+        }
+        run_state(self.current, self.states[self.current].commands, env)?;
+        self.settle(env)
+    }
+
+    /// 分派事件，见[`StateMachine::dispatch`]
+    fn dispatch(
+        &mut self,
+        event: &str,
+        payload: Value,
+        env: &mut Env<'a>,
+    ) -> Result<bool, RuntimeError> {
+        if !self.handle(self.current, event, payload, env)? {
+            return Ok(false);
+        }
+        self.settle(env)?;
+        Ok(true)
+    }
+}
+
+/// 超时的秒数，时长可以是数字(秒)、时长或者带时间单位的数字
+fn timeout_seconds<'a>(
+    timeout: &'a Timeout<'a>,
+    env: &mut Env<'a>,
+) -> Result<Decimal, RuntimeError> {
+    match eval_expression(&timeout.duration, env)? {
+        Value::Number(n) => Ok(n),
+        value => value
+            .as_duration()
+            .ok_or_else(|| mismatch("timeout", &[&value])),
+    }
+}
+
+/// 执行一个状态块，出错时调用栈中有这个状态
+fn run_state<'a>(
+    name: &str,
+    commands: &'a [Command<'a>],
+    env: &mut Env<'a>,
+) -> Result<(), RuntimeError> {
+    env.push_frame(Frame::State(name.to_string()));
+    let result = exec_scope(commands, env);
+    if result.is_err() {
+        env.trace_error(None);
+    }
+    env.pop_frame();
+    result.map(drop)
+}
+
+/// 执行顶层除了状态块之外的语句，定义函数和全局变量，顶层的defer在执行完之后执行
+fn run_setup<'a>(commands: &'a [Command<'a>], env: &mut Env<'a>) -> Result<(), RuntimeError> {
+    with_defers(env, |env| {
+        for command in commands {
+            if matches!(command, Command::StateBlock { .. }) {
+                continue;
+            }
+            if !matches!(exec_command(command, env)?, Flow::Next) {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// 按当前状态执行状态块的状态机，见[`Interpreter::state_machine`]
+#[derive(Debug)]
+pub struct StateMachine<'a> {
+    interpreter: Interpreter<'a>,
+    states: States<'a>,
+}
+
+impl<'a> StateMachine<'a> {
+    /// 收集顶层的状态块，执行其他顶层语句，从`initial`开始；顶层语句中请求的切换立即生效，
+    /// 最后分派开始的状态的enter事件
+    pub(super) fn new(
+        mut interpreter: Interpreter<'a>,
+        commands: &'a [Command<'a>],
+        initial: &str,
+    ) -> Result<Self, EvalError> {
+        let blocks: Vec<_> = commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::StateBlock {
+                    name,
+                    parent,
+                    timeout,
+                    handlers,
+                    commands,
+                } => Some((
+                    *name,
+                    Block {
+                        parent: *parent,
+                        timeout: timeout.as_ref(),
+                        handlers,
+                        commands,
+                    },
+                )),
+                _ => None,
+            })
+            .collect();
+        let mut states = States {
+            states: HashMap::new(),
+            current: "",
+            elapsed: Decimal::ZERO,
+        };
+        interpreter.traced(|env| {
+            // 按脚本中的顺序合并，出错时报告的总是同一个状态
+            let lookup = blocks.iter().copied().collect();
+            for &(name, _) in &blocks {
+                let state = inherit(&lookup, name, &mut vec![])?;
+                states.states.insert(name, state);
+            }
+            let initial = states.resolve(initial)?;
+            run_setup(commands, env)?;
+            let first = match env.take_state() {
+                Some(next) => states.resolve(next)?,
+                None => initial,
+            };
+            states.enter(first, Value::Nil, env)
+        })?;
+        Ok(Self {
+            interpreter,
+            states,
+        })
+    }
+
+    /// 执行一轮，`delta`是距离上一轮经过的时间(例如Bevy的`Time::delta`)
+    pub fn tick(&mut self, delta: Duration) -> Result<(), EvalError> {
+        self.interpreter
+            .traced(|env| self.states.tick(seconds(delta), env))
+    }
+
+    /// 把事件交给当前状态处理，`payload`是事件的值，处理的语句中可以用`event`变量读取；
+    /// 返回当前状态有没有处理这个事件，处理中请求的切换在处理完之后生效
+    pub fn dispatch(&mut self, event: &str, payload: Value) -> Result<bool, EvalError> {
+        self.interpreter
+            .traced(|env| self.states.dispatch(event, payload, env))
+    }
+
+    /// 当前所处的状态
+    pub const fn current(&self) -> &'a str {
+        self.states.current
+    }
+
+    /// 当前状态已经持续的秒数
+    pub const fn elapsed(&self) -> Decimal {
+        self.states.elapsed
+    }
+
+    /// 执行脚本的解释器，可以查看变量
+    pub const fn interpreter(&self) -> &Interpreter<'a> {
+        &self.interpreter
+    }
+
+    /// 可修改的解释器，用于宿主设置全局变量或者注册函数
+    pub const fn interpreter_mut(&mut self) -> &mut Interpreter<'a> {
+        &mut self.interpreter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rust_decimal::Decimal;
+
+    use crate::{
+        eval::{
+            error::{Frame, RuntimeError},
+            interpreter::Interpreter,
+            value::Value,
+        },
+        parser::parse,
+    };
+
+    use super::StateMachine;
+
+    const fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn number(n: i64) -> Value {
+        Value::Number(Decimal::from(n))
+    }
+
+    #[test]
+    fn state_timeout() {
+        let src = "\
+alerts = 0
+@alert:
+\ttimeout 2s -> @patrol
+\talerts += 1
+@patrol:
+\ttimeout wait -> @alert
+\tpatrolled = true
+wait = 1.5
+";
+        let ast = parse(src).unwrap();
+        let mut machine = Interpreter::new().state_machine(&ast, "alert").unwrap();
+        machine.tick(ms(1000)).unwrap();
+        machine.tick(ms(500)).unwrap();
+        assert_eq!(machine.current(), "alert");
+        assert_eq!(machine.elapsed(), Decimal::new(15, 1));
+        // 超过时长之后先切换，这一轮执行新状态的状态块
+        machine.tick(ms(600)).unwrap();
+        assert_eq!(machine.current(), "patrol");
+        assert_eq!(machine.elapsed(), Decimal::ZERO);
+        let env = machine.interpreter().env();
+        assert_eq!(env.get("alerts"), Some(&number(2)));
+        assert_eq!(env.get("patrolled"), Some(&Value::Bool(true)));
+        // 数字按秒计算，可以引用变量
+        machine.tick(ms(1000)).unwrap();
+        assert_eq!(machine.current(), "patrol");
+        machine.tick(ms(500)).unwrap();
+        assert_eq!(machine.current(), "alert");
+        assert_eq!(machine.interpreter().env().get("alerts"), Some(&number(3)));
+    }
+
+    #[test]
+    fn state_switch() {
+        let src = "\
+@idle:
+\ttimeout 1s -> @idle
+\tif hp < 5:
+\t\t@flee
+@flee:
+\t@nowhere
+hp = 10
+@idle
+";
+        let ast = parse(src).unwrap();
+        let mut machine = Interpreter::new().state_machine(&ast, "flee").unwrap();
+        // 顶层请求的切换立即生效
+        assert_eq!(machine.current(), "idle");
+        machine.tick(ms(800)).unwrap();
+        machine
+            .interpreter_mut()
+            .env_mut()
+            .set("hp", number(3))
+            .unwrap();
+        // 请求的切换在状态块执行完之后生效，重新计时
+        machine.tick(ms(100)).unwrap();
+        assert_eq!(machine.current(), "flee");
+        assert_eq!(machine.elapsed(), Decimal::ZERO);
+        let error = machine.tick(ms(100)).unwrap_err();
+        assert_eq!(
+            error.error,
+            RuntimeError::UndefinedState("nowhere".to_string())
+        );
+        assert!(
+            Interpreter::new()
+                .state_machine(&ast, "missing")
+                .is_err_and(|error| error.frames.is_empty())
+        );
+
+        let ast = parse("@idle:\n\ttimeout 'soon' -> @idle\n").unwrap();
+        let mut machine = Interpreter::new().state_machine(&ast, "idle").unwrap();
+        let error = machine.tick(ms(100)).unwrap_err();
+        assert_eq!(
+            error.error,
+            RuntimeError::TypeMismatch {
+                op: "timeout".to_string(),
+                types: vec!["字符串"]
+            }
+        );
+        let ast = parse("@idle:\n\tx = 1 / 0\n").unwrap();
+        let mut machine = Interpreter::new().state_machine(&ast, "idle").unwrap();
+        let error = machine.tick(ms(100)).unwrap_err();
+        assert_eq!(error.frames, [Frame::State("idle".to_string())]);
+    }
+
+    #[test]
+    fn state_events() {
+        let src = "\
+hits = 0
+entered = 0
+left = ''
+from = ''
+@guard:
+\ton \"enter\":
+\t\tentered += 1
+\ton \"Attacked\":
+\t\thits += event
+\t\tif hits >= 3:
+\t\t\t@flee
+\ton \"exit\":
+\t\tleft = event
+\t\tseen = true
+\tguarding = true
+@flee:
+\ton \"enter\":
+\t\tfrom = event
+";
+        let ast = parse(src).unwrap();
+        let mut machine = Interpreter::new().state_machine(&ast, "guard").unwrap();
+        // 开始的状态也会收到enter事件
+        assert_eq!(machine.interpreter().env().get("entered"), Some(&number(1)));
+        assert_eq!(machine.dispatch("Attacked", number(2)), Ok(true));
+        assert_eq!(machine.current(), "guard");
+        // 没有处理的事件被忽略
+        assert_eq!(machine.dispatch("Healed", Value::Nil), Ok(false));
+        assert_eq!(machine.dispatch("enter", Value::Nil), Ok(true));
+        assert_eq!(machine.interpreter().env().get("entered"), Some(&number(2)));
+        // 处理中请求的切换在处理完之后生效，依次分派exit和enter事件
+        assert_eq!(machine.dispatch("Attacked", number(1)), Ok(true));
+        assert_eq!(machine.current(), "flee");
+        let env = machine.interpreter().env();
+        assert_eq!(env.get("hits"), Some(&number(3)));
+        assert_eq!(env.get("left"), Some(&Value::Str("flee".to_string())));
+        assert_eq!(env.get("from"), Some(&Value::Str("guard".to_string())));
+        // 处理中定义的变量只在处理的语句中可见，状态块没有执行过
+        assert_eq!(env.get("seen"), None);
+        assert_eq!(env.get("event"), None);
+        assert_eq!(env.get("guarding"), None);
+        // flee没有处理Attacked
+        assert_eq!(machine.dispatch("Attacked", number(1)), Ok(false));
+    }
+
+    #[test]
+    fn state_inheritance() {
+        let src = "\
+log = ''
+@combat:
+\ttimeout 5 -> @rest
+\ton \"enter\":
+\t\tlog += 'enter '
+\ton \"Attacked\":
+\t\tlog += 'block '
+\tlog += 'fight '
+@attack extends @combat:
+\ton \"Attacked\":
+\t\tlog += 'counter '
+\tlog += 'strike '
+@rest extends @combat:
+\ton \"exit\":
+\t\tlog += 'up '
+";
+        let ast = parse(src).unwrap();
+        let mut machine = Interpreter::new().state_machine(&ast, "attack").unwrap();
+        let log = |machine: &StateMachine| machine.interpreter().env().get("log").cloned();
+        let str = |s: &str| Some(Value::Str(s.to_string()));
+        // 继承父状态的enter，自己的状态块语句代替父状态的
+        assert_eq!(log(&machine), str("enter "));
+        machine.tick(ms(100)).unwrap();
+        assert_eq!(log(&machine), str("enter strike "));
+        // 同名事件的处理覆盖父状态的
+        assert_eq!(machine.dispatch("Attacked", Value::Nil), Ok(true));
+        assert_eq!(log(&machine), str("enter strike counter "));
+        // 继承父状态的超时；没有语句的子状态执行父状态的语句
+        machine.tick(ms(5000)).unwrap();
+        assert_eq!(machine.current(), "rest");
+        assert_eq!(log(&machine), str("enter strike counter enter fight "));
+        assert_eq!(machine.dispatch("Attacked", Value::Nil), Ok(true));
+        assert_eq!(
+            log(&machine),
+            str("enter strike counter enter fight block ")
+        );
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        let src = "@a extends @b:\n\tx = 1\n@b extends @c:\n\tx = 2\n@c extends @b:\n\tx = 3\n";
+        let ast = parse(src).unwrap();
+        let error = Interpreter::new().state_machine(&ast, "a").unwrap_err();
+        assert_eq!(
+            error.error,
+            RuntimeError::InheritanceCycle(vec!["b".to_string(), "c".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            error.error.to_string(),
+            "状态的继承形成了循环：@b -> @c -> @b"
+        );
+        let ast = parse("@a extends @ghost:\n\tx = 1\n").unwrap();
+        assert_eq!(
+            Interpreter::new()
+                .state_machine(&ast, "a")
+                .map_err(|error| error.error)
+                .unwrap_err(),
+            RuntimeError::UndefinedState("ghost".to_string())
+        );
+    }
+}
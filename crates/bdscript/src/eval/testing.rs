@@ -0,0 +1,117 @@
+//! 运行脚本内的测试(`test "名字":`块)
+
+use crate::parser::scanner::Command;
+
+use super::{
+    env::{Env, Function},
+    error::RuntimeError,
+    exec_scope,
+};
+
+/// 单个测试的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    /// 失败时的错误(包括断言失败)，通过时为None
+    pub error: Option<RuntimeError>,
+}
+
+/// 所有测试的结果，按测试在脚本中出现的顺序排列
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestReport {
+    pub results: Vec<TestResult>,
+}
+
+impl TestReport {
+    /// 通过的测试数量
+    pub fn passed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| result.error.is_none())
+            .count()
+    }
+
+    /// 失败的测试
+    pub fn failed(&self) -> impl Iterator<Item = &TestResult> {
+        self.results.iter().filter(|result| result.error.is_some())
+    }
+
+    /// 是否全部通过
+    pub fn is_ok(&self) -> bool {
+        self.failed().next().is_none()
+    }
+}
+
+/// 依次运行顶层的测试块
+///
+/// 每个测试都在`env`的一份副本中运行，能调用顶层定义的函数和宿主函数，
+/// 但是不会执行其他顶层语句，测试之间也互不影响
+pub fn run_tests<'a>(commands: &'a [Command<'a>], env: &Env<'a>) -> TestReport {
+    let mut base = env.clone();
+    for command in commands {
+        if let Command::Function {
+            name,
+            args,
+            commands,
+            ..
+        } = command
+        {
+            base.define_fn(name, Function { args, commands });
+        }
+    }
+    let results = commands
+        .iter()
+        .filter_map(|command| match command {
+            Command::Test { name, body } => Some(TestResult {
+                name: name.to_string(),
+                error: exec_scope(body, &mut base.clone()).err(),
+            }),
+            _ => None,
+        })
+        .collect();
+    TestReport { results }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::{
+        eval::{error::RuntimeError, interpreter::Interpreter, value::Value},
+        parser::parse,
+    };
+
+    use super::TestResult;
+
+    #[test]
+    fn run_script_tests() {
+        let src = "fn heal(hp):\n\treturn hp + 5\nran = 1\ntest \"heal adds five\":\n\tassert(heal(1) == 6)\n\tleaked = 1\ntest \"heal caps\":\n\tassert(heal(99) == 100, 'heal应该封顶')\ntest \"sees other tests\":\n\tassert(leaked)\n";
+        let ast = parse(src).unwrap();
+        let mut interpreter = Interpreter::new();
+        // 正常执行时跳过测试
+        interpreter.run(&ast).unwrap();
+        assert_eq!(
+            interpreter.env().get("ran"),
+            Some(&Value::Number(Decimal::ONE))
+        );
+        assert_eq!(interpreter.env().get("leaked"), None);
+
+        let report = Interpreter::new().run_tests(&ast);
+        assert_eq!(report.passed(), 1);
+        assert!(!report.is_ok());
+        // 测试看不到其他测试定义的变量
+        assert_eq!(
+            report.failed().collect::<Vec<_>>(),
+            [
+                &TestResult {
+                    name: "heal caps".to_string(),
+                    error: Some(RuntimeError::AssertionFailed("heal应该封顶".to_string())),
+                },
+                &TestResult {
+                    name: "sees other tests".to_string(),
+                    error: Some(RuntimeError::Undefined("leaked".to_string())),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! 运行时错误的位置和调用栈
+//!
+//! 语法树中的名字、字符串都是从源码中借用的切片，出错时用表达式中这些切片的范围作为出错位置，
+//! 不需要在每个节点上保存位置。没有借用源码的表达式(例如`1 / 0`)没有位置，由外层的表达式或语句给出
+
+use std::{borrow::Cow, ops::Range};
+
+use crate::parser::{
+    error::span_in,
+    scanner::{Command, Expression, Object},
+    visit::{Visitor, walk_expression},
+};
+
+use super::{
+    env::Env,
+    error::{EvalError, Frame, RuntimeError},
+};
+
+/// 出错时记录下来的位置和调用栈，错误从最内层向外传递，外层只补上还没有的位置
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    /// 出错的表达式在源码中的位置
+    pub span: Option<Range<usize>>,
+    /// 出错时的调用栈，最外层在前
+    pub frames: Vec<Frame>,
+}
+
+impl Trace {
+    /// 和错误一起组成[`EvalError`]
+    pub fn into_error(self, error: RuntimeError) -> EvalError {
+        EvalError {
+            error,
+            span: self.span,
+            frames: self.frames,
+        }
+    }
+}
+
+/// 合并表达式中从源码借用的切片的范围
+struct SpanCollector<'s> {
+    src: &'s str,
+    span: Option<Range<usize>>,
+}
+
+impl SpanCollector<'_> {
+    fn add(&mut self, name: &str) {
+        let Some(range) = span_in(self.src, name) else {
+            return;
+        };
+        self.span = Some(match self.span.take() {
+            Some(span) => span.start.min(range.start)..span.end.max(range.end),
+            None => range,
+        });
+    }
+}
+
+impl<'a> Visitor<'a> for SpanCollector<'_> {
+    fn visit_expression(&mut self, expression: &Expression<'a>) {
+        match expression {
+            Expression::Object(
+                Object::Variable(name) | Object::Symbol(name) | Object::Quantity(_, name),
+            )
+            | Expression::Object(Object::Str(Cow::Borrowed(name))) => self.add(name),
+            Expression::Query {
+                with_compoents,
+                without_compoents,
+                ..
+            } => {
+                for name in with_compoents.iter().chain(without_compoents) {
+                    self.add(name);
+                }
+            }
+            _ => {}
+        }
+        walk_expression(self, expression);
+    }
+}
+
+/// 表达式在源码中的大致位置：其中借用源码的名字、字符串覆盖的范围
+pub fn expression_span(src: &str, expression: &Expression<'_>) -> Option<Range<usize>> {
+    let mut collector = SpanCollector { src, span: None };
+    collector.visit_expression(expression);
+    collector.span
+}
+
+/// 语句开头部分(不包括块内的语句)在源码中的大致位置
+pub fn command_span(src: &str, command: &Command<'_>) -> Option<Range<usize>> {
+    let mut collector = SpanCollector { src, span: None };
+    match command {
+        Command::Expression(expression)
+        | Command::Yield(expression)
+        | Command::Return(Some(expression)) => collector.visit_expression(expression),
+        Command::While { condition, .. } => collector.visit_expression(condition),
+        Command::If { if_branch, .. } => {
+            if let Some(branch) = if_branch.first() {
+                collector.visit_expression(&branch.condition);
+            }
+        }
+        Command::For { var, iter, .. } => {
+            collector.add(var);
+            collector.visit_expression(iter);
+        }
+        Command::With { expr, binding, .. } => {
+            collector.visit_expression(expr);
+            collector.add(binding);
+        }
+        Command::Import(Cow::Borrowed(name))
+        | Command::SetState(name)
+        | Command::StateBlock { name, .. }
+        | Command::Template { name, .. }
+        | Command::Invoke { name, .. }
+        | Command::Function { name, .. } => collector.add(name),
+        _ => {}
+    }
+    collector.span
+}
+
+/// 表达式出错时记录位置，只在出错时调用，不影响正常执行的栈帧
+#[cold]
+#[inline(never)]
+pub(super) fn locate_expression(expression: &Expression<'_>, env: &mut Env<'_>) {
+    let span = env
+        .source()
+        .and_then(|src| expression_span(src, expression));
+    env.trace_error(span);
+}
+
+/// 语句出错时记录位置，见[`locate_expression`]
+#[cold]
+#[inline(never)]
+pub(super) fn locate_command(command: &Command<'_>, env: &mut Env<'_>) {
+    let span = env.source().and_then(|src| command_span(src, command));
+    env.trace_error(span);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse, scanner::Command};
+
+    use super::{command_span, expression_span};
+
+    #[test]
+    fn spans_from_source() {
+        let src = "hp / (armor - 1)\nfor x in 5:\n\tbreak\n1 / 0\n";
+        let ast = parse(src).unwrap();
+        let Command::Expression(expression) = &ast[0] else {
+            panic!("第一条语句是表达式");
+        };
+        // 常量没有借用源码，范围到最后一个名字为止
+        assert_eq!(expression_span(src, expression), Some(0..11));
+        // 只包括语句开头，不包括块内的语句
+        let start = src.find('x').unwrap();
+        let for_loop = ast
+            .iter()
+            .find(|command| matches!(command, Command::For { .. }))
+            .unwrap();
+        assert_eq!(command_span(src, for_loop), Some(start..start + 1));
+        // 没有借用源码的表达式没有位置
+        let last = ast
+            .iter()
+            .rfind(|command| matches!(command, Command::Expression(_)))
+            .unwrap();
+        assert_eq!(command_span(src, last), None);
+        // 不是从这段源码解析出的语法树也没有位置
+        assert_eq!(command_span("hp", &ast[0]), None);
+    }
+}
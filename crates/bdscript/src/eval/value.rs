@@ -0,0 +1,316 @@
+//! 运行时的值
+
+use std::{collections::BTreeSet, fmt, time::Duration};
+
+use glam::{Vec2, Vec3};
+use rust_decimal::{Decimal, RoundingStrategy, prelude::ToPrimitive};
+
+use crate::parser::tokenizer::FormatSpec;
+
+use super::{error::RuntimeError, world::Entity};
+
+/// 运行时的值
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Value {
+    /// 数字
+    Number(Decimal),
+    /// 带单位的数字，例如`10s`，单位由宿主解释
+    Quantity(Decimal, String),
+    /// 时长，单位为秒，见[`Value::expect_duration`]
+    Duration(Decimal),
+    /// 字符串
+    Str(String),
+    /// 布尔值
+    Bool(bool),
+    /// 符号(例如`:attack`)，按名字比较
+    Symbol(String),
+    /// 颜色(RGBA)
+    Color([u8; 4]),
+    /// 二维向量
+    Vec2(Vec2),
+    /// 三维向量，和Bevy的`Transform::translation`相同
+    Vec3(Vec3),
+    /// 数组
+    Array(Vec<Self>),
+    /// 元组
+    Tuple(Vec<Self>),
+    /// 字典，按插入顺序保存键值对
+    Dict(Vec<(Self, Self)>),
+    /// 查询得到的实体集合
+    EntitySet(BTreeSet<Entity>),
+    /// 函数，按名字引用脚本函数、宿主函数或者内置函数，调用时再查找
+    Function(String),
+    /// null
+    Nil,
+    /// 没有值，赋值、if、while等语句的结果，不能参与运算
+    Unit,
+}
+
+impl Value {
+    /// 类型名，用于报错
+    pub const fn type_name(&self) -> &'static str {
+        match self {
+            Self::Number(_) => "数字",
+            Self::Quantity(..) => "带单位的数字",
+            Self::Duration(_) => "时长",
+            Self::Str(_) => "字符串",
+            Self::Bool(_) => "布尔值",
+            Self::Symbol(_) => "符号",
+            Self::Color(_) => "颜色",
+            Self::Vec2(_) => "二维向量",
+            Self::Vec3(_) => "三维向量",
+            Self::Array(_) => "数组",
+            Self::Tuple(_) => "元组",
+            Self::Dict(_) => "字典",
+            Self::EntitySet(_) => "实体集合",
+            Self::Function(_) => "函数",
+            Self::Nil => "null",
+            Self::Unit => "无值",
+        }
+    }
+
+    /// 作为函数的第`arg`个参数(从0开始)取出数字，类型不对时报[`RuntimeError::ArgType`]
+    pub const fn expect_number(&self, arg: usize) -> Result<Decimal, RuntimeError> {
+        match self {
+            Self::Number(n) => Ok(*n),
+            value => Err(RuntimeError::ArgType {
+                arg,
+                expected: "数字",
+                found: value.type_name(),
+            }),
+        }
+    }
+
+    /// 作为函数的第`arg`个参数(从0开始)取出实体，实体是非负整数编号，类型不对时报[`RuntimeError::ArgType`]
+    pub fn expect_entity(&self, arg: usize) -> Result<Entity, RuntimeError> {
+        match self {
+            Self::Number(n) if n.fract().is_zero() => n.to_u64(),
+            _ => None,
+        }
+        .ok_or_else(|| RuntimeError::ArgType {
+            arg,
+            expected: "实体",
+            found: self.type_name(),
+        })
+    }
+
+    /// 作为函数的第`arg`个参数(从0开始)取出时长的秒数，类型不对时报[`RuntimeError::ArgType`]
+    ///
+    /// 时间单位(`ns`、`us`、`ms`、`s`、`h`)的带单位的数字也可以作为时长
+    pub fn expect_duration(&self, arg: usize) -> Result<Decimal, RuntimeError> {
+        self.as_duration().ok_or_else(|| RuntimeError::ArgType {
+            arg,
+            expected: "时长",
+            found: self.type_name(),
+        })
+    }
+
+    /// 时长的秒数，时间单位的带单位的数字换算成秒，其他值为None
+    pub fn as_duration(&self) -> Option<Decimal> {
+        match self {
+            Self::Duration(seconds) => Some(*seconds),
+            Self::Quantity(n, unit) => match unit.as_str() {
+                "ns" => n.checked_div(Decimal::from(1_000_000_000)),
+                "us" => n.checked_div(Decimal::from(1_000_000)),
+                "ms" => n.checked_div(Decimal::ONE_THOUSAND),
+                "s" => Some(*n),
+                "h" => n.checked_mul(Decimal::from(3600)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// 作为函数的第`arg`个参数(从0开始)取出三维向量，类型不对时报[`RuntimeError::ArgType`]
+    pub const fn expect_vec3(&self, arg: usize) -> Result<Vec3, RuntimeError> {
+        match self {
+            Self::Vec3(v) => Ok(*v),
+            value => Err(RuntimeError::ArgType {
+                arg,
+                expected: "三维向量",
+                found: value.type_name(),
+            }),
+        }
+    }
+
+    /// 作为函数的第`arg`个参数(从0开始)取出字符串，类型不对时报[`RuntimeError::ArgType`]
+    pub fn expect_str(&self, arg: usize) -> Result<&str, RuntimeError> {
+        match self {
+            Self::Str(s) => Ok(s),
+            value => Err(RuntimeError::ArgType {
+                arg,
+                expected: "字符串",
+                found: value.type_name(),
+            }),
+        }
+    }
+
+    /// 作为函数的第`arg`个参数(从0开始)取出函数名，类型不对时报[`RuntimeError::ArgType`]
+    pub fn expect_function(&self, arg: usize) -> Result<&str, RuntimeError> {
+        match self {
+            Self::Function(name) => Ok(name),
+            value => Err(RuntimeError::ArgType {
+                arg,
+                expected: "函数",
+                found: value.type_name(),
+            }),
+        }
+    }
+
+    /// 按格式转换成字符串：精度只能用于数字、带单位的数字和时长(四舍五入)，
+    /// 宽度不足时在左边补空格，数字用0补齐时补在符号之后
+    pub fn format(&self, spec: &FormatSpec) -> Result<String, RuntimeError> {
+        let (number, unit) = match self {
+            Self::Number(n) => (*n, ""),
+            Self::Quantity(n, unit) => (*n, unit.as_str()),
+            Self::Duration(seconds) => (*seconds, "s"),
+            value if spec.precision.is_some() => {
+                return Err(RuntimeError::TypeMismatch {
+                    op: format!(":{spec}"),
+                    types: vec![value.type_name()],
+                });
+            }
+            value => return Ok(format!("{:>width$}", value.to_string(), width = spec.width)),
+        };
+        let digits = spec.precision.map_or_else(
+            || number.normalize().to_string(),
+            |precision| {
+                let rounded = number.round_dp_with_strategy(
+                    u32::try_from(precision).unwrap_or(u32::MAX),
+                    RoundingStrategy::MidpointAwayFromZero,
+                );
+                format!("{rounded:.precision$}")
+            },
+        );
+        let (sign, digits) = digits
+            .strip_prefix('-')
+            .map_or(("", digits.as_str()), |digits| ("-", digits));
+        Ok(if spec.zero {
+            let width = spec.width.saturating_sub(sign.len() + unit.len());
+            format!("{sign}{digits:0>width$}{unit}")
+        } else {
+            format!(
+                "{:>width$}",
+                format!("{sign}{digits}{unit}"),
+                width = spec.width
+            )
+        })
+    }
+
+    /// 作为条件时的真假，`false`、`0`、空字符串、`null`和无值为假，其他值为真
+    pub const fn is_truthy(&self) -> bool {
+        match self {
+            Self::Bool(b) => *b,
+            Self::Number(n) | Self::Quantity(n, _) | Self::Duration(n) => !n.is_zero(),
+            Self::Str(s) => !s.is_empty(),
+            Self::Nil | Self::Unit => false,
+            Self::Symbol(_)
+            | Self::Color(_)
+            | Self::Vec2(_)
+            | Self::Vec3(_)
+            | Self::Array(_)
+            | Self::Tuple(_)
+            | Self::Dict(_)
+            | Self::EntitySet(_)
+            | Self::Function(_) => true,
+        }
+    }
+}
+
+/// 宿主的时长的秒数，精确到纳秒
+pub(crate) fn seconds(duration: Duration) -> Decimal {
+    Decimal::from_i128_with_scale(duration.as_nanos().cast_signed(), 9).normalize()
+}
+
+impl From<Duration> for Value {
+    /// 宿主的时长(例如Bevy的`Time::delta`)转换成脚本中的时长，精确到纳秒
+    fn from(duration: Duration) -> Self {
+        Self::Duration(seconds(duration))
+    }
+}
+
+impl From<Vec2> for Value {
+    fn from(v: Vec2) -> Self {
+        Self::Vec2(v)
+    }
+}
+
+impl From<Vec3> for Value {
+    fn from(v: Vec3) -> Self {
+        Self::Vec3(v)
+    }
+}
+
+/// 依次输出多个值，用`, `分隔
+fn write_values<'v>(
+    f: &mut fmt::Formatter<'_>,
+    values: impl IntoIterator<Item = &'v Value>,
+) -> fmt::Result {
+    for (i, value) in values.into_iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        match value {
+            Value::Str(s) => write!(f, "{s:?}")?,
+            value => write!(f, "{value}")?,
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{}", n.normalize()),
+            Self::Quantity(n, unit) => write!(f, "{}{unit}", n.normalize()),
+            Self::Duration(seconds) => write!(f, "{}s", seconds.normalize()),
+            Self::Str(s) => write!(f, "{s}"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Symbol(name) => write!(f, ":{name}"),
+            // 不透明时省略透明度
+            Self::Color([r, g, b, u8::MAX]) => write!(f, "#{r:02x}{g:02x}{b:02x}"),
+            Self::Color([r, g, b, a]) => write!(f, "#{r:02x}{g:02x}{b:02x}{a:02x}"),
+            Self::Vec2(v) => write!(f, "vec2({}, {})", v.x, v.y),
+            Self::Vec3(v) => write!(f, "vec3({}, {}, {})", v.x, v.y, v.z),
+            Self::Array(items) => {
+                write!(f, "[")?;
+                write_values(f, items)?;
+                write!(f, "]")
+            }
+            Self::Tuple(items) => {
+                write!(f, "(")?;
+                write_values(f, items)?;
+                if items.len() == 1 {
+                    write!(f, ",")?;
+                }
+                write!(f, ")")
+            }
+            Self::Dict(items) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_values(f, [key])?;
+                    write!(f, ": ")?;
+                    write_values(f, [value])?;
+                }
+                write!(f, "}}")
+            }
+            Self::EntitySet(entities) => {
+                write!(f, "Query[")?;
+                for (i, entity) in entities.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{entity}")?;
+                }
+                write!(f, "]")
+            }
+            Self::Function(name) => write!(f, "fn {name}"),
+            Self::Nil => write!(f, "null"),
+            Self::Unit => write!(f, "()"),
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! 二维、三维向量
+//!
+//! 向量的分量是`f32`，和Bevy使用的glam向量相同，可以直接和`Transform::translation`互相转换。
+//! 分量和长度等结果转换成数字时按`f32`的精度取值
+
+use glam::{Vec2, Vec3};
+use rust_decimal::{Decimal, prelude::*};
+
+use crate::parser::scanner::BinaryOp;
+
+use super::{builtin::arity, error::RuntimeError, mismatch, op_symbol, value::Value};
+
+/// 把`f32`转换成数字，NaN和无穷大报溢出
+fn number(x: f32) -> Result<Value, RuntimeError> {
+    Decimal::from_f32(x)
+        .map(Value::Number)
+        .ok_or(RuntimeError::Overflow)
+}
+
+/// 作为第`arg`个参数取出数字并转换成`f32`
+fn component(value: &Value, arg: usize) -> Result<f32, RuntimeError> {
+    value
+        .expect_number(arg)?
+        .to_f32()
+        .ok_or(RuntimeError::Overflow)
+}
+
+/// 作为函数的第`arg`个参数取出向量，类型不对时报[`RuntimeError::ArgType`]
+const fn expect_vector(value: &Value, arg: usize) -> Result<&Value, RuntimeError> {
+    match value {
+        Value::Vec2(_) | Value::Vec3(_) => Ok(value),
+        value => Err(RuntimeError::ArgType {
+            arg,
+            expected: "向量",
+            found: value.type_name(),
+        }),
+    }
+}
+
+/// 向量的运算：同维的向量之间可以加减，`@`是点积，和数字之间可以乘除
+pub(super) fn binary(op: &BinaryOp, lhs: &Value, rhs: &Value) -> Result<Value, RuntimeError> {
+    match (op, lhs, rhs) {
+        (BinaryOp::Add, Value::Vec2(a), Value::Vec2(b)) => Ok(Value::Vec2(a + b)),
+        (BinaryOp::Sub, Value::Vec2(a), Value::Vec2(b)) => Ok(Value::Vec2(a - b)),
+        (BinaryOp::Add, Value::Vec3(a), Value::Vec3(b)) => Ok(Value::Vec3(a + b)),
+        (BinaryOp::Sub, Value::Vec3(a), Value::Vec3(b)) => Ok(Value::Vec3(a - b)),
+        (BinaryOp::Transform, Value::Vec2(a), Value::Vec2(b)) => number(a.dot(*b)),
+        (BinaryOp::Transform, Value::Vec3(a), Value::Vec3(b)) => number(a.dot(*b)),
+        (BinaryOp::Div, _, Value::Number(n)) if n.is_zero() => Err(RuntimeError::DivByZero),
+        (BinaryOp::Mul | BinaryOp::Div, vector, Value::Number(n))
+        | (BinaryOp::Mul, Value::Number(n), vector) => {
+            let n = component(&Value::Number(*n), 0)?;
+            match (op, vector) {
+                (BinaryOp::Mul, Value::Vec2(v)) => Ok(Value::Vec2(v * n)),
+                (BinaryOp::Mul, Value::Vec3(v)) => Ok(Value::Vec3(v * n)),
+                (_, Value::Vec2(v)) => Ok(Value::Vec2(v / n)),
+                (_, Value::Vec3(v)) => Ok(Value::Vec3(v / n)),
+                _ => Err(mismatch(op_symbol(op), &[lhs, rhs])),
+            }
+        }
+        _ => Err(mismatch(op_symbol(op), &[lhs, rhs])),
+    }
+}
+
+/// 取出向量的分量`v.x`、`v.y`、`v.z`
+pub(super) fn field(value: &Value, name: &str) -> Result<Value, RuntimeError> {
+    match (value, name) {
+        (Value::Vec2(v), "x") => number(v.x),
+        (Value::Vec2(v), "y") => number(v.y),
+        (Value::Vec3(v), "x") => number(v.x),
+        (Value::Vec3(v), "y") => number(v.y),
+        (Value::Vec3(v), "z") => number(v.z),
+        (value, name) => Err(mismatch(format!(".{name}"), &[value])),
+    }
+}
+
+/// `vec2(x, y)`
+pub(super) fn vec2(args: &[Value]) -> Result<Value, RuntimeError> {
+    arity("vec2", args, 2)?;
+    Ok(Value::Vec2(Vec2::new(
+        component(&args[0], 0)?,
+        component(&args[1], 1)?,
+    )))
+}
+
+/// `vec3(x, y, z)`
+pub(super) fn vec3(args: &[Value]) -> Result<Value, RuntimeError> {
+    arity("vec3", args, 3)?;
+    Ok(Value::Vec3(Vec3::new(
+        component(&args[0], 0)?,
+        component(&args[1], 1)?,
+        component(&args[2], 2)?,
+    )))
+}
+
+/// `length(v)`，向量的长度
+pub(super) fn length(args: &[Value]) -> Result<Value, RuntimeError> {
+    arity("length", args, 1)?;
+    match expect_vector(&args[0], 0)? {
+        Value::Vec2(v) => number(v.length()),
+        Value::Vec3(v) => number(v.length()),
+        value => Err(mismatch("length", &[value])),
+    }
+}
+
+/// `normalize(v)`，方向相同的单位向量，零向量仍然是零向量
+pub(super) fn normalize(args: &[Value]) -> Result<Value, RuntimeError> {
+    arity("normalize", args, 1)?;
+    match expect_vector(&args[0], 0)? {
+        Value::Vec2(v) => Ok(Value::Vec2(v.normalize_or_zero())),
+        Value::Vec3(v) => Ok(Value::Vec3(v.normalize_or_zero())),
+        value => Err(mismatch("normalize", &[value])),
+    }
+}
+
+/// `dot(a, b)`，点积，和`a @ b`相同
+pub(super) fn dot(args: &[Value]) -> Result<Value, RuntimeError> {
+    arity("dot", args, 2)?;
+    binary(
+        &BinaryOp::Transform,
+        expect_vector(&args[0], 0)?,
+        expect_vector(&args[1], 1)?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec2, Vec3};
+    use rust_decimal::Decimal;
+
+    use crate::{
+        eval::{error::RuntimeError, interpreter::Interpreter, value::Value},
+        parser::parse,
+    };
+
+    fn eval(src: &str) -> Result<Value, RuntimeError> {
+        let ast = parse(src).unwrap();
+        Interpreter::new()
+            .eval_program(&ast)
+            .map_err(|error| error.error)
+    }
+
+    fn number(n: &str) -> Result<Value, RuntimeError> {
+        Ok(Value::Number(n.parse::<Decimal>().unwrap()))
+    }
+
+    #[test]
+    fn vector_arithmetic() {
+        assert_eq!(
+            eval("vec2(1, 2) + vec2(0.5, -1)"),
+            Ok(Value::Vec2(Vec2::new(1.5, 1.0)))
+        );
+        assert_eq!(
+            eval("vec3(1, 2, 3) - vec3(1, 1, 1)"),
+            Ok(Value::Vec3(Vec3::new(0.0, 1.0, 2.0)))
+        );
+        assert_eq!(
+            eval("vec3(1, 2, 3) * 2"),
+            Ok(Value::Vec3(Vec3::new(2.0, 4.0, 6.0)))
+        );
+        assert_eq!(
+            eval("0.5 * vec2(4, 2)"),
+            Ok(Value::Vec2(Vec2::new(2.0, 1.0)))
+        );
+        assert_eq!(
+            eval("-vec2(4, 2) / 2"),
+            Ok(Value::Vec2(Vec2::new(-2.0, -1.0)))
+        );
+        assert_eq!(eval("vec3(1, 2, 3) @ vec3(4, 5, 6)"), number("32"));
+        assert_eq!(eval("dot(vec2(1, 0), vec2(0, 1))"), number("0"));
+        assert_eq!(
+            eval("'at ' + vec2(1, 2.5)"),
+            Ok(Value::Str("at vec2(1, 2.5)".to_string()))
+        );
+        // 不同维的向量、向量和数字相加都会报错
+        assert_eq!(
+            eval("vec2(1, 2) + vec3(1, 2, 3)"),
+            Err(RuntimeError::TypeMismatch {
+                op: "+".to_string(),
+                types: vec!["二维向量", "三维向量"]
+            })
+        );
+        assert!(matches!(
+            eval("vec2(1, 2) + 1"),
+            Err(RuntimeError::TypeMismatch { .. })
+        ));
+        assert_eq!(eval("vec2(1, 2) / 0"), Err(RuntimeError::DivByZero));
+    }
+
+    #[test]
+    fn vector_length() {
+        assert_eq!(eval("length(vec2(3, 4))"), number("5"));
+        assert_eq!(eval("vec3(2, 3, 6).length()"), number("7"));
+        assert_eq!(
+            eval("v = vec2(0, 5)\nv.normalize()"),
+            Ok(Value::Vec2(Vec2::new(0.0, 1.0)))
+        );
+        // 零向量仍然是零向量
+        assert_eq!(
+            eval("normalize(vec3(0, 0, 0))"),
+            Ok(Value::Vec3(Vec3::ZERO))
+        );
+        assert_eq!(
+            eval("length(3)"),
+            Err(RuntimeError::ArgType {
+                arg: 0,
+                expected: "向量",
+                found: "数字"
+            })
+        );
+    }
+
+    #[test]
+    fn vector_components() {
+        assert_eq!(eval("v = vec3(1, -2, 0.5)\nv.x + v.y * v.z"), number("0"));
+        assert_eq!(eval("vec2(0.25, 8).y"), number("8"));
+        assert_eq!(eval("vec2(0.25, 8).x"), number("0.25"));
+        assert_eq!(
+            eval("vec2(1, 2).z"),
+            Err(RuntimeError::TypeMismatch {
+                op: ".z".to_string(),
+                types: vec!["二维向量"]
+            })
+        );
+        assert_eq!(eval("v = vec2(1, 2)\nv.dot(vec2(3, 4))"), number("11"));
+    }
+}
@@ -0,0 +1,214 @@
+//! 宿主提供的世界
+
+use std::fmt;
+
+/// 实体，使用宿主的实体编号(例如bevy的`Entity::to_bits`)
+pub type Entity = u64;
+
+/// 组件类型的编号，由宿主分配
+pub type ComponentId = usize;
+
+/// 宿主提供的世界，脚本中的查询通过它找到实体
+pub trait World {
+    /// 根据组件名找到组件类型，没有这个组件时返回None
+    fn component_id(&self, name: &str) -> Option<ComponentId>;
+
+    /// 世界中所有的实体
+    fn entities(&self) -> Vec<Entity>;
+
+    /// 实体是否拥有这个组件
+    fn has_component(&self, entity: Entity, component: ComponentId) -> bool;
+
+    /// 同时拥有with中所有组件、并且没有without中任何组件的实体，
+    /// 默认逐个检查所有实体，宿主可以换成自己的查询(例如bevy的`QueryBuilder`)
+    fn query(&self, with: &[ComponentId], without: &[ComponentId]) -> Vec<Entity> {
+        self.entities()
+            .into_iter()
+            .filter(|&entity| {
+                with.iter().all(|&c| self.has_component(entity, c))
+                    && !without.iter().any(|&c| self.has_component(entity, c))
+            })
+            .collect()
+    }
+
+    /// 实体的直接子实体(例如bevy的`Children`)，查询的`of`会用到，默认没有层级关系
+    fn children(&self, _entity: Entity) -> Vec<Entity> {
+        vec![]
+    }
+}
+
+impl fmt::Debug for dyn World + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "World")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use rust_decimal::{Decimal, prelude::ToPrimitive};
+
+    use crate::{
+        eval::{
+            env::{Env, NativeFn},
+            error::RuntimeError,
+            eval_expression,
+            value::Value,
+        },
+        parser::{parse, scanner::Command},
+    };
+
+    use super::{ComponentId, Entity, World};
+
+    /// 测试用的世界，按组件名保存拥有它的实体，以及每个实体的子实体
+    struct MockWorld(Vec<(&'static str, Vec<Entity>)>, Vec<(Entity, Vec<Entity>)>);
+
+    impl World for MockWorld {
+        fn component_id(&self, name: &str) -> Option<ComponentId> {
+            self.0.iter().position(|(n, _)| *n == name)
+        }
+
+        fn entities(&self) -> Vec<Entity> {
+            let entities: BTreeSet<_> = self.0.iter().flat_map(|(_, e)| e).copied().collect();
+            entities.into_iter().collect()
+        }
+
+        fn has_component(&self, entity: Entity, component: ComponentId) -> bool {
+            self.0[component].1.contains(&entity)
+        }
+
+        fn children(&self, entity: Entity) -> Vec<Entity> {
+            self.1
+                .iter()
+                .find(|(parent, _)| *parent == entity)
+                .map(|(_, children)| children.clone())
+                .unwrap_or_default()
+        }
+    }
+
+    #[test]
+    fn query_world() {
+        let world = MockWorld(
+            vec![
+                ("Enemy", vec![1, 2]),
+                ("Health", vec![1, 2, 3]),
+                ("Dead", vec![2]),
+                ("Frozen", vec![3]),
+            ],
+            vec![],
+        );
+        let query = |src: &str| {
+            let ast = parse(src).unwrap();
+            let Command::Expression(expression) = &ast[0] else {
+                panic!("expected expression, got {:?}", ast[0]);
+            };
+            let mut env = Env::new();
+            env.set_world(&world);
+            eval_expression(expression, &mut env)
+        };
+        let set = |entities: &[Entity]| Ok(Value::EntitySet(entities.iter().copied().collect()));
+        assert_eq!(query("Query<Health>"), set(&[1, 2, 3]));
+        assert_eq!(query("Query<Enemy|Health>"), set(&[1, 2]));
+        assert_eq!(query("Query<Enemy|Health|!Dead>"), set(&[1]));
+        assert_eq!(query("Query<Health, !Dead, !Frozen>"), set(&[1]));
+        assert_eq!(query("Query"), set(&[1, 2, 3]));
+        assert_eq!(
+            query("Query<Boss>"),
+            Err(RuntimeError::UnknownComponent("Boss".to_string()))
+        );
+    }
+
+    #[test]
+    fn query_of_parent() {
+        // 10和20是两辆车，1、2装在10上，3装在20上，4没有装在车上
+        let world = MockWorld(
+            vec![("Turret", vec![1, 2, 3, 4]), ("Vehicle", vec![10, 20])],
+            vec![(10, vec![1, 2, 5]), (20, vec![3])],
+        );
+        let query = |src: &str| {
+            let ast = parse(src).unwrap();
+            let mut env = Env::new();
+            env.set_world(&world);
+            env.define("vehicle", Value::Number(Decimal::from(10)));
+            let mut result = Ok(Value::Nil);
+            for command in &ast {
+                if let Command::Expression(expression) = command {
+                    result = eval_expression(expression, &mut env);
+                }
+            }
+            result
+        };
+        let set = |entities: &[Entity]| Ok(Value::EntitySet(entities.iter().copied().collect()));
+        assert_eq!(query("Query<Turret> of vehicle"), set(&[1, 2]));
+        assert_eq!(query("Query<Turret> of Query<Vehicle>"), set(&[1, 2, 3]));
+        assert_eq!(query("Query<Turret> of 3"), set(&[]));
+        assert_eq!(
+            query("Query<Turret> of vehicle order_by -entity limit 1"),
+            Ok(Value::Array(vec![Value::Number(Decimal::TWO)]))
+        );
+        assert!(matches!(
+            query("Query<Turret> of 'a'"),
+            Err(RuntimeError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn query_order_by_limit() {
+        let world = MockWorld(vec![("Enemy", vec![1, 2, 3, 4, 5])], vec![]);
+        let query = |src: &str| {
+            let ast = parse(src).unwrap();
+            let Command::Expression(expression) = &ast[0] else {
+                panic!("expected expression, got {:?}", ast[0]);
+            };
+            let mut env = Env::new();
+            env.set_world(&world);
+            // 每个敌人到自己的距离
+            env.define_native(
+                "dist",
+                NativeFn::new(|args| match args {
+                    [Value::Number(entity)] => {
+                        let dist = [50, 10, 30, 20, 40][entity.to_usize().unwrap() - 1];
+                        Ok(Value::Number(Decimal::from(dist)))
+                    }
+                    _ => Err(RuntimeError::Unsupported("dist的参数".to_string())),
+                }),
+            );
+            eval_expression(expression, &mut env)
+        };
+        let array = |entities: &[i64]| {
+            Ok(Value::Array(
+                entities
+                    .iter()
+                    .map(|&entity| Value::Number(Decimal::from(entity)))
+                    .collect(),
+            ))
+        };
+        // 最近的三个敌人
+        assert_eq!(
+            query("Query<Enemy> order_by dist limit 3"),
+            array(&[2, 4, 3])
+        );
+        assert_eq!(
+            query("Query<Enemy> order_by -entity limit 2"),
+            array(&[5, 4])
+        );
+        assert_eq!(
+            query("Query<Enemy> order_by entity % 2"),
+            array(&[2, 4, 1, 3, 5])
+        );
+        // 没有order_by时按实体编号截取
+        assert_eq!(
+            query("Query<Enemy> limit 2"),
+            Ok(Value::EntitySet([1, 2].into_iter().collect()))
+        );
+        assert!(matches!(
+            query("Query<Enemy> limit -1"),
+            Err(RuntimeError::Unsupported(_))
+        ));
+        assert!(matches!(
+            query("Query<Enemy> order_by entity > 2 ? 'a' : 1"),
+            Err(RuntimeError::TypeMismatch { .. })
+        ));
+    }
+}
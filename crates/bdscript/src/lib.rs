@@ -2,4 +2,5 @@
 #![warn(clippy::all, clippy::nursery)]
 #![feature(iterator_try_collect)]
 #![feature(decl_macro)]
-mod parser;
+pub mod eval;
+pub mod parser;
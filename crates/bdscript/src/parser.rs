@@ -1,3 +1,775 @@
 //! 语法分析器
+pub mod dialect;
+pub mod diff;
+mod directive;
+pub mod error;
+pub mod import;
+pub mod incremental;
+pub mod indent;
+mod label;
+pub mod limit;
+pub mod operator;
 pub mod scanner;
+pub mod schema;
+mod template;
 pub mod tokenizer;
+pub mod visit;
+
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
+
+use chumsky::{
+    Parser,
+    input::{Input, Stream},
+    span::SimpleSpan,
+};
+use logos::Logos;
+
+use self::{
+    dialect::Dialect,
+    error::ParseError,
+    indent::IndentStyle,
+    limit::Budget,
+    operator::OperatorSyntax,
+    scanner::{Command, Spanned, build_ast},
+    tokenizer::{FStringSegment, Token},
+};
+
+/// 解析配置
+#[derive(Debug, Clone, Default)]
+pub struct ParseConfig {
+    /// 条件编译标志，`#if FLAG`和`#endif`之间的代码只有在定义了FLAG时才会被解析
+    pub flags: HashSet<String>,
+    /// 最多允许的语句数量(包括嵌套在块中的语句)，None为不限制
+    pub max_statements: Option<usize>,
+    /// 最多允许的表达式节点数量，None为不限制
+    pub max_expression_nodes: Option<usize>,
+    /// 方言，文件第一行的`#!bdscript 方言名`会覆盖这个设置
+    pub dialect: Dialect,
+    /// 运算符别名，例如把`greater than`映射为`>`，单词之间用一个空格分隔
+    ///
+    /// 只有连续出现的标识符完整匹配别名时才会被替换，单独的`greater`仍然是标识符
+    pub aliases: HashMap<String, Token<'static>>,
+    /// 缩进方式，None时制表符和四个空格都可以用，但是同一行中不能混用
+    pub indent_style: Option<IndentStyle>,
+    /// 自定义二元运算符，解析成[`BinaryOp::Custom`](scanner::BinaryOp::Custom)，下标就是在这里的位置，
+    /// 见[`operator`]
+    pub operators: Vec<OperatorSyntax>,
+}
+
+impl ParseConfig {
+    /// 解析源码，只要出现错误就返回所有错误
+    pub fn parse<'a>(&self, src: &'a str) -> Result<Vec<Command<'a>>, Vec<ParseError>> {
+        match self.parse_with_recovery(src) {
+            (ast, errors) if errors.is_empty() => Ok(ast),
+            (_, errors) => Err(errors),
+        }
+    }
+
+    /// 带错误恢复的解析
+    ///
+    /// 出错的语句会被跳过(留下[`Command::NewLine`]占位)，
+    /// 返回尽可能完整的语法树以及所有的错误，方便编辑器集成
+    pub fn parse_with_recovery<'a>(&self, src: &'a str) -> (Vec<Command<'a>>, Vec<ParseError>) {
+        let (ast, errors) = self.parse_spanned_with_recovery(src);
+        (
+            ast.into_iter().map(|command| command.node).collect(),
+            errors,
+        )
+    }
+
+    /// 解析源码，顶层的每条语句都带有在源码中的位置，方便报告诊断信息
+    pub fn parse_spanned<'a>(
+        &self,
+        src: &'a str,
+    ) -> Result<Vec<Spanned<Command<'a>>>, Vec<ParseError>> {
+        match self.parse_spanned_with_recovery(src) {
+            (ast, errors) if errors.is_empty() => Ok(ast),
+            (_, errors) => Err(errors),
+        }
+    }
+
+    /// 带错误恢复的解析，保留顶层语句的位置
+    fn parse_spanned_with_recovery<'a>(
+        &self,
+        src: &'a str,
+    ) -> (Vec<Spanned<Command<'a>>>, Vec<ParseError>) {
+        let mut errors = vec![];
+        let budget = Budget::new(self);
+        let ast = self.parse_commands(src, &budget, &mut errors);
+        let ast = self.resolve(src, ast, &budget, &mut errors);
+        (ast, errors)
+    }
+
+    /// 把源码解析成语法树，模板还没有展开
+    ///
+    /// 超过`budget`的限制后不再读取后面的记号，并丢弃整棵语法树
+    fn parse_commands<'a>(
+        &self,
+        src: &'a str,
+        budget: &Budget,
+        errors: &mut Vec<ParseError>,
+    ) -> Vec<Spanned<Command<'a>>> {
+        let tokens = self.lex(src, errors);
+        let end_pos = src.len();
+        let tokens = budget.truncate(tokens);
+        let token_stream =
+            Stream::from_iter(tokens).map((end_pos..end_pos).into(), |(t, s)| (t, s));
+        let indent_count = Cell::new(0);
+        let (ast, parse_errors) =
+            build_ast(&indent_count, self.indent_style, &self.operators, budget)
+                .parse(token_stream)
+                .into_output_errors();
+        let parse_errors = parse_errors.into_iter().map(ParseError::from);
+        if let Some(error) = budget.error() {
+            // 记号流被截断后产生的错误没有意义，只保留越过限制之前的错误
+            let start = error.span().start;
+            errors.extend(parse_errors.filter(|parse_error| parse_error.span().end <= start));
+            errors.push(error);
+            return vec![];
+        }
+        errors.extend(parse_errors);
+        ast.unwrap_or_default()
+    }
+
+    /// 展开模板，展开出的语句同样计入`budget`
+    fn resolve<'a>(
+        &self,
+        src: &'a str,
+        ast: Vec<Spanned<Command<'a>>>,
+        budget: &Budget,
+        errors: &mut Vec<ParseError>,
+    ) -> Vec<Spanned<Command<'a>>> {
+        if budget.is_exceeded() {
+            return vec![];
+        }
+        let ast = template::expand(src, ast, budget, errors);
+        label::check(src, &ast, errors);
+        // 来自不可信资源的脚本可能非常大，超过限制时丢弃整棵语法树
+        if let Some(error) = budget.error() {
+            errors.push(error);
+            return vec![];
+        }
+        ast
+    }
+
+    /// 把源码切分为token，无法识别的字符会被记录到errors中
+    fn lex<'a>(&self, src: &'a str, errors: &mut Vec<ParseError>) -> Vec<(Token<'a>, SimpleSpan)> {
+        let dialect = dialect::from_directive(src, errors).unwrap_or(self.dialect);
+        let excluded = directive::excluded_ranges(src, &self.flags, errors);
+        let mut excluded = excluded.iter().peekable();
+        let mut tokens = vec![];
+        // 上一个自定义运算符的结束位置，运算符中间的token被丢弃
+        let mut operator_end = 0;
+        for (token, span) in Token::lexer(src).spanned() {
+            // 跳过被条件编译排除的token
+            while excluded.next_if(|range| range.end <= span.start).is_some() {}
+            if excluded
+                .peek()
+                .is_some_and(|range| range.contains(&span.start))
+            {
+                continue;
+            }
+            let start = span.start;
+            if start >= operator_end
+                && let Some((index, end)) = operator::match_at(&self.operators, src, start)
+            {
+                tokens.push((
+                    Token::CustomOp((index, &src[start..end])),
+                    (start..end).into(),
+                ));
+                operator_end = end;
+            }
+            if start < operator_end {
+                // 跨过运算符结尾的token，重新识别运算符之后的部分
+                if span.end > operator_end {
+                    for (token, inner) in Token::lexer(&src[operator_end..span.end]).spanned() {
+                        let inner = inner.start + operator_end..inner.end + operator_end;
+                        push_token(src, token, inner, &mut tokens, errors);
+                    }
+                }
+                continue;
+            }
+            push_token(src, token, span, &mut tokens, errors);
+        }
+        let mut tokens = drop_blank_lines(tokens);
+        if !self.aliases.is_empty() {
+            tokens = self.fold_aliases(tokens);
+        }
+        if dialect == Dialect::Strict {
+            dialect::check_strict(&tokens, errors);
+        }
+        tokens
+    }
+
+    /// 把匹配别名的标识符序列替换成对应的token，优先匹配最长的别名
+    fn fold_aliases<'a>(
+        &self,
+        tokens: Vec<(Token<'a>, SimpleSpan)>,
+    ) -> Vec<(Token<'a>, SimpleSpan)> {
+        let max_words = self
+            .aliases
+            .keys()
+            .map(|alias| alias.split(' ').count())
+            .max()
+            .unwrap_or_default();
+        let mut folded = Vec::with_capacity(tokens.len());
+        let mut i = 0;
+        while i < tokens.len() {
+            let matched = (1..=max_words).rev().find_map(|n| {
+                let words = tokens
+                    .get(i..i + n)?
+                    .iter()
+                    .map(|(token, _)| match token {
+                        Token::Ident(word) => Some(*word),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                let token = self.aliases.get(&words.join(" "))?;
+                Some((n, token.clone()))
+            });
+            match matched {
+                Some((n, token)) => {
+                    let span = (tokens[i].1.start..tokens[i + n - 1].1.end).into();
+                    folded.push((token, span));
+                    i += n;
+                }
+                None => {
+                    folded.push(tokens[i].clone());
+                    i += 1;
+                }
+            }
+        }
+        folded
+    }
+}
+
+/// 去掉只有空白(或者注释)的行，这些行对语法没有影响，
+/// 而且其中的缩进可能和所在的块不一致
+fn drop_blank_lines(tokens: Vec<(Token<'_>, SimpleSpan)>) -> Vec<(Token<'_>, SimpleSpan)> {
+    let mut kept = Vec::with_capacity(tokens.len());
+    // 当前行开头的缩进，遇到其他token时才保留
+    let mut indent = vec![];
+    let mut at_line_start = true;
+    for (token, span) in tokens {
+        match token {
+            Token::Tab(_) if at_line_start => indent.push((token, span)),
+            Token::Line if at_line_start => indent.clear(),
+            Token::Line => {
+                kept.push((token, span));
+                at_line_start = true;
+            }
+            _ => {
+                kept.append(&mut indent);
+                kept.push((token, span));
+                at_line_start = false;
+            }
+        }
+    }
+    kept
+}
+
+/// 把一个token加入序列，处理需要和前面的token结合的情况
+fn push_token<'a>(
+    src: &'a str,
+    token: Result<Token<'a>, ()>,
+    span: Range<usize>,
+    tokens: &mut Vec<(Token<'a>, SimpleSpan)>,
+    errors: &mut Vec<ParseError>,
+) {
+    match token {
+        // 紧跟在操作数后面的符号(例如`{a:b}`)，或者三元表达式、字典中
+        // 正在等待冒号的位置(例如`a ? b :c`、`{'a' :b}`)，拆成冒号和标识符
+        Ok(Token::Symbol(name))
+            if tokens.last().is_some_and(|(last, last_span)| {
+                last_span.end == span.start && last.is_operand()
+            }) || expects_colon(tokens) =>
+        {
+            tokens.push((Token::Colon, (span.start..span.start + 1).into()));
+            tokens.push((Token::Ident(name), (span.start + 1..span.end).into()));
+        }
+        // 紧跟在标识符后面的`!(`(例如`cooldown!(1s)`)是模板调用
+        Ok(Token::LeftParen) => {
+            if let [.., (Token::Ident(name), name_span), (Token::Not, not_span)] = tokens.as_slice()
+                && name_span.end == not_span.start
+                && not_span.end == span.start
+            {
+                let invoke = (Token::Invoke(name), (name_span.start..not_span.end).into());
+                tokens.truncate(tokens.len() - 2);
+                tokens.push(invoke);
+            }
+            tokens.push((Token::LeftParen, span.into()));
+        }
+        // f-string展开成开头、文本、内嵌表达式的token和结尾
+        Ok(Token::FString(segments)) => {
+            tokens.push((Token::FStringStart, (span.start..span.start + 2).into()));
+            for segment in segments {
+                match segment {
+                    FStringSegment::Text(text) => {
+                        tokens.push((Token::FStringPart(text), span.clone().into()));
+                    }
+                    FStringSegment::Expr(range, spec) => {
+                        let offset = span.start + range.start;
+                        let embedded = &src[offset..span.start + range.end];
+                        for (token, inner) in Token::lexer(embedded).spanned() {
+                            let inner = inner.start + offset..inner.end + offset;
+                            push_token(src, token, inner, tokens, errors);
+                        }
+                        // 格式从冒号开始，到结尾的`}`之前
+                        if let Some(spec) = spec {
+                            let start = span.start + range.end;
+                            let end = src[start..].find('}').map_or(start, |i| start + i);
+                            tokens.push((Token::FStringSpec(spec), (start..end).into()));
+                        }
+                    }
+                }
+            }
+            tokens.push((Token::FStringEnd, (span.end - 1..span.end).into()));
+        }
+        Ok(token) => tokens.push((token, span.into())),
+        Err(()) if &src[span.clone()] == "#[" => {
+            errors.push(ParseError::custom(span, "块注释缺少对应的]#"));
+        }
+        Err(()) => errors.push(ParseError::Lex { span }),
+    }
+}
+
+/// 当前位置是否在等待一个冒号：前面有还没有配对的`?`，
+/// 或者处在字典中还没有写冒号的键之后。冒号前面总是一个操作数，
+/// 所以`{:a: 1}`、`f(x, :a)`中的符号不受影响
+fn expects_colon(tokens: &[(Token<'_>, SimpleSpan)]) -> bool {
+    if !tokens.last().is_some_and(|(last, _)| last.is_operand()) {
+        return false;
+    }
+    let mut depth = 0usize;
+    // 从后往前已经遇到、还没有和`?`配对的冒号
+    let mut colons = 0usize;
+    // 当前字典项(最后一个逗号之后)中是否已经有冒号
+    let mut item_has_colon = false;
+    let mut item_closed = false;
+    for (token, _) in tokens.iter().rev() {
+        match token {
+            Token::RightParen | Token::RightBracket | Token::RightBrace => depth += 1,
+            Token::LeftParen | Token::LeftBracket if depth == 0 => return false,
+            Token::LeftBrace if depth == 0 => return !item_has_colon,
+            Token::LeftParen | Token::LeftBracket | Token::LeftBrace => depth -= 1,
+            _ if depth > 0 => {}
+            Token::Line => return false,
+            Token::Colon => {
+                colons += 1;
+                item_has_colon |= !item_closed;
+            }
+            Token::Question if colons == 0 => return true,
+            Token::Question => colons -= 1,
+            Token::Comma => item_closed = true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// 使用默认配置解析源码，只要出现错误就返回所有错误
+pub fn parse(src: &str) -> Result<Vec<Command<'_>>, Vec<ParseError>> {
+    ParseConfig::default().parse(src)
+}
+
+/// 使用默认配置进行带错误恢复的解析，见[`ParseConfig::parse_with_recovery`]
+pub fn parse_with_recovery(src: &str) -> (Vec<Command<'_>>, Vec<ParseError>) {
+    ParseConfig::default().parse_with_recovery(src)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::{
+        ParseConfig,
+        dialect::Dialect,
+        error::{ParseError, SizeLimit},
+        indent::IndentStyle,
+        operator::{Associativity, OperatorSyntax, Precedence},
+        parse, parse_with_recovery,
+        scanner::{BinaryOp, Command, Expression, Object},
+        tokenizer::Token,
+    };
+
+    #[test]
+    fn parse_hello_world() {
+        let ast = parse("print('hello,world')\n").unwrap();
+        assert_eq!(ast.len(), 1);
+    }
+
+    #[test]
+    fn parse_spanned_statements() {
+        let src = "a = 1\nif a:\n    b = 2\ntemplate t():\n    c = 3\nt!()\n";
+        let ast = ParseConfig::default().parse_spanned(src).unwrap();
+        let statements = ast
+            .iter()
+            .map(|command| src[command.span.into_range()].trim_end())
+            .collect::<Vec<_>>();
+        // 模板展开出的语句沿用调用语句的位置
+        assert_eq!(statements, ["a = 1", "if a:\n    b = 2", "t!()"]);
+        assert_eq!(
+            ast.into_iter()
+                .map(|command| command.node)
+                .collect::<Vec<_>>(),
+            parse(src).unwrap()
+        );
+
+        let src = "a = 1\nb = )\n";
+        let errors = parse(src).unwrap_err();
+        assert_eq!(&src[errors[0].span()], ")");
+    }
+
+    #[test]
+    fn parse_degenerate_input() {
+        for src in [
+            "",
+            "\n\n",
+            "   \n\t\n    ",
+            "# comment\n#[ block ]#\n# last",
+        ] {
+            assert_eq!(parse(src), Ok(vec![]), "{src:?}");
+        }
+    }
+
+    #[test]
+    fn parse_with_recovery_keeps_valid_statements() {
+        let src = "a = 1\n) oops\nb = 2\n] bad\nc = 3\n";
+        let (ast, errors) = parse_with_recovery(src);
+        let names = ast
+            .iter()
+            .filter_map(|command| match command {
+                Command::Expression(Expression::Binary { lhs, .. }) => match lhs.as_ref() {
+                    Expression::Object(Object::Variable(name)) => Some(*name),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(&src[errors[0].span()], ")");
+        assert!(parse(src).is_err());
+    }
+
+    #[test]
+    fn parse_symbol_and_dict_colon() {
+        let ast = parse("state = :attack\nd = {'a':b}\n").unwrap();
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[0] else {
+            panic!("expected assignment, got {:?}", ast[0]);
+        };
+        assert!(matches!(
+            rhs.as_ref(),
+            Expression::Object(Object::Symbol("attack"))
+        ));
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[1] else {
+            panic!("expected assignment, got {:?}", ast[1]);
+        };
+        let Expression::Object(Object::Dict(items)) = rhs.as_ref() else {
+            panic!("expected dict, got {rhs:?}");
+        };
+        assert!(matches!(
+            &items[0],
+            Expression::Object(Object::DictItem(..))
+        ));
+    }
+
+    #[test]
+    fn parse_spaced_colon_before_ident() {
+        let ast = parse("x = a ? b :c\nd = {'a' :b, 'c' :d}\ns = f(:attack)\n").unwrap();
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[0] else {
+            panic!("expected assignment, got {:?}", ast[0]);
+        };
+        let Expression::Ternary { otherwise, .. } = rhs.as_ref() else {
+            panic!("expected ternary, got {rhs:?}");
+        };
+        assert!(matches!(
+            otherwise.as_ref(),
+            Expression::Object(Object::Variable("c"))
+        ));
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[1] else {
+            panic!("expected assignment, got {:?}", ast[1]);
+        };
+        let Expression::Object(Object::Dict(items)) = rhs.as_ref() else {
+            panic!("expected dict, got {rhs:?}");
+        };
+        assert_eq!(items.len(), 2);
+        assert!(
+            items
+                .iter()
+                .all(|item| matches!(item, Expression::Object(Object::DictItem(..))))
+        );
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[2] else {
+            panic!("expected assignment, got {:?}", ast[2]);
+        };
+        assert!(format!("{rhs:?}").contains("Symbol(\"attack\")"));
+    }
+
+    #[test]
+    fn parse_conditional_directives() {
+        let src = "a = 1\n#if DEBUG\nprint(a)\n#endif\nb = 2\n";
+        let debug = ParseConfig {
+            flags: HashSet::from(["DEBUG".to_string()]),
+            ..Default::default()
+        };
+        let ast = debug.parse(src).unwrap();
+        assert_eq!(ast.len(), 3);
+        assert!(matches!(
+            &ast[1],
+            Command::Expression(Expression::Binary {
+                op: BinaryOp::Call,
+                ..
+            })
+        ));
+        let ast = parse(src).unwrap();
+        assert_eq!(ast.len(), 2);
+        assert!(ast.iter().all(|command| !matches!(
+            command,
+            Command::Expression(Expression::Binary {
+                op: BinaryOp::Call,
+                ..
+            })
+        )));
+        assert!(parse("#if DEBUG\na = 1\n").is_err());
+    }
+
+    #[test]
+    fn parse_size_limits() {
+        let src = "a = 1\nb = 2\nif a:\n    c = a + b\n";
+        let config = ParseConfig {
+            max_statements: Some(3),
+            ..Default::default()
+        };
+        let errors = config.parse(src).unwrap_err();
+        // 块中的语句先计入，越过限制的是整条if语句
+        assert_eq!(
+            errors,
+            [ParseError::TooLarge {
+                span: 12..src.len(),
+                kind: SizeLimit::Statements,
+                limit: 3
+            }]
+        );
+        // 越过限制后不再解析后面的记号
+        let errors = config
+            .parse("a = 1\nb = 2\nc = 3\nd = 4\n)))\n")
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            [ParseError::TooLarge {
+                span: 18..24,
+                kind: SizeLimit::Statements,
+                limit: 3
+            }]
+        );
+        let config = ParseConfig {
+            max_statements: Some(4),
+            max_expression_nodes: Some(4),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.parse(src).unwrap_err()[..],
+            [ParseError::TooLarge {
+                kind: SizeLimit::ExpressionNodes,
+                ..
+            }]
+        ));
+        let config = ParseConfig {
+            max_statements: Some(4),
+            max_expression_nodes: Some(12),
+            ..Default::default()
+        };
+        assert!(config.parse(src).is_ok());
+
+        // 嵌套的模板调用每一层都翻倍，展开过程中就会停下
+        let mut src = "template t0():\n    x = 1\n".to_string();
+        for i in 1..40 {
+            src += &format!("template t{i}():\n    t{}!()\n    t{}!()\n", i - 1, i - 1);
+        }
+        src += "t39!()\n";
+        let config = ParseConfig {
+            max_statements: Some(1000),
+            ..Default::default()
+        };
+        let errors = config.parse(&src).unwrap_err();
+        assert_eq!(
+            errors,
+            [ParseError::TooLarge {
+                span: src.len() - 7..src.len(),
+                kind: SizeLimit::Statements,
+                limit: 1000
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_reports_lex_errors() {
+        let (_, errors) = parse_with_recovery("a = 1 $\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span(), 6..7);
+
+        let (_, errors) = parse_with_recovery("a = 1\n#[ unclosed\n");
+        assert_eq!(errors[0].span(), 6..8);
+        assert_eq!(errors[0].message(), "块注释缺少对应的]#");
+    }
+
+    #[test]
+    fn parse_strict_dialect() {
+        let src = "if hp = 0:\n    flee()\n";
+        assert!(parse(src).is_ok());
+
+        let strict = format!("#!bdscript strict\n{src}");
+        let errors = parse(&strict).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(&strict[errors[0].span()], "=");
+
+        let config = ParseConfig {
+            dialect: Dialect::Strict,
+            ..Default::default()
+        };
+        let errors = config
+            .parse("if a:\n    b = 1\nif c:\n\td = 2\n")
+            .unwrap_err();
+        assert_eq!(errors[0].message(), "缩进方式不一致");
+    }
+
+    #[test]
+    fn parse_indent_style() {
+        let tabs = "if a:\n\tif b:\n\t\tc = 1\n";
+        let spaces = "if a:\n    if b:\n        c = 1\n";
+        let mixed = "if a:\n\tif b:\n\t    c = 1\n";
+        assert!(parse(tabs).is_ok());
+        assert!(parse(spaces).is_ok());
+        let errors = parse(mixed).unwrap_err();
+        assert_eq!(errors[0].message(), "同一行中混用了制表符和空格缩进");
+        assert_eq!(&mixed[errors[0].span()], "\t    ");
+
+        let config = ParseConfig {
+            indent_style: Some(IndentStyle::Tab),
+            ..Default::default()
+        };
+        assert!(config.parse(tabs).is_ok());
+        let errors = config.parse(spaces).unwrap_err();
+        assert_eq!(errors[0].message(), "缩进应该使用制表符");
+        assert_eq!(errors[0].span(), 6..10);
+
+        let config = ParseConfig {
+            indent_style: Some(IndentStyle::Spaces),
+            ..Default::default()
+        };
+        assert!(config.parse(spaces).is_ok());
+        assert!(config.parse(mixed).is_err());
+    }
+
+    #[test]
+    fn parse_indent_mismatch() {
+        // 缩进过多，按所在的块继续解析
+        let src = "if a:\n\tb = 1\n\t\t\tc = 2\nd = 3\n";
+        let (ast, errors) = parse_with_recovery(src);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "缩进不一致，应该是1层，实际是3层");
+        assert_eq!(&src[errors[0].span()], "\t\t\t");
+        assert!(
+            matches!(&ast[0], Command::If { if_branch, .. } if if_branch[0].commands.len() == 2)
+        );
+
+        // 缩进不够，块是空的
+        let src = "if a:\nb = 1\n";
+        let errors = parse(src).unwrap_err();
+        assert_eq!(errors[0].message(), "缩进不一致，应该是1层，实际是0层");
+        assert_eq!(&src[errors[0].span()], "b");
+
+        let src = "if a:\n\tif b:\n\tc = 1\n";
+        let errors = parse(src).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "缩进不一致，应该是2层，实际是1层");
+        assert_eq!(&src[errors[0].span()], "\tc");
+
+        let src = "if a:\n\tb = 1\nelse:\nc = 2\n";
+        let errors = parse(src).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "缩进不一致，应该是1层，实际是0层");
+        assert_eq!(&src[errors[0].span()], "c");
+    }
+
+    #[test]
+    fn parse_operator_aliases() {
+        let config = ParseConfig {
+            aliases: HashMap::from([
+                ("greater than".to_string(), Token::Greater),
+                ("equals".to_string(), Token::Equal),
+            ]),
+            ..Default::default()
+        };
+        let ast = config.parse("a greater than b\ngreater = 1\n").unwrap();
+        assert!(matches!(
+            &ast[0],
+            Command::Expression(Expression::Binary {
+                op: BinaryOp::Greater,
+                ..
+            })
+        ));
+        assert!(matches!(
+            &ast[1],
+            Command::Expression(Expression::Binary { lhs, op: BinaryOp::Assign, .. })
+                if **lhs == Expression::Object(Object::Variable("greater"))
+        ));
+
+        // 没有别名配置时只是普通的标识符
+        let ast = parse("greater = than\n").unwrap();
+        assert!(matches!(
+            &ast[0],
+            Command::Expression(Expression::Binary { lhs, rhs, .. })
+                if **lhs == Expression::Object(Object::Variable("greater"))
+                    && **rhs == Expression::Object(Object::Variable("than"))
+        ));
+    }
+
+    #[test]
+    fn parse_custom_operators() {
+        let operator = |symbol: &str, precedence, associativity| OperatorSyntax {
+            symbol: symbol.to_string(),
+            precedence,
+            associativity,
+        };
+        let config = ParseConfig {
+            operators: vec![
+                operator("<=>", Precedence::Comparison, Associativity::Left),
+                operator("~>", Precedence::Sum, Associativity::Right),
+            ],
+            ..Default::default()
+        };
+        let var = |name| Box::new(Expression::Object(Object::Variable(name)));
+        let custom = |index, lhs, rhs| {
+            Box::new(Expression::Binary {
+                op: BinaryOp::Custom(index),
+                lhs,
+                rhs,
+            })
+        };
+        // ~>和+同级，右结合；<=>和比较同级
+        let ast = config.parse("a ~> b ~> c <=> d\n").unwrap();
+        assert_eq!(
+            ast[0],
+            Command::Expression(*custom(
+                0,
+                custom(1, var("a"), custom(1, var("b"), var("c"))),
+                var("d")
+            ))
+        );
+        // 没有登记时仍然按内置的记号处理
+        let ast = parse("a <=> b\n");
+        assert!(ast.is_err());
+        // 字符串中的写法不受影响
+        let ast = config.parse("s = '<=>'\n").unwrap();
+        assert!(matches!(
+            &ast[0],
+            Command::Expression(Expression::Binary { rhs, .. })
+                if **rhs == Expression::Object(Object::Str("<=>".into()))
+        ));
+    }
+}
@@ -0,0 +1,62 @@
+//! 方言(`#!bdscript strict`)
+//!
+//! 文件第一行可以用`#!bdscript 方言名`指定按哪种方言解析，
+//! 严格方言会在词法分析之后额外检查一些宽松方言允许的写法
+
+use chumsky::span::SimpleSpan;
+
+use super::{error::ParseError, tokenizer::Token};
+
+/// 解析方言
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// 宽松方言
+    #[default]
+    Lenient,
+    /// 严格方言，要求缩进方式一致，条件中不允许出现`=`
+    Strict,
+}
+
+/// 读取第一行的方言指令，没有指令时返回None
+pub fn from_directive(src: &str, errors: &mut Vec<ParseError>) -> Option<Dialect> {
+    let line = src.lines().next()?;
+    let name = line.strip_prefix("#!bdscript")?.trim();
+    match name {
+        "strict" => Some(Dialect::Strict),
+        "lenient" => Some(Dialect::Lenient),
+        _ => {
+            errors.push(ParseError::custom(
+                0..line.len(),
+                format!("未知的方言{name:?}"),
+            ));
+            None
+        }
+    }
+}
+
+/// 严格方言的检查
+pub(super) fn check_strict(tokens: &[(Token<'_>, SimpleSpan)], errors: &mut Vec<ParseError>) {
+    let mut indent = None;
+    let mut in_condition = false;
+    for (token, span) in tokens {
+        match token {
+            // 同一个文件只能用一种缩进
+            Token::Tab(current) => match indent {
+                None => indent = Some(current),
+                Some(first) if first != current => {
+                    errors.push(ParseError::custom(span.into_range(), "缩进方式不一致"));
+                }
+                Some(_) => {}
+            },
+            Token::If | Token::Elif | Token::While => in_condition = true,
+            Token::Colon | Token::Line => in_condition = false,
+            Token::Assign if in_condition => {
+                errors.push(ParseError::custom(
+                    span.into_range(),
+                    "条件中不允许使用=，比较请使用==",
+                ));
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! 比较两棵语法树，给热重载和版本管理工具使用
+
+use std::mem;
+
+use super::scanner::Command;
+
+/// 语法树的变化(粗粒度，只比较顶层语句和函数)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AstChange<'a> {
+    /// 新增的函数
+    FunctionAdded(&'a str),
+    /// 删除的函数
+    FunctionRemoved(&'a str),
+    /// 函数的参数或函数体发生了变化
+    FunctionModified(&'a str),
+    /// 新增的语句，值为语句在新语法树中的下标
+    StatementAdded(usize),
+    /// 删除的语句，值为语句在旧语法树中的下标
+    StatementRemoved(usize),
+    /// 修改的语句，值为语句在旧、新语法树中的下标
+    StatementModified(usize, usize),
+}
+
+/// 取出顶层函数的名字
+const fn function_name<'a>(command: &Command<'a>) -> Option<&'a str> {
+    match command {
+        Command::Function { name, .. } => Some(name),
+        _ => None,
+    }
+}
+
+/// 比较两棵语法树
+///
+/// 函数按名字匹配，其他语句(忽略占位行)按最长公共子序列对齐，
+/// 同一个位置上先删后增的语句视为修改
+pub fn diff_ast<'a>(old: &[Command<'a>], new: &[Command<'a>]) -> Vec<AstChange<'a>> {
+    let mut changes = vec![];
+
+    // 函数
+    for command in old {
+        if let Some(name) = function_name(command) {
+            match new.iter().find(|c| function_name(c) == Some(name)) {
+                None => changes.push(AstChange::FunctionRemoved(name)),
+                Some(other) if other != command => changes.push(AstChange::FunctionModified(name)),
+                Some(_) => {}
+            }
+        }
+    }
+    for command in new {
+        if let Some(name) = function_name(command)
+            && !old.iter().any(|c| function_name(c) == Some(name))
+        {
+            changes.push(AstChange::FunctionAdded(name));
+        }
+    }
+
+    // 语句
+    let statements = |commands: &[Command<'a>]| {
+        commands
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| function_name(c).is_none() && !matches!(c, Command::NewLine))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>()
+    };
+    let old_statements = statements(old);
+    let new_statements = statements(new);
+
+    // lcs[i][j]为old_statements[i..]和new_statements[j..]的最长公共子序列长度
+    let (n, m) = (old_statements.len(), new_statements.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[old_statements[i]] == new[new_statements[j]] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut removed = vec![];
+    let mut added = vec![];
+    loop {
+        let same = i < n && j < m && old[old_statements[i]] == new[new_statements[j]];
+        if same || (i == n && j == m) {
+            // 一段差异结束，成对的删除和新增合并成修改
+            let paired = removed.len().min(added.len());
+            for (&o, &a) in removed.iter().zip(&added) {
+                changes.push(AstChange::StatementModified(o, a));
+            }
+            changes.extend(
+                mem::take(&mut removed)
+                    .into_iter()
+                    .skip(paired)
+                    .map(AstChange::StatementRemoved),
+            );
+            changes.extend(
+                mem::take(&mut added)
+                    .into_iter()
+                    .skip(paired)
+                    .map(AstChange::StatementAdded),
+            );
+            if !same {
+                break;
+            }
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            removed.push(old_statements[i]);
+            i += 1;
+        } else {
+            added.push(new_statements[j]);
+            j += 1;
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse, scanner::Command};
+
+    use super::{AstChange, diff_ast};
+
+    #[test]
+    fn diff_added_function() {
+        let old = parse("a = 1\nb = 2\n").unwrap();
+        let mut new = old.clone();
+        new.push(Command::Function {
+            public: true,
+            name: "on_tick",
+            args: vec![],
+            commands: vec![],
+        });
+        assert_eq!(diff_ast(&old, &new), [AstChange::FunctionAdded("on_tick")]);
+        assert_eq!(
+            diff_ast(&new, &old),
+            [AstChange::FunctionRemoved("on_tick")]
+        );
+        assert!(diff_ast(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn diff_statements() {
+        let old = parse("a = 1\nb = 2\nc = 3\n").unwrap();
+        let new = parse("a = 1\nb = 5\nc = 3\nd = 4\n").unwrap();
+        assert_eq!(
+            diff_ast(&old, &new),
+            [
+                AstChange::StatementModified(1, 1),
+                AstChange::StatementAdded(3)
+            ]
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! 条件编译指令(`#if FLAG`/`#else`/`#endif`)
+//!
+//! 在词法分析之前按行扫描源码，算出需要排除的区间，
+//! 落在这些区间里的token不会交给语法分析器
+
+use std::{collections::HashSet, ops::Range};
+
+use super::error::ParseError;
+
+/// 一层`#if`
+struct Frame {
+    /// 条件是否成立
+    active: bool,
+    /// 是否已经遇到过`#else`
+    has_else: bool,
+    /// `#if`所在的位置，用于报错
+    span: Range<usize>,
+}
+
+/// 计算被条件编译指令排除的源码区间(指令所在的行本身也会被排除)
+pub fn excluded_ranges(
+    src: &str,
+    flags: &HashSet<String>,
+    errors: &mut Vec<ParseError>,
+) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    let mut stack: Vec<Frame> = vec![];
+    let mut offset = 0;
+    for line in src.split_inclusive('\n') {
+        let line_range = offset..offset + line.len();
+        offset += line.len();
+
+        let trimmed = line.trim();
+        let start = line_range.start + (line.len() - line.trim_start().len());
+        let span = start..start + trimmed.len();
+        let mut words = trimmed.split_whitespace();
+        let is_directive = match words.next() {
+            Some("#if") => {
+                match (words.next(), words.next()) {
+                    (Some(flag), None) => stack.push(Frame {
+                        active: flags.contains(flag),
+                        has_else: false,
+                        span,
+                    }),
+                    _ => {
+                        errors.push(ParseError::custom(span.clone(), "#if后面需要一个标志名"));
+                        // 仍然压栈，避免后面的#endif再报一次错
+                        stack.push(Frame {
+                            active: false,
+                            has_else: false,
+                            span,
+                        });
+                    }
+                }
+                true
+            }
+            Some("#else") => {
+                match stack.last_mut() {
+                    Some(frame) if !frame.has_else => {
+                        frame.active = !frame.active;
+                        frame.has_else = true;
+                    }
+                    Some(_) => errors.push(ParseError::custom(span, "重复的#else")),
+                    None => errors.push(ParseError::custom(span, "#else没有对应的#if")),
+                }
+                true
+            }
+            Some("#endif") => {
+                if stack.pop().is_none() {
+                    errors.push(ParseError::custom(span, "#endif没有对应的#if"));
+                }
+                true
+            }
+            _ => false,
+        };
+
+        if is_directive || stack.iter().any(|frame| !frame.active) {
+            match ranges.last_mut() {
+                Some(last) if last.end == line_range.start => last.end = line_range.end,
+                _ => ranges.push(line_range),
+            }
+        }
+    }
+    for frame in stack {
+        errors.push(ParseError::custom(frame.span, "#if缺少对应的#endif"));
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::excluded_ranges;
+
+    #[test]
+    fn nested_directives() {
+        let src = "a\n#if A\nb\n#if B\nc\n#else\nd\n#endif\n#endif\ne\n";
+        let flags = HashSet::from(["A".to_string()]);
+        let mut errors = vec![];
+        let kept = excluded_ranges(src, &flags, &mut errors)
+            .into_iter()
+            .rev()
+            .fold(src.to_string(), |mut kept, range| {
+                kept.replace_range(range, "");
+                kept
+            });
+        assert!(errors.is_empty());
+        assert_eq!(kept, "a\nb\nd\ne\n");
+    }
+
+    #[test]
+    fn unbalanced_directives() {
+        let mut errors = vec![];
+        excluded_ranges("#endif\n#if A\n", &HashSet::new(), &mut errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].span(), 0..6);
+        assert_eq!(errors[1].span(), 7..12);
+    }
+}
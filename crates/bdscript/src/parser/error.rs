@@ -0,0 +1,135 @@
+//! 解析过程中产生的错误
+
+use std::{fmt, ops::Range};
+
+use chumsky::error::{Rich, RichReason};
+
+use super::tokenizer::Token;
+
+/// 解析配置中可以限制的大小
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeLimit {
+    /// 语句数量(包括嵌套在块中的语句)
+    Statements,
+    /// 表达式节点数量
+    ExpressionNodes,
+}
+
+impl fmt::Display for SizeLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Statements => write!(f, "语句数量"),
+            Self::ExpressionNodes => write!(f, "表达式节点数量"),
+        }
+    }
+}
+
+/// 从`src`中借用的`name`在源码中的位置，`name`不在`src`的范围内时返回None
+pub(crate) fn span_in(src: &str, name: &str) -> Option<Range<usize>> {
+    let start = (name.as_ptr() as usize).checked_sub(src.as_ptr() as usize)?;
+    let end = start.checked_add(name.len())?;
+    (end <= src.len()).then_some(start..end)
+}
+
+/// 解析错误，携带源码中的位置，可以直接交给ariadne/miette之类的库渲染
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// 词法错误，无法识别的字符
+    Lex { span: Range<usize> },
+    /// 语法错误
+    Syntax {
+        span: Range<usize>,
+        /// 错误描述
+        message: String,
+        /// 期望出现的token
+        expected: Vec<String>,
+        /// 实际出现的token，为None时代表已经到了文件末尾
+        found: Option<String>,
+    },
+    /// 脚本超过了解析配置中的大小限制
+    TooLarge {
+        span: Range<usize>,
+        kind: SizeLimit,
+        limit: usize,
+    },
+}
+
+impl ParseError {
+    /// 自定义描述的语法错误
+    pub fn custom(span: Range<usize>, message: impl ToString) -> Self {
+        Self::Syntax {
+            span,
+            message: message.to_string(),
+            expected: vec![],
+            found: None,
+        }
+    }
+
+    /// 错误在源码中的位置
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Self::Lex { span } | Self::Syntax { span, .. } | Self::TooLarge { span, .. } => {
+                span.clone()
+            }
+        }
+    }
+
+    /// 把错误的位置整体后移，用于把在源码片段上解析出的错误映射回完整源码
+    pub const fn shifted(mut self, offset: usize) -> Self {
+        match &mut self {
+            Self::Lex { span } | Self::Syntax { span, .. } | Self::TooLarge { span, .. } => {
+                *span = span.start + offset..span.end + offset;
+            }
+        }
+        self
+    }
+
+    /// 错误描述
+    pub fn message(&self) -> String {
+        match self {
+            Self::Lex { .. } => "无法识别的字符".to_string(),
+            Self::Syntax { message, .. } => message.clone(),
+            Self::TooLarge { kind, limit, .. } => format!("{kind}超过了限制{limit}"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let span = self.span();
+        write!(f, "{}..{}: {}", span.start, span.end, self.message())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl<'a> From<Rich<'a, Token<'a>>> for ParseError {
+    fn from(error: Rich<'a, Token<'a>>) -> Self {
+        let expected = error.expected().map(ToString::to_string).collect();
+        let found = error.found().map(ToString::to_string);
+        let message = match error.reason() {
+            RichReason::Custom(message) => message.clone(),
+            reason => reason.to_string(),
+        };
+        Self::Syntax {
+            span: error.span().into_range(),
+            message,
+            expected,
+            found,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::span_in;
+
+    #[test]
+    fn span_in_source() {
+        let src = "chase!(t)\n";
+        assert_eq!(span_in(src, &src[..5]), Some(0..5));
+        // 名字不在src的范围内时没有位置
+        assert_eq!(span_in(&src[1..], &src[..5]), None);
+        assert_eq!(span_in(&src[..3], &src[..5]), None);
+    }
+}
@@ -0,0 +1,206 @@
+//! 导入其他脚本文件
+//!
+//! `import "path"`在解析完成后处理：通过宿主提供的[`Loader`]读取被导入的源码并解析，
+//! 把其中顶层的函数和状态块插入到导入语句所在的位置。被导入文件中的导入会递归处理，
+//! 同一个文件只合并一次，循环导入会报错
+
+use std::{collections::HashSet, fmt};
+
+use super::{ParseConfig, error::ParseError, scanner::Command};
+
+/// 宿主提供的脚本加载器，例如bevy的资源加载器
+pub trait Loader<'a> {
+    /// 读取路径对应的源码，不存在时返回None
+    fn load(&self, path: &str) -> Option<&'a str>;
+}
+
+/// 处理导入时产生的错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportError {
+    /// 加载器找不到这个文件
+    NotFound(String),
+    /// 循环导入，依次记录导入链上的路径(首尾是同一个文件)
+    Cycle(Vec<String>),
+    /// 文件中有语法错误
+    Parse {
+        path: String,
+        errors: Vec<ParseError>,
+    },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(path) => write!(f, "找不到导入的文件{path}"),
+            Self::Cycle(chain) => write!(f, "循环导入：{}", chain.join(" -> ")),
+            Self::Parse { path, errors } => {
+                write!(f, "{path}中有{}个语法错误", errors.len())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// 递归处理导入时的状态
+struct Resolver<'l, 'a> {
+    config: &'l ParseConfig,
+    loader: &'l dyn Loader<'a>,
+    /// 当前的导入链
+    chain: Vec<String>,
+    /// 已经合并过的文件
+    merged: HashSet<String>,
+}
+
+impl<'a> Resolver<'_, 'a> {
+    /// 加载并解析一个文件，处理其中的导入
+    fn load(&mut self, path: &str) -> Result<Vec<Command<'a>>, ImportError> {
+        if self.chain.iter().any(|p| p == path) {
+            let mut chain = self.chain.clone();
+            chain.push(path.to_string());
+            return Err(ImportError::Cycle(chain));
+        }
+        let src = self
+            .loader
+            .load(path)
+            .ok_or_else(|| ImportError::NotFound(path.to_string()))?;
+        let ast = self
+            .config
+            .parse(src)
+            .map_err(|errors| ImportError::Parse {
+                path: path.to_string(),
+                errors,
+            })?;
+        self.chain.push(path.to_string());
+        let mut resolved = vec![];
+        for command in ast {
+            match command {
+                Command::Import(import) => {
+                    // 已经合并过的文件不再合并，但是导入链上的文件要报循环导入
+                    if self.merged.contains(import.as_ref())
+                        && !self.chain.iter().any(|p| *p == import)
+                    {
+                        continue;
+                    }
+                    let definitions = self.load(&import)?;
+                    self.merged.insert(import.into_owned());
+                    resolved.extend(definitions.into_iter().filter(|command| {
+                        matches!(
+                            command,
+                            Command::Function { .. } | Command::StateBlock { .. }
+                        )
+                    }));
+                }
+                command => resolved.push(command),
+            }
+        }
+        self.chain.pop();
+        Ok(resolved)
+    }
+}
+
+impl ParseConfig {
+    /// 通过加载器读取并解析`path`，处理其中(以及被导入文件中)的所有导入
+    ///
+    /// 被导入文件中只有顶层的函数和状态块会被合并，其他语句会被忽略
+    pub fn parse_with_imports<'a>(
+        &self,
+        path: &str,
+        loader: &dyn Loader<'a>,
+    ) -> Result<Vec<Command<'a>>, ImportError> {
+        Resolver {
+            config: self,
+            loader,
+            chain: vec![],
+            merged: HashSet::new(),
+        }
+        .load(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rust_decimal::Decimal;
+
+    use crate::{
+        eval::{interpreter::Interpreter, value::Value},
+        parser::{ParseConfig, scanner::Command},
+    };
+
+    use super::{ImportError, Loader};
+
+    /// 测试用的加载器，按路径保存源码
+    struct MockLoader(HashMap<&'static str, &'static str>);
+
+    impl<'a> Loader<'a> for MockLoader {
+        fn load(&self, path: &str) -> Option<&'a str> {
+            self.0.get(path).copied()
+        }
+    }
+
+    #[test]
+    fn import_functions() {
+        let loader = MockLoader(HashMap::from([
+            (
+                "main.bd",
+                "import \"combat.bd\"\nimport \"util.bd\"\nx = damage(3)\n",
+            ),
+            (
+                "combat.bd",
+                "include \"util.bd\"\nfn damage(n):\n\treturn double(n) + 1\nignored = 1\n",
+            ),
+            ("util.bd", "fn double(n):\n\treturn n * 2\n"),
+            ("states.bd", "import \"util.bd\"\n@idle:\n\twait()\n"),
+        ]));
+        let config = ParseConfig::default();
+        let ast = config.parse_with_imports("main.bd", &loader).unwrap();
+        // util.bd只合并一次
+        let functions = ast
+            .iter()
+            .filter(|command| matches!(command, Command::Function { .. }))
+            .count();
+        assert_eq!(functions, 2);
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&ast).unwrap();
+        assert_eq!(
+            interpreter.env().get("x"),
+            Some(&Value::Number(Decimal::from(7)))
+        );
+        // 被导入文件中不是定义的语句不会被合并
+        assert_eq!(interpreter.env().get("ignored"), None);
+
+        let ast = config.parse_with_imports("states.bd", &loader).unwrap();
+        assert!(matches!(&ast[0], Command::Function { name: "double", .. }));
+        assert!(matches!(&ast[1], Command::StateBlock { name: "idle", .. }));
+    }
+
+    #[test]
+    fn import_errors() {
+        let loader = MockLoader(HashMap::from([
+            ("a.bd", "import \"b.bd\"\n"),
+            ("b.bd", "import \"a.bd\"\n"),
+            ("c.bd", "import \"missing.bd\"\n"),
+            ("d.bd", "import \"bad.bd\"\n"),
+            ("bad.bd", "fn (\n"),
+        ]));
+        let config = ParseConfig::default();
+        assert_eq!(
+            config.parse_with_imports("a.bd", &loader),
+            Err(ImportError::Cycle(vec![
+                "a.bd".to_string(),
+                "b.bd".to_string(),
+                "a.bd".to_string()
+            ]))
+        );
+        assert_eq!(
+            config.parse_with_imports("c.bd", &loader),
+            Err(ImportError::NotFound("missing.bd".to_string()))
+        );
+        assert!(matches!(
+            config.parse_with_imports("d.bd", &loader),
+            Err(ImportError::Parse { path, .. }) if path == "bad.bd"
+        ));
+    }
+}
@@ -0,0 +1,548 @@
+//! 增量解析，给编辑器集成使用
+//!
+//! 源码按顶层语句切分成若干块(从第0列开始的行开启新的一块，
+//! `elif`/`else`、注释以及条件编译块、块注释、字符串内部的行除外)，每块单独解析。
+//! 修改源码后只重新解析和修改区间相交的块，其余块的语法树直接复用，
+//! 只把其中的字符串切片重新指向新的源码。模板展开和大小限制作用于整个脚本
+
+use std::{borrow::Cow, ops::Range};
+
+use super::{
+    ParseConfig, dialect,
+    error::ParseError,
+    limit::Budget,
+    scanner::{Branch, Command, Expression, Handler, Object, Spanned, Timeout},
+};
+
+/// 一个顶层块
+#[derive(Debug, Clone)]
+struct Chunk<'a> {
+    /// 在源码中的位置
+    range: Range<usize>,
+    /// 解析出来的指令(模板还没有展开)，位置相对于整个源码
+    commands: Vec<Spanned<Command<'a>>>,
+}
+
+/// 增量解析的结果
+#[derive(Debug, Clone)]
+pub struct IncrementalParse<'a> {
+    src: &'a str,
+    config: ParseConfig,
+    chunks: Vec<Chunk<'a>>,
+    /// 展开模板之后的语法树
+    ast: Vec<Spanned<Command<'a>>>,
+    /// 上一次解析中真正重新解析的块数
+    reparsed: usize,
+}
+
+/// 把源码按顶层语句切分成块，返回每块的位置
+fn split_chunks(src: &str, offset: usize) -> Vec<Range<usize>> {
+    let mut starts = vec![];
+    let mut directive_depth = 0usize;
+    let mut comment_depth = 0usize;
+    // 跨行的字符串字面量还没有结束时记录它的引号
+    let mut quote = None;
+    let mut line_start = 0;
+    for line in src.split_inclusive('\n') {
+        // 字符串和块注释中的行不会开启新的块
+        let in_literal = quote.is_some() || comment_depth > 0;
+        let trimmed = line.trim();
+        if !in_literal && trimmed.starts_with("#if ") {
+            directive_depth += 1;
+        } else if !in_literal && trimmed == "#endif" {
+            directive_depth = directive_depth.saturating_sub(1);
+        }
+        let starts_chunk = directive_depth == 0
+            && !in_literal
+            && line.starts_with(|c: char| !c.is_whitespace() && c != '#')
+            && !line.starts_with("elif")
+            && !line.starts_with("else");
+        if starts_chunk || line_start == 0 {
+            starts.push(line_start);
+        }
+        scan_line(line, &mut quote, &mut comment_depth);
+        line_start += line.len();
+    }
+    let mut ranges = vec![];
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(src.len());
+        ranges.push(offset + start..offset + end);
+    }
+    ranges
+}
+
+/// 扫描一行，更新行尾时未结束的字符串引号和块注释的层数
+fn scan_line(line: &str, quote: &mut Option<char>, comment_depth: &mut usize) {
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (*quote, c) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(open), c) if c == open => *quote = None,
+            (Some(_), _) => {}
+            (None, '#') if chars.peek() == Some(&'[') => {
+                chars.next();
+                *comment_depth += 1;
+            }
+            (None, ']') if *comment_depth > 0 && chars.peek() == Some(&'#') => {
+                chars.next();
+                *comment_depth -= 1;
+            }
+            (None, _) if *comment_depth > 0 => {}
+            // 行注释
+            (None, '#') => return,
+            (None, '"' | '\'') => *quote = Some(c),
+            (None, _) => {}
+        }
+    }
+}
+
+impl ParseConfig {
+    /// 解析源码，保留分块信息以便之后增量解析
+    pub fn parse_incremental<'a>(
+        &self,
+        src: &'a str,
+    ) -> Result<IncrementalParse<'a>, Vec<ParseError>> {
+        // 第一行的方言指令对所有块都生效
+        let mut config = self.clone();
+        config.dialect = dialect::from_directive(src, &mut vec![]).unwrap_or(self.dialect);
+        let chunks = config.parse_chunks(src, 0..src.len())?;
+        let reparsed = chunks.len();
+        IncrementalParse::new(src, config, chunks, reparsed)
+    }
+
+    /// 解析源码中的一段区间
+    fn parse_chunks<'a>(
+        &self,
+        src: &'a str,
+        region: Range<usize>,
+    ) -> Result<Vec<Chunk<'a>>, Vec<ParseError>> {
+        let mut chunks = vec![];
+        let mut errors = vec![];
+        // 分块解析时也尽早停下，合并后的语法树在展开模板前会重新计算
+        let budget = Budget::new(self);
+        for range in split_chunks(&src[region.clone()], region.start) {
+            let mut chunk_errors = vec![];
+            let commands = self
+                .parse_commands(&src[range.clone()], &budget, &mut chunk_errors)
+                .into_iter()
+                .map(|command| Spanned {
+                    span: (command.span.start + range.start..command.span.end + range.start).into(),
+                    node: command.node,
+                })
+                .collect();
+            if chunk_errors.is_empty() {
+                chunks.push(Chunk { range, commands });
+            } else {
+                errors.extend(
+                    chunk_errors
+                        .into_iter()
+                        .map(|error| error.shifted(range.start)),
+                );
+            }
+            if budget.is_exceeded() {
+                break;
+            }
+        }
+        if errors.is_empty() {
+            Ok(chunks)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl<'a> IncrementalParse<'a> {
+    /// 把各块的语法树合并起来，展开模板并检查大小限制
+    fn new(
+        src: &'a str,
+        config: ParseConfig,
+        chunks: Vec<Chunk<'a>>,
+        reparsed: usize,
+    ) -> Result<Self, Vec<ParseError>> {
+        let ast: Vec<_> = chunks
+            .iter()
+            .flat_map(|chunk| chunk.commands.iter().cloned())
+            .collect();
+        let mut errors = vec![];
+        let budget = Budget::new(&config);
+        budget.charge_ast(&ast);
+        let ast = config.resolve(src, ast, &budget, &mut errors);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            src,
+            config,
+            chunks,
+            ast,
+            reparsed,
+        })
+    }
+
+    /// 整个脚本的语法树
+    pub fn ast(&self) -> Vec<Command<'a>> {
+        self.ast
+            .iter()
+            .map(|command| command.node.clone())
+            .collect()
+    }
+
+    /// 整个脚本的语法树，顶层的每条语句都带有在源码中的位置
+    pub fn spanned_ast(&self) -> &[Spanned<Command<'a>>] {
+        &self.ast
+    }
+
+    /// 上一次解析中真正重新解析的块数
+    pub const fn reparsed(&self) -> usize {
+        self.reparsed
+    }
+
+    /// 源码被修改后重新解析
+    ///
+    /// `edit`为旧源码中被替换掉的区间，`new_src`为修改后的完整源码
+    pub fn reparse<'b>(
+        &self,
+        new_src: &'b str,
+        edit: Range<usize>,
+    ) -> Result<IncrementalParse<'b>, Vec<ParseError>> {
+        let inserted = new_src.len() + edit.len() - self.src.len();
+        let shift = |offset: usize| offset + inserted - edit.len();
+
+        // 和修改区间相交或相邻的块都需要重新解析
+        let first = self
+            .chunks
+            .iter()
+            .rposition(|chunk| chunk.range.start < edit.start)
+            .unwrap_or(0);
+        let last = self
+            .chunks
+            .iter()
+            .rposition(|chunk| chunk.range.start <= edit.end)
+            .unwrap_or(0)
+            .max(first);
+        let region = match (self.chunks.get(first), self.chunks.get(last)) {
+            (Some(first), Some(last)) => first.range.start..shift(last.range.end),
+            _ => 0..new_src.len(),
+        };
+
+        let reparsed = self.config.parse_chunks(new_src, region)?;
+        let rebase_chunk = |chunk: &Chunk<'a>, shift: &dyn Fn(usize) -> usize| {
+            let rebase = |s: &str| -> &'b str {
+                let start = s.as_ptr() as usize - self.src.as_ptr() as usize;
+                let start = shift(start);
+                &new_src[start..start + s.len()]
+            };
+            Chunk {
+                range: shift(chunk.range.start)..shift(chunk.range.end),
+                commands: chunk
+                    .commands
+                    .iter()
+                    .map(|command| Spanned {
+                        node: rebase_command(&command.node, &rebase),
+                        span: (shift(command.span.start)..shift(command.span.end)).into(),
+                    })
+                    .collect(),
+            }
+        };
+
+        let mut chunks = vec![];
+        let before = self.chunks.get(..first).unwrap_or_default();
+        chunks.extend(
+            before
+                .iter()
+                .map(|chunk| rebase_chunk(chunk, &|offset| offset)),
+        );
+        let reparsed_count = reparsed.len();
+        chunks.extend(reparsed);
+        let after = self.chunks.get(last + 1..).unwrap_or_default();
+        chunks.extend(after.iter().map(|chunk| rebase_chunk(chunk, &shift)));
+        IncrementalParse::new(new_src, self.config.clone(), chunks, reparsed_count)
+    }
+}
+
+/// 借用源码的字符串重新指向新的源码，处理过转义的字符串本身就不借用源码
+// 需要区分借用和拥有两种情况，不能换成&str
+#[allow(clippy::ptr_arg)]
+fn rebase_str<'b>(s: &Cow<'_, str>, rebase: &dyn Fn(&str) -> &'b str) -> Cow<'b, str> {
+    match s {
+        Cow::Borrowed(s) => Cow::Borrowed(rebase(s)),
+        Cow::Owned(s) => Cow::Owned(s.clone()),
+    }
+}
+
+/// 把指令中的字符串切片重新指向新的源码
+fn rebase_command<'b>(command: &Command<'_>, rebase: &dyn Fn(&str) -> &'b str) -> Command<'b> {
+    let commands = |commands: &[Command<'_>]| {
+        commands
+            .iter()
+            .map(|command| rebase_command(command, rebase))
+            .collect()
+    };
+    match command {
+        Command::Expression(expression) => {
+            Command::Expression(rebase_expression(expression, rebase))
+        }
+        Command::If {
+            if_branch,
+            else_branch,
+        } => Command::If {
+            if_branch: if_branch
+                .iter()
+                .map(|branch| Branch {
+                    condition: rebase_expression(&branch.condition, rebase),
+                    commands: commands(&branch.commands),
+                })
+                .collect(),
+            else_branch: else_branch.as_deref().map(commands),
+        },
+        Command::While {
+            condition,
+            command,
+            label,
+        } => Command::While {
+            condition: Box::new(rebase_expression(condition, rebase)),
+            command: commands(command),
+            label: label.map(rebase),
+        },
+        Command::For {
+            var,
+            iter,
+            commands: body,
+            label,
+        } => Command::For {
+            var: rebase(var),
+            iter: rebase_expression(iter, rebase),
+            commands: commands(body),
+            label: label.map(rebase),
+        },
+        Command::Function {
+            public,
+            name,
+            args,
+            commands: body,
+        } => Command::Function {
+            public: *public,
+            name: rebase(name),
+            args: args.iter().map(|arg| rebase(arg)).collect(),
+            commands: commands(body),
+        },
+        Command::Defer(command) => Command::Defer(Box::new(rebase_command(command, rebase))),
+        Command::Yield(expression) => Command::Yield(rebase_expression(expression, rebase)),
+        Command::Return(expression) => Command::Return(
+            expression
+                .as_ref()
+                .map(|expression| rebase_expression(expression, rebase)),
+        ),
+        Command::With {
+            expr,
+            binding,
+            body,
+        } => Command::With {
+            expr: rebase_expression(expr, rebase),
+            binding: rebase(binding),
+            body: commands(body),
+        },
+        Command::Template { name, params, body } => Command::Template {
+            name: rebase(name),
+            params: params.iter().map(|param| rebase(param)).collect(),
+            body: commands(body),
+        },
+        Command::Invoke { name, args } => Command::Invoke {
+            name: rebase(name),
+            args: args
+                .iter()
+                .map(|arg| rebase_expression(arg, rebase))
+                .collect(),
+        },
+        Command::Test { name, body } => Command::Test {
+            name: rebase_str(name, rebase),
+            body: commands(body),
+        },
+        Command::Import(path) => Command::Import(rebase_str(path, rebase)),
+        Command::SetState(name) => Command::SetState(rebase(name)),
+        Command::StateBlock {
+            name,
+            parent,
+            timeout,
+            handlers,
+            commands: body,
+        } => Command::StateBlock {
+            name: rebase(name),
+            parent: parent.map(rebase),
+            timeout: timeout.as_ref().map(|timeout| Timeout {
+                duration: rebase_expression(&timeout.duration, rebase),
+                state: rebase(timeout.state),
+            }),
+            handlers: handlers
+                .iter()
+                .map(|handler| Handler {
+                    event: rebase_str(&handler.event, rebase),
+                    commands: commands(&handler.commands),
+                })
+                .collect(),
+            commands: commands(body),
+        },
+        Command::Break(label) => Command::Break(label.map(rebase)),
+        Command::Continue(label) => Command::Continue(label.map(rebase)),
+        Command::NewLine => Command::NewLine,
+    }
+}
+
+/// 把表达式中的字符串切片重新指向新的源码
+fn rebase_expression<'b>(
+    expression: &Expression<'_>,
+    rebase: &dyn Fn(&str) -> &'b str,
+) -> Expression<'b> {
+    let boxed = |expression: &Expression<'_>| Box::new(rebase_expression(expression, rebase));
+    let list = |items: &[Expression<'_>]| {
+        items
+            .iter()
+            .map(|item| rebase_expression(item, rebase))
+            .collect()
+    };
+    match expression {
+        Expression::Object(object) => Expression::Object(match object {
+            Object::Constant(num) => Object::Constant(*num),
+            Object::Bool(b) => Object::Bool(*b),
+            Object::Quantity(num, unit) => Object::Quantity(*num, rebase(unit)),
+            Object::Str(s) => Object::Str(rebase_str(s, rebase)),
+            Object::Variable(s) => Object::Variable(rebase(s)),
+            Object::Symbol(s) => Object::Symbol(rebase(s)),
+            Object::Color(rgba) => Object::Color(*rgba),
+            Object::FString(parts) => Object::FString(list(parts)),
+            Object::Tuple(items) => Object::Tuple(list(items)),
+            Object::Array(items) => Object::Array(list(items)),
+            Object::DictItem(key, value) => Object::DictItem(boxed(key), boxed(value)),
+            Object::Dict(items) => Object::Dict(list(items)),
+        }),
+        Expression::Unary { op, hs } => Expression::Unary {
+            op: op.clone(),
+            hs: boxed(hs),
+        },
+        Expression::Binary { op, lhs, rhs } => Expression::Binary {
+            op: op.clone(),
+            lhs: boxed(lhs),
+            rhs: boxed(rhs),
+        },
+        Expression::Priority(expression) => Expression::Priority(boxed(expression)),
+        Expression::Format { value, spec } => Expression::Format {
+            value: boxed(value),
+            spec: *spec,
+        },
+        Expression::SpreadIf { condition, value } => Expression::SpreadIf {
+            condition: boxed(condition),
+            value: boxed(value),
+        },
+        Expression::Range {
+            start,
+            end,
+            inclusive,
+        } => Expression::Range {
+            start: boxed(start),
+            end: boxed(end),
+            inclusive: *inclusive,
+        },
+        Expression::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => Expression::Ternary {
+            cond: boxed(cond),
+            then: boxed(then),
+            otherwise: boxed(otherwise),
+        },
+        Expression::Query {
+            with_compoents,
+            without_compoents,
+            hierarchy,
+            order_by,
+            limit,
+        } => Expression::Query {
+            with_compoents: with_compoents.iter().map(|s| rebase(s)).collect(),
+            without_compoents: without_compoents.iter().map(|s| rebase(s)).collect(),
+            hierarchy: hierarchy.as_deref().map(boxed),
+            order_by: order_by.as_deref().map(boxed),
+            limit: limit.as_deref().map(boxed),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{ParseConfig, diff::AstChange, diff::diff_ast, parse};
+
+    #[test]
+    fn reparse_single_statement() {
+        let old_src = "a = 1\nif a:\n    b = 2\nelse:\n    b = 3\nc = a + b\n";
+        let old = ParseConfig::default().parse_incremental(old_src).unwrap();
+        assert_eq!(old.ast(), parse(old_src).unwrap());
+
+        // 把`b = 2`改成`b = 20`
+        let edit = old_src.find("2").unwrap()..old_src.find("2").unwrap() + 1;
+        let new_src = old_src.replacen('2', "20", 1);
+        let new = old.reparse(&new_src, edit).unwrap();
+        assert_eq!(new.reparsed(), 1);
+        assert_eq!(new.ast(), parse(&new_src).unwrap());
+        assert_eq!(
+            diff_ast(&old.ast(), &new.ast()),
+            [AstChange::StatementModified(1, 1)]
+        );
+
+        // 在末尾追加一条语句
+        let end = new_src.len();
+        let appended = format!("{new_src}d = c\n");
+        let new = new.reparse(&appended, end..end).unwrap();
+        // 最后一个块和新增的块
+        assert_eq!(new.reparsed(), 2);
+        assert_eq!(new.ast(), parse(&appended).unwrap());
+        assert_eq!(
+            new.spanned_ast(),
+            ParseConfig::default().parse_spanned(&appended).unwrap()
+        );
+    }
+
+    #[test]
+    fn reparse_block_comment() {
+        let old_src = "a = 1\n#[\nb = 2\n]#\nc = 3\n";
+        let old = ParseConfig::default().parse_incremental(old_src).unwrap();
+        assert_eq!(old.ast(), parse(old_src).unwrap());
+        let at = old_src.find('c').unwrap();
+        let new_src = old_src.replace('3', "4");
+        let new = old.reparse(&new_src, at + 4..at + 5).unwrap();
+        assert_eq!(new.ast(), parse(&new_src).unwrap());
+    }
+
+    #[test]
+    fn reparse_merges_chunks() {
+        // 缩进改变之后原本独立的语句会并入上一个块
+        let old_src = "if a:\n    b = 1\nc = 2\n";
+        let old = ParseConfig::default().parse_incremental(old_src).unwrap();
+        let at = old_src.find('c').unwrap();
+        let new_src = format!("{}    {}", &old_src[..at], &old_src[at..]);
+        let new = old.reparse(&new_src, at..at).unwrap();
+        assert_eq!(new.ast(), parse(&new_src).unwrap());
+        assert_eq!(new.ast().len(), 1);
+    }
+
+    #[test]
+    fn reparse_multiline_string() {
+        let old_src = "say(\"a\nb = 'x'\n\")\nc = 1\n";
+        let old = ParseConfig::default().parse_incremental(old_src).unwrap();
+        assert_eq!(old.ast(), parse(old_src).unwrap());
+        assert_eq!(old.ast().len(), 2);
+
+        let at = old_src.find('1').unwrap();
+        let new_src = old_src.replace('1', "2");
+        let new = old.reparse(&new_src, at..at + 1).unwrap();
+        assert_eq!(new.reparsed(), 1);
+        assert_eq!(new.ast(), parse(&new_src).unwrap());
+
+        // 修改字符串内部的行
+        let at = new_src.find('x').unwrap();
+        let edited = new_src.replace('x', "y");
+        let new = new.reparse(&edited, at..at + 1).unwrap();
+        assert_eq!(new.ast(), parse(&edited).unwrap());
+    }
+}
@@ -0,0 +1,207 @@
+//! 缩进规范化
+//!
+//! 由模板生成的脚本常常缩进不统一(整体多缩进了几格、用两个空格缩进等)，
+//! [`normalize_indentation`]按块结构把每一行重新缩进成规范的制表符缩进，
+//! 字符串和块注释跨行的内容保持原样
+
+use std::fmt;
+
+/// 一个制表符按多少列计算，和词法分析中四个空格算一层缩进保持一致
+const TAB_WIDTH: usize = 4;
+
+/// 一层缩进的写法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IndentStyle {
+    /// 一个制表符
+    Tab,
+    /// 四个空格
+    Spaces,
+}
+
+impl fmt::Display for IndentStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tab => write!(f, "制表符"),
+            Self::Spaces => write!(f, "四个空格"),
+        }
+    }
+}
+
+/// 扫描到行尾时所处的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Code,
+    /// 在字符串中，记录引号
+    Str(char),
+    /// 在块注释中，记录嵌套层数
+    BlockComment(usize),
+}
+
+/// 扫描一行代码，返回行尾的状态以及这一行是否有代码、最后一个有效字符是否是`:`
+fn scan(line: &str, mut state: State) -> (State, bool, bool) {
+    let mut has_code = false;
+    let mut last = None;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match state {
+            State::Str(quote) => {
+                if c == '\\' {
+                    chars.next();
+                } else if c == quote {
+                    state = State::Code;
+                }
+            }
+            State::BlockComment(depth) => {
+                if c == '#' && line[i + 1..].starts_with('[') {
+                    chars.next();
+                    state = State::BlockComment(depth + 1);
+                } else if c == ']' && line[i + 1..].starts_with('#') {
+                    chars.next();
+                    state = if depth == 1 {
+                        State::Code
+                    } else {
+                        State::BlockComment(depth - 1)
+                    };
+                }
+            }
+            State::Code if c == '#' => {
+                let rest = &line[i + 1..];
+                if rest.starts_with('[') {
+                    chars.next();
+                    state = State::BlockComment(1);
+                    continue;
+                }
+                let digits = rest.len()
+                    - rest
+                        .trim_start_matches(|c: char| c.is_ascii_hexdigit())
+                        .len();
+                let followed_by_ident = rest[digits..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_alphanumeric() || c == '_');
+                if !matches!(digits, 6 | 8) || followed_by_ident {
+                    // 行注释
+                    break;
+                }
+                // 颜色
+                has_code = true;
+                last = Some(c);
+            }
+            State::Code => {
+                if c == '"' || c == '\'' {
+                    state = State::Str(c);
+                }
+                if !c.is_whitespace() {
+                    has_code = true;
+                    last = Some(c);
+                }
+            }
+        }
+    }
+    (state, has_code, state == State::Code && last == Some(':'))
+}
+
+/// 行首空白的宽度
+fn indent_width(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| c.is_whitespace())
+        .map(|c| if c == '\t' { TAB_WIDTH } else { 1 })
+        .sum()
+}
+
+/// 按`level`层缩进输出还没有输出的空行和注释，空行不缩进
+fn flush(normalized: &mut String, pending: &mut Vec<&str>, level: usize) {
+    for content in pending.drain(..) {
+        if !content.trim().is_empty() {
+            normalized.extend(std::iter::repeat_n('\t', level));
+        }
+        normalized.push_str(content.trim_start_matches(|c: char| c != '\n' && c.is_whitespace()));
+    }
+}
+
+/// 按块结构把源码重新缩进成每层一个制表符
+///
+/// 以`:`结尾的行开启一个新块，之后缩进更深的行属于这个块，
+/// 回退到外层的缩进时结束块；没有开启新块却缩进更深的行按同一层处理。
+/// 空行和注释不影响块结构，注释和它后面的第一行代码缩进相同
+pub fn normalize_indentation(src: &str) -> String {
+    let mut normalized = String::with_capacity(src.len());
+    // 还没有输出的空行和注释，等到下一行代码确定了缩进再输出
+    let mut pending = vec![];
+    // 每一层块的原始缩进宽度
+    let mut widths: Vec<usize> = vec![];
+    let mut opens_block = false;
+    let mut state = State::Code;
+    for line in src.split_inclusive('\n') {
+        // 上一行的字符串或者块注释还没有结束，原样保留
+        if state != State::Code {
+            normalized.push_str(line);
+            (state, _, opens_block) = scan(line, state);
+            continue;
+        }
+
+        let content = line.trim_start_matches([' ', '\t']);
+        let (next_state, has_code, ends_with_colon) = scan(content, state);
+        state = next_state;
+        if !has_code {
+            pending.push(content);
+            // 跨行的块注释后面的行会原样输出，开头这一行不能再等
+            if state != State::Code {
+                flush(
+                    &mut normalized,
+                    &mut pending,
+                    widths.len().saturating_sub(1),
+                );
+            }
+            continue;
+        }
+
+        let width = indent_width(line);
+        match widths.last() {
+            None => widths.push(width),
+            Some(&top) if opens_block && width > top => widths.push(width),
+            Some(_) => {
+                while widths.len() > 1 && widths.last().is_some_and(|&top| width < top) {
+                    widths.pop();
+                }
+            }
+        }
+        flush(&mut normalized, &mut pending, widths.len() - 1);
+        normalized.extend(std::iter::repeat_n('\t', widths.len() - 1));
+        normalized.push_str(content);
+        opens_block = ends_with_colon;
+    }
+    flush(
+        &mut normalized,
+        &mut pending,
+        widths.len().saturating_sub(1),
+    );
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parse;
+
+    use super::normalize_indentation;
+
+    #[test]
+    fn normalize_over_indented() {
+        let src = "        if hp < 10:\n            flee()\n        attack()\n";
+        let normalized = normalize_indentation(src);
+        assert_eq!(normalized, "if hp < 10:\n\tflee()\nattack()\n");
+        assert!(parse(&normalized).is_ok());
+    }
+
+    #[test]
+    fn normalize_space_indented() {
+        let src = "fn tick():\n  while hp > 0:\n    # 注释\n    say(\"a:\n      b\")\n\n    attack()\n    # 回到外层\n  flee()\n  # 结尾\n";
+        let normalized = normalize_indentation(src);
+        // 注释和它后面的第一行代码缩进相同
+        assert_eq!(
+            normalized,
+            "fn tick():\n\twhile hp > 0:\n\t\t# 注释\n\t\tsay(\"a:\n      b\")\n\n\t\tattack()\n\t# 回到外层\n\tflee()\n\t# 结尾\n"
+        );
+        assert!(parse(&normalized).is_ok());
+    }
+}
@@ -0,0 +1,90 @@
+//! 循环标签检查
+//!
+//! `break outer`/`continue outer`只能指向包含它的带标签循环，函数、测试和状态块的边界会切断外层的标签。
+//! 模板展开之后再检查，模板体中的标签可以指向调用处外层的循环
+
+use std::{mem, ops::Range};
+
+use super::{
+    error::{ParseError, span_in},
+    scanner::{Command, Spanned},
+    visit::{Visitor, walk_command},
+};
+
+/// 按嵌套顺序记录所在循环的标签
+struct LabelChecker<'e, 'a> {
+    src: &'a str,
+    /// 所在的循环，没有标签的循环为None
+    loops: Vec<Option<&'a str>>,
+    /// 正在检查的顶层语句的位置，找不到标签的位置时用它报错
+    statement: Range<usize>,
+    errors: &'e mut Vec<ParseError>,
+}
+
+impl<'a> Visitor<'a> for LabelChecker<'_, 'a> {
+    fn visit_command(&mut self, command: &Command<'a>) {
+        match command {
+            Command::While { label, .. } | Command::For { label, .. } => {
+                self.loops.push(*label);
+                walk_command(self, command);
+                self.loops.pop();
+            }
+            Command::Function { .. } | Command::Test { .. } | Command::StateBlock { .. } => {
+                let loops = mem::take(&mut self.loops);
+                walk_command(self, command);
+                self.loops = loops;
+            }
+            Command::Break(Some(label)) | Command::Continue(Some(label))
+                if !self.loops.contains(&Some(*label)) =>
+            {
+                let span = span_in(self.src, label).unwrap_or_else(|| self.statement.clone());
+                self.errors
+                    .push(ParseError::custom(span, format!("未定义的循环标签{label}")));
+            }
+            _ => walk_command(self, command),
+        }
+    }
+}
+
+/// 检查`break`/`continue`的标签都指向包含它的循环
+pub(super) fn check(src: &str, ast: &[Spanned<Command>], errors: &mut Vec<ParseError>) {
+    let mut checker = LabelChecker {
+        src,
+        loops: vec![],
+        statement: 0..0,
+        errors,
+    };
+    for command in ast {
+        checker.statement = command.span.into_range();
+        checker.visit_command(&command.node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{error::ParseError, parse};
+
+    #[test]
+    fn unknown_label() {
+        assert!(parse("outer: for x in xs:\n\twhile 1:\n\t\tbreak outer\n").is_ok());
+        let src = "outer: for x in xs:\n\tbreak inner\n";
+        let start = src.find("inner").unwrap();
+        assert_eq!(
+            parse(src),
+            Err(vec![ParseError::custom(
+                start..start + 5,
+                "未定义的循环标签inner"
+            )])
+        );
+        // 函数内不能跳出函数外的循环
+        let src = "outer: for x in xs:\n\tfn f():\n\t\tcontinue outer\n";
+        let start = src.rfind("outer").unwrap();
+        assert_eq!(
+            parse(src),
+            Err(vec![ParseError::custom(
+                start..start + 5,
+                "未定义的循环标签outer"
+            )])
+        );
+    }
+}
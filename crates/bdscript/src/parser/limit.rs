@@ -0,0 +1,146 @@
+//! 解析时的大小限制
+//!
+//! 每条语句解析完成时就计入语句和表达式节点的数量，超过限制后记号流提前结束，
+//! 模板展开出的语句同样计入，这样不可信的脚本不会先被完整地解析和展开
+
+use std::{cell::Cell, mem, rc::Rc, slice};
+
+use chumsky::span::SimpleSpan;
+
+use super::{
+    ParseConfig,
+    error::{ParseError, SizeLimit},
+    scanner::{Command, Expression, Spanned},
+    visit::{Visitor, walk_command, walk_expression},
+};
+
+/// 统计语句和表达式节点的数量
+#[derive(Default)]
+struct NodeCounter {
+    statements: usize,
+    expression_nodes: usize,
+    /// 只统计最外层的语句，块中的语句在解析时已经单独计入
+    shallow: bool,
+    /// 正在访问块中的语句
+    nested: bool,
+}
+
+impl<'a> Visitor<'a> for NodeCounter {
+    fn visit_command(&mut self, command: &Command<'a>) {
+        if self.shallow && self.nested {
+            return;
+        }
+        if !matches!(command, Command::NewLine) {
+            self.statements += 1;
+        }
+        // defer后面的语句不是块，不会单独计入
+        let nested = mem::replace(&mut self.nested, !matches!(command, Command::Defer(_)));
+        walk_command(self, command);
+        self.nested = nested;
+    }
+
+    fn visit_expression(&mut self, expression: &Expression<'a>) {
+        self.expression_nodes += 1;
+        walk_expression(self, expression);
+    }
+}
+
+/// 语句和表达式节点的预算，整个脚本的解析和模板展开共用一份
+#[derive(Debug, Default)]
+pub struct Budget {
+    max_statements: Option<usize>,
+    max_expression_nodes: Option<usize>,
+    statements: Cell<usize>,
+    expression_nodes: Cell<usize>,
+    /// 第一次超过的限制以及越过限制的语句的位置，和截断后的记号流共享
+    exceeded: Rc<Cell<Option<(SizeLimit, usize, SimpleSpan)>>>,
+}
+
+impl Budget {
+    /// 按解析配置中的限制创建预算，默认不限制
+    pub fn new(config: &ParseConfig) -> Self {
+        Self {
+            max_statements: config.max_statements,
+            max_expression_nodes: config.max_expression_nodes,
+            ..Default::default()
+        }
+    }
+
+    /// 是否已经超过了限制
+    pub fn is_exceeded(&self) -> bool {
+        self.exceeded.get().is_some()
+    }
+
+    /// 超过限制后提前结束记号流
+    pub fn truncate<I: IntoIterator>(&self, tokens: I) -> impl Iterator<Item = I::Item> + use<I> {
+        let exceeded = Rc::clone(&self.exceeded);
+        tokens
+            .into_iter()
+            .take_while(move |_| exceeded.get().is_none())
+    }
+
+    /// 超过限制时的错误
+    pub fn error(&self) -> Option<ParseError> {
+        self.exceeded
+            .get()
+            .map(|(kind, limit, span)| ParseError::TooLarge {
+                span: span.into_range(),
+                kind,
+                limit,
+            })
+    }
+
+    /// 计入刚解析完的一条语句，块中的语句已经在解析时计入，这里不再重复统计
+    pub fn charge_statement(&self, command: &Command, span: SimpleSpan) {
+        let mut counter = NodeCounter {
+            shallow: true,
+            ..Default::default()
+        };
+        counter.visit_command(command);
+        self.charge(counter, span);
+    }
+
+    /// 计入整条语句，包括块中的语句
+    pub fn charge_commands(&self, commands: &[Command], span: SimpleSpan) {
+        let mut counter = NodeCounter::default();
+        for command in commands {
+            counter.visit_command(command);
+        }
+        self.charge(counter, span);
+    }
+
+    /// 计入分块解析后合并起来的语法树
+    pub fn charge_ast(&self, ast: &[Spanned<Command>]) {
+        for command in ast {
+            if self.is_exceeded() {
+                return;
+            }
+            self.charge_commands(slice::from_ref(&command.node), command.span);
+        }
+    }
+
+    fn charge(&self, counter: NodeCounter, span: SimpleSpan) {
+        if self.is_exceeded() {
+            return;
+        }
+        let statements = self.statements.get() + counter.statements;
+        let expression_nodes = self.expression_nodes.get() + counter.expression_nodes;
+        self.statements.set(statements);
+        self.expression_nodes.set(expression_nodes);
+        let exceeded = [
+            (SizeLimit::Statements, self.max_statements, statements),
+            (
+                SizeLimit::ExpressionNodes,
+                self.max_expression_nodes,
+                expression_nodes,
+            ),
+        ]
+        .into_iter()
+        .find_map(|(kind, limit, count)| {
+            limit
+                .filter(|&limit| count > limit)
+                .map(|limit| (kind, limit, span))
+        });
+        self.exceeded.set(exceeded);
+    }
+}
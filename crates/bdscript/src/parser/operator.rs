@@ -0,0 +1,52 @@
+//! 自定义二元运算符
+//!
+//! 宿主在解析配置中登记运算符的写法、优先级和结合性，词法分析时把源码中紧挨着的这几个字符合成一个
+//! [`Token::CustomOp`](super::tokenizer::Token::CustomOp)，解析成[`BinaryOp::Custom`](super::scanner::BinaryOp::Custom)，
+//! 下标是运算符在[`ParseConfig::operators`](super::ParseConfig::operators)中的位置。
+//! 运算的实现见[`Operators`](crate::eval::operator::Operators)
+
+/// 自定义运算符的优先级，和同名的内置运算符同级
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precedence {
+    /// `||`、`|`
+    Or,
+    /// `&&`
+    And,
+    /// `==`、`<`等比较
+    Comparison,
+    /// `+`、`-`
+    Sum,
+    /// `*`、`/`、`%`、`@`
+    Product,
+}
+
+/// 同一优先级的运算连用时的结合方向，内置运算符都是左结合
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+/// 自定义运算符的写法
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperatorSyntax {
+    /// 运算符，例如`<=>`，不能包含空白
+    pub symbol: String,
+    pub precedence: Precedence,
+    pub associativity: Associativity,
+}
+
+/// 源码中从`start`开始的自定义运算符，有多个匹配时取最长的，返回运算符的下标和结束位置
+pub(super) fn match_at(
+    operators: &[OperatorSyntax],
+    src: &str,
+    start: usize,
+) -> Option<(usize, usize)> {
+    let rest = &src[start..];
+    operators
+        .iter()
+        .enumerate()
+        .filter(|(_, operator)| !operator.symbol.is_empty() && rest.starts_with(&operator.symbol))
+        .max_by_key(|(_, operator)| operator.symbol.len())
+        .map(|(index, operator)| (index, start + operator.symbol.len()))
+}
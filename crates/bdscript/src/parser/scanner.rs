@@ -1,13 +1,22 @@
 //! 解析表达式
 
-use std::cell::Cell;
+use std::{borrow::Cow, cell::Cell, collections::HashSet, iter};
 
-use chumsky::{input::ValueInput, prelude::*, Parser};
+use chumsky::{
+    Parser,
+    input::{Emitter, ValueInput},
+    prelude::*,
+};
 use rust_decimal::Decimal;
 
-use super::tokenizer::Token;
+use super::{
+    indent::IndentStyle,
+    limit::Budget,
+    operator::{Associativity, OperatorSyntax, Precedence},
+    tokenizer::{FormatSpec, Token},
+};
 /// 一元运算符
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UnaryOp {
     // 正负号
     Plus,
@@ -16,10 +25,15 @@ pub enum UnaryOp {
     Not,
     // ?运算
     Question,
+    // 自增/自减(i++、i--)，只能出现一次，x++ ++会报错
+    Increment,
+    Decrement,
+    // 展开(*rest)，只能出现在多重赋值的左边
+    Spread,
 }
 
 /// 二元运算符
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BinaryOp {
     // 基本运算符
     Add,
@@ -28,6 +42,8 @@ pub enum BinaryOp {
     Div,
     Pow,
     Mod,
+    // 变换运算(矩阵/变换作用于向量，或者向量点积)
+    Transform,
 
     // 逻辑运算
     Equal,
@@ -39,6 +55,8 @@ pub enum BinaryOp {
     And,
     Or,
     Not,
+    // 字典合并
+    Merge,
 
     // 赋值运算符
     Assign,
@@ -48,6 +66,8 @@ pub enum BinaryOp {
     DivAssign,
     ModAssign,
     PowAssign,
+    // 字典合并赋值
+    OrAssign,
 
     // 访问运算符
     // 通过索引拿到对象
@@ -58,53 +78,88 @@ pub enum BinaryOp {
     Dot,
     // 调用运算符
     Call,
+
+    // 解析配置中登记的自定义运算符，值是登记的下标
+    Custom(usize),
 }
 
 /// 对象
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Object<'a> {
     /// 常量
     Constant(Decimal),
+    /// 布尔值
+    Bool(bool),
+    /// 带单位的常量，例如`10s`，单位由运行时解释
+    Quantity(Decimal, &'a str),
     /// 字符串
-    Str(&'a str),
+    Str(Cow<'a, str>),
     /// 变量(标识符，变量可以是函数名，也可以是变量名)
     Variable(&'a str),
+    /// 符号(例如`:attack`)，按名字比较，用于状态和消息标签
+    Symbol(&'a str),
+    /// 颜色(RGBA)
+    Color([u8; 4]),
+    /// f-string，由文本([`Object::Str`])和内嵌表达式依次组成
+    FString(Vec<Expression<'a>>),
     /// 元组
     Tuple(Vec<Expression<'a>>),
     /// 数组
     Array(Vec<Expression<'a>>),
-    /// 字典项(TODO: 未实现)
+    /// 字典项(key: value)
     DictItem(Box<Expression<'a>>, Box<Expression<'a>>),
-    /// 字典(TODO: 未实现)
+    /// 字典，每一项都是[`Object::DictItem`]
     Dict(Vec<Expression<'a>>),
 }
 /// 表达式
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Expression<'a> {
     /// 单独一个对象
     Object(Object<'a>),
     /// 运算
-    Unary {
-        op: UnaryOp,
-        hs: Box<Expression<'a>>,
-    },
+    Unary { op: UnaryOp, hs: Box<Self> },
     /// 双值运算
     Binary {
         op: BinaryOp,
-        lhs: Box<Expression<'a>>,
-        rhs: Box<Expression<'a>>,
+        lhs: Box<Self>,
+        rhs: Box<Self>,
     },
     /// 优先运算符
-    Priority(Box<Expression<'a>>),
+    Priority(Box<Self>),
+    /// 范围，`start..end`或者包含end的`start..=end`
+    Range {
+        start: Box<Self>,
+        end: Box<Self>,
+        inclusive: bool,
+    },
+    /// 条件表达式，`cond ? then : otherwise`
+    Ternary {
+        cond: Box<Self>,
+        then: Box<Self>,
+        otherwise: Box<Self>,
+    },
+    /// 条件展开，`*if cond then expr`，只能出现在数组和字典字面量中，条件成立时才包含这个元素
+    SpreadIf {
+        condition: Box<Self>,
+        value: Box<Self>,
+    },
+    /// 按格式转换成字符串，f-string中的`{value:.2}`，只能出现在f-string中
+    Format { value: Box<Self>, spec: FormatSpec },
     /// 查询运算符
     Query {
         with_compoents: Vec<&'a str>,
         without_compoents: Vec<&'a str>,
+        /// 只查询这个实体的子实体(of)
+        hierarchy: Option<Box<Self>>,
+        /// 按每个实体计算出的值排序(order_by)
+        order_by: Option<Box<Self>>,
+        /// 最多返回的实体数量(limit)
+        limit: Option<Box<Self>>,
     },
 }
 
 /// 分支
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Branch<'a> {
     /// 条件
     pub condition: Expression<'a>,
@@ -112,31 +167,119 @@ pub struct Branch<'a> {
     pub commands: Vec<Command<'a>>,
 }
 
+/// 状态的超时，`timeout 3s -> @next`，状态持续的时间达到时长之后自动切换到另一个状态
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timeout<'a> {
+    /// 时长，数字按秒计算
+    pub duration: Expression<'a>,
+    /// 超时之后切换到的状态
+    pub state: &'a str,
+}
+
+/// 状态中的事件处理，`on "Attacked":`，处于这个状态时收到同名的事件就执行块内的语句
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handler<'a> {
+    /// 事件名
+    pub event: Cow<'a, str>,
+    pub commands: Vec<Command<'a>>,
+}
+
+/// 状态块中的一项，解析完成后分别放进[`Command::StateBlock`]的各个字段
+enum StateItem<'a> {
+    Command(Command<'a>),
+    Timeout(Timeout<'a>),
+    Handler(Handler<'a>),
+}
+
 /// 指令
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command<'a> {
     /// 表达式
     Expression(Expression<'a>),
     /// 条件表达式
     If {
         if_branch: Vec<Branch<'a>>,
-        else_branch: Option<Vec<Command<'a>>>,
+        else_branch: Option<Vec<Self>>,
     },
     /// 循环表达式
     While {
         condition: Box<Expression<'a>>,
-        command: Vec<Command<'a>>,
+        command: Vec<Self>,
+        /// 循环标签，`outer: while ...:`
+        label: Option<&'a str>,
+    },
+    /// 遍历循环，依次把iter中的元素绑定到var上执行块内的语句
+    For {
+        var: &'a str,
+        iter: Expression<'a>,
+        commands: Vec<Self>,
+        /// 循环标签，`outer: for ...:`
+        label: Option<&'a str>,
     },
     /// 函数定义
     Function {
+        /// 是否是`pub fn`，只有公开的函数可以被宿主调用
+        public: bool,
         name: &'a str,
         args: Vec<&'a str>,
-        commands: Vec<Command<'a>>,
+        commands: Vec<Self>,
+    },
+    /// 延迟执行，在所在的函数/状态块退出时按注册的逆序执行
+    Defer(Box<Self>),
+    /// 挂起脚本并把值交给宿主，下次调用`next()`时从这里继续
+    Yield(Expression<'a>),
+    /// 临时绑定，`with expr as name:`，name只在块内可见
+    With {
+        expr: Expression<'a>,
+        binding: &'a str,
+        body: Vec<Self>,
+    },
+    /// 模板定义，`template name(params):`，解析完成后会被展开到调用处
+    Template {
+        name: &'a str,
+        params: Vec<&'a str>,
+        body: Vec<Self>,
+    },
+    /// 模板调用，`name!(args)`
+    Invoke {
+        name: &'a str,
+        args: Vec<Expression<'a>>,
+    },
+    /// 脚本内的测试，`test "name":`，由测试工具单独运行，正常执行时跳过
+    Test { name: Cow<'a, str>, body: Vec<Self> },
+    /// 导入其他脚本文件，由加载器负责把其中的函数和状态合并进来
+    Import(Cow<'a, str>),
+    /// 从函数返回，可以不带返回值
+    Return(Option<Expression<'a>>),
+    /// 跳出所在的循环，带标签时跳出标签对应的循环
+    Break(Option<&'a str>),
+    /// 跳过本次循环剩下的语句，带标签时继续标签对应的循环
+    Continue(Option<&'a str>),
+    /// 切换到另一个状态，`@patrol`
+    SetState(&'a str),
+    /// 状态块，`@patrol:`，处于这个状态时执行块内的语句
+    StateBlock {
+        name: &'a str,
+        /// 继承的状态，`@attack extends @combat:`
+        parent: Option<&'a str>,
+        /// 状态的超时，块中最多只能有一个
+        timeout: Option<Timeout<'a>>,
+        /// 事件处理，每个事件最多只能有一个
+        handlers: Vec<Handler<'a>>,
+        commands: Vec<Self>,
     },
     /// 占位行
     NewLine,
 }
 
+/// 带有源码位置的节点
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spanned<T> {
+    pub node: T,
+    /// 在源码中的位置
+    pub span: SimpleSpan,
+}
+
 /// 方便进行indent增加
 macro add_indent($indent_count:expr) {
     |s| {
@@ -150,25 +293,171 @@ macro sub_indent($indent_count:expr) {
     $indent_count.set($indent_count.get() - 1);
 }
 
-/// Indent解析
-pub struct Indent(usize);
-pub fn build_ast<'s, I>(
-    indent_count: &'s Cell<usize>,
-) -> impl Parser<'s, I, Vec<Command<'s>>, extra::Err<Rich<'s, Token<'s>>>> + Clone
+/// 同一优先级的二元运算，内置运算符左结合，`custom`是这一级的自定义运算符的下标和结合性
+fn binary_tier<'s, I, P, O>(
+    operand: P,
+    op: O,
+    custom: Vec<(usize, Associativity)>,
+) -> Boxed<'s, 's, I, Expression<'s>, extra::Err<Rich<'s, Token<'s>>>>
 where
     I: ValueInput<'s, Token = Token<'s>, Span = SimpleSpan>,
+    P: Parser<'s, I, Expression<'s>, extra::Err<Rich<'s, Token<'s>>>> + Clone + 's,
+    O: Parser<'s, I, BinaryOp, extra::Err<Rich<'s, Token<'s>>>> + Clone + 's,
 {
+    let custom_op = select! {
+        Token::CustomOp((index, _)) if custom.iter().any(|(i, _)| *i == index) => {
+            let &(_, associativity) = custom.iter().find(|(i, _)| *i == index).expect("已经检查过");
+            (BinaryOp::Custom(index), associativity)
+        }
+    };
+    operand
+        .clone()
+        .then(
+            op.map(|op| (op, Associativity::Left))
+                .or(custom_op)
+                .then(operand)
+                .repeated()
+                .collect::<Vec<_>>(),
+        )
+        .map(|(first, rest)| fold_tier(first, rest))
+        .boxed()
+}
+
+/// 按结合性把同一优先级的运算组合起来，连续的右结合运算从右往左组合
+fn fold_tier<'s>(
+    first: Expression<'s>,
+    rest: Vec<((BinaryOp, Associativity), Expression<'s>)>,
+) -> Expression<'s> {
+    let binary = |op, lhs, rhs| Expression::Binary {
+        op,
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    };
+    let mut lhs = first;
+    let mut rest = rest.into_iter().peekable();
+    while let Some(((op, associativity), rhs)) = rest.next() {
+        if associativity == Associativity::Left {
+            lhs = binary(op, lhs, rhs);
+            continue;
+        }
+        // 收集连续的右结合运算，a op1 b op2 c => a op1 (b op2 c)
+        let mut operands = vec![rhs];
+        let mut ops = vec![op];
+        while let Some(((op, _), rhs)) =
+            rest.next_if(|((_, associativity), _)| *associativity == Associativity::Right)
+        {
+            ops.push(op);
+            operands.push(rhs);
+        }
+        let mut rhs = operands.pop().expect("至少有一个操作数");
+        while let Some(operand) = operands.pop() {
+            rhs = binary(ops.pop().expect("运算符比操作数少一个"), operand, rhs);
+        }
+        lhs = binary(ops.pop().expect("还剩第一个运算符"), lhs, rhs);
+    }
+    lhs
+}
+
+/// 检查查询尖括号中的记号，组件之间用|或者,分隔，组件前面带!表示排除这个组件
+///
+/// 返回组件以及是否排除，不合法的记号会报告错误并跳过，不会影响后面的解析
+fn query_components<'s>(
+    tokens: Vec<(Token<'s>, SimpleSpan)>,
+    close: SimpleSpan,
+    emitter: &mut Emitter<Rich<'s, Token<'s>>>,
+) -> Vec<(bool, &'s str)> {
+    let mut components = vec![];
+    // 上一个记号是否是分隔符(或者刚开始)，分隔符之后需要组件
+    let mut expect_component = true;
+    let mut exclude = false;
+    for (token, span) in tokens {
+        match token {
+            Token::Ident(name) if expect_component => {
+                components.push((exclude, name));
+                expect_component = false;
+                exclude = false;
+            }
+            Token::Not if expect_component && !exclude => exclude = true,
+            Token::Pipe | Token::Or | Token::Comma if !expect_component => expect_component = true,
+            Token::Ident(name) => emitter.emit(Rich::custom(
+                span,
+                format!("查询组件{name}前面缺少分隔符|或者,"),
+            )),
+            // 出错的记号当作分隔符/组件处理，避免同一个错误报告多次
+            Token::And => {
+                emitter.emit(Rich::custom(
+                    span,
+                    "查询中的组件用|或者,分隔，排除组件写作!组件",
+                ));
+                expect_component = true;
+            }
+            token => {
+                emitter.emit(Rich::custom(span, format!("查询中不能出现{token}")));
+                expect_component = false;
+                exclude = false;
+            }
+        }
+    }
+    if expect_component && (exclude || !components.is_empty()) {
+        emitter.emit(Rich::custom(close, "查询中缺少组件"));
+    }
+    components
+}
+
+/// 缩进层数和所在的块不一致
+fn indent_mismatch<'s>(span: SimpleSpan, expected: usize, found: usize) -> Rich<'s, Token<'s>> {
+    Rich::custom(
+        span,
+        format!("缩进不一致，应该是{expected}层，实际是{found}层"),
+    )
+}
+
+/// 构建语法树，顶层的每条语句都带有在源码中的位置
+///
+/// 每条语句(包括块中的语句)解析完成时计入`budget`
+pub fn build_ast<'s, 'b, I>(
+    indent_count: &'b Cell<usize>,
+    indent_style: Option<IndentStyle>,
+    operators: &[OperatorSyntax],
+    budget: &'b Budget,
+) -> impl Parser<'s, I, Vec<Spanned<Command<'s>>>, extra::Err<Rich<'s, Token<'s>>>> + Clone + 'b
+where
+    's: 'b,
+    I: ValueInput<'s, Token = Token<'s>, Span = SimpleSpan>,
+{
+    // 每一级优先级的自定义运算符
+    let tier = |precedence| {
+        operators
+            .iter()
+            .enumerate()
+            .filter(|(_, operator)| operator.precedence == precedence)
+            .map(|(index, operator)| (index, operator.associativity))
+            .collect::<Vec<_>>()
+    };
+    let tiers = [
+        Precedence::Product,
+        Precedence::Sum,
+        Precedence::Comparison,
+        Precedence::And,
+        Precedence::Or,
+    ]
+    .map(tier);
     recursive(|ast| {
         // 解析行
         let parse_empty = just(Token::Line).to(Command::NewLine);
 
         // 解析表达式
-        let parse_expression = recursive(|expression| {
-            // 解析基础对象(必须在parse_key_value之后，否则会覆盖parse_key_value的匹配)
+        let [product, sum, comparison, and, or] = tiers.clone();
+        let expression = recursive(|expression| {
+            // 解析基础对象
             let parse_base_object = select! {
                 Token::Number(num) => Expression::Object(Object::Constant(num)),
+                Token::Quantity((num, unit)) => Expression::Object(Object::Quantity(num, unit)),
                 Token::Str(s) => Expression::Object(Object::Str(s)),
                 Token::Ident(s) => Expression::Object(Object::Variable(s)),
+                Token::Bool(b) => Expression::Object(Object::Bool(b)),
+                Token::Symbol(s) => Expression::Object(Object::Symbol(s)),
+                Token::Color(rgba) => Expression::Object(Object::Color(rgba)),
             };
 
             // 解析键值对
@@ -178,8 +467,48 @@ where
                 .map(|(k, v)| Expression::Object(Object::DictItem(Box::new(k), Box::new(v))))
                 .boxed();
 
+            // 解析字典、数组和元组中的一项，键值对只能出现在这些字面量中，
+            // 这样条件表达式`a ? b : c`中的`b : c`不会被当成键值对
+            let parse_item = parse_key_value.or(expression.clone()).boxed();
+
+            // 解析f-string
+            let parse_fstring = just(Token::FStringStart)
+                .ignore_then(
+                    select! {
+                        Token::FStringPart(s) => Expression::Object(Object::Str(s)),
+                    }
+                    .or(expression
+                        .clone()
+                        .then(select! { Token::FStringSpec(spec) => spec }.or_not())
+                        .map(|(value, spec)| match spec {
+                            Some(spec) => Expression::Format {
+                                value: Box::new(value),
+                                spec,
+                            },
+                            None => value,
+                        }))
+                    .repeated()
+                    .collect(),
+                )
+                .then_ignore(just(Token::FStringEnd))
+                .map(|parts| Expression::Object(Object::FString(parts)))
+                .boxed();
+
+            // 解析条件展开元素，then是上下文关键字
+            let parse_element = just(Token::Mul)
+                .ignore_then(just(Token::If))
+                .ignore_then(expression.clone())
+                .then_ignore(just(Token::Ident("then")))
+                .then(parse_item.clone())
+                .map(|(condition, value)| Expression::SpreadIf {
+                    condition: Box::new(condition),
+                    value: Box::new(value),
+                })
+                .or(parse_item.clone())
+                .boxed();
+
             // 解析数组
-            let parse_array = expression
+            let parse_array = parse_element
                 .clone()
                 .separated_by(just(Token::Comma))
                 .allow_trailing()
@@ -188,8 +517,8 @@ where
                 .map(|e| Expression::Object(Object::Array(e)))
                 .boxed();
 
-            // 解析字典
-            let parse_dict = expression
+            // 解析花括号中的元素
+            let parse_braced = parse_element
                 .clone()
                 .separated_by(just(Token::Comma))
                 .allow_trailing()
@@ -198,9 +527,28 @@ where
                 .map(|e| Expression::Object(Object::Dict(e)))
                 .boxed();
 
-            // 解析元组
-            let parse_tuple = expression
+            // 解析字典，每一项都必须是键值对(或者展开出键值对的条件展开)
+            let parse_dict = parse_element
                 .clone()
+                .validate(|e, extra, emitter| {
+                    let item = match &e {
+                        Expression::SpreadIf { value, .. } => value.as_ref(),
+                        item => item,
+                    };
+                    if !matches!(item, Expression::Object(Object::DictItem(..))) {
+                        emitter.emit(Rich::custom(extra.span(), "字典中只能是键值对(key: value)"));
+                    }
+                    e
+                })
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect()
+                .delimited_by(just(Token::LeftBrace), just(Token::RightBrace))
+                .map(|e| Expression::Object(Object::Dict(e)))
+                .boxed();
+
+            // 解析元组
+            let parse_tuple = parse_item
                 .separated_by(just(Token::Comma))
                 .allow_trailing()
                 .collect()
@@ -208,143 +556,252 @@ where
                 .map(|e| Expression::Object(Object::Tuple(e)))
                 .boxed();
 
+            // 解析括号，只有一个表达式并且没有逗号时是优先运算，否则是元组
+            let parse_priority = expression
+                .clone()
+                .delimited_by(just(Token::LeftParen), just(Token::RightParen))
+                .map(|e| Expression::Priority(Box::new(e)))
+                .boxed();
+
             // 解析查询运算符
             let parse_query_single = {
-                let parse_query = just(Token::And).or(just(Token::Or)).then(select! {
-                    Token::Ident(s) => s
-                });
+                // 尖括号中的记号先原样收集，再逐个检查，
+                // 这样写错的查询会报告具体的错误，而不是被当成Query < a的比较
+                let parse_components = just(Token::Less)
+                    .ignore_then(
+                        any()
+                            .and_is(just(Token::Greater).not())
+                            .and_is(just(Token::Line).not())
+                            .map_with(|token, extra| (token, extra.span()))
+                            .repeated()
+                            .collect::<Vec<_>>(),
+                    )
+                    .then(just(Token::Greater).map_with(|_, extra| extra.span()))
+                    .validate(|(tokens, close), _, emitter| {
+                        query_components(tokens, close, emitter)
+                    });
 
                 just(Token::Query)
-                    .ignore_then(
-                        just(Token::Less)
-                            .ignore_then(
-                                select! {
-                                    Token::Ident(s) => s
-                                }
-                                .then(
-                                    parse_query
-                                        .clone()
-                                        .repeated()
-                                        .collect::<Vec<(Token, &str)>>(),
-                                )
-                                .or_not(),
-                            )
-                            .then_ignore(just(Token::Greater))
+                    .ignore_then(parse_components.or_not())
+                    // of、order_by和limit是上下文关键字，其他地方仍然可以当变量名用
+                    .then(
+                        just(Token::Ident("of"))
+                            .ignore_then(expression.clone())
+                            .or_not(),
+                    )
+                    .then(
+                        just(Token::Ident("order_by"))
+                            .ignore_then(expression.clone())
+                            .or_not(),
+                    )
+                    .then(
+                        just(Token::Ident("limit"))
+                            .ignore_then(expression.clone())
                             .or_not(),
                     )
-                    .map(|e| {
-                        if let Some(Some((first, e))) = e {
-                            let mut with_compoents = vec![first];
-                            let mut without_compoents = vec![];
-                            for (logic, compoent) in e {
-                                match logic {
-                                    Token::Or => {
-                                        with_compoents.push(compoent);
-                                    }
-                                    Token::And => {
-                                        without_compoents.push(compoent);
-                                    }
-                                    token => panic!("unexpected token:{token:?}"),
-                                }
-                            }
-                            Expression::Query {
-                                with_compoents,
-                                without_compoents,
-                            }
-                        } else {
-                            Expression::Query {
-                                with_compoents: vec![],
-                                without_compoents: vec![],
-                            }
+                    .map(|(((components, hierarchy), order_by), limit)| {
+                        let (without, with): (Vec<_>, Vec<_>) = components
+                            .unwrap_or_default()
+                            .into_iter()
+                            .partition(|(exclude, _)| *exclude);
+                        let with_compoents = with.into_iter().map(|(_, c)| c).collect();
+                        let without_compoents = without.into_iter().map(|(_, c)| c).collect();
+                        Expression::Query {
+                            with_compoents,
+                            without_compoents,
+                            hierarchy: hierarchy.map(Box::new),
+                            order_by: order_by.map(Box::new),
+                            limit: limit.map(Box::new),
                         }
                     })
             }
             .boxed();
 
             // 解析值
-            let parse_value = parse_key_value
-                .or(parse_base_object)
+            let parse_value = parse_base_object
+                .or(parse_fstring)
                 .or(parse_array.clone())
                 .or(parse_dict.clone())
+                .or(parse_priority)
                 .or(parse_tuple.clone())
                 .or(parse_query_single.clone());
-            // 解析左运算符
-            let parse_left_op = select! {
-                Token::Add=>UnaryOp::Plus,
-                Token::Sub=>UnaryOp::Minus,
-                Token::Not=>UnaryOp::Not,
-            };
+            // 后缀运算：调用、索引、取表、字段访问和?运算，None代表?运算
+            let parse_postfix_op = parse_tuple
+                .clone()
+                .map(|e| Some((BinaryOp::Call, e)))
+                .or(parse_array.clone().map(|e| Some((BinaryOp::Index, e))))
+                .or(parse_braced.map(|e| Some((BinaryOp::Key, e))))
+                .or(just(Token::Dot)
+                    .ignore_then(select! {
+                        Token::Ident(s) => Expression::Object(Object::Variable(s)),
+                    })
+                    .map(|e| Some((BinaryOp::Dot, e))))
+                // 后面跟着`expr :`时是条件表达式而不是?运算
+                .or(just(Token::Question)
+                    .and_is(
+                        just(Token::Question)
+                            .then(expression.clone())
+                            .then(just(Token::Colon))
+                            .not(),
+                    )
+                    .to(None));
+            let parse_postfix = parse_value
+                .foldl(parse_postfix_op.repeated(), |hs, op| match op {
+                    Some((op, rhs)) => Expression::Binary {
+                        op,
+                        lhs: Box::new(hs),
+                        rhs: Box::new(rhs),
+                    },
+                    None => Expression::Unary {
+                        op: UnaryOp::Question,
+                        hs: Box::new(hs),
+                    },
+                })
+                .boxed();
 
-            // 解析左表达式基本值
-            let parse_left_value = parse_left_op
-                .then(parse_value.clone())
-                .map(|(op, hs)| Expression::Unary {
-                    op,
-                    hs: Box::new(hs),
+            // 自增/自减，不能连续使用
+            let parse_step = parse_postfix
+                .then(
+                    select! {
+                        Token::PlusOne => UnaryOp::Increment,
+                        Token::MinusOne => UnaryOp::Decrement,
+                    }
+                    .or_not(),
+                )
+                .map(|(hs, op)| match op {
+                    Some(op) => Expression::Unary {
+                        op,
+                        hs: Box::new(hs),
+                    },
+                    None => hs,
                 })
                 .boxed();
-            // 解析问号运算基本值
-            let parse_left_question = parse_value
+
+            // 前缀运算
+            let parse_unary = select! {
+                Token::Add => UnaryOp::Plus,
+                Token::Sub => UnaryOp::Minus,
+                Token::Not => UnaryOp::Not,
+            }
+            .repeated()
+            .foldr(parse_step, |op, hs| Expression::Unary {
+                op,
+                hs: Box::new(hs),
+            })
+            .boxed();
+
+            // 乘方，右结合
+            let parse_pow = parse_unary
                 .clone()
-                .then(just(Token::Question))
-                .map(|(hs, _)| Expression::Unary {
-                    op: UnaryOp::Question,
-                    hs: Box::new(hs),
+                .then_ignore(just(Token::Pow))
+                .repeated()
+                .foldr(parse_unary, |lhs, rhs| Expression::Binary {
+                    op: BinaryOp::Pow,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
                 })
                 .boxed();
 
-            // 解析运算符
-            let parse_binary_op = select! {
-                Token::Add => BinaryOp::Add,
-                Token::Sub => BinaryOp::Sub,
-                Token::Mul => BinaryOp::Mul,
-                Token::Div => BinaryOp::Div,
-                Token::Equal => BinaryOp::Equal,
-                Token::NotEqual => BinaryOp::NotEqual,
-                Token::Greater => BinaryOp::Greater,
-                Token::Less => BinaryOp::Less,
-                Token::GreaterEqual => BinaryOp::GreaterEqual,
-                Token::LessEqual => BinaryOp::LessEqual,
-                Token::And => BinaryOp::And,
-                Token::Or => BinaryOp::Or,
-                Token::Pow => BinaryOp::Pow,
-                Token::Mod => BinaryOp::Mod,
-                Token::Not => BinaryOp::Not,
-                Token::Dot => BinaryOp::Dot,
-                Token::Assign=>BinaryOp::Assign,
-                Token::AddAssign=>BinaryOp::AddAssign,
-                Token::SubAssign=>BinaryOp::SubAssign,
-                Token::MulAssign=>BinaryOp::MulAssign,
-                Token::DivAssign=>BinaryOp::DivAssign,
-                Token::ModAssign=>BinaryOp::ModAssign,
-                Token::PowAssign=>BinaryOp::PowAssign,
-            };
-            // 解析基础运算符
-            let parse_base_binary_op_value = parse_binary_op.then(expression.clone()).boxed();
-            // call运算符，拿到call的数据
-            let parse_call_binary_op_value =
-                parse_tuple.clone().map(|e| (BinaryOp::Call, e)).boxed();
-            // index运算符，拿到index数据
-            let parse_index_binary_op_value =
-                parse_array.clone().map(|e| (BinaryOp::Index, e)).boxed();
-            // 取表运算符，拿到表数据
-            let parse_table_binary_op_value =
-                parse_dict.clone().map(|e| (BinaryOp::Key, e)).boxed();
-            // 基本表达式
-            let parse_base_value = parse_left_question
-                .clone()
-                .or(parse_value.clone())
-                .or(parse_left_value.clone());
-            // 运算表达式
-            let parse_op_value = parse_base_binary_op_value
+            let parse_product = binary_tier(
+                parse_pow,
+                select! {
+                    Token::Mul => BinaryOp::Mul,
+                    Token::Div => BinaryOp::Div,
+                    Token::Mod => BinaryOp::Mod,
+                    Token::At => BinaryOp::Transform,
+                },
+                product.clone(),
+            );
+            let parse_sum = binary_tier(
+                parse_product,
+                select! {
+                    Token::Add => BinaryOp::Add,
+                    Token::Sub => BinaryOp::Sub,
+                },
+                sum.clone(),
+            );
+            // 范围，不能连续使用
+            let parse_range = parse_sum
                 .clone()
-                .or(parse_call_binary_op_value.clone())
-                .or(parse_index_binary_op_value.clone())
-                .or(parse_table_binary_op_value.clone());
+                .then(
+                    just(Token::Range)
+                        .to(false)
+                        .or(just(Token::RangeInclusive).to(true))
+                        .then(parse_sum)
+                        .or_not(),
+                )
+                .map(|(start, end)| match end {
+                    Some((inclusive, end)) => Expression::Range {
+                        start: Box::new(start),
+                        end: Box::new(end),
+                        inclusive,
+                    },
+                    None => start,
+                })
+                .boxed();
+            let parse_comparison = binary_tier(
+                parse_range,
+                select! {
+                    Token::Equal => BinaryOp::Equal,
+                    Token::NotEqual => BinaryOp::NotEqual,
+                    Token::Greater => BinaryOp::Greater,
+                    Token::Less => BinaryOp::Less,
+                    Token::GreaterEqual => BinaryOp::GreaterEqual,
+                    Token::LessEqual => BinaryOp::LessEqual,
+                    Token::Not => BinaryOp::Not,
+                },
+                comparison.clone(),
+            );
+            let parse_and = binary_tier(
+                parse_comparison,
+                just(Token::And).to(BinaryOp::And),
+                and.clone(),
+            );
+            // 字典合并和||同一优先级
+            let parse_or = binary_tier(
+                parse_and,
+                select! {
+                    Token::Or => BinaryOp::Or,
+                    Token::Pipe => BinaryOp::Merge,
+                },
+                or.clone(),
+            );
+
+            // 条件表达式，右结合
+            let parse_ternary = recursive(|ternary| {
+                parse_or
+                    .then(
+                        just(Token::Question)
+                            .ignore_then(expression.clone())
+                            .then_ignore(just(Token::Colon))
+                            .then(ternary)
+                            .or_not(),
+                    )
+                    .map(|(cond, branches)| match branches {
+                        Some((then, otherwise)) => Expression::Ternary {
+                            cond: Box::new(cond),
+                            then: Box::new(then),
+                            otherwise: Box::new(otherwise),
+                        },
+                        None => cond,
+                    })
+            });
+
+            // 赋值，右结合
+            let parse_assign_op = select! {
+                Token::Assign => BinaryOp::Assign,
+                Token::AddAssign => BinaryOp::AddAssign,
+                Token::SubAssign => BinaryOp::SubAssign,
+                Token::MulAssign => BinaryOp::MulAssign,
+                Token::DivAssign => BinaryOp::DivAssign,
+                Token::ModAssign => BinaryOp::ModAssign,
+                Token::PowAssign => BinaryOp::PowAssign,
+                Token::OrAssign => BinaryOp::OrAssign,
+            };
 
             // 解析表达式
-            parse_base_value
-                .then(parse_op_value.clone().or_not())
+            parse_ternary
+                .then(parse_assign_op.then(expression).or_not())
                 .map(|(hs, op)| {
                     if let Some((op, ts)) = op {
                         Expression::Binary {
@@ -356,17 +813,105 @@ where
                         hs
                     }
                 })
-        })
-        .then_ignore(just(Token::Line).or_not());
-        // 忽略tab
-        let parse_ignored_tab = just(Token::Tab)
+        });
+        let parse_expression = expression.clone().then_ignore(just(Token::Line).or_not());
+
+        // 多重赋值，`first, *rest = xs`，*变量收集两端之外剩下的元素，最多只能有一个；
+        // 右边有多个值时组成元组，例如`a, b = b, a`
+        let parse_target = just(Token::Mul)
+            .or_not()
+            .then(select! { Token::Ident(s) => Expression::Object(Object::Variable(s)) })
+            .map(|(star, target)| match star {
+                Some(_) => Expression::Unary {
+                    op: UnaryOp::Spread,
+                    hs: Box::new(target),
+                },
+                None => target,
+            });
+        let parse_destructure = parse_target
+            .clone()
+            .then(
+                just(Token::Comma)
+                    .ignore_then(parse_target)
+                    .repeated()
+                    .at_least(1)
+                    .collect::<Vec<_>>(),
+            )
+            .then_ignore(just(Token::Assign))
+            .then(
+                expression
+                    .clone()
+                    .separated_by(just(Token::Comma))
+                    .at_least(1)
+                    .collect::<Vec<_>>(),
+            )
+            .then_ignore(just(Token::Line).or_not())
+            .validate(|((first, rest), mut values), extra, emitter| {
+                let targets: Vec<_> = iter::once(first).chain(rest).collect();
+                let stars = targets
+                    .iter()
+                    .filter(|target| matches!(target, Expression::Unary { .. }))
+                    .count();
+                if stars > 1 {
+                    emitter.emit(Rich::custom(extra.span(), "多重赋值中最多只能有一个*变量"));
+                }
+                let value = if values.len() == 1 {
+                    values.remove(0)
+                } else {
+                    Expression::Object(Object::Tuple(values))
+                };
+                Command::Expression(Expression::Binary {
+                    op: BinaryOp::Assign,
+                    lhs: Box::new(Expression::Object(Object::Tuple(targets))),
+                    rhs: Box::new(value),
+                })
+            });
+        // 忽略tab，同时检查缩进方式，缩进过多时报错并按当前的块处理
+        let parse_ignored_tab = select! { Token::Tab(style) => style }
+            .map_with(|style, extra| (style, extra.span()))
             .repeated()
-            .configure(|repeated, _| repeated.exactly(indent_count.get()));
+            .configure(|repeated, _| repeated.at_least(indent_count.get()))
+            .collect::<Vec<(IndentStyle, SimpleSpan)>>()
+            .validate(move |tabs, _, emitter| {
+                let Some(&(first, first_span)) = tabs.first() else {
+                    return;
+                };
+                let whole_line = (first_span.start..tabs[tabs.len() - 1].1.end).into();
+                if tabs.len() > indent_count.get() {
+                    emitter.emit(indent_mismatch(whole_line, indent_count.get(), tabs.len()));
+                }
+                let expected = indent_style.unwrap_or(first);
+                if let Some(&(_, span)) = tabs.iter().find(|(style, _)| *style != expected) {
+                    // 没有指定缩进方式时报告整行的缩进
+                    let (span, message) = indent_style.map_or_else(
+                        || (whole_line, "同一行中混用了制表符和空格缩进".to_string()),
+                        |style| (span, format!("缩进应该使用{style}")),
+                    );
+                    emitter.emit(Rich::custom(span, message));
+                }
+            })
+            .ignored();
+        // 块的开头`:`和换行，块的第一行必须比当前多缩进一层，否则块是空的
+        let parse_block_start = just(Token::Colon)
+            .then(just(Token::Line))
+            .ignore_then(
+                select! { Token::Tab(_) => () }
+                    .repeated()
+                    .count()
+                    .then_ignore(any().or_not())
+                    .map_with(|found, extra| -> (usize, SimpleSpan) { (found, extra.span()) })
+                    .rewind(),
+            )
+            .validate(move |(found, span), _, emitter| {
+                if found <= indent_count.get() {
+                    emitter.emit(indent_mismatch(span, indent_count.get() + 1, found));
+                }
+            });
 
         // elif解析器
         let parse_elif = just(Token::Elif)
             .ignore_then(parse_expression.clone())
-            .then_ignore(just(Token::Colon).then(just(Token::Line)))
+            .then_ignore(parse_block_start.clone())
             .map(add_indent!(indent_count))
             .then(ast.clone().repeated().collect())
             .map(|(condition, commands)| {
@@ -378,7 +923,7 @@ where
             });
         // else解析器
         let parse_else = just(Token::Else)
-            .ignore_then(just(Token::Colon).then(just(Token::Line)))
+            .ignore_then(parse_block_start.clone())
             .map(add_indent!(indent_count))
             .then(ast.clone().repeated().collect::<Vec<Command>>())
             .map(|(_, commands)| {
@@ -388,7 +933,7 @@ where
         // if解析器
         let parse_if = just(Token::If)
             .ignore_then(parse_expression.clone())
-            .then_ignore(just(Token::Colon).then(just(Token::Line)))
+            .then_ignore(parse_block_start.clone())
             .map(add_indent!(indent_count))
             .then(ast.clone().repeated().collect())
             .map(|(condition, commands)| {
@@ -408,11 +953,332 @@ where
                 }
             });
 
+        // 循环标签，`outer:`
+        let parse_label = select! {
+            Token::Ident(label) => label
+        }
+        .then_ignore(just(Token::Colon))
+        .or_not();
+
+        // while解析器
+        let parse_while = parse_label
+            .clone()
+            .then_ignore(just(Token::While))
+            .then(parse_expression.clone())
+            .then_ignore(parse_block_start.clone())
+            .map(add_indent!(indent_count))
+            .then(ast.clone().repeated().collect())
+            .map(|((label, condition), command)| {
+                sub_indent!(indent_count);
+                Command::While {
+                    condition: Box::new(condition),
+                    command,
+                    label,
+                }
+            });
+
+        // for解析器
+        let parse_for = parse_label
+            .then_ignore(just(Token::For))
+            .then(select! {
+                Token::Ident(var) => var
+            })
+            .then_ignore(just(Token::In))
+            .then(parse_expression.clone())
+            .then_ignore(parse_block_start.clone())
+            .map(add_indent!(indent_count))
+            .then(ast.clone().repeated().collect())
+            .map(|(((label, var), iter), commands)| {
+                sub_indent!(indent_count);
+                Command::For {
+                    var,
+                    iter,
+                    commands,
+                    label,
+                }
+            });
+
+        // yield解析器
+        let parse_yield = just(Token::Yield)
+            .ignore_then(parse_expression.clone())
+            .map(Command::Yield);
+
+        // return解析器
+        let parse_return = just(Token::Return)
+            .ignore_then(expression.clone().or_not())
+            .then_ignore(just(Token::Line).ignored().or(end()))
+            .map(Command::Return);
+
+        // break/continue解析器，可以带循环标签
+        let loop_label = select! {
+            Token::Ident(label) => label
+        }
+        .or_not();
+        let parse_loop_control = just(Token::Break)
+            .ignore_then(loop_label)
+            .map(Command::Break)
+            .or(just(Token::Continue)
+                .ignore_then(loop_label)
+                .map(Command::Continue))
+            .then_ignore(just(Token::Line).ignored().or(end()));
+
+        // test解析器，test是上下文关键字，必须在表达式之前尝试
+        let parse_test = just(Token::Ident("test"))
+            .ignore_then(select! {
+                Token::Str(name) => name
+            })
+            .then_ignore(parse_block_start.clone())
+            .map(add_indent!(indent_count))
+            .then(ast.clone().repeated().collect())
+            .map(|(name, body)| {
+                sub_indent!(indent_count);
+                Command::Test { name, body }
+            });
+
+        // 模板定义解析器
+        let parse_template = just(Token::Template)
+            .ignore_then(select! {
+                Token::Ident(s) => s
+            })
+            .then(
+                select! {
+                    Token::Ident(s) => s
+                }
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect()
+                .delimited_by(just(Token::LeftParen), just(Token::RightParen)),
+            )
+            .then_ignore(parse_block_start.clone())
+            .map(add_indent!(indent_count))
+            .then(ast.clone().repeated().collect())
+            .map(|((name, params), body)| {
+                sub_indent!(indent_count);
+                Command::Template { name, params, body }
+            });
+
+        // 函数定义解析器
+        let parse_function = just(Token::Pub)
+            .or_not()
+            .map(|public| public.is_some())
+            .then_ignore(just(Token::Fn))
+            .then(select! {
+                Token::Ident(s) => s
+            })
+            .then(
+                select! {
+                    Token::Ident(s) => s
+                }
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect()
+                .delimited_by(just(Token::LeftParen), just(Token::RightParen)),
+            )
+            .then_ignore(parse_block_start.clone())
+            .map(add_indent!(indent_count))
+            .then(ast.clone().repeated().collect())
+            .map(|(((public, name), args), commands)| {
+                sub_indent!(indent_count);
+                Command::Function {
+                    public,
+                    name,
+                    args,
+                    commands,
+                }
+            });
+
+        // 模板调用解析器
+        let parse_invoke = select! {
+            Token::Invoke(name) => name
+        }
+        .then(
+            expression
+                .clone()
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect()
+                .delimited_by(just(Token::LeftParen), just(Token::RightParen)),
+        )
+        .then_ignore(just(Token::Line).or_not())
+        .map(|(name, args)| Command::Invoke { name, args });
+
+        // import解析器
+        let parse_import = just(Token::Import)
+            .ignore_then(select! {
+                Token::Str(path) => Command::Import(path),
+            })
+            .then_ignore(just(Token::Line).or_not());
+
+        // 状态切换解析器，状态名必须单独占一行
+        let parse_set_state = select! {
+            Token::State(name) => Command::SetState(name),
+        }
+        .then_ignore(just(Token::Line).ignored().or(end()));
+
+        // defer解析器，后面跟一条表达式语句或者状态切换
+        let parse_defer = just(Token::Defer)
+            .ignore_then(
+                parse_expression
+                    .clone()
+                    .map(Command::Expression)
+                    .or(parse_set_state.clone()),
+            )
+            .map(|command| Command::Defer(Box::new(command)));
+
+        // 状态超时解析器，timeout是上下文关键字，只能写在状态块中，必须在语句之前尝试
+        let parse_timeout = parse_ignored_tab
+            .ignore_then(just(Token::Ident("timeout")))
+            .ignore_then(expression.clone())
+            .then_ignore(just(Token::Arrow))
+            .then(select! {
+                Token::State(state) => state,
+            })
+            .then_ignore(just(Token::Line).ignored().or(end()))
+            .map(|(duration, state)| StateItem::Timeout(Timeout { duration, state }));
+
+        // 事件处理解析器，on也是只能写在状态块中的上下文关键字
+        let parse_handler = parse_ignored_tab
+            .ignore_then(just(Token::Ident("on")))
+            .ignore_then(select! {
+                Token::Str(event) => event
+            })
+            .then_ignore(parse_block_start.clone())
+            .map(add_indent!(indent_count))
+            .then(ast.clone().repeated().collect())
+            .map(|(event, commands)| {
+                sub_indent!(indent_count);
+                StateItem::Handler(Handler { event, commands })
+            });
+
+        // 状态块解析器，extends是上下文关键字
+        let parse_state_block = select! {
+            Token::State(name) => name,
+        }
+        .then(
+            just(Token::Ident("extends"))
+                .ignore_then(select! {
+                    Token::State(parent) => parent,
+                })
+                .or_not(),
+        )
+        .then_ignore(parse_block_start.clone())
+        .map(add_indent!(indent_count))
+        .then(
+            parse_timeout
+                .or(parse_handler)
+                .or(ast.clone().map(StateItem::Command))
+                .repeated()
+                .collect::<Vec<_>>(),
+        )
+        .validate(|((name, parent), items), extra, emitter| {
+            sub_indent!(indent_count);
+            let mut timeout = None;
+            let mut handlers: Vec<Handler> = vec![];
+            let mut commands = vec![];
+            for item in items {
+                match item {
+                    StateItem::Command(command) => commands.push(command),
+                    StateItem::Timeout(clause) => {
+                        if timeout.replace(clause).is_some() {
+                            emitter.emit(Rich::custom(
+                                extra.span(),
+                                format!("状态{name}中有多个timeout"),
+                            ));
+                        }
+                    }
+                    StateItem::Handler(handler) => {
+                        if handlers.iter().any(|other| other.event == handler.event) {
+                            emitter.emit(Rich::custom(
+                                extra.span(),
+                                format!("状态{name}中重复处理事件{:?}", handler.event),
+                            ));
+                        }
+                        handlers.push(handler);
+                    }
+                }
+            }
+            Command::StateBlock {
+                name,
+                parent,
+                timeout,
+                handlers,
+                commands,
+            }
+        });
+
+        // with解析器
+        let parse_with = just(Token::With)
+            .ignore_then(parse_expression.clone())
+            .then_ignore(just(Token::As))
+            .then(select! {
+                Token::Ident(s) => s
+            })
+            .then_ignore(parse_block_start.clone())
+            .map(add_indent!(indent_count))
+            .then(ast.clone().repeated().collect())
+            .map(|((expr, binding), body)| {
+                sub_indent!(indent_count);
+                Command::With {
+                    expr,
+                    binding,
+                    body,
+                }
+            });
+
         parse_ignored_tab
-            .ignore_then(parse_empty.or(parse_expression.map(Command::Expression).or(parse_if)))
+            .ignore_then(
+                parse_empty
+                    .or(parse_test)
+                    // 循环标签看起来像变量，循环必须在表达式之前尝试
+                    .or(parse_while)
+                    .or(parse_for)
+                    .or(parse_destructure)
+                    .or(parse_expression.map(Command::Expression))
+                    .or(parse_if)
+                    .or(parse_function)
+                    .or(parse_defer)
+                    .or(parse_yield)
+                    .or(parse_return)
+                    .or(parse_loop_control)
+                    .or(parse_import)
+                    .or(parse_set_state)
+                    .or(parse_state_block)
+                    .or(parse_with)
+                    .or(parse_template)
+                    .or(parse_invoke),
+            )
+            .map_with(move |command, extra| {
+                budget.charge_statement(&command, extra.span());
+                command
+            })
+    })
+    // 出错时跳过这一行，留下占位行继续解析
+    .recover_with(via_parser(
+        any()
+            .and_is(just(Token::Line).not())
+            .repeated()
+            .at_least(1)
+            .then(just(Token::Line).ignored().or(end()))
+            .to(Command::NewLine),
+    ))
+    .map_with(|node, extra| Spanned {
+        node,
+        span: extra.span(),
     })
     .repeated()
-    .collect()
+    .collect::<Vec<_>>()
+    // 同一个文件中不能有同名的状态块
+    .validate(|commands, _, emitter| {
+        let mut states = HashSet::new();
+        for command in &commands {
+            if let Command::StateBlock { name, .. } = command.node
+                && !states.insert(name)
+            {
+                emitter.emit(Rich::custom(command.span, format!("重复定义的状态{name}")));
+            }
+        }
+        commands
+    })
 }
 
 #[cfg(test)]
@@ -420,19 +1286,21 @@ mod tests {
     use std::cell::Cell;
 
     use chumsky::{
-        input::{Input, Stream},
         Parser,
+        input::{Input, Stream},
     };
     use logos::Logos;
 
-    use crate::parser::tokenizer::Token;
+    use crate::parser::{limit::Budget, parse, parse_with_recovery, tokenizer::Token};
 
-    use super::build_ast;
+    use rust_decimal::Decimal;
+
+    use super::{BinaryOp, Branch, Command, Expression, FormatSpec, Object, UnaryOp, build_ast};
 
     #[test]
     fn test_build_ast() {
         let lex = r#"
-if Query<Dog|Cat&Pig>:
+if Query<Dog|Cat|!Pig>:
     1+2
 elif 1:
     1
@@ -440,39 +1308,874 @@ elif 1:
 "#;
         let token_sequence = Token::lexer(lex)
             .spanned()
-            .map(|(token_result, span)| {
-                let token = token_result.unwrap();
-                print!("{:?}\t", token);
-                (token, span.into())
-            })
+            .map(|(token_result, span)| (token_result.unwrap(), span.into()))
             .collect::<Vec<_>>();
 
         // Construct a token stream suitable for the parser
         let end_pos = lex.len();
-        let token_stream = Stream::from_iter(token_sequence).spanned((end_pos..end_pos).into());
+        let token_stream =
+            Stream::from_iter(token_sequence).map((end_pos..end_pos).into(), |(t, s)| (t, s));
         let indent_count = Cell::new(0);
         // Attempt to parse the token stream into an abstract syntax tree (AST)
-        let ast = build_ast(&indent_count)
+        let ast = build_ast(&indent_count, None, &[], &Budget::default())
             .parse(token_stream)
             .into_result()
-            .map_err(|parse_errors| format!("Parsing error: {:?}", parse_errors));
-        println!("{:#?}", ast);
+            .unwrap();
+        let num = |n: i64| Expression::Object(Object::Constant(Decimal::from(n)));
+        // 开头和结尾的空行各是一个占位行
+        assert_eq!(
+            ast.into_iter().map(|c| c.node).collect::<Vec<_>>(),
+            [
+                Command::NewLine,
+                Command::If {
+                    if_branch: vec![
+                        Branch {
+                            condition: Expression::Query {
+                                with_compoents: vec!["Dog", "Cat"],
+                                without_compoents: vec!["Pig"],
+                                hierarchy: None,
+                                order_by: None,
+                                limit: None,
+                            },
+                            commands: vec![Command::Expression(Expression::Binary {
+                                op: BinaryOp::Add,
+                                lhs: Box::new(num(1)),
+                                rhs: Box::new(num(2)),
+                            })],
+                        },
+                        Branch {
+                            condition: num(1),
+                            commands: vec![Command::Expression(num(1))],
+                        },
+                    ],
+                    else_branch: None,
+                },
+                Command::NewLine,
+            ]
+        );
+        assert_eq!(indent_count.get(), 0);
+    }
+
+    #[test]
+    fn test_defer() {
+        let ast = parse("defer close(door)\nopen(door)\n").unwrap();
+        assert!(matches!(
+            &ast[0],
+            Command::Defer(command) if matches!(
+                command.as_ref(),
+                Command::Expression(Expression::Binary { op: BinaryOp::Call, .. })
+            )
+        ));
+        assert!(matches!(&ast[1], Command::Expression(_)));
+
+        let ast = parse("defer @idle\n").unwrap();
+        assert!(matches!(
+            &ast[0],
+            Command::Defer(command) if **command == Command::SetState("idle")
+        ));
+    }
+
+    #[test]
+    fn test_function() {
+        let ast = parse("fn tick():\n    attack()\nfn on_hit(damage):\n    hp = hp - damage\npub fn move_to(x, y):\n    pos = [x, y]\n").unwrap();
+        let signatures = ast
+            .iter()
+            .map(|command| match command {
+                Command::Function {
+                    public,
+                    name,
+                    args,
+                    commands,
+                } => (*public, *name, args.clone(), commands.len()),
+                _ => panic!("expected function, got {command:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            signatures,
+            [
+                (false, "tick", vec![], 1),
+                (false, "on_hit", vec!["damage"], 1),
+                (true, "move_to", vec!["x", "y"], 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_state() {
+        let ast = parse("if hp < 10:\n    @flee\n@patrol").unwrap();
+        let Command::If { if_branch, .. } = &ast[0] else {
+            panic!("expected if, got {:?}", ast[0]);
+        };
+        assert_eq!(if_branch[0].commands, [Command::SetState("flee")]);
+        assert_eq!(ast[1], Command::SetState("patrol"));
+        assert!(parse("@patrol attack()\n").is_err());
+    }
+
+    #[test]
+    fn test_state_block() {
+        let ast = parse(
+            "@patrol:\n    move_to(next_waypoint)\n    if enemy:\n        @chase\n@chase:\n    move_to(enemy.pos)\n",
+        )
+        .unwrap();
+        let states = ast
+            .iter()
+            .map(|command| match command {
+                Command::StateBlock { name, commands, .. } => (*name, commands.len()),
+                _ => panic!("expected state block, got {command:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(states, [("patrol", 2), ("chase", 1)]);
+
+        let src = "@patrol:\n    wait()\n@patrol:\n    run()\n";
+        let (_, errors) = parse_with_recovery(src);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "重复定义的状态patrol");
+        assert_eq!(errors[0].span().start, 20);
+    }
+
+    #[test]
+    fn test_state_timeout() {
+        let ast = parse("@alert:\n    timeout 2s -> @patrol\n    look()\n").unwrap();
+        let Command::StateBlock {
+            name,
+            timeout: Some(timeout),
+            commands,
+            ..
+        } = &ast[0]
+        else {
+            panic!("expected state block with timeout, got {:?}", ast[0]);
+        };
+        assert_eq!(*name, "alert");
+        assert_eq!(
+            timeout.duration,
+            Expression::Object(Object::Quantity(Decimal::TWO, "s"))
+        );
+        assert_eq!(timeout.state, "patrol");
+        assert_eq!(commands.len(), 1);
+        // timeout是上下文关键字，状态块之外仍然可以作为变量名
+        assert!(parse("timeout = 3\n@idle:\n    timeout += 1\n").is_ok());
+        assert!(parse("timeout 2s -> @patrol\n").is_err());
+
+        let src = "@alert:\n    timeout 1 -> @a\n    timeout 2 -> @b\n";
+        let (_, errors) = parse_with_recovery(src);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "状态alert中有多个timeout");
+    }
+
+    #[test]
+    fn test_state_handlers() {
+        let ast =
+            parse("@guard:\n    on \"Attacked\":\n        @flee\n    look()\n    on \"exit\":\n        x = 1\n")
+                .unwrap();
+        let Command::StateBlock {
+            handlers, commands, ..
+        } = &ast[0]
+        else {
+            panic!("expected state block, got {:?}", ast[0]);
+        };
+        let events = handlers
+            .iter()
+            .map(|handler| (handler.event.as_ref(), handler.commands.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(events, [("Attacked", 1), ("exit", 1)]);
+        assert_eq!(commands.len(), 1);
+        assert!(parse("on \"Attacked\":\n    x = 1\n").is_err());
+
+        let src = "@guard:\n    on \"a\":\n        x = 1\n    on \"a\":\n        x = 2\n";
+        let (_, errors) = parse_with_recovery(src);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "状态guard中重复处理事件\"a\"");
+    }
+
+    #[test]
+    fn test_state_extends() {
+        let ast = parse("@combat:\n    fight()\n@attack extends @combat:\n    strike()\n").unwrap();
+        let parents = ast
+            .iter()
+            .map(|command| match command {
+                Command::StateBlock { name, parent, .. } => (*name, *parent),
+                _ => panic!("expected state block, got {command:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(parents, [("combat", None), ("attack", Some("combat"))]);
+        assert!(parse("@attack extends combat:\n    strike()\n").is_err());
+    }
+
+    #[test]
+    fn test_return() {
+        let ast =
+            parse("fn check(hp):\n    if hp > 0:\n        return\n    return hp * 2\n").unwrap();
+        let Command::Function { commands, .. } = &ast[0] else {
+            panic!("expected function, got {:?}", ast[0]);
+        };
+        let Command::If { if_branch, .. } = &commands[0] else {
+            panic!("expected if, got {:?}", commands[0]);
+        };
+        assert_eq!(if_branch[0].commands, [Command::Return(None)]);
+        assert!(matches!(
+            &commands[1],
+            Command::Return(Some(Expression::Binary {
+                op: BinaryOp::Mul,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_loop_control() {
+        let ast = parse("while 1:\n    if done:\n        break\n    continue\n").unwrap();
+        let Command::While { command, .. } = &ast[0] else {
+            panic!("expected while, got {:?}", ast[0]);
+        };
+        let Command::If { if_branch, .. } = &command[0] else {
+            panic!("expected if, got {:?}", command[0]);
+        };
+        assert_eq!(if_branch[0].commands, [Command::Break(None)]);
+        assert_eq!(command[1], Command::Continue(None));
+        assert!(parse("break 1\n").is_err());
+    }
+
+    #[test]
+    fn test_loop_label() {
+        let ast = parse(
+            "outer: for x in xs:\n    inner: while 1:\n        break outer\n    continue outer\n",
+        )
+        .unwrap();
+        let Command::For {
+            commands, label, ..
+        } = &ast[0]
+        else {
+            panic!("expected for, got {:?}", ast[0]);
+        };
+        assert_eq!(*label, Some("outer"));
+        let Command::While { command, label, .. } = &commands[0] else {
+            panic!("expected while, got {:?}", commands[0]);
+        };
+        assert_eq!(*label, Some("inner"));
+        assert_eq!(command, &[Command::Break(Some("outer"))]);
+        assert_eq!(commands[1], Command::Continue(Some("outer")));
+    }
+
+    #[test]
+    fn test_for() {
+        let ast = parse(
+            "for e in Query<Enemy>:
+    attack(e)
+flee()
+",
+        )
+        .unwrap();
+        let Command::For {
+            var,
+            iter,
+            commands,
+            label: None,
+        } = &ast[0]
+        else {
+            panic!("expected for, got {:?}", ast[0]);
+        };
+        assert_eq!(*var, "e");
+        assert!(matches!(iter, Expression::Query { .. }));
+        assert_eq!(commands.len(), 1);
+        assert!(matches!(&ast[1], Command::Expression(_)));
+    }
+
+    #[test]
+    fn test_yield() {
+        let ast = parse("if ready:\n    yield target\nyield 1 + 2\n").unwrap();
+        let Command::If { if_branch, .. } = &ast[0] else {
+            panic!("expected if, got {:?}", ast[0]);
+        };
+        assert!(matches!(
+            if_branch[0].commands[0],
+            Command::Yield(Expression::Object(Object::Variable("target")))
+        ));
+        assert!(matches!(
+            ast[1],
+            Command::Yield(Expression::Binary {
+                op: BinaryOp::Add,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_query_order_by_limit() {
+        let ast = parse("targets = Query<Enemy> order_by dist limit 3\n").unwrap();
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[0] else {
+            panic!("expected assignment, got {:?}", ast[0]);
+        };
+        let Expression::Query {
+            with_compoents,
+            order_by: Some(order_by),
+            limit: Some(limit),
+            ..
+        } = rhs.as_ref()
+        else {
+            panic!("expected query with clauses, got {rhs:?}");
+        };
+        assert_eq!(with_compoents, &["Enemy"]);
+        assert!(matches!(
+            order_by.as_ref(),
+            Expression::Object(Object::Variable("dist"))
+        ));
+        assert!(matches!(
+            limit.as_ref(),
+            Expression::Object(Object::Constant(n)) if *n == Decimal::from(3)
+        ));
+
+        let ast = parse("Query<Enemy>\n").unwrap();
+        assert!(matches!(
+            &ast[0],
+            Command::Expression(Expression::Query {
+                hierarchy: None,
+                order_by: None,
+                limit: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_query_of() {
+        let ast = parse("for_each(Query<Turret> of vehicle limit 2)\n").unwrap();
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[0] else {
+            panic!("expected call, got {:?}", ast[0]);
+        };
+        let Expression::Object(Object::Tuple(args)) = rhs.as_ref() else {
+            panic!("expected arguments, got {rhs:?}");
+        };
+        let Expression::Query {
+            with_compoents,
+            hierarchy: Some(hierarchy),
+            limit: Some(_),
+            ..
+        } = &args[0]
+        else {
+            panic!("expected query of parent, got {:?}", args[0]);
+        };
+        assert_eq!(with_compoents, &["Turret"]);
+        assert!(matches!(
+            hierarchy.as_ref(),
+            Expression::Object(Object::Variable("vehicle"))
+        ));
+    }
+
+    #[test]
+    fn test_query_components() {
+        fn query(src: &str) -> (Vec<&str>, Vec<&str>) {
+            match parse(src).unwrap().remove(0) {
+                Command::Expression(Expression::Query {
+                    with_compoents,
+                    without_compoents,
+                    ..
+                }) => (with_compoents, without_compoents),
+                command => panic!("expected query, got {command:?}"),
+            }
+        }
+        // |分隔的组件都必须有，!开头的组件必须没有
+        assert_eq!(
+            query("Query<Dog|Cat|!Pig>\n"),
+            (vec!["Dog", "Cat"], vec!["Pig"])
+        );
+        assert_eq!(query("Query<!Pig|Dog>\n"), (vec!["Dog"], vec!["Pig"]));
+        assert_eq!(query("Query<>\n"), (vec![], vec![]));
+        // 排除多个组件
+        assert_eq!(
+            query("Query<Enemy, !Dead, !Frozen>\n"),
+            (vec!["Enemy"], vec!["Dead", "Frozen"])
+        );
+        assert_eq!(
+            query("Query<Enemy | Health | !Dead | !Frozen>\n"),
+            (vec!["Enemy", "Health"], vec!["Dead", "Frozen"])
+        );
+
+        let errors = parse("Query<Dog&Pig>\n").unwrap_err();
+        assert!(
+            errors[0]
+                .to_string()
+                .contains("查询中的组件用|或者,分隔，排除组件写作!组件")
+        );
+    }
+
+    #[test]
+    fn test_query_errors() {
+        let messages = |src: &str| {
+            let (_, errors) = parse_with_recovery(src);
+            errors.iter().map(ToString::to_string).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages("x = Query<Dog Pig>\n"),
+            ["14..17: 查询组件Pig前面缺少分隔符|或者,"]
+        );
+        assert_eq!(messages("x = Query<Dog|>\n"), ["14..15: 查询中缺少组件"]);
+        assert_eq!(messages("x = Query<Dog|1>\n"), ["14..15: 查询中不能出现1"]);
+        // 查询中的错误不影响后面语句的解析和报错
+        let errors = messages("x = Query<Dog&Pig>\ny = Query<Cat|>\nz = 1\n");
+        assert_eq!(
+            errors,
+            [
+                "13..14: 查询中的组件用|或者,分隔，排除组件写作!组件",
+                "33..34: 查询中缺少组件"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_color() {
+        let ast = parse("draw_line(a, b, #ff8000) # 橙色\n").unwrap();
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[0] else {
+            panic!("expected call, got {:?}", ast[0]);
+        };
+        assert!(matches!(
+            rhs.as_ref(),
+            Expression::Object(Object::Tuple(args))
+                if args[2] == Expression::Object(Object::Color([255, 128, 0, 255]))
+        ));
+    }
+
+    #[test]
+    fn test_test_block() {
+        let ast =
+            parse("test \"flee when hurt\":\n    hp = 1\n    assert(hp == 1)\ntest = 1\n").unwrap();
+        let Command::Test { name, body } = &ast[0] else {
+            panic!("expected test block, got {:?}", ast[0]);
+        };
+        assert_eq!(*name, "flee when hurt");
+        assert_eq!(body.len(), 2);
+        // 其他地方test仍然是普通的标识符
+        assert!(matches!(
+            &ast[1],
+            Command::Expression(Expression::Binary { lhs, .. })
+                if **lhs == Expression::Object(Object::Variable("test"))
+        ));
+    }
+
+    #[test]
+    fn test_spread_if() {
+        let ast = parse("items = [base, *if has_bonus then bonus]\n").unwrap();
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[0] else {
+            panic!("expected assignment, got {:?}", ast[0]);
+        };
+        let Expression::Object(Object::Array(items)) = rhs.as_ref() else {
+            panic!("expected array, got {rhs:?}");
+        };
+        assert_eq!(
+            items[1],
+            Expression::SpreadIf {
+                condition: Box::new(Expression::Object(Object::Variable("has_bonus"))),
+                value: Box::new(Expression::Object(Object::Variable("bonus"))),
+            }
+        );
+    }
+
+    #[test]
+    fn test_fstring() {
+        let ast = parse("log(f\"hp is {entity.health}, {f'{n}'}\")\n").unwrap();
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[0] else {
+            panic!("expected call, got {:?}", ast[0]);
+        };
+        let Expression::Object(Object::Tuple(args)) = rhs.as_ref() else {
+            panic!("expected arguments, got {rhs:?}");
+        };
+        let Expression::Object(Object::FString(parts)) = &args[0] else {
+            panic!("expected f-string, got {:?}", args[0]);
+        };
+        assert_eq!(parts[0], Expression::Object(Object::Str("hp is ".into())));
+        assert!(matches!(
+            parts[1],
+            Expression::Binary {
+                op: BinaryOp::Dot,
+                ..
+            }
+        ));
+        assert_eq!(parts[2], Expression::Object(Object::Str(", ".into())));
+        // 内嵌表达式中还可以有f-string
+        assert_eq!(
+            parts[3],
+            Expression::Object(Object::FString(vec![Expression::Object(Object::Variable(
+                "n"
+            ))]))
+        );
+    }
+
+    #[test]
+    // 测试的是f-string的格式，不是Rust的格式字符串
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn test_fstring_spec() {
+        let ast = parse("f\"{hp:.2}\"\n").unwrap();
+        assert_eq!(
+            ast,
+            [Command::Expression(Expression::Object(Object::FString(
+                vec![Expression::Format {
+                    value: Box::new(Expression::Object(Object::Variable("hp"))),
+                    spec: FormatSpec {
+                        zero: false,
+                        width: 0,
+                        precision: Some(2)
+                    }
+                }]
+            )))]
+        );
+    }
+
+    #[test]
+    fn test_bool() {
+        let ast = parse("if true:\n    alert = false\n").unwrap();
+        let Command::If { if_branch, .. } = &ast[0] else {
+            panic!("expected if, got {:?}", ast[0]);
+        };
+        assert_eq!(
+            if_branch[0].condition,
+            Expression::Object(Object::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_while() {
+        let ast =
+            parse("while hp > 0:\n    if enemy:\n        attack()\n    hp = hp - 1\nflee()\n")
+                .unwrap();
+        let Command::While {
+            condition, command, ..
+        } = &ast[0]
+        else {
+            panic!("expected while, got {:?}", ast[0]);
+        };
+        assert!(matches!(
+            condition.as_ref(),
+            Expression::Binary {
+                op: BinaryOp::Greater,
+                ..
+            }
+        ));
+        assert_eq!(command.len(), 2);
+        assert!(matches!(command[0], Command::If { .. }));
+        assert!(matches!(&ast[1], Command::Expression(_)));
+    }
+
+    #[test]
+    fn test_with() {
+        let ast = parse("with target as t:\n    attack(t)\nprint(t)\n").unwrap();
+        let Command::With {
+            expr,
+            binding,
+            body,
+        } = &ast[0]
+        else {
+            panic!("expected with block, got {:?}", ast[0]);
+        };
+        assert_eq!(expr, &Expression::Object(Object::Variable("target")));
+        assert_eq!(*binding, "t");
+        assert_eq!(body.len(), 1);
+        assert!(matches!(&ast[1], Command::Expression(_)));
+    }
+
+    #[test]
+    fn test_transform() {
+        let ast = parse("pos = turret @ offset\n").unwrap();
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[0] else {
+            panic!("expected assignment, got {:?}", ast[0]);
+        };
+        assert!(matches!(
+            rhs.as_ref(),
+            Expression::Binary {
+                op: BinaryOp::Transform,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let num = |n: i64| Expression::Object(Object::Constant(Decimal::from(n)));
+        let var = |name| Expression::Object(Object::Variable(name));
+        let binary = |op, lhs, rhs| Expression::Binary {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        };
+
+        let ast =
+            parse("1 + 2 * 3\na = b = c\nok = hp > 0 && !dead || f(x).y == 2 ^ 3 ^ 2\n").unwrap();
+        assert_eq!(
+            ast[0],
+            Command::Expression(binary(
+                BinaryOp::Add,
+                num(1),
+                binary(BinaryOp::Mul, num(2), num(3))
+            ))
+        );
+        assert_eq!(
+            ast[1],
+            Command::Expression(binary(
+                BinaryOp::Assign,
+                var("a"),
+                binary(BinaryOp::Assign, var("b"), var("c"))
+            ))
+        );
+        let call = binary(
+            BinaryOp::Call,
+            var("f"),
+            Expression::Object(Object::Tuple(vec![var("x")])),
+        );
+        assert_eq!(
+            ast[2],
+            Command::Expression(binary(
+                BinaryOp::Assign,
+                var("ok"),
+                binary(
+                    BinaryOp::Or,
+                    binary(
+                        BinaryOp::And,
+                        binary(BinaryOp::Greater, var("hp"), num(0)),
+                        Expression::Unary {
+                            op: UnaryOp::Not,
+                            hs: Box::new(var("dead")),
+                        }
+                    ),
+                    binary(
+                        BinaryOp::Equal,
+                        binary(BinaryOp::Dot, call, var("y")),
+                        binary(BinaryOp::Pow, num(2), binary(BinaryOp::Pow, num(3), num(2)))
+                    )
+                )
+            ))
+        );
+    }
+
+    #[test]
+    fn test_left_associative() {
+        let num = |n: i64| Expression::Object(Object::Constant(Decimal::from(n)));
+        let binary = |op, lhs, rhs| Expression::Binary {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        };
+        let ast = parse("10 - 3 - 2\n8 / 4 % 3\n").unwrap();
+        assert_eq!(
+            ast[0],
+            Command::Expression(binary(
+                BinaryOp::Sub,
+                binary(BinaryOp::Sub, num(10), num(3)),
+                num(2)
+            ))
+        );
+        assert_eq!(
+            ast[1],
+            Command::Expression(binary(
+                BinaryOp::Mod,
+                binary(BinaryOp::Div, num(8), num(4)),
+                num(3)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_dict() {
+        let ast = parse("a = {}\nb = {'hp': 10}\n").unwrap();
+        let dicts = ast
+            .iter()
+            .map(|command| match command {
+                Command::Expression(Expression::Binary { rhs, .. }) => rhs.as_ref().clone(),
+                _ => panic!("expected assignment, got {command:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dicts,
+            [
+                Expression::Object(Object::Dict(vec![])),
+                Expression::Object(Object::Dict(vec![Expression::Object(Object::DictItem(
+                    Box::new(Expression::Object(Object::Str("hp".into()))),
+                    Box::new(Expression::Object(Object::Constant(Decimal::from(10)))),
+                ))]))
+            ]
+        );
+
+        let src = "c = {'hp': 10, speed}\n";
+        let (_, errors) = parse_with_recovery(src);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(&src[errors[0].span()], "speed");
+        assert_eq!(errors[0].message(), "字典中只能是键值对(key: value)");
+    }
+
+    #[test]
+    fn test_priority() {
+        let num = |n: i64| Expression::Object(Object::Constant(Decimal::from(n)));
+        let binary = |op, lhs, rhs| Expression::Binary {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        };
+        let ast = parse("(1 + 2) * 3\n(x,)\n").unwrap();
+        assert_eq!(
+            ast[0],
+            Command::Expression(binary(
+                BinaryOp::Mul,
+                Expression::Priority(Box::new(binary(BinaryOp::Add, num(1), num(2)))),
+                num(3)
+            ))
+        );
+        assert_eq!(
+            ast[1],
+            Command::Expression(Expression::Object(Object::Tuple(vec![Expression::Object(
+                Object::Variable("x")
+            )])))
+        );
+    }
+
+    #[test]
+    fn test_ternary() {
+        let var = |name| Box::new(Expression::Object(Object::Variable(name)));
+        let ast = parse("speed = low ? slow : fast\nx = a ? b : c ? d : e\ny = f()?\n").unwrap();
+        let rhs = |command: &Command<'static>| match command {
+            Command::Expression(Expression::Binary { rhs, .. }) => rhs.as_ref().clone(),
+            _ => panic!("expected assignment, got {command:?}"),
+        };
+        assert_eq!(
+            rhs(&ast[0]),
+            Expression::Ternary {
+                cond: var("low"),
+                then: var("slow"),
+                otherwise: var("fast"),
+            }
+        );
+        assert_eq!(
+            rhs(&ast[1]),
+            Expression::Ternary {
+                cond: var("a"),
+                then: var("b"),
+                otherwise: Box::new(Expression::Ternary {
+                    cond: var("c"),
+                    then: var("d"),
+                    otherwise: var("e"),
+                }),
+            }
+        );
+        assert!(matches!(
+            rhs(&ast[2]),
+            Expression::Unary {
+                op: UnaryOp::Question,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_increment() {
+        let ast = parse("i++\nenemy.hp--\n").unwrap();
+        assert_eq!(
+            ast[0],
+            Command::Expression(Expression::Unary {
+                op: UnaryOp::Increment,
+                hs: Box::new(Expression::Object(Object::Variable("i"))),
+            })
+        );
+        assert!(matches!(
+            &ast[1],
+            Command::Expression(Expression::Unary {
+                op: UnaryOp::Decrement,
+                hs,
+            }) if matches!(hs.as_ref(), Expression::Binary { op: BinaryOp::Dot, .. })
+        ));
+        assert!(parse("x++ ++\n").is_err());
+    }
+
+    #[test]
+    fn test_destructure() {
+        let var = |name| Expression::Object(Object::Variable(name));
+        let ast = parse("first, *middle, last = [1, 2, 3, 4]\na, b = b, a\n").unwrap();
+        let Command::Expression(Expression::Binary {
+            op: BinaryOp::Assign,
+            lhs,
+            rhs,
+        }) = &ast[0]
+        else {
+            panic!("expected assignment, got {:?}", ast[0]);
+        };
+        assert_eq!(
+            lhs.as_ref(),
+            &Expression::Object(Object::Tuple(vec![
+                var("first"),
+                Expression::Unary {
+                    op: UnaryOp::Spread,
+                    hs: Box::new(var("middle")),
+                },
+                var("last"),
+            ]))
+        );
+        assert!(matches!(rhs.as_ref(), Expression::Object(Object::Array(_))));
+        // 右边的多个值组成元组
+        assert!(matches!(
+            &ast[1],
+            Command::Expression(Expression::Binary { rhs, .. })
+                if rhs.as_ref() == &Expression::Object(Object::Tuple(vec![var("b"), var("a")]))
+        ));
+        let errors = parse("*a, b, *c = xs\n").unwrap_err();
+        assert_eq!(errors[0].message(), "多重赋值中最多只能有一个*变量");
+    }
+
+    #[test]
+    fn test_range() {
+        let num = |n: i64| Box::new(Expression::Object(Object::Constant(Decimal::from(n))));
+        let ast = parse("0..10\n0..=10\n10..0\nfor i in 0..n+1:\n    i\n").unwrap();
+        assert_eq!(
+            ast[..3],
+            [(0, 10, false), (0, 10, true), (10, 0, false)].map(|(start, end, inclusive)| {
+                Command::Expression(Expression::Range {
+                    start: num(start),
+                    end: num(end),
+                    inclusive,
+                })
+            })
+        );
+        let Command::For { iter, .. } = &ast[3] else {
+            panic!("expected for, got {:?}", ast[3]);
+        };
+        assert!(matches!(
+            iter,
+            Expression::Range { end, inclusive: false, .. }
+                if matches!(end.as_ref(), Expression::Binary { op: BinaryOp::Add, .. })
+        ));
+    }
+
+    #[test]
+    fn test_dict_merge() {
+        let ast = parse("merged = defaults | {'hp': 10}\nconfig |= overrides\n").unwrap();
+        let Command::Expression(Expression::Binary { rhs, .. }) = &ast[0] else {
+            panic!("expected assignment, got {:?}", ast[0]);
+        };
+        assert!(matches!(
+            rhs.as_ref(),
+            Expression::Binary {
+                op: BinaryOp::Merge,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &ast[1],
+            Command::Expression(Expression::Binary {
+                op: BinaryOp::OrAssign,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_import() {
+        let ast = parse("import \"ai/common.bd\"\ninclude 'ai/combat.bd'\n").unwrap();
+        assert_eq!(ast[0], Command::Import("ai/common.bd".into()));
+        assert_eq!(ast[1], Command::Import("ai/combat.bd".into()));
     }
 }
 
 #[cfg(test)]
 mod test22 {
-    use std::{
-        cell::{Cell, RefCell},
-        rc::Rc,
-    };
+    use std::cell::Cell;
 
-    use chumsky::{
-        error::{EmptyErr, Rich, Simple},
-        extra::{self, State},
-        prelude::{just, one_of},
-        select, text, ConfigIterParser, ConfigParser, IterParser, Parser,
-    };
+    use chumsky::{ConfigIterParser, Parser, extra, prelude::just};
+
+    /// `configure`在解析时才读取缩进层数，前面的解析器修改之后后面立刻生效
     #[test]
     fn hahaha() {
         let indent_count = Cell::new(0);
@@ -487,6 +2190,12 @@ mod test22 {
             .or_not()
             .then(generic.with_ctx(()));
 
-        println!("{:#?}", parse.parse(b"b00").into_result());
+        assert_eq!(
+            parse.parse(b"b00".as_slice()).into_result(),
+            Ok((Some(b'b'), ()))
+        );
+        assert!(parse.parse(b"b0".as_slice()).has_errors());
+        indent_count.set(0);
+        assert_eq!(parse.parse(b"".as_slice()).into_result(), Ok((None, ())));
     }
 }
@@ -0,0 +1,272 @@
+//! 校验脚本是否满足宿主要求的接口
+//!
+//! 嵌入到特定AI槽位的脚本(比如"攻击行为")需要定义若干固定的函数和状态块，
+//! 宿主可以用[`Schema`]描述它们，在加载脚本时调用[`validate`]检查，
+//! 另外可以用[`validate_components`]检查查询中用到的组件是否都已经注册
+
+use std::{collections::HashSet, fmt, ops::Range};
+
+use super::{
+    error::span_in,
+    scanner::{Command, Expression},
+    visit::{Visitor, walk_commands, walk_expression},
+};
+
+/// 要求脚本定义的函数签名
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionSignature {
+    pub name: String,
+    /// 参数个数
+    pub arity: usize,
+}
+
+impl FunctionSignature {
+    pub fn new(name: impl ToString, arity: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            arity,
+        }
+    }
+}
+
+/// 脚本需要满足的接口
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    /// 必须定义的顶层函数
+    pub functions: Vec<FunctionSignature>,
+    /// 必须定义的顶层状态块(不带@)
+    pub states: Vec<String>,
+}
+
+/// 校验失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaError {
+    /// 缺少函数
+    MissingFunction(String),
+    /// 函数的参数个数不对
+    WrongArity {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// 缺少状态块
+    MissingState(String),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingFunction(name) => write!(f, "缺少函数{name}"),
+            Self::WrongArity {
+                name,
+                expected,
+                found,
+            } => write!(f, "函数{name}需要{expected}个参数，实际定义了{found}个"),
+            Self::MissingState(name) => write!(f, "缺少状态块@{name}"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// 检查语法树中的顶层函数和状态块是否满足接口要求，返回所有不满足的地方
+pub fn validate(ast: &[Command<'_>], schema: &Schema) -> Result<(), Vec<SchemaError>> {
+    let mut errors = vec![];
+    for signature in &schema.functions {
+        let args = ast.iter().find_map(|command| match command {
+            Command::Function { name, args, .. } if *name == signature.name => Some(args),
+            _ => None,
+        });
+        match args {
+            None => errors.push(SchemaError::MissingFunction(signature.name.clone())),
+            Some(args) if args.len() != signature.arity => errors.push(SchemaError::WrongArity {
+                name: signature.name.clone(),
+                expected: signature.arity,
+                found: args.len(),
+            }),
+            Some(_) => {}
+        }
+    }
+    for state in &schema.states {
+        let defined = ast
+            .iter()
+            .any(|command| matches!(command, Command::StateBlock { name, .. } if name == state));
+        if !defined {
+            errors.push(SchemaError::MissingState(state.clone()));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// 查询中引用了没有注册的组件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownComponent {
+    pub name: String,
+    /// 组件名在源码中的位置，组件名不是从源码中借用的(例如语法树不是从这份源码解析出来的)时为None
+    pub span: Option<Range<usize>>,
+}
+
+impl fmt::Display for UnknownComponent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(span) = &self.span {
+            write!(f, "{}..{}: ", span.start, span.end)?;
+        }
+        write!(f, "未注册的组件{}", self.name)
+    }
+}
+
+impl std::error::Error for UnknownComponent {}
+
+/// 收集查询中没有注册的组件
+struct ComponentChecker<'c> {
+    src: &'c str,
+    components: &'c HashSet<&'c str>,
+    errors: Vec<UnknownComponent>,
+}
+
+impl<'a> Visitor<'a> for ComponentChecker<'_> {
+    fn visit_expression(&mut self, expression: &Expression<'a>) {
+        if let Expression::Query {
+            with_compoents,
+            without_compoents,
+            ..
+        } = expression
+        {
+            for name in with_compoents.iter().chain(without_compoents) {
+                if !self.components.contains(name) {
+                    self.errors.push(UnknownComponent {
+                        name: name.to_string(),
+                        span: span_in(self.src, name),
+                    });
+                }
+            }
+        }
+        walk_expression(self, expression);
+    }
+}
+
+/// 检查语法树中所有查询引用的组件是否都已经注册，`ast`必须是从`src`解析出来的
+pub fn validate_components(
+    src: &str,
+    ast: &[Command<'_>],
+    components: &HashSet<&str>,
+) -> Result<(), Vec<UnknownComponent>> {
+    let mut checker = ComponentChecker {
+        src,
+        components,
+        errors: vec![],
+    };
+    walk_commands(&mut checker, ast);
+    if checker.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(checker.errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse, scanner::Command};
+
+    use std::collections::HashSet;
+
+    use super::{
+        FunctionSignature, Schema, SchemaError, UnknownComponent, validate, validate_components,
+    };
+
+    #[test]
+    fn validate_missing_function() {
+        let schema = Schema {
+            functions: vec![
+                FunctionSignature::new("on_tick", 0),
+                FunctionSignature::new("on_hit", 1),
+            ],
+            ..Default::default()
+        };
+        let mut ast = parse("a = 1\n").unwrap();
+        ast.push(Command::Function {
+            public: true,
+            name: "on_hit",
+            args: vec!["damage", "source"],
+            commands: vec![],
+        });
+        assert_eq!(
+            validate(&ast, &schema),
+            Err(vec![
+                SchemaError::MissingFunction("on_tick".to_string()),
+                SchemaError::WrongArity {
+                    name: "on_hit".to_string(),
+                    expected: 1,
+                    found: 2
+                }
+            ])
+        );
+
+        let ast = [
+            Command::Function {
+                public: true,
+                name: "on_tick",
+                args: vec![],
+                commands: vec![],
+            },
+            Command::Function {
+                public: true,
+                name: "on_hit",
+                args: vec!["damage"],
+                commands: vec![],
+            },
+        ];
+        assert_eq!(validate(&ast, &schema), Ok(()));
+    }
+
+    #[test]
+    fn validate_missing_state() {
+        let schema = Schema {
+            functions: vec![FunctionSignature::new("on_tick", 0)],
+            states: vec!["patrol".to_string(), "chase".to_string()],
+        };
+        // 嵌套在函数里的状态切换不算定义了状态块
+        let ast = parse("fn on_tick():\n    @chase\n@patrol:\n    move_to(next)\n").unwrap();
+        assert_eq!(
+            validate(&ast, &schema),
+            Err(vec![SchemaError::MissingState("chase".to_string())])
+        );
+        assert_eq!(
+            SchemaError::MissingState("chase".to_string()).to_string(),
+            "缺少状态块@chase"
+        );
+        let ast = parse(
+            "fn on_tick():\n    @chase\n@patrol:\n    move_to(next)\n@chase:\n    attack()\n",
+        )
+        .unwrap();
+        assert_eq!(validate(&ast, &schema), Ok(()));
+    }
+
+    #[test]
+    fn validate_unknown_component() {
+        let src = "for e in Query<Enemy | Ememy | !Dead>:\n    attack(e)\n";
+        let ast = parse(src).unwrap();
+        let components = HashSet::from(["Enemy", "Dead"]);
+        assert_eq!(
+            validate_components(src, &ast, &components),
+            Err(vec![UnknownComponent {
+                name: "Ememy".to_string(),
+                span: Some(23..28)
+            }])
+        );
+        // 语法树不是从这份源码解析出来的时候没有位置
+        assert_eq!(
+            validate_components("", &ast, &components),
+            Err(vec![UnknownComponent {
+                name: "Ememy".to_string(),
+                span: None
+            }])
+        );
+        let components = HashSet::from(["Enemy", "Ememy", "Dead"]);
+        assert_eq!(validate_components(src, &ast, &components), Ok(()));
+    }
+}
@@ -0,0 +1,211 @@
+//! 模板展开
+//!
+//! `template name(params):`定义的模板在解析完成后被内联到每个`name!(args)`调用处，
+//! 参数替换只作用于变量，不会替换字符串、符号以及`.`后面的字段名
+//!
+//! 展开不是卫生的：模板体中除参数以外的变量不会改名，展开后就是调用处的同名变量，
+//! 模板体中赋值的临时变量会覆盖调用者的变量。变量名借用自源码，展开时没有地方存放新生成的名字，
+//! 需要临时变量的模板应当把变量名也作为参数传进来
+
+use std::{collections::HashMap, mem, ops::Range};
+
+use super::{
+    error::{ParseError, span_in},
+    limit::Budget,
+    scanner::{BinaryOp, Command, Expression, Object, Spanned},
+    visit::{VisitorMut, walk_command_mut, walk_expression_mut},
+};
+
+/// 把模板体中的参数替换成调用时传入的表达式
+struct Substitute<'m, 'a> {
+    args: &'m HashMap<&'a str, Expression<'a>>,
+}
+
+impl<'a> VisitorMut<'a> for Substitute<'_, 'a> {
+    fn visit_expression_mut(&mut self, expression: &mut Expression<'a>) {
+        match expression {
+            Expression::Object(Object::Variable(name)) => {
+                if let Some(arg) = self.args.get(name) {
+                    *expression = arg.clone();
+                }
+            }
+            // 字段名不是变量
+            Expression::Binary {
+                op: BinaryOp::Dot,
+                lhs,
+                ..
+            } => self.visit_expression_mut(lhs),
+            _ => walk_expression_mut(self, expression),
+        }
+    }
+}
+
+/// 模板展开器
+struct Expander<'t, 'a> {
+    src: &'a str,
+    templates: HashMap<&'a str, (&'t [&'a str], &'t [Command<'a>])>,
+    /// 正在展开的模板，用于发现递归
+    expanding: Vec<&'a str>,
+    /// 正在展开的顶层语句的位置，找不到模板名的位置时用它报错
+    statement: Range<usize>,
+    /// 展开出的语句计入解析时的预算，超过限制后不再展开
+    budget: &'t Budget,
+    errors: &'t mut Vec<ParseError>,
+}
+
+impl<'a> VisitorMut<'a> for Expander<'_, 'a> {
+    fn visit_commands_mut(&mut self, commands: &mut Vec<Command<'a>>) {
+        for mut command in mem::take(commands) {
+            match command {
+                Command::Invoke { name, args } => commands.extend(self.invoke(name, args)),
+                Command::Template { name, .. } => self
+                    .errors
+                    .push(ParseError::custom(self.span_of(name), "模板只能定义在顶层")),
+                _ => {
+                    walk_command_mut(self, &mut command);
+                    commands.push(command);
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Expander<'_, 'a> {
+    /// 模板名在源码中的位置，找不到时用所在的顶层语句的位置
+    fn span_of(&self, name: &str) -> Range<usize> {
+        span_in(self.src, name).unwrap_or_else(|| self.statement.clone())
+    }
+
+    /// 展开一次模板调用
+    fn invoke(&mut self, name: &'a str, args: Vec<Expression<'a>>) -> Vec<Command<'a>> {
+        if self.budget.is_exceeded() {
+            return vec![];
+        }
+        let span = self.span_of(name);
+        let Some(&(params, body)) = self.templates.get(name) else {
+            self.errors
+                .push(ParseError::custom(span, format!("未定义的模板{name}")));
+            return vec![];
+        };
+        if params.len() != args.len() {
+            self.errors.push(ParseError::custom(
+                span,
+                format!(
+                    "模板{name}需要{}个参数，实际传入了{}个",
+                    params.len(),
+                    args.len()
+                ),
+            ));
+            return vec![];
+        }
+        if self.expanding.contains(&name) {
+            self.errors
+                .push(ParseError::custom(span, format!("模板{name}递归展开")));
+            return vec![];
+        }
+
+        let args = params.iter().copied().zip(args).collect();
+        let mut body = body.to_vec();
+        Substitute { args: &args }.visit_commands_mut(&mut body);
+        // 嵌套的模板调用可能指数级地展开，每展开一层都先计入预算
+        self.budget
+            .charge_commands(&body, self.statement.clone().into());
+        if self.budget.is_exceeded() {
+            return vec![];
+        }
+        // 模板体中还可能调用其他模板
+        self.expanding.push(name);
+        self.visit_commands_mut(&mut body);
+        self.expanding.pop();
+        body
+    }
+}
+
+/// 展开语法树中所有的模板调用，并去掉模板定义
+///
+/// 顶层的模板调用展开出的语句沿用调用语句的位置，
+/// 展开后超过`budget`的限制时以所在的顶层语句的位置报错
+pub fn expand<'a>(
+    src: &'a str,
+    ast: Vec<Spanned<Command<'a>>>,
+    budget: &Budget,
+    errors: &mut Vec<ParseError>,
+) -> Vec<Spanned<Command<'a>>> {
+    let (definitions, ast): (Vec<_>, Vec<_>) = ast
+        .into_iter()
+        .partition(|command| matches!(command.node, Command::Template { .. }));
+    let mut templates = HashMap::new();
+    for definition in &definitions {
+        if let Command::Template { name, params, body } = &definition.node
+            && templates
+                .insert(*name, (params.as_slice(), body.as_slice()))
+                .is_some()
+        {
+            errors.push(ParseError::custom(
+                span_in(src, name).unwrap_or_else(|| definition.span.into_range()),
+                format!("重复定义的模板{name}"),
+            ));
+        }
+    }
+    let mut expander = Expander {
+        src,
+        templates,
+        expanding: vec![],
+        statement: 0..0,
+        budget,
+        errors,
+    };
+    let mut expanded = vec![];
+    for Spanned { node, span } in ast {
+        expander.statement = span.into_range();
+        let mut commands = vec![node];
+        expander.visit_commands_mut(&mut commands);
+        expanded.extend(commands.into_iter().map(|node| Spanned { node, span }));
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse, parse_with_recovery};
+
+    #[test]
+    fn expand_template() {
+        let expanded = parse(
+            "template chase(timer, pos):\n    if timer > 3:\n        timer = 0\n        move_to(pos, target.pos)\nchase!(t, home)\n",
+        )
+        .unwrap();
+        // `.`后面的字段名不会被当作参数替换
+        let written = parse("if t > 3:\n    t = 0\n    move_to(home, target.pos)\n").unwrap();
+        assert_eq!(expanded, written);
+    }
+
+    #[test]
+    fn template_locals_are_not_hygienic() {
+        let src = "template swap(a, b):\n    tmp = a\n    a = b\n    b = tmp\ntmp = 'caller'\nswap!(x, y)\n";
+        let written = parse("tmp = 'caller'\ntmp = x\nx = y\ny = tmp\n").unwrap();
+        // 模板体中的tmp就是调用处的tmp
+        assert_eq!(parse(src).unwrap(), written);
+        // 把临时变量也作为参数传入时不会碰到调用者的变量
+        let src = "template swap(a, b, tmp):\n    tmp = a\n    a = b\n    b = tmp\ntmp = 'caller'\nswap!(x, y, t)\n";
+        let written = parse("tmp = 'caller'\nt = x\nx = y\ny = t\n").unwrap();
+        assert_eq!(parse(src).unwrap(), written);
+    }
+
+    #[test]
+    fn expand_template_errors() {
+        let src = "template a():\n    b!()\ntemplate b():\n    a!()\na!()\nc!(1)\n";
+        let (_, errors) = parse_with_recovery(src);
+        let messages = errors
+            .iter()
+            .map(|error| (&src[error.span()], error.message()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                ("a", "模板a递归展开".to_string()),
+                ("c", "未定义的模板c".to_string())
+            ]
+        );
+    }
+}
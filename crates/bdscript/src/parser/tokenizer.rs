@@ -1,13 +1,18 @@
 //! 从文件中解析出来token
 
-use logos::Logos;
+use logos::{FilterResult, Lexer, Logos};
 use rust_decimal::Decimal;
-use std::str::FromStr;
+use std::{borrow::Cow, fmt, ops::Range, str::FromStr};
+
+use super::indent::IndentStyle;
 
 #[derive(Logos, Debug, Eq, PartialEq, Clone)]
 pub enum Token<'a> {
     #[regex("@[a-zA-Z_][a-zA-Z0-9_]*", |lex| &lex.slice()[1..])]
     State(&'a str),
+    /// 变换运算符，`@`后面紧跟标识符时是状态
+    #[token("@")]
+    At,
     #[token("(")]
     LeftParen,
     #[token(")")]
@@ -50,6 +55,8 @@ pub enum Token<'a> {
     ModAssign,
     #[token("^=")]
     PowAssign,
+    #[token("|=")]
+    OrAssign,
     #[token("==")]
     Equal,
     #[token("!=")]
@@ -67,9 +74,11 @@ pub enum Token<'a> {
     #[token("and")]
     And,
     #[token("||")]
-    #[token("|")]
     #[token("or")]
     Or,
+    /// 字典合并，也是查询中组件的分隔符
+    #[token("|")]
+    Pipe,
     #[token("!")]
     Not,
     #[token("?")]
@@ -86,45 +95,552 @@ pub enum Token<'a> {
     While,
     #[token("for")]
     For,
+    #[token("in")]
+    In,
     #[token("pub")]
     Pub,
     #[token("fn")]
     Fn,
+    #[token("defer")]
+    Defer,
+    #[token("yield")]
+    Yield,
+    #[token("return")]
+    Return,
+    #[token("break")]
+    Break,
+    #[token("continue")]
+    Continue,
+    #[token("template")]
+    Template,
+    /// 模板调用`name!(`中的`name!`，由词法分析之后的处理合成，词法分析器本身不会产生
+    Invoke(&'a str),
+    #[token("import")]
+    #[token("include")]
+    Import,
+    #[token("with")]
+    With,
+    #[token("as")]
+    As,
     #[token("Query")]
     Query,
+    #[token("true", |_| true)]
+    #[token("false", |_| false)]
+    Bool(bool),
     #[token(",")]
     Comma,
     #[token(".")]
     Dot,
+    #[token("..")]
+    Range,
+    #[token("..=")]
+    RangeInclusive,
+    /// 状态超时的目标，`timeout 3s -> @next`
+    #[token("->")]
+    Arrow,
     #[token("\n")]
     Line,
-    #[regex(r#""[^"]*""# , |lex|{
-        let slice=lex.slice();
-        &slice[0..slice.len()-1]
-    })]
-    #[regex(r#"'[^']*'"# , |lex|{
-        let slice=lex.slice();
-        &slice[1..slice.len()-1]
-    })]
-    Str(&'a str),
-    #[regex(r"[0-9]*\.?[0-9]+([eE][-+]?[0-9]+)?", |lex| Decimal::from_str(lex.slice()).unwrap())]
+    /// 字符串，没有转义字符时直接借用源码
+    #[regex(r#""([^"\\]|\\.)*""#, |lex| unquote(lex.slice()))]
+    #[regex(r#"'([^'\\]|\\.)*'"#, |lex| unquote(lex.slice()))]
+    Str(Cow<'a, str>),
+    /// f-string，`f"hp is {entity.health}"`，词法分析之后会被展开成
+    /// [`Token::FStringStart`]、文本、内嵌表达式的token和[`Token::FStringEnd`]
+    #[token("f\"", fstring)]
+    #[token("f'", fstring)]
+    FString(Vec<FStringSegment<'a>>),
+    /// f-string的开头，由词法分析之后的处理合成
+    FStringStart,
+    /// f-string中的一段文本，由词法分析之后的处理合成
+    FStringPart(Cow<'a, str>),
+    /// f-string内嵌表达式后面的格式，`{x:.2}`中的`:.2`，由词法分析之后的处理合成
+    FStringSpec(FormatSpec),
+    /// f-string的结尾，由词法分析之后的处理合成
+    FStringEnd,
+    /// 解析配置中登记的自定义运算符，记录下标和写法，由词法分析之后的处理合成
+    CustomOp((usize, &'a str)),
+    #[regex(r"([0-9][0-9_]*)?\.?[0-9][0-9_]*([eE][-+]?[0-9]+)?", number)]
+    #[regex(r"0[xXoObB][0-9a-zA-Z_]*", |lex| radix_number(lex.slice()), priority = 10)]
     Number(Decimal),
+    #[regex(r"([0-9][0-9_]*)?\.?[0-9][0-9_]*([eE][-+]?[0-9]+)?[a-zA-Z]+", |lex| quantity(lex.slice()))]
+    Quantity((Decimal, &'a str)),
     #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice())]
     Ident(&'a str),
-    #[regex(r"#[^\n]*", logos::skip)]
-    Comment,
-    #[token("    ")]
-    #[token("\t")]
-    Tab,
+    #[regex(":[a-zA-Z_][a-zA-Z0-9_]*", |lex| &lex.slice()[1..])]
+    Symbol(&'a str),
+    /// 颜色字面量`#RRGGBB`/`#RRGGBBAA`，其他以`#`开头的内容都是注释(包括`#[ ... ]#`块注释)
+    #[token("#", hash)]
+    Color([u8; 4]),
+    /// 一层缩进，记录是用制表符还是四个空格写的
+    #[token("    ", |_| IndentStyle::Spaces)]
+    #[token("\t", |_| IndentStyle::Tab)]
+    Tab(IndentStyle),
     #[regex(r" ", logos::skip)]
     Whitespace,
 }
 
+/// 解析数字
+///
+/// 数字后面紧跟`%`(或`‰`)，并且再后面不是操作数时，视为百分数(千分数)，
+/// 这样`50%`是0.5，而`a%b`、`50 % b`仍然是取模
+fn number<'a>(lex: &mut Lexer<'a, Token<'a>>) -> Option<Decimal> {
+    let value = Decimal::from_str(&strip_separators(lex.slice(), |c| c.is_ascii_digit())?).ok()?;
+    let mut rest = lex.remainder().chars();
+    let (scale, suffix) = match rest.next() {
+        Some(c @ '%') => (Decimal::ONE_HUNDRED, c),
+        Some(c @ '‰') => (Decimal::ONE_THOUSAND, c),
+        _ => return Some(value),
+    };
+    let followed_by_operand = rest
+        .next()
+        .is_some_and(|c| c.is_alphanumeric() || "_.([{\"'=".contains(c));
+    if followed_by_operand {
+        Some(value)
+    } else {
+        lex.bump(suffix.len_utf8());
+        Some(value / scale)
+    }
+}
+
+/// 去掉数字中的分隔符`_`，例如`1_000_000`
+///
+/// `_`的两边都必须是数字，开头、结尾或者连续的`_`都是词法错误
+fn strip_separators(digits: &str, is_digit: fn(char) -> bool) -> Option<Cow<'_, str>> {
+    if !digits.contains('_') {
+        return Some(Cow::Borrowed(digits));
+    }
+    let chars = digits.chars().collect::<Vec<_>>();
+    let valid = chars.iter().enumerate().all(|(i, &c)| {
+        c != '_'
+            || (i > 0
+                && chars.get(i + 1).is_some_and(|&next| is_digit(next))
+                && is_digit(chars[i - 1]))
+    });
+    valid.then(|| Cow::Owned(digits.replace('_', "")))
+}
+
+/// 解析`0x`/`0o`/`0b`开头的整数，出现不属于该进制的数字或者超出[`Decimal`]的范围时为词法错误
+///
+/// 字面量本身没有符号，最大可以写到96位，例如`0xFFFFFFFFFFFFFFFF`
+fn radix_number(slice: &str) -> Option<Decimal> {
+    let radix = match &slice[1..2] {
+        "x" | "X" => 16,
+        "o" | "O" => 8,
+        _ => 2,
+    };
+    let digits = strip_separators(&slice[2..], |c| c.is_ascii_hexdigit())?;
+    let value = i128::from_str_radix(&digits, radix).ok()?;
+    Decimal::try_from_i128_with_scale(value, 0).ok()
+}
+
+/// 去掉字符串两边的引号并处理转义字符
+fn unquote(slice: &str) -> Option<Cow<'_, str>> {
+    unescape(&slice[1..slice.len() - 1])
+}
+
+/// 处理转义字符，无法识别的转义为词法错误
+///
+/// 支持`\n`、`\t`、`\r`、`\\`、`\"`、`\'`、`\0`以及`\u{...}`
+fn unescape(s: &str) -> Option<Cow<'_, str>> {
+    if !s.contains('\\') {
+        return Some(Cow::Borrowed(s));
+    }
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        unescaped.push(match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            c @ ('\\' | '"' | '\'') => c,
+            'u' => {
+                let rest = chars.as_str().strip_prefix('{')?;
+                let end = rest.find('}')?;
+                let c = char::from_u32(u32::from_str_radix(&rest[..end], 16).ok()?)?;
+                chars = rest[end + 1..].chars();
+                c
+            }
+            _ => return None,
+        });
+    }
+    Some(Cow::Owned(unescaped))
+}
+
+/// f-string中的一段
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FStringSegment<'a> {
+    /// 文本，`{{`、`}}`和转义字符都已经处理
+    Text(Cow<'a, str>),
+    /// `{}`中的表达式相对于f-string开头的位置(不包括花括号和格式)，以及表达式后面的格式
+    Expr(Range<usize>, Option<FormatSpec>),
+}
+
+/// 数字的格式，`[0][宽度][.精度]`，例如`.2`、`03`、`8.3`
+///
+/// 用在f-string的`{x:.2}`和`format`的`{0:.2}`中
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatSpec {
+    /// 用0而不是空格补齐宽度
+    pub zero: bool,
+    /// 最小宽度，不足时在左边补齐
+    pub width: usize,
+    /// 小数位数
+    pub precision: Option<usize>,
+}
+
+impl FormatSpec {
+    /// 解析格式，不是合法的格式时返回None
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (width, precision) = match spec.split_once('.') {
+            Some((width, precision)) => (width, Some(digits(precision)?)),
+            None => (spec, None),
+        };
+        if width.is_empty() && precision.is_none() {
+            return None;
+        }
+        Some(Self {
+            zero: width.starts_with('0'),
+            width: if width.is_empty() { 0 } else { digits(width)? },
+            precision,
+        })
+    }
+}
+
+/// 只由数字组成的非负整数
+fn digits(s: &str) -> Option<usize> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+impl fmt::Display for FormatSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.zero {
+            write!(f, "0")?;
+        }
+        if self.width > 0 {
+            write!(f, "{}", self.width)?;
+        }
+        if let Some(precision) = self.precision {
+            write!(f, ".{precision}")?;
+        }
+        Ok(())
+    }
+}
+
+/// 解析f-string，把内容切分成文本和内嵌表达式
+///
+/// 内嵌表达式中可以出现字符串和花括号，f-string不能跨行，
+/// 单独的`}`、空的`{}`以及没有结尾的f-string都是词法错误
+fn fstring<'a>(lex: &mut Lexer<'a, Token<'a>>) -> Option<Vec<FStringSegment<'a>>> {
+    let prefix = lex.slice().len();
+    let quote = lex.slice().as_bytes()[1];
+    let rest = lex.remainder();
+    let bytes = rest.as_bytes();
+    let mut segments = vec![];
+    let (mut i, mut text_start) = (0, 0);
+    loop {
+        let c = *bytes.get(i)?;
+        match c {
+            _ if c == quote => break,
+            b'\n' => return None,
+            b'\\' => i += 2,
+            b'{' | b'}' if bytes.get(i + 1) == Some(&c) => i += 2,
+            b'}' => return None,
+            b'{' => {
+                push_text(&mut segments, &rest[text_start..i])?;
+                let end = embedded_end(bytes, i + 1)?;
+                let spec = format_colon(bytes, i + 1, end)
+                    .and_then(|colon| Some((colon, FormatSpec::parse(&rest[colon + 1..end])?)));
+                let expr_end = spec.map_or(end, |(colon, _)| colon);
+                if rest[i + 1..expr_end].trim().is_empty() {
+                    return None;
+                }
+                segments.push(FStringSegment::Expr(
+                    prefix + i + 1..prefix + expr_end,
+                    spec.map(|(_, spec)| spec),
+                ));
+                i = end + 1;
+                text_start = i;
+            }
+            _ => i += 1,
+        }
+    }
+    push_text(&mut segments, &rest[text_start..i])?;
+    lex.bump(i + 1);
+    Some(segments)
+}
+
+/// 找到内嵌表达式结尾的`}`，跳过其中的字符串和成对的花括号
+fn embedded_end(bytes: &[u8], mut i: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    loop {
+        match *bytes.get(i)? {
+            b'\n' => return None,
+            b'}' if depth == 0 => return Some(i),
+            b'}' => depth -= 1,
+            b'{' => depth += 1,
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while *bytes.get(i)? != quote {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+}
+
+/// 内嵌表达式中分隔格式的冒号：不在括号和字符串中，也不是条件表达式`a ? b : c`的冒号
+fn format_colon(bytes: &[u8], start: usize, end: usize) -> Option<usize> {
+    let (mut depth, mut ternaries, mut colon) = (0usize, 0usize, None);
+    let mut i = start;
+    while i < end {
+        match bytes[i] {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth = depth.saturating_sub(1),
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while i < end && bytes[i] != quote {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'?' if depth == 0 => ternaries += 1,
+            b':' if depth == 0 && ternaries > 0 => ternaries -= 1,
+            b':' if depth == 0 => colon = Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    colon
+}
+
+/// 处理f-string中的一段文本，空文本会被忽略
+fn push_text<'a>(segments: &mut Vec<FStringSegment<'a>>, raw: &'a str) -> Option<()> {
+    let text = if raw.is_empty() {
+        return Some(());
+    } else if raw.contains("{{") || raw.contains("}}") {
+        let raw = raw.replace("{{", "{").replace("}}", "}");
+        Cow::Owned(unescape(&raw)?.into_owned())
+    } else {
+        unescape(raw)?
+    };
+    segments.push(FStringSegment::Text(text));
+    Some(())
+}
+
+/// 数字后面可以紧跟的单位，其他字母紧跟在数字后面时为词法错误，
+/// 这样`2e`之类写错的指数不会被当成单位
+pub const UNITS: &[&str] = &[
+    "ns", "us", "ms", "s", "m", "h", "mm", "cm", "km", "deg", "rad", "px", "hz",
+];
+
+/// 解析带单位的数字，例如`10s`、`5m`，单位只能是[`UNITS`]中的一个，交给运行时解释
+fn quantity(slice: &str) -> Option<(Decimal, &str)> {
+    let split = slice
+        .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+        .len();
+    let unit = &slice[split..];
+    if !UNITS.contains(&unit) {
+        return None;
+    }
+    let digits = strip_separators(&slice[..split], |c| c.is_ascii_digit())?;
+    Some((Decimal::from_str(&digits).ok()?, unit))
+}
+
+/// 解析`#`开头的内容
+///
+/// 当前token之前(同一行中)的内容是否要求后面跟一个操作数，
+/// 行首以及操作数后面的`#`都是注释，例如`#facade pattern`、`x = 1 #decade`
+fn expects_operand<'a>(lex: &Lexer<'a, Token<'a>>) -> bool {
+    let line = lex.source()[..lex.span().start]
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .trim_end();
+    let word = line
+        .rsplit(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default();
+    line.ends_with(|c: char| "=+-*/%^@(,[{:?<>!&|".contains(c))
+        || matches!(
+            word,
+            "return" | "yield" | "defer" | "and" | "or" | "in" | "then"
+        )
+}
+
+/// 把6位或8位十六进制数字转换成颜色，省略透明度时为不透明
+fn rgba(digits: &str) -> Option<[u8; 4]> {
+    let mut rgba = [0, 0, 0, u8::MAX];
+    for (channel, pair) in rgba.iter_mut().zip(digits.as_bytes().chunks(2)) {
+        *channel = u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(rgba)
+}
+
+/// `#`出现在需要操作数的位置(跟在运算符、左括号、逗号或者`return`等关键字后面)，
+/// 并且后面紧跟6位或8位十六进制数字(再后面不是标识符)时为颜色，
+/// `#[`开头的是块注释，一直到配对的`]#`(可以嵌套)，没有结尾时只把`#[`报告为词法错误，
+/// 否则一直到行尾都是注释，例如`# comment`、`#if`
+fn hash<'a>(lex: &mut Lexer<'a, Token<'a>>) -> FilterResult<[u8; 4], ()> {
+    let rest = lex.remainder();
+    if rest.starts_with('[') {
+        let mut depth = 1usize;
+        let mut i = 1;
+        while i < rest.len() {
+            if rest[i..].starts_with('[') && rest[..i].ends_with('#') {
+                depth += 1;
+            } else if rest[i..].starts_with("]#") {
+                depth -= 1;
+                if depth == 0 {
+                    lex.bump(i + 2);
+                    return FilterResult::Skip;
+                }
+                i += 1;
+            }
+            i += rest[i..].chars().next().map_or(1, char::len_utf8);
+        }
+        lex.bump(1);
+        return FilterResult::Error(());
+    }
+    let digits = rest.len()
+        - rest
+            .trim_start_matches(|c: char| c.is_ascii_hexdigit())
+            .len();
+    let followed_by_ident = rest[digits..]
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphanumeric() || c == '_');
+    if matches!(digits, 6 | 8) && !followed_by_ident && expects_operand(lex) {
+        let color = rgba(&rest[..digits]);
+        lex.bump(digits);
+        color.map_or(FilterResult::Error(()), FilterResult::Emit)
+    } else {
+        lex.bump(rest.find('\n').unwrap_or(rest.len()));
+        FilterResult::Skip
+    }
+}
+
+impl Token<'_> {
+    /// 是否是操作数的结尾，紧跟在操作数后面的`:name`不是符号而是冒号加标识符
+    pub const fn is_operand(&self) -> bool {
+        matches!(
+            self,
+            Self::Ident(_)
+                | Self::Number(_)
+                | Self::Quantity(..)
+                | Self::Str(_)
+                | Self::Symbol(_)
+                | Self::Color(_)
+                | Self::Bool(_)
+                | Self::FString(_)
+                | Self::FStringEnd
+                | Self::RightParen
+                | Self::RightBracket
+                | Self::RightBrace
+        )
+    }
+}
+
+/// 输出token在源码中的样子，用于错误信息
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::State(s) => write!(f, "@{s}"),
+            Self::At => write!(f, "@"),
+            Self::LeftParen => write!(f, "("),
+            Self::RightParen => write!(f, ")"),
+            Self::LeftBrace => write!(f, "{{"),
+            Self::RightBrace => write!(f, "}}"),
+            Self::LeftBracket => write!(f, "["),
+            Self::RightBracket => write!(f, "]"),
+            Self::Add => write!(f, "+"),
+            Self::PlusOne => write!(f, "++"),
+            Self::Sub => write!(f, "-"),
+            Self::MinusOne => write!(f, "--"),
+            Self::Mul => write!(f, "*"),
+            Self::Div => write!(f, "/"),
+            Self::Mod => write!(f, "%"),
+            Self::Pow => write!(f, "^"),
+            Self::Assign => write!(f, "="),
+            Self::AddAssign => write!(f, "+="),
+            Self::SubAssign => write!(f, "-="),
+            Self::MulAssign => write!(f, "*="),
+            Self::DivAssign => write!(f, "/="),
+            Self::ModAssign => write!(f, "%="),
+            Self::PowAssign => write!(f, "^="),
+            Self::OrAssign => write!(f, "|="),
+            Self::Equal => write!(f, "=="),
+            Self::NotEqual => write!(f, "!="),
+            Self::Greater => write!(f, ">"),
+            Self::Less => write!(f, "<"),
+            Self::GreaterEqual => write!(f, ">="),
+            Self::LessEqual => write!(f, "<="),
+            Self::And => write!(f, "&&"),
+            Self::Or => write!(f, "||"),
+            Self::Pipe => write!(f, "|"),
+            Self::Not => write!(f, "!"),
+            Self::Question => write!(f, "?"),
+            Self::Colon => write!(f, ":"),
+            Self::If => write!(f, "if"),
+            Self::Elif => write!(f, "elif"),
+            Self::Else => write!(f, "else"),
+            Self::While => write!(f, "while"),
+            Self::For => write!(f, "for"),
+            Self::In => write!(f, "in"),
+            Self::Pub => write!(f, "pub"),
+            Self::Fn => write!(f, "fn"),
+            Self::Defer => write!(f, "defer"),
+            Self::Yield => write!(f, "yield"),
+            Self::Return => write!(f, "return"),
+            Self::Break => write!(f, "break"),
+            Self::Continue => write!(f, "continue"),
+            Self::Template => write!(f, "template"),
+            Self::Invoke(name) => write!(f, "{name}!"),
+            Self::Import => write!(f, "import"),
+            Self::With => write!(f, "with"),
+            Self::As => write!(f, "as"),
+            Self::Query => write!(f, "Query"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Comma => write!(f, ","),
+            Self::Dot => write!(f, "."),
+            Self::Range => write!(f, ".."),
+            Self::RangeInclusive => write!(f, "..="),
+            Self::Arrow => write!(f, "->"),
+            Self::Line => write!(f, "换行"),
+            Self::Str(s) => write!(f, "{s:?}"),
+            Self::FString(_) => write!(f, "f-string"),
+            Self::FStringStart => write!(f, "f\""),
+            Self::FStringPart(s) => write!(f, "{s:?}"),
+            Self::FStringSpec(spec) => write!(f, ":{spec}"),
+            Self::FStringEnd => write!(f, "\""),
+            Self::CustomOp((_, symbol)) => write!(f, "{symbol}"),
+            Self::Number(num) => write!(f, "{num}"),
+            Self::Quantity((num, unit)) => write!(f, "{num}{unit}"),
+            Self::Ident(s) => write!(f, "{s}"),
+            Self::Symbol(s) => write!(f, ":{s}"),
+            Self::Color([r, g, b, a]) => write!(f, "#{r:02x}{g:02x}{b:02x}{a:02x}"),
+            Self::Tab(_) => write!(f, "缩进"),
+            Self::Whitespace => write!(f, " "),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::borrow::Cow;
+
     use logos::Logos;
+    use rust_decimal::Decimal;
 
-    use super::Token;
+    use super::{FStringSegment, FormatSpec, Token};
 
     #[test]
     fn token_hello_world() {
@@ -136,4 +652,306 @@ mod tests {
             println!("{:?}", token);
         }
     }
+
+    #[test]
+    fn token_percent() {
+        let tokens = Token::lexer("50% 5‰ (12.5%)").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::Number(Decimal::new(5, 1))),
+                Ok(Token::Number(Decimal::new(5, 3))),
+                Ok(Token::LeftParen),
+                Ok(Token::Number(Decimal::new(125, 3))),
+                Ok(Token::RightParen),
+            ]
+        );
+        let tokens = Token::lexer("a % b 50 % b 50%b").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::Ident("a")),
+                Ok(Token::Mod),
+                Ok(Token::Ident("b")),
+                Ok(Token::Number(Decimal::from(50))),
+                Ok(Token::Mod),
+                Ok(Token::Ident("b")),
+                Ok(Token::Number(Decimal::from(50))),
+                Ok(Token::Mod),
+                Ok(Token::Ident("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn token_unit_suffix() {
+        let tokens = Token::lexer("10s 1.5ms 1e3 1e5s").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::Quantity((Decimal::from(10), "s"))),
+                Ok(Token::Quantity((Decimal::new(15, 1), "ms"))),
+                Ok(Token::Number(Decimal::from(1000))),
+                Ok(Token::Quantity((Decimal::from(100_000), "s"))),
+            ]
+        );
+        // 不是单位的字母，缺少指数的科学计数法，以及和进制前缀冲突的单位
+        for src in ["2e", "3kg", "0bytes"] {
+            assert_eq!(Token::lexer(src).collect::<Vec<_>>(), [Err(())], "{src}");
+        }
+    }
+
+    #[test]
+    fn token_symbol() {
+        let tokens = Token::lexer(":attack :flee").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [Ok(Token::Symbol("attack")), Ok(Token::Symbol("flee"))]
+        );
+        let tokens = Token::lexer("if x:\n").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::If),
+                Ok(Token::Ident("x")),
+                Ok(Token::Colon),
+                Ok(Token::Line)
+            ]
+        );
+    }
+
+    #[test]
+    fn token_at() {
+        let tokens = Token::lexer("@idle v1 @ v2").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::State("idle")),
+                Ok(Token::Ident("v1")),
+                Ok(Token::At),
+                Ok(Token::Ident("v2"))
+            ]
+        );
+    }
+
+    #[test]
+    fn token_color() {
+        let tokens =
+            Token::lexer("c = #ff0000 # comment\nreturn [#00ff0080]\n").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::Ident("c")),
+                Ok(Token::Assign),
+                Ok(Token::Color([255, 0, 0, 255])),
+                Ok(Token::Line),
+                Ok(Token::Return),
+                Ok(Token::LeftBracket),
+                Ok(Token::Color([0, 255, 0, 128])),
+                Ok(Token::RightBracket),
+                Ok(Token::Line)
+            ]
+        );
+        // 行首的颜色也是注释，单独的颜色值要写在表达式中
+        let tokens = Token::lexer("#ff0000\nc = #00ff00\n").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::Line),
+                Ok(Token::Ident("c")),
+                Ok(Token::Assign),
+                Ok(Token::Color([0, 255, 0, 255])),
+                Ok(Token::Line)
+            ]
+        );
+        // 行首和操作数后面的#是注释，即使后面是6位十六进制字母
+        let tokens = Token::lexer("#facade pattern\nx = 1 #decade\n").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::Line),
+                Ok(Token::Ident("x")),
+                Ok(Token::Assign),
+                Ok(Token::Number(Decimal::ONE)),
+                Ok(Token::Line)
+            ]
+        );
+        // 位数不对或者后面紧跟标识符时仍然是注释
+        let tokens = Token::lexer("#fff\n#ff0000ff00\n#abcdefg\n").collect::<Vec<_>>();
+        assert_eq!(tokens, [Ok(Token::Line), Ok(Token::Line), Ok(Token::Line)]);
+    }
+
+    #[test]
+    fn token_radix() {
+        let tokens = Token::lexer("0xFF 0Xff 0o17 0O17 0b1010 0B1 0").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [255, 255, 15, 15, 10, 1, 0].map(|n| Ok(Token::Number(Decimal::from(n))))
+        );
+        // 超过64位的字面量
+        let src = format!("0xFFFFFFFFFFFFFFFF 0b{}", "1".repeat(64));
+        let tokens = Token::lexer(&src).collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [u64::MAX; 2].map(|n| Ok(Token::Number(Decimal::from(n))))
+        );
+        assert_eq!(
+            Token::lexer("0xFFFFFFFFFFFFFFFFFFFFFFFF").next(),
+            Some(Ok(Token::Number(Decimal::MAX)))
+        );
+        // 不属于该进制的数字，或者超出范围
+        for src in ["0b2", "0o8", "0xg", "0x", "0x1000000000000000000000000"] {
+            assert_eq!(Token::lexer(src).collect::<Vec<_>>(), [Err(())], "{src}");
+        }
+    }
+
+    #[test]
+    fn token_digit_separators() {
+        let tokens = Token::lexer("1_000.5 0xFF_FF 1_000ms").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::Number(Decimal::new(10005, 1))),
+                Ok(Token::Number(Decimal::from(0xFFFF))),
+                Ok(Token::Quantity((Decimal::from(1000), "ms")))
+            ]
+        );
+        for src in ["1__0", "1_", "1_.5"] {
+            assert_eq!(Token::lexer(src).collect::<Vec<_>>(), [Err(())], "{src}");
+        }
+        assert_eq!(
+            Token::lexer("_1").collect::<Vec<_>>(),
+            [Ok(Token::Ident("_1"))]
+        );
+    }
+
+    #[test]
+    fn token_escapes() {
+        let tokens =
+            Token::lexer(r#""line\nbreak" 'it\'s' "\u{4f60}\t\\" "plain""#).collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::Str("line\nbreak".into())),
+                Ok(Token::Str("it's".into())),
+                Ok(Token::Str("你\t\\".into())),
+                Ok(Token::Str("plain".into()))
+            ]
+        );
+        assert!(matches!(&tokens[3], Ok(Token::Str(Cow::Borrowed(_)))));
+        for src in [r#""\q""#, r#""\u{110000}""#, r#""\u{}""#] {
+            assert_eq!(Token::lexer(src).collect::<Vec<_>>(), [Err(())], "{src}");
+        }
+    }
+
+    #[test]
+    fn token_fstring() {
+        let src = r#"f"hp {{is}} {entity.health}!" f'{d["k"]}' f"{ {'a': 1} }""#;
+        let tokens = Token::lexer(src).collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::FString(vec![
+                    FStringSegment::Text("hp {is} ".into()),
+                    FStringSegment::Expr(13..26, None),
+                    FStringSegment::Text("!".into())
+                ])),
+                Ok(Token::FString(vec![FStringSegment::Expr(3..9, None)])),
+                Ok(Token::FString(vec![FStringSegment::Expr(3..13, None)])),
+            ]
+        );
+        for src in [r#"f"a } b""#, r#"f"{}""#, r#"f"{a""#, "f\"a\nb\""] {
+            assert!(Token::lexer(src).next().unwrap().is_err(), "{src}");
+        }
+    }
+
+    #[test]
+    // 测试的是f-string的格式，不是Rust的格式字符串
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn token_fstring_spec() {
+        let spec = |zero, width, precision| {
+            Some(FormatSpec {
+                zero,
+                width,
+                precision,
+            })
+        };
+        let src = r#"f"{x:.2} {5:03} {a ? b :c} {d['k:2']} {:idle} {y:8.1}""#;
+        let tokens = Token::lexer(src).collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [Ok(Token::FString(vec![
+                FStringSegment::Expr(3..4, spec(false, 0, Some(2))),
+                FStringSegment::Text(" ".into()),
+                FStringSegment::Expr(10..11, spec(true, 3, None)),
+                FStringSegment::Text(" ".into()),
+                // 条件表达式的冒号、字符串中的冒号和符号都不是格式
+                FStringSegment::Expr(17..25, None),
+                FStringSegment::Text(" ".into()),
+                FStringSegment::Expr(28..36, None),
+                FStringSegment::Text(" ".into()),
+                FStringSegment::Expr(39..44, None),
+                FStringSegment::Text(" ".into()),
+                FStringSegment::Expr(47..48, spec(false, 8, Some(1))),
+            ]))]
+        );
+        assert_eq!(FormatSpec::parse("x"), None);
+        assert_eq!(FormatSpec::parse("."), None);
+        assert_eq!(FormatSpec::parse(""), None);
+        assert_eq!(FormatSpec::parse("08.3").unwrap().to_string(), "08.3");
+        // 只有格式没有表达式
+        assert!(Token::lexer(r#"f"{:.2}""#).next().unwrap().is_err());
+    }
+
+    #[test]
+    fn token_number_overflow() {
+        let huge = "99999999999999999999999999999999999999999";
+        for src in [huge.to_string(), format!("{huge}m"), format!("0x{huge}")] {
+            assert_eq!(Token::lexer(&src).next(), Some(Err(())), "{src}");
+        }
+        assert!(crate::parser::parse(&format!("a = {huge}\n")).is_err());
+    }
+
+    #[test]
+    fn token_range() {
+        let tokens = Token::lexer("0..10 a..=b 1.5").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::Number(Decimal::from(0))),
+                Ok(Token::Range),
+                Ok(Token::Number(Decimal::from(10))),
+                Ok(Token::Ident("a")),
+                Ok(Token::RangeInclusive),
+                Ok(Token::Ident("b")),
+                Ok(Token::Number(Decimal::new(15, 1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn token_block_comment() {
+        let tokens =
+            Token::lexer("a #[ outer #[ inner ]# still\n comment ]# b").collect::<Vec<_>>();
+        assert_eq!(tokens, [Ok(Token::Ident("a")), Ok(Token::Ident("b"))]);
+        // 没有结尾的块注释只报告开头的`#[`
+        let mut lexer = Token::lexer("a\n#[ never closed\n");
+        assert_eq!(lexer.next(), Some(Ok(Token::Ident("a"))));
+        assert_eq!(lexer.next(), Some(Ok(Token::Line)));
+        assert_eq!(lexer.next(), Some(Err(())));
+        assert_eq!(lexer.span(), 2..4);
+    }
+
+    #[test]
+    fn token_bool() {
+        let tokens = Token::lexer("true false truely").collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::Bool(true)),
+                Ok(Token::Bool(false)),
+                Ok(Token::Ident("truely"))
+            ]
+        );
+    }
 }
@@ -0,0 +1,332 @@
+//! 遍历语法树
+//!
+//! 实现[`Visitor`]并只重写关心的方法，其余节点由`walk_*`函数负责递归，
+//! 需要修改语法树时实现[`VisitorMut`]
+
+use super::scanner::{Branch, Command, Expression, Object};
+
+/// 语法树访问者
+pub trait Visitor<'a> {
+    /// 访问指令，默认继续访问子节点
+    fn visit_command(&mut self, command: &Command<'a>) {
+        walk_command(self, command);
+    }
+
+    /// 访问表达式，默认继续访问子节点
+    fn visit_expression(&mut self, expression: &Expression<'a>) {
+        walk_expression(self, expression);
+    }
+}
+
+/// 依次访问多条指令
+pub fn walk_commands<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, commands: &[Command<'a>]) {
+    for command in commands {
+        visitor.visit_command(command);
+    }
+}
+
+/// 访问分支的条件和指令
+fn walk_branch<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, branch: &Branch<'a>) {
+    visitor.visit_expression(&branch.condition);
+    walk_commands(visitor, &branch.commands);
+}
+
+/// 访问指令的所有子节点
+pub fn walk_command<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, command: &Command<'a>) {
+    match command {
+        Command::Expression(expression) => visitor.visit_expression(expression),
+        Command::If {
+            if_branch,
+            else_branch,
+        } => {
+            for branch in if_branch {
+                walk_branch(visitor, branch);
+            }
+            if let Some(commands) = else_branch {
+                walk_commands(visitor, commands);
+            }
+        }
+        Command::While {
+            condition, command, ..
+        } => {
+            visitor.visit_expression(condition);
+            walk_commands(visitor, command);
+        }
+        Command::For { iter, commands, .. } => {
+            visitor.visit_expression(iter);
+            walk_commands(visitor, commands);
+        }
+        Command::Function { commands, .. } => walk_commands(visitor, commands),
+        Command::StateBlock {
+            timeout,
+            handlers,
+            commands,
+            ..
+        } => {
+            if let Some(timeout) = timeout {
+                visitor.visit_expression(&timeout.duration);
+            }
+            for handler in handlers {
+                walk_commands(visitor, &handler.commands);
+            }
+            walk_commands(visitor, commands);
+        }
+        Command::Defer(command) => visitor.visit_command(command),
+        Command::Yield(expression) => visitor.visit_expression(expression),
+        Command::Return(expression) => {
+            if let Some(expression) = expression {
+                visitor.visit_expression(expression);
+            }
+        }
+        Command::With { expr, body, .. } => {
+            visitor.visit_expression(expr);
+            walk_commands(visitor, body);
+        }
+        Command::Template { body, .. } | Command::Test { body, .. } => {
+            walk_commands(visitor, body);
+        }
+        Command::Invoke { args, .. } => {
+            for arg in args {
+                visitor.visit_expression(arg);
+            }
+        }
+        Command::Break(_)
+        | Command::Continue(_)
+        | Command::Import(_)
+        | Command::SetState(_)
+        | Command::NewLine => {}
+    }
+}
+
+/// 访问表达式的所有子节点
+pub fn walk_expression<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, expression: &Expression<'a>) {
+    match expression {
+        Expression::Object(object) => match object {
+            Object::Tuple(items)
+            | Object::Array(items)
+            | Object::Dict(items)
+            | Object::FString(items) => {
+                for item in items {
+                    visitor.visit_expression(item);
+                }
+            }
+            Object::DictItem(key, value) => {
+                visitor.visit_expression(key);
+                visitor.visit_expression(value);
+            }
+            Object::Constant(_)
+            | Object::Bool(_)
+            | Object::Quantity(..)
+            | Object::Str(_)
+            | Object::Variable(_)
+            | Object::Symbol(_)
+            | Object::Color(_) => {}
+        },
+        Expression::Unary { hs, .. } => visitor.visit_expression(hs),
+        Expression::Binary { lhs, rhs, .. } => {
+            visitor.visit_expression(lhs);
+            visitor.visit_expression(rhs);
+        }
+        Expression::Priority(expression)
+        | Expression::Format {
+            value: expression, ..
+        } => {
+            visitor.visit_expression(expression);
+        }
+        Expression::SpreadIf { condition, value } => {
+            visitor.visit_expression(condition);
+            visitor.visit_expression(value);
+        }
+        Expression::Range { start, end, .. } => {
+            visitor.visit_expression(start);
+            visitor.visit_expression(end);
+        }
+        Expression::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => {
+            visitor.visit_expression(cond);
+            visitor.visit_expression(then);
+            visitor.visit_expression(otherwise);
+        }
+        Expression::Query {
+            hierarchy,
+            order_by,
+            limit,
+            ..
+        } => {
+            for clause in [hierarchy, order_by, limit].into_iter().flatten() {
+                visitor.visit_expression(clause);
+            }
+        }
+    }
+}
+
+/// 可以修改语法树的访问者
+pub trait VisitorMut<'a> {
+    /// 访问一个块中的所有指令，可以在这里增删指令，默认逐条访问
+    fn visit_commands_mut(&mut self, commands: &mut Vec<Command<'a>>) {
+        walk_commands_mut(self, commands);
+    }
+
+    /// 访问指令，默认继续访问子节点
+    fn visit_command_mut(&mut self, command: &mut Command<'a>) {
+        walk_command_mut(self, command);
+    }
+
+    /// 访问表达式，默认继续访问子节点
+    fn visit_expression_mut(&mut self, expression: &mut Expression<'a>) {
+        walk_expression_mut(self, expression);
+    }
+}
+
+/// 依次访问块中的每条指令
+pub fn walk_commands_mut<'a, V: VisitorMut<'a> + ?Sized>(
+    visitor: &mut V,
+    commands: &mut Vec<Command<'a>>,
+) {
+    for command in commands {
+        visitor.visit_command_mut(command);
+    }
+}
+
+/// 访问指令的所有子节点
+pub fn walk_command_mut<'a, V: VisitorMut<'a> + ?Sized>(
+    visitor: &mut V,
+    command: &mut Command<'a>,
+) {
+    match command {
+        Command::Expression(expression) | Command::Yield(expression) => {
+            visitor.visit_expression_mut(expression);
+        }
+        Command::If {
+            if_branch,
+            else_branch,
+        } => {
+            for branch in if_branch {
+                visitor.visit_expression_mut(&mut branch.condition);
+                visitor.visit_commands_mut(&mut branch.commands);
+            }
+            if let Some(commands) = else_branch {
+                visitor.visit_commands_mut(commands);
+            }
+        }
+        Command::While {
+            condition, command, ..
+        } => {
+            visitor.visit_expression_mut(condition);
+            visitor.visit_commands_mut(command);
+        }
+        Command::For { iter, commands, .. } => {
+            visitor.visit_expression_mut(iter);
+            visitor.visit_commands_mut(commands);
+        }
+        Command::Function { commands, .. } => visitor.visit_commands_mut(commands),
+        Command::StateBlock {
+            timeout,
+            handlers,
+            commands,
+            ..
+        } => {
+            if let Some(timeout) = timeout {
+                visitor.visit_expression_mut(&mut timeout.duration);
+            }
+            for handler in handlers {
+                visitor.visit_commands_mut(&mut handler.commands);
+            }
+            visitor.visit_commands_mut(commands);
+        }
+        Command::Defer(command) => visitor.visit_command_mut(command),
+        Command::With { expr, body, .. } => {
+            visitor.visit_expression_mut(expr);
+            visitor.visit_commands_mut(body);
+        }
+        Command::Template { body, .. } | Command::Test { body, .. } => {
+            visitor.visit_commands_mut(body);
+        }
+        Command::Return(expression) => {
+            if let Some(expression) = expression {
+                visitor.visit_expression_mut(expression);
+            }
+        }
+        Command::Invoke { args, .. } => {
+            for arg in args {
+                visitor.visit_expression_mut(arg);
+            }
+        }
+        Command::Break(_)
+        | Command::Continue(_)
+        | Command::Import(_)
+        | Command::SetState(_)
+        | Command::NewLine => {}
+    }
+}
+
+/// 访问表达式的所有子节点
+pub fn walk_expression_mut<'a, V: VisitorMut<'a> + ?Sized>(
+    visitor: &mut V,
+    expression: &mut Expression<'a>,
+) {
+    match expression {
+        Expression::Object(object) => match object {
+            Object::Tuple(items)
+            | Object::Array(items)
+            | Object::Dict(items)
+            | Object::FString(items) => {
+                for item in items {
+                    visitor.visit_expression_mut(item);
+                }
+            }
+            Object::DictItem(key, value) => {
+                visitor.visit_expression_mut(key);
+                visitor.visit_expression_mut(value);
+            }
+            Object::Constant(_)
+            | Object::Bool(_)
+            | Object::Quantity(..)
+            | Object::Str(_)
+            | Object::Variable(_)
+            | Object::Symbol(_)
+            | Object::Color(_) => {}
+        },
+        Expression::Unary { hs, .. } => visitor.visit_expression_mut(hs),
+        Expression::Binary { lhs, rhs, .. } => {
+            visitor.visit_expression_mut(lhs);
+            visitor.visit_expression_mut(rhs);
+        }
+        Expression::Priority(expression)
+        | Expression::Format {
+            value: expression, ..
+        } => {
+            visitor.visit_expression_mut(expression);
+        }
+        Expression::SpreadIf { condition, value } => {
+            visitor.visit_expression_mut(condition);
+            visitor.visit_expression_mut(value);
+        }
+        Expression::Range { start, end, .. } => {
+            visitor.visit_expression_mut(start);
+            visitor.visit_expression_mut(end);
+        }
+        Expression::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => {
+            visitor.visit_expression_mut(cond);
+            visitor.visit_expression_mut(then);
+            visitor.visit_expression_mut(otherwise);
+        }
+        Expression::Query {
+            hierarchy,
+            order_by,
+            limit,
+            ..
+        } => {
+            for clause in [hierarchy, order_by, limit].into_iter().flatten() {
+                visitor.visit_expression_mut(clause);
+            }
+        }
+    }
+}
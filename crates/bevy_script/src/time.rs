@@ -0,0 +1,72 @@
+//! 把Bevy的[`Time`]交给脚本
+
+use bdscript::eval::{builtin::arity, interpreter::Interpreter, value::Value};
+use bevy_time::Time;
+
+/// 登记读取[`Time`]的宿主函数：`now()`是启动以来经过的时长，`delta()`是上一帧的时长，
+/// 两者都是[`Value::Duration`]。每帧执行脚本前用当帧的[`Time`]登记
+pub fn register_time(interpreter: &mut Interpreter<'_>, time: &Time) {
+    let now = Value::from(time.elapsed());
+    let delta = Value::from(time.delta());
+    interpreter.register_fn("now", move |args| {
+        arity("now", args, 0)?;
+        Ok(now.clone())
+    });
+    interpreter.register_fn("delta", move |args| {
+        arity("delta", args, 0)?;
+        Ok(delta.clone())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bdscript::{
+        eval::{interpreter::Interpreter, value::Value},
+        parser::parse,
+    };
+    use bevy_app::{App, Update};
+    use bevy_ecs::{
+        resource::Resource,
+        system::{Res, ResMut},
+    };
+    use bevy_time::{Time, TimePlugin, TimeUpdateStrategy};
+
+    use super::register_time;
+
+    /// 脚本算出的冷却剩余时间
+    #[derive(Resource, Default)]
+    struct Cooldown(Vec<Value>);
+
+    fn tick_cooldown(time: Res<Time>, mut cooldown: ResMut<Cooldown>) {
+        let ast = parse("(1s - delta(), now())").unwrap();
+        let mut interpreter = Interpreter::new();
+        register_time(&mut interpreter, &time);
+        cooldown.0.push(interpreter.eval_program(&ast).unwrap());
+    }
+
+    #[test]
+    fn script_reads_delta() {
+        let mut app = App::new();
+        app.add_plugins(TimePlugin)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+                250,
+            )))
+            .init_resource::<Cooldown>()
+            .add_systems(Update, tick_cooldown);
+        for _ in 0..3 {
+            app.update();
+        }
+        let millis = |ms| Value::from(Duration::from_millis(ms));
+        // 第一帧没有上一帧，delta()为0
+        assert_eq!(
+            app.world().resource::<Cooldown>().0,
+            [
+                Value::Tuple(vec![millis(1000), millis(0)]),
+                Value::Tuple(vec![millis(750), millis(250)]),
+                Value::Tuple(vec![millis(750), millis(500)]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! 在脚本和[`Transform`]之间转换位置
+
+use bdscript::eval::{error::RuntimeError, value::Value};
+use bevy_transform::components::Transform;
+
+/// 实体的位置，交给脚本时是三维向量
+pub fn translation(transform: &Transform) -> Value {
+    Value::Vec3(transform.translation)
+}
+
+/// 用脚本算出的位置设置实体的位置，二维向量只设置x和y，保留原来的z
+pub fn set_translation(transform: &mut Transform, value: &Value) -> Result<(), RuntimeError> {
+    transform.translation = match value {
+        Value::Vec2(v) => v.extend(transform.translation.z),
+        value => value.expect_vec3(0)?,
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bdscript::{
+        eval::{error::RuntimeError, interpreter::Interpreter, value::Value},
+        parser::parse,
+    };
+    use bevy_transform::components::Transform;
+
+    use super::{set_translation, translation};
+
+    #[test]
+    fn move_transform() {
+        let mut transform = Transform::from_xyz(1.0, 2.0, 3.0);
+        let position = translation(&transform);
+        let ast = parse("position() + normalize(vec3(0, 0, 2)) * 4").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.register_fn("position", move |_| Ok(position.clone()));
+        let moved = interpreter.eval_program(&ast).unwrap();
+        set_translation(&mut transform, &moved).unwrap();
+        assert_eq!(transform, Transform::from_xyz(1.0, 2.0, 7.0));
+        // 二维向量保留z
+        let ast = parse("vec2(5, 6)").unwrap();
+        let flat = Interpreter::new().eval_program(&ast).unwrap();
+        set_translation(&mut transform, &flat).unwrap();
+        assert_eq!(transform, Transform::from_xyz(5.0, 6.0, 7.0));
+        assert!(matches!(
+            set_translation(&mut transform, &Value::Nil),
+            Err(RuntimeError::ArgType { .. })
+        ));
+    }
+}
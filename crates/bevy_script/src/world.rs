@@ -0,0 +1,245 @@
+//! 让脚本查询Bevy的[`World`]
+
+use std::{cell::RefCell, collections::HashMap};
+
+use bdscript::eval::world::{self, ComponentId as ScriptComponentId};
+use bevy_ecs::{
+    component::{Component, ComponentId},
+    entity::Entity,
+    hierarchy::Children,
+    query::QueryBuilder,
+    world::World,
+};
+
+/// 交给脚本的Bevy世界，脚本中的实体是[`Entity::to_bits`]
+///
+/// 脚本只能查询用[`register_component`](Self::register_component)登记过名字的组件，
+/// 查询由组件的[`ComponentId`]动态构建。借用的是`&mut World`，通常在独占系统中创建
+pub struct ScriptWorld<'w> {
+    world: RefCell<&'w mut World>,
+    components: HashMap<String, ComponentId>,
+}
+
+impl<'w> ScriptWorld<'w> {
+    pub fn new(world: &'w mut World) -> Self {
+        Self {
+            world: RefCell::new(world),
+            components: HashMap::new(),
+        }
+    }
+
+    /// 以name为名登记组件，脚本中用`Query<name>`查询
+    pub fn register_component<C: Component>(&mut self, name: &str) {
+        let id = self.world.get_mut().register_component::<C>();
+        self.components.insert(name.to_string(), id);
+    }
+}
+
+impl world::World for ScriptWorld<'_> {
+    fn component_id(&self, name: &str) -> Option<ScriptComponentId> {
+        self.components.get(name).map(|id| id.index())
+    }
+
+    fn entities(&self) -> Vec<world::Entity> {
+        self.query(&[], &[])
+    }
+
+    fn has_component(&self, entity: world::Entity, component: ScriptComponentId) -> bool {
+        let world = self.world.borrow();
+        Entity::try_from_bits(entity)
+            .and_then(|entity| world.get_entity(entity).ok())
+            .is_some_and(|entity| entity.contains_id(ComponentId::new(component)))
+    }
+
+    fn query(
+        &self,
+        with: &[ScriptComponentId],
+        without: &[ScriptComponentId],
+    ) -> Vec<world::Entity> {
+        let mut world = self.world.borrow_mut();
+        let mut builder = QueryBuilder::<Entity>::new(&mut world);
+        for &id in with {
+            builder.with_id(ComponentId::new(id));
+        }
+        for &id in without {
+            builder.without_id(ComponentId::new(id));
+        }
+        let mut query = builder.build();
+        query.iter(&world).map(Entity::to_bits).collect()
+    }
+
+    fn children(&self, entity: world::Entity) -> Vec<world::Entity> {
+        let world = self.world.borrow();
+        Entity::try_from_bits(entity)
+            .and_then(|entity| world.get::<Children>(entity))
+            .map(|children| children.iter().copied().map(Entity::to_bits).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bdscript::{
+        eval::{
+            builtin::arity, error::RuntimeError, interpreter::Interpreter, value::Value,
+            world::World as _,
+        },
+        parser::parse,
+    };
+    use bevy_ecs::{component::Component, entity::Entity, hierarchy::ChildOf, world::World};
+    use bevy_transform::components::Transform;
+
+    use crate::transform::translation;
+
+    use super::ScriptWorld;
+
+    #[derive(Component)]
+    struct Enemy;
+
+    #[derive(Component)]
+    struct Dead;
+
+    #[derive(Component)]
+    struct Turret;
+
+    #[derive(Component)]
+    struct Vehicle;
+
+    /// 执行脚本，返回最后一个表达式的值
+    fn eval(world: &ScriptWorld<'_>, src: &str) -> Result<Value, RuntimeError> {
+        let ast = parse(src).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_world(world);
+        interpreter.eval_program(&ast).map_err(|e| e.error)
+    }
+
+    fn entity_set(entities: &[Entity]) -> Value {
+        Value::EntitySet(entities.iter().map(|e| e.to_bits()).collect())
+    }
+
+    #[test]
+    fn query_components() {
+        let mut world = World::new();
+        let alive = world.spawn(Enemy).id();
+        let dead = world.spawn((Enemy, Dead)).id();
+        let corpse = world.spawn(Dead).id();
+        let mut script_world = ScriptWorld::new(&mut world);
+        script_world.register_component::<Enemy>("Enemy");
+        script_world.register_component::<Dead>("Dead");
+        script_world.register_component::<Turret>("Turret");
+        assert_eq!(
+            eval(&script_world, "Query<Enemy>"),
+            Ok(entity_set(&[alive, dead]))
+        );
+        assert_eq!(
+            eval(&script_world, "Query<Enemy|!Dead>"),
+            Ok(entity_set(&[alive]))
+        );
+        assert_eq!(
+            eval(&script_world, "Query<Dead, !Enemy>"),
+            Ok(entity_set(&[corpse]))
+        );
+        // 登记过但没有实体拥有的组件
+        assert_eq!(eval(&script_world, "Query<Turret>"), Ok(entity_set(&[])));
+        assert_eq!(
+            eval(&script_world, "Query<Boss>"),
+            Err(RuntimeError::UnknownComponent("Boss".to_string()))
+        );
+        assert!(
+            script_world.has_component(dead.to_bits(), script_world.component_id("Dead").unwrap())
+        );
+    }
+
+    #[test]
+    fn closest_enemies() {
+        let mut world = World::new();
+        let far = world
+            .spawn((Enemy, Transform::from_xyz(9.0, 0.0, 0.0)))
+            .id();
+        let near = world
+            .spawn((Enemy, Transform::from_xyz(1.0, 1.0, 0.0)))
+            .id();
+        let middle = world
+            .spawn((Enemy, Transform::from_xyz(0.0, -4.0, 0.0)))
+            .id();
+        let closest = world
+            .spawn((Enemy, Transform::from_xyz(0.0, 0.0, 1.0)))
+            .id();
+        // 死掉的敌人最近，但被排除
+        world.spawn((Enemy, Dead, Transform::from_xyz(0.5, 0.0, 0.0)));
+        world.spawn(Transform::from_xyz(0.1, 0.0, 0.0));
+        let positions: HashMap<_, _> = world
+            .query::<(Entity, &Transform)>()
+            .iter(&world)
+            .map(|(entity, transform)| (entity.to_bits(), translation(transform)))
+            .collect();
+        let mut script_world = ScriptWorld::new(&mut world);
+        script_world.register_component::<Enemy>("Enemy");
+        script_world.register_component::<Dead>("Dead");
+        let closest_three =
+            parse("Query<Enemy, !Dead> order_by length(position(entity)) limit 3").unwrap();
+        let all = parse("Query<Enemy, !Dead> order_by length(position(entity))").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_world(&script_world);
+        interpreter.register_fn("position", move |args| {
+            arity("position", args, 1)?;
+            Ok(positions
+                .get(&args[0].expect_entity(0)?)
+                .cloned()
+                .unwrap_or(Value::Nil))
+        });
+        let entities = |entities: &[Entity]| {
+            Value::Array(
+                entities
+                    .iter()
+                    .map(|entity| Value::Number(entity.to_bits().into()))
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            interpreter.eval_program(&closest_three),
+            Ok(entities(&[closest, near, middle]))
+        );
+        assert_eq!(
+            interpreter.eval_program(&all),
+            Ok(entities(&[closest, near, middle, far]))
+        );
+    }
+
+    #[test]
+    fn query_children() {
+        let mut world = World::new();
+        let tank = world.spawn(Vehicle).id();
+        let left = world.spawn((Turret, ChildOf(tank))).id();
+        let right = world.spawn((Turret, ChildOf(tank))).id();
+        // 装在车上的不是炮塔
+        world.spawn(ChildOf(tank));
+        let truck = world.spawn(Vehicle).id();
+        let mounted = world.spawn((Turret, ChildOf(truck))).id();
+        // 没有装在车上的炮塔
+        world.spawn(Turret);
+        let mut script_world = ScriptWorld::new(&mut world);
+        script_world.register_component::<Turret>("Turret");
+        script_world.register_component::<Vehicle>("Vehicle");
+        assert_eq!(
+            eval(
+                &script_world,
+                &format!("Query<Turret> of {}", tank.to_bits())
+            ),
+            Ok(entity_set(&[left, right]))
+        );
+        assert_eq!(
+            eval(&script_world, "Query<Turret> of Query<Vehicle>"),
+            Ok(entity_set(&[left, right, mounted]))
+        );
+        assert_eq!(
+            eval(
+                &script_world,
+                &format!("Query<Turret> of {}", left.to_bits())
+            ),
+            Ok(entity_set(&[]))
+        );
+    }
+}